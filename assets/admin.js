@@ -0,0 +1,245 @@
+"use strict";
+
+// --base-path, every absolute URL below starts with it
+const BASE = document.documentElement.dataset.base ?? "";
+
+const message = document.getElementById("message");
+let polling = null;
+
+// State-changing requests have to echo the CSRF cookie when signed in with a session
+function csrfToken() {
+  const cookie = document.cookie.split("; ").find((c) => c.startsWith("m3s_csrf="));
+  return cookie ? decodeURIComponent(cookie.slice("m3s_csrf=".length)) : "";
+}
+
+// The ID lets an admin find the failure in the logs
+function withRequestId(message, response) {
+  const id = response.headers.get("x-request-id");
+  return id ? `${message} (request ${id})` : message;
+}
+
+async function api(method, url, body) {
+  const headers = { "x-csrf-token": csrfToken() };
+  if (body !== undefined) {
+    headers["content-type"] = "application/json";
+  }
+  const response = await fetch(BASE + url, {
+    method,
+    headers,
+    credentials: "same-origin",
+    body: body === undefined ? undefined : JSON.stringify(body),
+  });
+  if (response.status === 401) {
+    location.href = BASE + "/login?next=" + encodeURIComponent(BASE + "/admin");
+    throw new Error("Signed out");
+  }
+  if (!response.ok) {
+    // Errors come as problem details, the detail says what was wrong when there's more to say
+    const problem = await response.json().catch(() => ({}));
+    const text = problem.detail || `${response.status} ${response.statusText}`;
+    throw new Error(withRequestId(text, response));
+  }
+  const type = response.headers.get("content-type") || "";
+  return type.includes("json") ? response.json() : null;
+}
+
+function report(error) {
+  message.textContent = error.message;
+  message.className = "error";
+}
+
+function notify(text) {
+  message.textContent = text;
+  message.className = "";
+}
+
+function formatBytes(bytes) {
+  const units = ["B", "KB", "MB", "GB", "TB"];
+  let unit = 0;
+  while (bytes >= 1024 && unit < units.length - 1) {
+    bytes /= 1024;
+    unit += 1;
+  }
+  return `${bytes.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+}
+
+function formatTime(time) {
+  return time ? new Date(time).toLocaleString() : "never";
+}
+
+function definitions(list, rows) {
+  list.replaceChildren(
+    ...rows.flatMap(([name, value]) => {
+      const dt = document.createElement("dt");
+      dt.textContent = name;
+      const dd = document.createElement("dd");
+      dd.textContent = value;
+      return [dt, dd];
+    }),
+  );
+}
+
+function cell(content) {
+  const td = document.createElement("td");
+  if (content instanceof Node) {
+    td.append(content);
+  } else {
+    td.textContent = content ?? "";
+  }
+  return td;
+}
+
+async function refreshStatus() {
+  const status = await api("GET", "/api/admin/status");
+  const scan = status.scan;
+
+  definitions(document.getElementById("scan"), [
+    ["State", scan.scanning ? `Scanning, ${scan.scanned} files so far` : "Idle"],
+    ...(status.reachable ? [] : [["Library", "Not reachable, check the mount"]]),
+    ["Scope", `${scan.scope.path ?? "Everything"}${scan.scope.full ? ", every file re-read" : ""}`],
+    ["Last started", formatTime(scan.started)],
+    ["Last finished", formatTime(scan.finished)],
+    ...(scan.error ? [["Last error", scan.error]] : []),
+  ]);
+  definitions(document.getElementById("storage"), [
+    ["Indexed files", `${status.index.files} (${formatBytes(status.index.bytes)})`],
+    ["Thumbnail cache", `${status.thumbnails.files} (${formatBytes(status.thumbnails.bytes)})`],
+    ...(status.disk
+      ? [["Free space", `${formatBytes(status.disk.available)} of ${formatBytes(status.disk.total)}`
+        + (status.low_on_space ? ", too little to save new thumbnails" : "")]]
+      : []),
+  ]);
+
+  document.querySelector("#errors tbody").replaceChildren(
+    ...status.errors.map((entry) => {
+      const tr = document.createElement("tr");
+      tr.className = entry.level.toLowerCase();
+      tr.append(
+        cell(formatTime(entry.time)),
+        cell(entry.level),
+        cell(entry.target),
+        cell(entry.message),
+        cell(entry.request_id ?? ""),
+      );
+      return tr;
+    }),
+  );
+
+  document.getElementById("rescan").disabled = scan.scanning;
+  document.getElementById("cancel-scan").hidden = !scan.scanning;
+  document.getElementById("prune").disabled = scan.scanning;
+
+  // Follow a running scan until it's done
+  clearTimeout(polling);
+  if (scan.scanning) {
+    polling = setTimeout(() => refreshStatus().catch(report), 2000);
+  }
+}
+
+async function refreshUsers() {
+  const users = await api("GET", "/api/admin/users");
+
+  document.querySelector("#users tbody").replaceChildren(
+    ...users.map((user) => {
+      const role = document.createElement("select");
+      for (const name of ["viewer", "uploader", "admin"]) {
+        role.append(new Option(name[0].toUpperCase() + name.slice(1), name, false, name === user.role));
+      }
+      role.addEventListener("change", () => {
+        api("PATCH", `/api/admin/users/${encodeURIComponent(user.username)}`, { role: role.value })
+          .then(() => notify(`${user.username} is now ${role.value}`))
+          .catch(report);
+      });
+
+      const remove = document.createElement("button");
+      remove.textContent = "Delete";
+      remove.addEventListener("click", () => {
+        if (!confirm(`Delete ${user.username}?`)) {
+          return;
+        }
+        api("DELETE", `/api/admin/users/${encodeURIComponent(user.username)}`)
+          .then(refreshUsers)
+          .then(() => notify(`Deleted ${user.username}`))
+          .catch(report);
+      });
+
+      const tr = document.createElement("tr");
+      tr.append(
+        cell(user.username),
+        cell(role),
+        cell(user.root ?? "Everything"),
+        cell(formatTime(user.created)),
+        cell(remove),
+      );
+      return tr;
+    }),
+  );
+}
+
+document.getElementById("new-user").addEventListener("submit", (event) => {
+  event.preventDefault();
+  const form = event.target;
+  const user = {
+    username: form.username.value,
+    password: form.password.value,
+    role: form.role.value,
+  };
+  api("POST", "/api/admin/users", user)
+    .then(() => {
+      form.reset();
+      notify(`Added ${user.username}`);
+      return refreshUsers();
+    })
+    .catch(report);
+});
+
+document.getElementById("rescan").addEventListener("click", () => {
+  const query = new URLSearchParams();
+  const folder = document.getElementById("scan-folder").value.trim();
+  if (folder) {
+    query.set("path", folder);
+  }
+  if (document.getElementById("scan-full").checked) {
+    query.set("full", "true");
+  }
+  api("POST", `/api/admin/scan?${query}`)
+    .then(() => notify("Scan started"))
+    .then(refreshStatus)
+    .catch(report);
+});
+
+document.getElementById("cancel-scan").addEventListener("click", () => {
+  api("POST", "/api/admin/scan/cancel")
+    .then(() => notify("Cancelling the scan, the index stays as it was"))
+    .then(refreshStatus)
+    .catch(report);
+});
+
+document.getElementById("verify").addEventListener("click", () => {
+  const output = document.getElementById("verification");
+  output.textContent = "Verifying…";
+  api("POST", "/api/admin/verify")
+    .then((result) => {
+      const problems = [
+        ...result.missing.map((path) => `Missing: ${path}`),
+        ...result.changed.map((path) => `Changed: ${path}`),
+      ];
+      output.textContent = problems.length === 0
+        ? `All ${result.checked} files are in place`
+        : `${problems.length} of ${result.checked} files need a rescan:\n${problems.join("\n")}`;
+    })
+    .catch((error) => {
+      output.textContent = "";
+      report(error);
+    });
+});
+
+document.getElementById("prune").addEventListener("click", () => {
+  api("POST", "/api/admin/prune")
+    .then((removed) => notify(`Removed ${removed.files} thumbnails (${formatBytes(removed.bytes)})`))
+    .then(refreshStatus)
+    .catch(report);
+});
+
+refreshStatus().catch(report);
+refreshUsers().catch(report);
@@ -0,0 +1,725 @@
+"use strict";
+
+// --base-path, every absolute URL below starts with it
+const BASE = document.documentElement.dataset.base ?? "";
+
+const grid = document.getElementById("grid");
+const status = document.getElementById("status");
+const folderList = document.getElementById("folders");
+const breadcrumbs = document.getElementById("breadcrumbs");
+const lightbox = document.getElementById("lightbox");
+
+// Items currently shown in the grid, in order, for lightbox navigation
+let items = [];
+let current = -1;
+// Bumped on every navigation so responses for a previous view are dropped
+let generation = 0;
+// Thumbnail widths the server makes, from the last listing
+let thumbnailSizes = [];
+
+// Filled from /locale.json before the first view renders
+let locale = "en";
+let messages = {};
+
+function t(key, params = {}) {
+  return (messages[key] ?? key).replace(/\{(\w+)\}/g, (_, name) => params[name] ?? "");
+}
+
+async function loadLocale() {
+  try {
+    ({ locale, messages } = await getJson(BASE + "/locale.json"));
+  } catch {
+    // Keys are shown as they are, better than no page
+  }
+  document.documentElement.lang = locale;
+  for (const element of document.querySelectorAll("[data-i18n]")) {
+    element.textContent = t(element.dataset.i18n);
+  }
+  for (const element of document.querySelectorAll("[data-i18n-title]")) {
+    element.title = t(element.dataset.i18nTitle);
+  }
+}
+
+function monthName(month, year, style = "long") {
+  return new Date(year, month - 1).toLocaleString(locale, { month: style });
+}
+
+function encodePath(path) {
+  return path.split("/").map(encodeURIComponent).join("/");
+}
+
+// The ID lets an admin find the failure in the logs
+function withRequestId(message, response) {
+  const id = response.headers.get("x-request-id");
+  return id ? `${message} (request ${id})` : message;
+}
+
+async function getJson(url) {
+  const response = await fetch(url, { credentials: "same-origin" });
+  if (response.status === 401) {
+    location.href = BASE + "/login?next=" + encodeURIComponent(location.pathname + location.hash);
+    throw new Error("Signed out");
+  }
+  if (!response.ok) {
+    throw new Error(withRequestId(`${response.status} ${response.statusText}`, response));
+  }
+  return response.json();
+}
+
+// A thumbnail or export URL, ending with `?`. Edits are part of it, so images from before an
+// edit aren't shown from the browser's cache.
+function derivedUrl(kind, item) {
+  const url = `${BASE}/api/media/${kind}/${encodePath(item.path)}?`;
+  return item.edits ? `${url}edits=${encodeURIComponent(JSON.stringify(item.edits))}&` : url;
+}
+
+// `width` is how wide the tile is drawn, the browser picks the thumbnail that covers it
+function tile(item, index, width = 360) {
+  const element = document.createElement("div");
+  element.className = `tile ${item.kind}`;
+  element.title = item.path;
+
+  const img = document.createElement("img");
+  img.loading = "lazy";
+  img.alt = item.path;
+  img.sizes = `${Math.ceil(width)}px`;
+  const src = derivedUrl("thumb", item);
+  const load = (retry) => {
+    const query = retry ? `&retry=${retry}` : "";
+    img.srcset = thumbnailSizes.map((size) => `${src}size=${size}${query} ${size}w`).join(", ");
+    img.src = retry ? `${src}retry=${retry}` : src;
+  };
+  load(0);
+  element.append(img);
+
+  // The server turns thumbnails away when too many are being made at once, try again a few
+  // times before leaving the tile blank. Videos have none to wait for.
+  let retries = 0;
+  img.addEventListener("error", () => {
+    if (item.kind === "image" && retries < 3) {
+      retries += 1;
+      setTimeout(() => load(retries), 2000 * retries);
+    }
+  });
+
+  element.addEventListener("click", () => open(index));
+  return element;
+}
+
+function append(newItems) {
+  const start = items.length;
+  items = items.concat(newItems);
+  grid.append(...newItems.map((item, i) => tile(item, start + i)));
+}
+
+function reset() {
+  generation += 1;
+  items = [];
+  observer?.disconnect();
+  observer = null;
+  sections = [];
+  document.getElementById("scrubber").replaceChildren();
+  document.body.classList.remove("timeline");
+  grid.classList.remove("justified");
+  grid.replaceChildren();
+  folderList.replaceChildren();
+  breadcrumbs.replaceChildren();
+  status.textContent = "";
+}
+
+// Justified rows: every row fills the width exactly, heights vary a little around TARGET_HEIGHT
+const TARGET_HEIGHT = 220;
+const GAP = 4;
+// Sections this far outside the viewport are loaded early and unloaded late
+const MARGIN = "1500px";
+
+function aspect(item) {
+  return item.width && item.height ? item.width / item.height : item.kind === "video" ? 16 / 9 : 1;
+}
+
+function justify(sectionItems, width) {
+  const boxes = [];
+  let row = [];
+  let ratios = 0;
+  let top = 0;
+
+  const place = (height) => {
+    let left = 0;
+    for (const item of row) {
+      const w = aspect(item) * height;
+      boxes.push({ left, top, width: w, height });
+      left += w + GAP;
+    }
+    top += height + GAP;
+    row = [];
+    ratios = 0;
+  };
+
+  for (const item of sectionItems) {
+    row.push(item);
+    ratios += aspect(item);
+    const gaps = (row.length - 1) * GAP;
+    if (ratios * TARGET_HEIGHT + gaps >= width) {
+      place((width - gaps) / ratios);
+    }
+  }
+  // The last row keeps its natural height instead of being stretched across
+  if (row.length > 0) {
+    place(TARGET_HEIGHT);
+  }
+
+  return { boxes, height: Math.max(top - GAP, 0) };
+}
+
+let sections = [];
+let observer = null;
+
+function estimateHeight(count, width) {
+  const perRow = Math.max(1, Math.floor(width / (TARGET_HEIGHT * 1.3)));
+  return Math.ceil(count / perRow) * (TARGET_HEIGHT + GAP);
+}
+
+async function loadSection(section, mine) {
+  if (section.items || section.loading) {
+    return;
+  }
+  section.loading = true;
+
+  const loaded = [];
+  while (loaded.length < section.month.count) {
+    const offset = section.month.offset + loaded.length;
+    const limit = Math.min(1000, section.month.count - loaded.length);
+    const page = await getJson(`${BASE}/api/timeline?offset=${offset}&limit=${limit}`);
+    if (mine !== generation) {
+      return;
+    }
+    if (page.items.length === 0) {
+      break;
+    }
+    thumbnailSizes = page.thumbnail_sizes;
+    loaded.push(...page.items);
+  }
+
+  loaded.forEach((item, i) => {
+    items[section.month.offset + i] = item;
+  });
+  section.items = loaded;
+  section.loading = false;
+  layoutSection(section);
+  if (section.visible) {
+    renderSection(section);
+  }
+}
+
+function layoutSection(section) {
+  const width = grid.clientWidth;
+  if (section.items) {
+    section.layout = justify(section.items, width);
+    section.body.style.height = `${section.layout.height}px`;
+  } else {
+    section.body.style.height = `${estimateHeight(section.month.count, width)}px`;
+  }
+}
+
+function renderSection(section) {
+  if (!section.layout || section.body.childElementCount > 0) {
+    return;
+  }
+  section.body.append(
+    ...section.items.map((item, i) => {
+      const box = section.layout.boxes[i];
+      const element = tile(item, section.month.offset + i, box.width);
+      Object.assign(element.style, {
+        left: `${box.left}px`,
+        top: `${box.top}px`,
+        width: `${box.width}px`,
+        height: `${box.height}px`,
+      });
+      return element;
+    }),
+  );
+}
+
+// Dropping far away tiles keeps the DOM small no matter how long the timeline is
+function unrenderSection(section) {
+  section.body.replaceChildren();
+}
+
+function scrubber(months) {
+  const list = document.getElementById("scrubber");
+  let year = null;
+  list.replaceChildren();
+
+  months.forEach((month, i) => {
+    if (month.year !== year) {
+      year = month.year;
+      const label = document.createElement("li");
+      label.className = "year";
+      label.textContent = year;
+      label.addEventListener("click", () => sections[i].element.scrollIntoView());
+      list.append(label);
+    }
+    const entry = document.createElement("li");
+    entry.className = "month";
+    entry.title = `${monthName(month.month, month.year)} ${month.year} (${month.count})`;
+    entry.textContent = monthName(month.month, month.year, "short");
+    entry.addEventListener("click", () => sections[i].element.scrollIntoView());
+    sections[i].marker = entry;
+    list.append(entry);
+  });
+}
+
+function highlightCurrent() {
+  const current = sections.find((s) => s.element.getBoundingClientRect().bottom > 80);
+  for (const section of sections) {
+    section.marker?.classList.toggle("current", section === current);
+  }
+}
+
+async function showTimeline() {
+  reset();
+  folderList.hidden = true;
+  breadcrumbs.hidden = true;
+  document.body.classList.add("timeline");
+
+  const mine = generation;
+  const months = await getJson(BASE + "/api/timeline/months");
+  if (mine !== generation) {
+    return;
+  }
+  if (months.length === 0) {
+    status.textContent = t("timeline.empty");
+    return;
+  }
+
+  grid.classList.add("justified");
+  sections = months.map((month) => {
+    const element = document.createElement("section");
+    const heading = document.createElement("h2");
+    heading.textContent = new Date(month.year, month.month - 1)
+      .toLocaleString(locale, { month: "long", year: "numeric" });
+    const body = document.createElement("div");
+    body.className = "rows";
+    element.append(heading, body);
+    return { month, element, body, items: null, layout: null, visible: false };
+  });
+  grid.append(...sections.map((s) => s.element));
+  sections.forEach(layoutSection);
+  scrubber(months);
+
+  observer = new IntersectionObserver(
+    (entries) => {
+      for (const entry of entries) {
+        const section = sections.find((s) => s.element === entry.target);
+        section.visible = entry.isIntersecting;
+        if (!section.visible) {
+          unrenderSection(section);
+        } else if (section.items) {
+          renderSection(section);
+        } else {
+          loadSection(section, mine).catch((error) => {
+            status.textContent = t("status.failed", { error: error.message });
+          });
+        }
+      }
+    },
+    { rootMargin: `${MARGIN} 0px` },
+  );
+  sections.forEach((s) => observer.observe(s.element));
+  highlightCurrent();
+}
+
+window.addEventListener("scroll", () => {
+  if (sections.length > 0) {
+    highlightCurrent();
+  }
+}, { passive: true });
+
+let resizeTimer = null;
+window.addEventListener("resize", () => {
+  clearTimeout(resizeTimer);
+  resizeTimer = setTimeout(() => {
+    for (const section of sections) {
+      unrenderSection(section);
+      layoutSection(section);
+      if (section.visible) {
+        renderSection(section);
+      }
+    }
+  }, 150);
+});
+
+async function showFolder(path) {
+  reset();
+  folderList.hidden = false;
+  breadcrumbs.hidden = false;
+
+  const mine = generation;
+  const folder = await getJson(BASE + (path ? "/api/folders/" + encodePath(path) : "/api/folders"));
+  if (mine !== generation) {
+    return;
+  }
+
+  const crumbs = [[t("folders.library"), ""]];
+  let prefix = "";
+  for (const part of path.split("/").filter(Boolean)) {
+    prefix = prefix ? `${prefix}/${part}` : part;
+    crumbs.push([part, prefix]);
+  }
+  breadcrumbs.replaceChildren(
+    ...crumbs.map(([name, target]) => {
+      const link = document.createElement("a");
+      link.href = "#/folders/" + encodePath(target);
+      link.textContent = name;
+      return link;
+    }),
+  );
+
+  folderList.replaceChildren(
+    ...folder.folders.map((sub) => {
+      const li = document.createElement("li");
+      const link = document.createElement("a");
+      link.href = "#/folders/" + encodePath(sub);
+      link.textContent = sub.split("/").pop();
+      li.append(link);
+      return li;
+    }),
+  );
+
+  thumbnailSizes = folder.thumbnail_sizes;
+  append(folder.items);
+  if (folder.folders.length === 0 && folder.items.length === 0) {
+    status.textContent = t("folders.empty");
+  }
+}
+
+const mediaBox = lightbox.querySelector(".media");
+const details = lightbox.querySelector(".details");
+
+// Longest edge panoramas are shown at. Phones make them tens of thousands of pixels wide, more
+// than is worth sending to be seen on a screen.
+const PANORAMA_SIZE = 8192;
+// What slow motion clips are slowed down to
+const NORMAL_FRAME_RATE = 30;
+
+// Zoom and pan state of the open image, in CSS pixels
+const view = { scale: 1, x: 0, y: 0 };
+const pointers = new Map();
+let pinch = null;
+
+function applyView() {
+  const img = mediaBox.querySelector("img");
+  if (!img) {
+    return;
+  }
+  img.style.transform = `translate(${view.x}px, ${view.y}px) scale(${view.scale})`;
+  mediaBox.classList.toggle("zoomed", view.scale > 1);
+}
+
+// Zooms around a point given in client coordinates, so whatever is under it stays put
+function zoomAt(scale, clientX, clientY) {
+  const img = mediaBox.querySelector("img");
+  if (!img) {
+    return;
+  }
+  const rect = mediaBox.getBoundingClientRect();
+  const px = clientX - rect.left;
+  const py = clientY - rect.top;
+  const next = Math.min(8, Math.max(1, scale));
+
+  view.x = px - ((px - view.x) * next) / view.scale;
+  view.y = py - ((py - view.y) * next) / view.scale;
+  view.scale = next;
+  if (next === 1) {
+    view.x = 0;
+    view.y = 0;
+  }
+  applyView();
+}
+
+function zoomCentre(factor) {
+  const rect = mediaBox.getBoundingClientRect();
+  zoomAt(view.scale * factor, rect.left + rect.width / 2, rect.top + rect.height / 2);
+}
+
+mediaBox.addEventListener("wheel", (event) => {
+  event.preventDefault();
+  zoomAt(view.scale * Math.pow(1.002, -event.deltaY), event.clientX, event.clientY);
+}, { passive: false });
+
+mediaBox.addEventListener("dblclick", (event) => {
+  zoomAt(view.scale > 1 ? 1 : 2.5, event.clientX, event.clientY);
+});
+
+mediaBox.addEventListener("pointerdown", (event) => {
+  if (!mediaBox.querySelector("img")) {
+    return;
+  }
+  mediaBox.setPointerCapture(event.pointerId);
+  pointers.set(event.pointerId, { x: event.clientX, y: event.clientY });
+  pinch = null;
+});
+
+mediaBox.addEventListener("pointermove", (event) => {
+  const last = pointers.get(event.pointerId);
+  if (!last) {
+    return;
+  }
+  const now = { x: event.clientX, y: event.clientY };
+  pointers.set(event.pointerId, now);
+
+  if (pointers.size === 2) {
+    const [a, b] = [...pointers.values()];
+    const distance = Math.hypot(a.x - b.x, a.y - b.y);
+    if (pinch) {
+      zoomAt(view.scale * (distance / pinch), (a.x + b.x) / 2, (a.y + b.y) / 2);
+    }
+    pinch = distance;
+  } else if (view.scale > 1) {
+    view.x += now.x - last.x;
+    view.y += now.y - last.y;
+    applyView();
+  }
+});
+
+for (const type of ["pointerup", "pointercancel"]) {
+  mediaBox.addEventListener(type, (event) => {
+    pointers.delete(event.pointerId);
+    pinch = null;
+  });
+}
+
+function formatExposure(seconds) {
+  return seconds >= 1 ? `${seconds}s` : `1/${Math.round(1 / seconds)}s`;
+}
+
+async function showDetails(item) {
+  const list = details.querySelector("dl");
+  const rows = [
+    [t("details.path"), item.path],
+    [t("details.taken"), new Date(item.taken).toLocaleString(locale)],
+    [t("details.size"), `${(item.size / 1024 / 1024).toLocaleString(locale, { maximumFractionDigits: 1 })} MB`],
+  ];
+  if (item.width && item.height) {
+    rows.push([t("details.dimensions"), `${item.width} × ${item.height}${item.hdr ? " · HDR" : ""}`]);
+  }
+  if (item.frame_rate) {
+    const speed = item.speed ? ` · ${t(`speed.${item.speed}`)}` : "";
+    rows.push([t("details.frame_rate"), `${item.frame_rate.toLocaleString(locale)} fps${speed}`]);
+  }
+
+  const render = () => {
+    list.replaceChildren(
+      ...rows.flatMap(([name, value]) => {
+        const dt = document.createElement("dt");
+        dt.textContent = name;
+        const dd = document.createElement("dd");
+        dd.textContent = value;
+        return [dt, dd];
+      }),
+    );
+  };
+  render();
+
+  const info = await getJson(BASE + "/api/media/info/" + encodePath(item.path));
+  // The user may have moved on while this was loading
+  if (items[current] !== item || !info.exif) {
+    return;
+  }
+  const exif = info.exif;
+  const camera = [exif.make, exif.model].filter(Boolean).join(" ");
+  const settings = [
+    exif.f_number && `ƒ/${exif.f_number.toFixed(1)}`,
+    exif.exposure_time && formatExposure(exif.exposure_time),
+    exif.iso && `ISO ${exif.iso}`,
+    exif.focal_length && `${Math.round(exif.focal_length)} mm`,
+  ].filter(Boolean);
+
+  if (camera) rows.push([t("details.camera"), camera]);
+  if (exif.lens) rows.push([t("details.lens"), exif.lens]);
+  if (settings.length > 0) rows.push([t("details.exposure"), settings.join(" · ")]);
+  if (exif.software) rows.push([t("details.software"), exif.software]);
+  render();
+}
+
+function open(index) {
+  // Timeline items are only known once their month has loaded
+  if (index < 0 || index >= items.length || !items[index]) {
+    return;
+  }
+  current = index;
+  const item = items[index];
+  const url = BASE + "/api/media/file/" + encodePath(item.path);
+
+  let media;
+  if (item.kind === "video") {
+    media = document.createElement("video");
+    media.controls = true;
+    media.autoplay = true;
+    media.preload = "metadata";
+    media.playsInline = true;
+    // Slow motion stored at the rate it was filmed plays in real time unless slowed down here
+    if (item.speed === "slow_motion" && item.frame_rate >= 2 * NORMAL_FRAME_RATE) {
+      media.defaultPlaybackRate = Math.max(NORMAL_FRAME_RATE / item.frame_rate, 0.125);
+    }
+    // No transcoding on the server yet, so formats like HEVC in Firefox can only be downloaded
+    media.addEventListener("error", () => {
+      if (current !== index) {
+        return;
+      }
+      const message = document.createElement("p");
+      message.className = "unsupported";
+      message.textContent = t("viewer.unsupported_video");
+      mediaBox.replaceChildren(message);
+    });
+  } else {
+    media = document.createElement("img");
+    media.alt = item.path;
+    media.draggable = false;
+  }
+  // Edits are only in the thumbnails and exports, the file is the photo as it was taken
+  if (item.panorama) {
+    media.src = `${derivedUrl("export", item)}max=${PANORAMA_SIZE}`;
+    media.addEventListener("load", () => fillPanorama(index, media), { once: true });
+  } else if (item.edits && thumbnailSizes.length > 0) {
+    media.src = `${derivedUrl("thumb", item)}size=${thumbnailSizes.at(-1)}`;
+  } else {
+    media.src = url;
+  }
+
+  view.scale = 1;
+  view.x = 0;
+  view.y = 0;
+  mediaBox.classList.remove("zoomed");
+  mediaBox.replaceChildren(media);
+
+  const download = lightbox.querySelector(".download");
+  download.href = url;
+  download.download = item.path.split("/").pop();
+
+  lightbox.querySelector("figcaption").textContent =
+    `${item.path} · ${new Date(item.taken).toLocaleString(locale)}`;
+  lightbox.hidden = false;
+
+  if (!details.hidden) {
+    showDetails(item).catch(() => {});
+  }
+}
+
+// Fitted to the screen a panorama is a thin strip, so it opens filling it the other way, from
+// its start, to be panned across
+function fillPanorama(index, img) {
+  if (current !== index || img.clientWidth === 0 || img.clientHeight === 0) {
+    return;
+  }
+  const rect = mediaBox.getBoundingClientRect();
+  const scale = Math.max(
+    (window.innerHeight * 0.85) / img.clientHeight,
+    rect.width / img.clientWidth,
+  );
+  zoomAt(scale, rect.left, rect.top);
+}
+
+function close() {
+  lightbox.hidden = true;
+  mediaBox.replaceChildren();
+  current = -1;
+}
+
+function toggleDetails() {
+  details.hidden = !details.hidden;
+  if (!details.hidden && items[current]) {
+    showDetails(items[current]).catch(() => {});
+  }
+}
+
+async function share() {
+  const item = items[current];
+  if (!item) {
+    return;
+  }
+  const url = new URL(BASE + "/api/media/file/" + encodePath(item.path), location.href);
+  // Whoever it's shared with gets the still, not a motion photo's video too
+  if (item.kind === "image") {
+    url.searchParams.set("strip_motion", "true");
+  }
+  if (navigator.share) {
+    await navigator.share({ title: item.path.split("/").pop(), url: url.href });
+  } else {
+    await navigator.clipboard.writeText(url.href);
+    status.textContent = t("viewer.link_copied");
+  }
+}
+
+lightbox.querySelector(".close").addEventListener("click", close);
+lightbox.querySelector(".info").addEventListener("click", toggleDetails);
+lightbox.querySelector(".share").addEventListener("click", () => share().catch(() => {}));
+lightbox.querySelector(".prev").addEventListener("click", () => open(current - 1));
+lightbox.querySelector(".next").addEventListener("click", () => open(current + 1));
+lightbox.addEventListener("click", (event) => {
+  if (event.target === lightbox) {
+    close();
+  }
+});
+
+document.addEventListener("keydown", (event) => {
+  if (lightbox.hidden || event.ctrlKey || event.metaKey || event.altKey) {
+    return;
+  }
+  switch (event.key) {
+    case "Escape":
+      close();
+      break;
+    case "ArrowLeft":
+      open(current - 1);
+      break;
+    case "ArrowRight":
+      open(current + 1);
+      break;
+    case "i":
+      toggleDetails();
+      break;
+    case "d":
+      lightbox.querySelector(".download").click();
+      break;
+    case "+":
+    case "=":
+      zoomCentre(1.5);
+      break;
+    case "-":
+      zoomCentre(1 / 1.5);
+      break;
+    case "0":
+      zoomAt(1, 0, 0);
+      break;
+    default:
+      return;
+  }
+  event.preventDefault();
+});
+
+function route() {
+  close();
+  const hash = decodeURIComponent(location.hash.replace(/^#\/?/, ""));
+  const view = hash.startsWith("folders") ? "folders" : "timeline";
+
+  for (const link of document.querySelectorAll("header nav a")) {
+    link.classList.toggle("active", link.dataset.view === view);
+  }
+
+  const shown = view === "folders" ? showFolder(hash.replace(/^folders\/?/, "")) : showTimeline();
+  shown.catch((error) => {
+    status.textContent = t("status.failed", { error: error.message });
+  });
+}
+
+window.addEventListener("hashchange", route);
+loadLocale().then(route);
+
+// Installable and usable offline; needs a secure context, so plain HTTP on the LAN just skips it
+if ("serviceWorker" in navigator) {
+  navigator.serviceWorker.register(BASE + "/sw.js").catch((error) => console.warn("Service worker failed", error));
+  document.querySelector("form[action$='/logout']").addEventListener("submit", () => {
+    navigator.serviceWorker.controller?.postMessage("sign-out");
+  });
+}
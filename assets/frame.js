@@ -0,0 +1,99 @@
+"use strict";
+
+// Options come from the URL so each frame can be set up once and left alone:
+// /frame?interval=30&folder=Holidays/2023&clock=0
+const params = new URLSearchParams(location.search);
+const INTERVAL = Math.max(3, Number(params.get("interval")) || 15) * 1000;
+const FOLDER = params.get("folder");
+const RETRY = 30 * 1000;
+// --base-path, every absolute URL below starts with it
+const BASE = document.documentElement.dataset.base ?? "";
+
+const slides = [...document.querySelectorAll(".slide")];
+const clock = document.getElementById("clock");
+let queue = [];
+let showing = 0;
+
+function encodePath(path) {
+  return path.split("/").map(encodeURIComponent).join("/");
+}
+
+// The ID lets an admin find the failure in the logs
+function withRequestId(message, response) {
+  const id = response.headers.get("x-request-id");
+  return id ? `${message} (request ${id})` : message;
+}
+
+async function refill() {
+  const url = BASE + (FOLDER ? "/api/slideshow?folder=" + encodeURIComponent(FOLDER) : "/api/slideshow");
+  const response = await fetch(url, { credentials: "same-origin" });
+  if (response.status === 401) {
+    location.href = BASE + "/login?next=" + encodeURIComponent(location.pathname + location.search);
+    throw new Error("Signed out");
+  }
+  if (!response.ok) {
+    throw new Error(withRequestId(`${response.status} ${response.statusText}`, response));
+  }
+  queue = await response.json();
+}
+
+// Resolves once the image is decoded, so the swap never shows a half loaded photo
+function load(item) {
+  const img = new Image();
+  img.src = BASE + "/api/media/file/" + encodePath(item.path);
+  return img.decode().then(() => img.src);
+}
+
+async function advance() {
+  try {
+    if (queue.length === 0) {
+      await refill();
+    }
+    const item = queue.shift();
+    if (item) {
+      const src = await load(item);
+      const next = slides[1 - showing];
+      next.src = src;
+      next.classList.add("shown");
+      slides[showing].classList.remove("shown");
+      showing = 1 - showing;
+    }
+    setTimeout(advance, INTERVAL);
+  } catch (error) {
+    // Network blips and deleted files keep the current photo up and try again later
+    console.warn("Slideshow paused", error);
+    setTimeout(advance, queue.length > 0 ? INTERVAL : RETRY);
+  }
+}
+
+function tick() {
+  clock.textContent = new Date().toLocaleTimeString([], { hour: "2-digit", minute: "2-digit" });
+  setTimeout(tick, 60 * 1000 - (Date.now() % (60 * 1000)));
+}
+
+// Keep the screen on where the browser allows it, the lock is dropped whenever the page is hidden
+async function keepAwake() {
+  try {
+    await navigator.wakeLock?.request("screen");
+  } catch {
+    // Not supported or not allowed, the device's own settings apply
+  }
+}
+
+document.addEventListener("visibilitychange", () => {
+  if (document.visibilityState === "visible") {
+    keepAwake();
+  }
+});
+document.addEventListener("click", () => {
+  if (!document.fullscreenElement) {
+    document.documentElement.requestFullscreen?.().catch(() => {});
+  }
+});
+
+if (params.get("clock") !== "0") {
+  clock.hidden = false;
+  tick();
+}
+keepAwake();
+advance();
@@ -0,0 +1,103 @@
+"use strict";
+
+// Bump to drop every cache when the caching rules change
+const VERSION = "v2";
+const SHELL = `shell-${VERSION}`;
+const DATA = `data-${VERSION}`;
+
+// Registered from --base-path, which makes that its scope
+const BASE = new URL(self.registration.scope).pathname.replace(/\/$/, "");
+const SHELL_FILES = ["/", "/app.js", "/style.css", "/locale.json", "/manifest.webmanifest", "/icon-192.png", "/icon-512.png"]
+  .map((file) => BASE + file);
+
+self.addEventListener("install", (event) => {
+  event.waitUntil(
+    caches.open(SHELL)
+      .then((cache) => cache.addAll(SHELL_FILES))
+      .then(() => self.skipWaiting()),
+  );
+});
+
+self.addEventListener("activate", (event) => {
+  event.waitUntil(
+    caches.keys()
+      .then((keys) => Promise.all(keys.filter((key) => key !== SHELL && key !== DATA).map((key) => caches.delete(key))))
+      .then(() => self.clients.claim()),
+  );
+});
+
+// Cached pages and thumbnails must not outlive the session on a shared device
+function signedOut(response) {
+  // Navigations see the redirect to the login page unfollowed, everything else follows it or gets a 401
+  return response.status === 401
+    || response.type === "opaqueredirect"
+    || (response.redirected && new URL(response.url).pathname === BASE + "/login");
+}
+
+async function clearData() {
+  await caches.delete(DATA);
+  await caches.delete(SHELL);
+}
+
+// The shell is fetched fresh while online, the cached copy only stands in when offline
+async function networkFirst(request, cacheName, fallback) {
+  const cache = await caches.open(cacheName);
+  try {
+    const response = await fetch(request);
+    if (signedOut(response)) {
+      await clearData();
+    } else if (response.ok) {
+      await cache.put(fallback || request, response.clone());
+    }
+    return response;
+  } catch (error) {
+    const cached = await cache.match(fallback || request);
+    if (cached) {
+      return cached;
+    }
+    throw error;
+  }
+}
+
+// Thumbnails are keyed on the file's modification time server side, so a cached one stays good.
+// Stored without the retry count, so retries share an entry while each size gets its own.
+async function cacheFirst(request) {
+  const cache = await caches.open(DATA);
+  const key = new URL(request.url);
+  key.searchParams.delete("retry");
+  const cached = await cache.match(key);
+  if (cached) {
+    return cached;
+  }
+  const response = await fetch(request);
+  if (response.ok) {
+    await cache.put(key, response.clone());
+  }
+  return response;
+}
+
+self.addEventListener("fetch", (event) => {
+  const request = event.request;
+  const url = new URL(request.url);
+  if (request.method !== "GET" || url.origin !== location.origin) {
+    return;
+  }
+
+  const path = url.pathname.slice(BASE.length);
+  if (request.mode === "navigate" && url.pathname === BASE + "/") {
+    event.respondWith(networkFirst(request, SHELL, BASE + "/"));
+  } else if (SHELL_FILES.includes(url.pathname)) {
+    event.respondWith(networkFirst(request, SHELL));
+  } else if (path.startsWith("/api/media/thumb/")) {
+    event.respondWith(cacheFirst(request));
+  } else if (path === "/api/timeline" || path.startsWith("/api/timeline/") || path.startsWith("/api/folders")) {
+    event.respondWith(networkFirst(request, DATA));
+  }
+  // Everything else, including full size files and the admin pages, goes straight to the network
+});
+
+self.addEventListener("message", (event) => {
+  if (event.data === "sign-out") {
+    event.waitUntil(clearData());
+  }
+});
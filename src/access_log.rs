@@ -0,0 +1,132 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::Result;
+use axum::{
+    body::HttpBody as _,
+    extract::{OriginalUri, Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::json;
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::{
+    args::{AccessLogArgs, AccessLogFormat, LogFileArgs},
+    logging::LogFile,
+    network::ClientIp,
+    request_id,
+};
+
+/// Who a request was made by, as the audit log names them, for the access log to pick up from
+/// the response
+#[derive(Debug, Clone)]
+pub struct User(pub String);
+
+/// One line per request, kept apart from the application's own logs
+pub struct AccessLog {
+    format: AccessLogFormat,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    /// None without --access-log. A file is rotated the same way as --log-file.
+    pub fn new(args: AccessLogArgs, rotation: &LogFileArgs) -> Result<Option<Self>> {
+        let out: Box<dyn Write + Send> = match args.access_log {
+            None => return Ok(None),
+            Some(path) if path == Path::new("-") => Box::new(io::stdout()),
+            Some(path) => Box::new(LogFile::open(&path, rotation)?),
+        };
+        Ok(Some(Self {
+            format: args.access_log_format,
+            out: Mutex::new(out),
+        }))
+    }
+}
+
+// Paths only, query strings can carry things like single sign-on codes
+pub async fn access_log(
+    State(log): State<Arc<AccessLog>>,
+    OriginalUri(uri): OriginalUri,
+    ClientIp(ip): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let version = request.version();
+    let referer = header_value(request.headers(), header::REFERER).map(str::to_string);
+    let user_agent = header_value(request.headers(), header::USER_AGENT).map(str::to_string);
+    let request_id = request_id::get(request.extensions()).map(str::to_string);
+
+    let response = next.run(request).await;
+
+    let latency = started.elapsed().as_millis();
+    let status = response.status().as_u16();
+    // Streamed bodies have no length up front, those are logged as - like other servers do
+    let bytes = header_value(response.headers(), header::CONTENT_LENGTH)
+        .and_then(|length| length.parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact());
+    let user = response
+        .extensions()
+        .get::<User>()
+        .map(|User(user)| user.as_str());
+    let now = OffsetDateTime::now_utc();
+
+    let line = match log.format {
+        AccessLogFormat::Combined => {
+            let time = now
+                .format(format_description!(
+                    "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000"
+                ))
+                .unwrap_or_default();
+            format!(
+                r#"{ip} - {} [{time}] "{method} {} {version:?}" {status} {} "{}" "{}" {latency} "{}""#,
+                user.unwrap_or("-"),
+                uri.path(),
+                bytes.map_or("-".to_string(), |b| b.to_string()),
+                quoted(referer.as_deref()),
+                quoted(user_agent.as_deref()),
+                quoted(request_id.as_deref()),
+            )
+        }
+        AccessLogFormat::Json => json!({
+            "time": now.format(&time::format_description::well_known::Rfc3339).unwrap_or_default(),
+            "method": method.as_str(),
+            "path": uri.path(),
+            "status": status,
+            "latency_ms": latency,
+            "bytes": bytes,
+            "client": ip,
+            "user": user,
+            "referer": referer,
+            "user_agent": user_agent,
+            "request_id": request_id,
+        })
+        .to_string(),
+    };
+
+    let mut out = log.out.lock().unwrap();
+    if let Err(e) = writeln!(out, "{line}") {
+        tracing::warn!("Failed to write access log: {e}");
+    }
+    drop(out);
+
+    response
+}
+
+// Escaped the way Apache does, so a quote in a header can't end the field early
+fn quoted(value: Option<&str>) -> String {
+    value.map_or("-".to_string(), |v| {
+        v.replace('\\', "\\\\").replace('"', "\\\"")
+    })
+}
+
+fn header_value(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
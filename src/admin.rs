@@ -0,0 +1,237 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api_error::ApiError,
+    auth::require_role,
+    library::{Library, ScanScope, ScanStatus},
+    recent_errors::{LogEntry, RecentErrors},
+    reload::Reloader,
+    thumbnails::{DiskUsage, FreeSpace, Thumbnails},
+    users::Role,
+    webhooks::{Event, Webhooks},
+};
+
+#[derive(Clone)]
+pub struct AdminState {
+    pub library: Arc<Library>,
+    pub thumbnails: Arc<Thumbnails>,
+    pub errors: RecentErrors,
+    pub reloader: Arc<Reloader>,
+    pub webhooks: Arc<Webhooks>,
+}
+
+pub fn router() -> Router<AdminState> {
+    let router = Router::new()
+        .route("/status", get(status))
+        .route("/scan", post(scan))
+        .route("/scan/status", get(scan_status))
+        .route("/scan/cancel", post(cancel_scan))
+        .route("/verify", post(verify))
+        .route("/prune", post(prune))
+        .route("/reload", post(reload))
+        .route("/log-level", get(log_level).put(set_log_level));
+    #[cfg(all(unix, feature = "profiling"))]
+    let router = router.route(
+        "/profile",
+        get(crate::profiling::profile).route_layer(middleware::from_fn(crate::timeout::unlimited)),
+    );
+    router.route_layer(middleware::from_fn_with_state(Role::Admin, require_role))
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    scan: ScanStatus,
+    reachable: bool,
+    index: DiskUsage,
+    thumbnails: DiskUsage,
+    // Where thumbnails are kept, missing if it couldn't be told
+    disk: Option<FreeSpace>,
+    low_on_space: bool,
+    errors: Vec<LogEntry>,
+}
+
+async fn status(State(state): State<AdminState>) -> Result<Json<Status>, ApiError> {
+    let media = state.library.list();
+    let index = DiskUsage {
+        files: media.len(),
+        bytes: media.iter().map(|m| m.size).sum(),
+    };
+
+    // Walks the cache directory, which can take a moment on a big library
+    let thumbnails = state.thumbnails.clone();
+    let (thumbnails, disk) =
+        tokio::task::spawn_blocking(move || (thumbnails.size(), thumbnails.free_space().ok()))
+            .await
+            .map_err(|e| ApiError::internal("Failed to measure thumbnails", e))?;
+
+    Ok(Json(Status {
+        scan: state.library.status(),
+        reachable: state.library.reachable(),
+        index,
+        thumbnails,
+        disk,
+        low_on_space: state.thumbnails.low_on_space(),
+        errors: state.errors.list(),
+    }))
+}
+
+// ?full=true re-reads every file, ?path=some/folder rescans only that folder
+async fn scan(
+    State(state): State<AdminState>,
+    Query(scope): Query<ScanScope>,
+) -> Result<StatusCode, ApiError> {
+    if state.library.status().scanning {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "A scan is already running",
+        ));
+    }
+    if !state.library.reachable() {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The library is not reachable, it's rescanned once it's back",
+        ));
+    }
+    if let Some(path) = &scope.path {
+        state
+            .library
+            .scan_folder(path)
+            .map_err(|e| ApiError::invalid_or_internal("Failed to scan folder", e))?;
+    }
+
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = state.library.scan_scope(scope) {
+            tracing::error!("Library scan failed: {e:#}");
+        }
+    });
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+// Just the scan from /status, cheap enough for scripts to poll
+async fn scan_status(State(state): State<AdminState>) -> Json<ScanStatus> {
+    Json(state.library.status())
+}
+
+// The index stays as it was before the scan, which finishes with "Cancelled" as its error
+async fn cancel_scan(State(state): State<AdminState>) -> Result<StatusCode, ApiError> {
+    match state.library.cancel_scan() {
+        true => Ok(StatusCode::ACCEPTED),
+        false => Err(ApiError::new(StatusCode::CONFLICT, "No scan is running")),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Verification {
+    checked: usize,
+    // Indexed files that are gone or have changed on disk, a rescan picks them up
+    missing: Vec<String>,
+    changed: Vec<String>,
+}
+
+async fn verify(State(state): State<AdminState>) -> Result<Json<Verification>, ApiError> {
+    let library = state.library.clone();
+    let verification = tokio::task::spawn_blocking(move || {
+        let media = library.list();
+        let mut verification = Verification {
+            checked: media.len(),
+            missing: Vec::new(),
+            changed: Vec::new(),
+        };
+
+        for media in media {
+            let path = media.path.to_string_lossy().into_owned();
+            let Ok(file) = library.root().resolve(&media.path) else {
+                verification.missing.push(path);
+                continue;
+            };
+            let unchanged = std::fs::metadata(file.absolute()).is_ok_and(|metadata| {
+                metadata.len() == media.size
+                    && metadata
+                        .modified()
+                        .is_ok_and(|modified| media.modified == modified)
+            });
+            if !unchanged {
+                verification.changed.push(path);
+            }
+        }
+
+        verification
+    })
+    .await
+    .map_err(|e| ApiError::internal("Failed to verify the index", e))?;
+
+    if !verification.missing.is_empty() || !verification.changed.is_empty() {
+        let data = serde_json::to_value(&verification)
+            .map_err(|e| ApiError::internal("Failed to verify the index", e))?;
+        state.webhooks.send(Event::IntegrityFailed, data);
+    }
+
+    Ok(Json(verification))
+}
+
+async fn prune(State(state): State<AdminState>) -> Result<Json<DiskUsage>, ApiError> {
+    // Against a partial index this would throw away thumbnails that are still needed
+    let status = state.library.status();
+    if status.scanning || status.finished.is_none() || status.error.is_some() {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "Pruning needs a complete scan of the library",
+        ));
+    }
+
+    let media = state.library.list();
+    let thumbnails = state.thumbnails.clone();
+    let removed = tokio::task::spawn_blocking(move || thumbnails.prune(&media))
+        .await
+        .map_err(|e| ApiError::internal("Failed to prune thumbnails", e))?;
+    Ok(Json(removed))
+}
+
+#[derive(Debug, Serialize)]
+struct Reloaded {
+    changed: Vec<String>,
+}
+
+// Same as sending SIGHUP, for setups where signalling the process is awkward
+async fn reload(State(state): State<AdminState>) -> Result<Json<Reloaded>, ApiError> {
+    let changed = state
+        .reloader
+        .reload()
+        .map_err(|e| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    Ok(Json(Reloaded { changed }))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LogLevel {
+    level: String,
+}
+
+async fn log_level(State(state): State<AdminState>) -> Result<Json<LogLevel>, ApiError> {
+    let level = state
+        .reloader
+        .log_level()
+        .map_err(|e| ApiError::internal("Failed to read the log level", e))?;
+    Ok(Json(LogLevel { level }))
+}
+
+// Takes the same directives as --log-level, e.g. "info,mmms::library=trace"
+async fn set_log_level(
+    State(state): State<AdminState>,
+    Json(LogLevel { level }): Json<LogLevel>,
+) -> Result<Json<LogLevel>, ApiError> {
+    let level = state
+        .reloader
+        .set_log_level(&level)
+        .map_err(|e| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    Ok(Json(LogLevel { level }))
+}
@@ -0,0 +1,199 @@
+use std::{any::Any, fmt};
+
+use axum::{
+    body::{self, Body},
+    extract::Request,
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::{request_id, safe_path::PathError};
+
+// Plain text bodies bigger than this aren't an error message worth passing on
+const MAX_DETAIL: usize = 4096;
+
+/// A change turned down because of what was asked for, rather than anything going wrong on
+/// the server. Told to the client by [`ApiError::invalid_or_internal`].
+#[derive(Debug)]
+pub struct Invalid(pub String);
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+/// An error for API clients, sent as an RFC 7807 problem by [`problem_details`]
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    status: StatusCode,
+    detail: Option<String>,
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    /// `detail` is shown to the client, so it says what was wrong with the request
+    pub fn new(status: StatusCode, detail: impl fmt::Display) -> Self {
+        Self {
+            status,
+            detail: Some(format!("{detail:#}")),
+            retry_after: None,
+        }
+    }
+
+    /// For 429 and 503, how many seconds the client should wait before trying again
+    pub fn with_retry_after(self, seconds: u64) -> Self {
+        Self {
+            retry_after: Some(seconds),
+            ..self
+        }
+    }
+
+    /// Logs the cause, the client is only told that something went wrong
+    pub fn internal(context: &str, e: impl fmt::Display) -> Self {
+        tracing::error!("{context}: {e:#}");
+        StatusCode::INTERNAL_SERVER_ERROR.into()
+    }
+
+    /// 400 with the reason for an [`Invalid`] change or path, [`ApiError::internal`] for anything
+    /// else, such as a store that couldn't be saved
+    pub fn invalid_or_internal(context: &str, e: anyhow::Error) -> Self {
+        let invalid = e.downcast_ref::<Invalid>().is_some()
+            || matches!(e.downcast_ref::<PathError>(), Some(PathError::Invalid(_)));
+        match invalid {
+            true => Self::new(StatusCode::BAD_REQUEST, e),
+            false => Self::internal(context, e),
+        }
+    }
+}
+
+impl From<StatusCode> for ApiError {
+    fn from(status: StatusCode) -> Self {
+        Self {
+            status,
+            detail: None,
+            retry_after: None,
+        }
+    }
+}
+
+// The body is written by problem_details, which knows the request ID
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = self.status.into_response();
+        if let Some(seconds) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, seconds.into());
+        }
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+/// Gives every error under /api an application/problem+json body.
+///
+/// That includes bare status codes from middleware such as authentication and rate limiting,
+/// and extractor rejections, whose plain text message becomes the detail.
+pub async fn problem_details(request: Request, next: Next) -> Response {
+    if !request.uri().path().starts_with("/api/") {
+        return next.run(request).await;
+    }
+    let request_id = request_id::get(request.extensions()).map(str::to_string);
+
+    let response = next.run(request).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let detail = match parts.extensions.remove::<ApiError>() {
+        Some(error) => error.detail,
+        None if is_text(&parts.headers) => body::to_bytes(body, MAX_DETAIL)
+            .await
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes.to_vec()).ok())
+            .filter(|text| !text.is_empty()),
+        None => None,
+    };
+
+    let problem = Problem {
+        kind: "about:blank",
+        title: status.canonical_reason().unwrap_or("Error"),
+        status: status.as_u16(),
+        detail,
+        request_id: request_id.as_deref(),
+    };
+    let body = serde_json::to_vec(&problem).expect("problems always serialize");
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/problem+json"),
+    );
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// For CatchPanicLayer, so a handler that panics answers 500 instead of dropping the connection.
+/// Logged inside the request's span, with its ID.
+pub fn panicked(panic: Box<dyn Any + Send>) -> Response {
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("no message");
+    ApiError::internal("Request handler panicked", message).into_response()
+}
+
+fn is_text(headers: &header::HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/plain"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn only_invalid_changes_are_bad_requests() {
+        let invalid = ApiError::invalid_or_internal("ctx", Invalid("No".into()).into());
+        assert_eq!(invalid.status, StatusCode::BAD_REQUEST);
+        assert_eq!(invalid.detail.as_deref(), Some("No"));
+
+        let path = PathError::Invalid(PathBuf::from("../x"));
+        let invalid = ApiError::invalid_or_internal("ctx", path.into());
+        assert_eq!(invalid.status, StatusCode::BAD_REQUEST);
+
+        let missing = PathError::NotFound(PathBuf::from("x"));
+        let internal = ApiError::invalid_or_internal("ctx", missing.into());
+        assert_eq!(internal.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        let io = Err::<(), _>(anyhow!("Is a directory")).context("Failed to write users.json");
+        let internal = ApiError::invalid_or_internal("ctx", io.unwrap_err());
+        assert_eq!(internal.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(internal.detail, None);
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::{Method, StatusCode},
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get},
+    Json, Router,
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine as _};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::{
+    api_error::ApiError,
+    audit::{Action, Audit},
+    auth::require_role,
+    store::{load_json, save_json},
+    users::Role,
+};
+
+const KEY_PREFIX: &str = "m3s_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    Full,
+    ReadOnly,
+    UploadOnly,
+}
+
+impl Scope {
+    pub fn role(self) -> Role {
+        match self {
+            Scope::Full => Role::Admin,
+            Scope::ReadOnly => Role::Viewer,
+            Scope::UploadOnly => Role::Uploader,
+        }
+    }
+
+    pub fn allows(self, method: &Method) -> bool {
+        match self {
+            Scope::Full => true,
+            Scope::ReadOnly => matches!(*method, Method::GET | Method::HEAD),
+            Scope::UploadOnly => matches!(*method, Method::POST | Method::PUT),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scope: Scope,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: OffsetDateTime,
+    hash: String,
+}
+
+#[derive(Debug)]
+pub struct ApiKeyStore {
+    path: PathBuf,
+    keys: RwLock<Vec<ApiKey>>,
+}
+
+impl ApiKeyStore {
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join("api_keys.json");
+
+        let keys = load_json(&path)?;
+
+        Ok(Self {
+            path,
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /// Returns the stored key and the plaintext token, which is only ever available here
+    pub fn create(&self, name: String, scope: Scope) -> Result<(ApiKey, String)> {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let token = format!("{KEY_PREFIX}{}", BASE64_URL_SAFE_NO_PAD.encode(secret));
+
+        let mut id = [0u8; 6];
+        rand::thread_rng().fill_bytes(&mut id);
+
+        let key = ApiKey {
+            id: BASE64_URL_SAFE_NO_PAD.encode(id),
+            name,
+            scope,
+            created: OffsetDateTime::now_utc(),
+            hash: hash_token(&token),
+        };
+
+        // Saved before it's swapped in, so a failed write leaves no key that's gone after a restart
+        let mut keys = self.keys.write().unwrap();
+        let mut updated = keys.clone();
+        updated.push(key.clone());
+        save_json(&self.path, &updated)?;
+        *keys = updated;
+
+        Ok((key, token))
+    }
+
+    pub fn list(&self) -> Vec<ApiKey> {
+        self.keys.read().unwrap().clone()
+    }
+
+    pub fn revoke(&self, id: &str) -> Result<bool> {
+        let mut keys = self.keys.write().unwrap();
+        let mut updated = keys.clone();
+        updated.retain(|k| k.id != id);
+
+        if updated.len() == keys.len() {
+            return Ok(false);
+        }
+
+        save_json(&self.path, &updated)?;
+        *keys = updated;
+        Ok(true)
+    }
+
+    pub fn verify(&self, token: &str) -> Option<ApiKey> {
+        if !token.starts_with(KEY_PREFIX) {
+            return None;
+        }
+
+        let hash = hash_token(token);
+        self.keys
+            .read()
+            .unwrap()
+            .iter()
+            .find(|k| k.hash == hash)
+            .cloned()
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub fn router() -> Router<Arc<ApiKeyStore>> {
+    Router::new()
+        .route("/", get(list_keys).post(create_key))
+        .route("/:id", delete(revoke_key))
+        .route_layer(middleware::from_fn_with_state(Role::Admin, require_role))
+}
+
+#[derive(Debug, Serialize)]
+struct ApiKeyView {
+    id: String,
+    name: String,
+    scope: Scope,
+    #[serde(with = "time::serde::rfc3339")]
+    created: OffsetDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+}
+
+impl From<ApiKey> for ApiKeyView {
+    fn from(k: ApiKey) -> Self {
+        Self {
+            id: k.id,
+            name: k.name,
+            scope: k.scope,
+            created: k.created,
+            key: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateKey {
+    name: String,
+    scope: Scope,
+}
+
+async fn list_keys(State(store): State<Arc<ApiKeyStore>>) -> Json<Vec<ApiKeyView>> {
+    Json(store.list().into_iter().map(Into::into).collect())
+}
+
+async fn create_key(
+    State(store): State<Arc<ApiKeyStore>>,
+    audit: Audit,
+    Json(CreateKey { name, scope }): Json<CreateKey>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (key, token) = store
+        .create(name, scope)
+        .map_err(|e| ApiError::internal("Failed to store API key", e))?;
+    audit.record(Action::ApiKeyCreated, &key.id);
+
+    let view = ApiKeyView {
+        key: Some(token),
+        ..key.into()
+    };
+    Ok((StatusCode::CREATED, Json(view)))
+}
+
+async fn revoke_key(
+    State(store): State<Arc<ApiKeyStore>>,
+    audit: Audit,
+    UrlPath(id): UrlPath<String>,
+) -> Result<StatusCode, ApiError> {
+    let revoked = store
+        .revoke(&id)
+        .map_err(|e| ApiError::internal("Failed to revoke API key", e))?;
+    if !revoked {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    audit.record(Action::ApiKeyRevoked, &id);
+    Ok(StatusCode::NO_CONTENT)
+}
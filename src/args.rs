@@ -1,18 +1,753 @@
 use std::path::PathBuf;
 
+use axum::http::{HeaderName, Method};
 use clap::Parser;
-use tracing::Level;
+use ipnet::IpNet;
+use time::Weekday;
+use tracing_subscriber::EnvFilter;
+
+use crate::{auth::BasicAuth, base_path::BasePath, server::Address, users::Role};
 
 #[derive(Parser, Debug)]
 pub struct Args {
-    #[arg(long, default_value = "INFO")]
-    pub log_level: Level,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML file with defaults for any of these flags, m3s.toml is used if it exists
+    #[arg(long, env = "M3S_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// A level or per-module directives such as mmms::library=debug,tower_http=warn,info
+    #[arg(long, env = "RUST_LOG", default_value = "info", value_parser = parse_log_filter)]
+    pub log_level: String,
+
+    #[arg(long, value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+
+    #[command(flatten)]
+    pub log_file: LogFileArgs,
 
-    #[arg(short = 'a', long, default_value = "127.0.0.1")]
-    pub address: String,
+    #[command(flatten)]
+    pub access_log: AccessLogArgs,
 
+    #[cfg(feature = "otlp")]
+    #[command(flatten)]
+    pub otlp: OtlpArgs,
+
+    /// Where to listen, repeat for several listeners, e.g. [::] and 0.0.0.0 for dual-stack.
+    /// Takes an optional port and http:// or https:// to pick TLS for that listener alone.
+    #[arg(short = 'a', long = "address", default_value = "127.0.0.1")]
+    pub addresses: Vec<Address>,
+
+    /// Port for addresses that don't give their own
     #[arg(short = 'p', long, default_value = "3000")]
     pub port: u16,
 
+    /// Serve everything under this path, for a reverse proxy that forwards /photos to m3s
+    #[arg(long, default_value = "/")]
+    pub base_path: BasePath,
+
+    /// Externally reachable URL m3s is served at, base path included, such as
+    /// https://example.com/photos. Makes links in MQTT messages and emails absolute.
+    #[arg(long)]
+    pub public_url: Option<String>,
+
+    /// Listen on a Unix socket instead of TCP, given as unix:/run/m3s.sock. Whatever connects is
+    /// taken for a reverse proxy, and its X-Forwarded-For is believed.
+    #[arg(long, value_parser = parse_unix_socket)]
+    pub listen: Option<PathBuf>,
+
+    /// Octal permissions for the --listen socket, 660 lets a proxy in the same group connect
+    #[arg(long, default_value = "660", value_parser = parse_socket_mode)]
+    pub socket_mode: u32,
+
+    /// Seconds requests in flight get to finish on SIGINT or SIGTERM before being cut off
+    #[arg(long, default_value = "30")]
+    pub shutdown_timeout: u64,
+
+    /// Require HTTP Basic authentication, given as user:argon2-phc-hash
+    #[arg(long)]
+    pub auth: Option<BasicAuth>,
+
+    /// How long a browser login stays valid
+    #[arg(long, default_value = "30")]
+    pub session_days: u32,
+
+    /// Where server state such as API keys is kept, defaults to .m3s inside the library directory
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Index from `scan --output` to serve from straight away, kept up to date by later scans.
+    /// Defaults to index.json in the data directory, which also keeps edits across restarts.
+    #[arg(long)]
+    pub index: Option<PathBuf>,
+
+    /// Seconds between checks that the library's filesystem still answers, for network mounts
+    /// that drop out. 0 to not check.
+    #[arg(long, default_value = "30")]
+    pub library_check_secs: u64,
+
+    /// Seconds a check may take before the library counts as unreachable
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    pub library_check_timeout_secs: u64,
+
+    #[command(flatten)]
+    pub thumbnail: ThumbnailArgs,
+
+    /// Megabytes of recently served thumbnails to keep in memory, 0 to always read them from disk
+    #[arg(long, default_value = "64")]
+    pub thumbnail_memory_mb: u64,
+
+    /// Newest photos whose thumbnails are loaded into memory at startup, so the first visit
+    /// after a restart is as quick as the ones after it
+    #[arg(long, default_value = "200")]
+    pub warm_thumbnails: usize,
+
+    /// Megabytes to leave free on the disk thumbnails are kept on, below that new ones are only
+    /// kept in memory. 0 to not check.
+    #[arg(long, default_value = "1024")]
+    pub cache_min_free_mb: u64,
+
+    /// Size of each read when streaming originals, larger suits fast disks and many big videos
+    #[arg(long, default_value = "64", value_parser = clap::value_parser!(u64).range(1..=16384))]
+    pub file_buffer_kb: u64,
+
+    #[command(flatten)]
+    pub oidc: OidcArgs,
+
+    #[command(flatten)]
+    pub tls: TlsArgs,
+
+    #[command(flatten)]
+    pub http: HttpArgs,
+
+    #[command(flatten)]
+    pub cors: CorsArgs,
+
+    #[command(flatten)]
+    pub headers: SecurityHeaderArgs,
+
+    #[command(flatten)]
+    pub network: NetworkArgs,
+
+    #[command(flatten)]
+    pub theme: ThemeArgs,
+
+    #[command(flatten)]
+    pub mqtt: MqttArgs,
+
+    #[command(flatten)]
+    pub smtp: SmtpArgs,
+
+    /// Requests per minute per client for regular API calls, 0 to disable
+    #[arg(long, default_value = "600")]
+    pub rate_limit_api: u32,
+
+    /// Requests per minute per client for thumbnail, preview and transcode generation
+    #[arg(long, default_value = "60")]
+    pub rate_limit_expensive: u32,
+
+    /// Thumbnails generated at once, defaults to the number of CPUs
+    #[arg(long)]
+    pub concurrency_expensive: Option<usize>,
+
+    /// Megabytes the thumbnails being generated at once may decode into between them, a
+    /// 100 megapixel panorama takes about 400
+    #[arg(long, default_value = "1024", value_parser = clap::value_parser!(u32).range(1..))]
+    pub memory_expensive_mb: u32,
+
+    /// Background tasks making thumbnails ahead of requests, 0 to only make them when asked for
+    #[arg(long, default_value = "1")]
+    pub thumbnail_workers: usize,
+
+    /// Requests that may wait for one of those slots, more get 503 and are asked to retry
+    #[arg(long, default_value = "200")]
+    pub queue_expensive: usize,
+
+    /// Seconds a request may take to be answered before it gets 503 instead
+    #[arg(long, default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout_api_secs: u64,
+
+    /// The same for thumbnails, which may wait for a slot before they are made
+    #[arg(long, default_value = "120", value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout_expensive_secs: u64,
+
+    /// Largest request body accepted, in kilobytes, only logins and admin forms send one
+    #[arg(long, default_value = "64")]
+    pub body_limit_kb: usize,
+
+    /// Failed logins allowed per account or address before lockouts start
+    #[arg(long, default_value = "5")]
+    pub lockout_attempts: u32,
+
+    /// Upper bound for the exponentially growing lockout
+    #[arg(long, default_value = "15")]
+    pub lockout_max_minutes: u64,
+
+    /// Requests per minute per address against the login endpoints
+    #[arg(long, default_value = "20")]
+    pub rate_limit_login: u32,
+
     pub directory: Option<PathBuf>,
 }
+
+// Everything but serve works on the library and data directory directly, without a server
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Run the web server, the default when no command is given
+    Serve,
+    /// Index the library once and report what was found
+    Scan {
+        /// Also generate any missing thumbnails
+        #[arg(long)]
+        thumbnails: bool,
+        /// Write the index here for a server started with --index, such as one on a slower
+        /// machine sharing the library. Files already in it are only looked at again if changed.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Check the data directory can be loaded and every indexed file can be read
+    Check,
+    /// Archive the data directory, index and config file, safe to run while serving
+    Backup {
+        #[arg(long)]
+        output: PathBuf,
+        /// Include cached thumbnails, which are otherwise regenerated on demand
+        #[arg(long)]
+        thumbnails: bool,
+    },
+    /// Unpack a backup into the data directory, with the server stopped
+    Restore {
+        #[arg(long)]
+        input: PathBuf,
+        /// Replace a data directory or config file that already exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Write a static HTML gallery with thumbnails and originals, for any plain web host
+    Export {
+        #[arg(long)]
+        output: PathBuf,
+        /// Only export these library folders, everything when not given
+        #[arg(long = "folder")]
+        folders: Vec<PathBuf>,
+    },
+    #[command(subcommand)]
+    Import(ImportCommand),
+    #[command(subcommand)]
+    Users(UsersCommand),
+    #[command(subcommand)]
+    Cache(CacheCommand),
+}
+
+/// Copy photos exported from another service into the library, run a scan after to index them
+#[derive(clap::Subcommand, Debug)]
+pub enum ImportCommand {
+    /// An unpacked Google Photos Takeout. Copies are dated from its JSON files.
+    Takeout {
+        input: PathBuf,
+        /// Library folder to copy into, keeping the Takeout's album folders
+        #[arg(long, default_value = "Google Photos")]
+        folder: PathBuf,
+    },
+    /// Photos exported from Apple Photos, by Photos itself or osxphotos. Copies are dated from
+    /// their XMP or JSON sidecars, which scans go by before the EXIF.
+    Apple {
+        input: PathBuf,
+        /// Library folder to copy into, keeping the export's folders
+        #[arg(long, default_value = "Apple Photos")]
+        folder: PathBuf,
+    },
+}
+
+/// Manage accounts
+#[derive(clap::Subcommand, Debug)]
+pub enum UsersCommand {
+    List,
+    /// Create an account, the password is read from standard input
+    Add {
+        username: String,
+        #[arg(long, value_enum, default_value = "viewer")]
+        role: Role,
+        /// Library subdirectory the user is confined to
+        #[arg(long)]
+        root: Option<PathBuf>,
+    },
+    /// Set a new password, read from standard input
+    Passwd {
+        username: String,
+    },
+    Remove {
+        username: String,
+    },
+}
+
+/// Manage the thumbnail cache
+#[derive(clap::Subcommand, Debug)]
+pub enum CacheCommand {
+    Size,
+    /// Delete thumbnails of files that are gone or have changed, after a fresh scan
+    Prune,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct OidcArgs {
+    /// Delegate browser logins to this OpenID Connect provider
+    #[arg(long = "oidc-issuer")]
+    pub issuer: Option<String>,
+
+    #[arg(long = "oidc-client-id")]
+    pub client_id: Option<String>,
+
+    #[arg(long = "oidc-client-secret")]
+    pub client_secret: Option<String>,
+
+    /// Externally reachable URL of /oidc/callback, as registered with the provider
+    #[arg(long = "oidc-redirect-url")]
+    pub redirect_url: Option<String>,
+
+    #[arg(long = "oidc-scopes", default_value = "openid profile email groups")]
+    pub scopes: String,
+
+    /// ID token claim used as the local username
+    #[arg(long = "oidc-username-claim", default_value = "preferred_username")]
+    pub username_claim: String,
+
+    #[arg(long = "oidc-groups-claim", default_value = "groups")]
+    pub groups_claim: String,
+
+    /// Members of this group become admins, and admins who leave it lose the role
+    #[arg(long = "oidc-admin-group")]
+    pub admin_group: Option<String>,
+
+    /// Members of this group become uploaders, everyone else outside the admin group a viewer.
+    /// Without it uploaders and viewers keep the role they were given in m3s
+    #[arg(long = "oidc-uploader-group")]
+    pub uploader_group: Option<String>,
+
+    /// Create local users on first login instead of rejecting unknown ones
+    #[arg(long = "oidc-create-users")]
+    pub create_users: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TlsArgs {
+    /// PEM certificate chain, serves HTTPS instead of HTTP when given with --tls-key
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Also listen for plain HTTP on this port and redirect it to HTTPS
+    #[arg(long)]
+    pub http_redirect_port: Option<u16>,
+
+    /// Only send cookies over HTTPS when a proxy in front ends TLS. Always so when m3s serves
+    /// HTTPS itself.
+    #[arg(long)]
+    pub secure_cookies: bool,
+
+    /// Obtain certificates for these domains from Let's Encrypt, the server must be reachable on 443
+    #[cfg(feature = "acme")]
+    #[arg(long = "acme-domain", conflicts_with = "tls_cert")]
+    pub acme_domains: Vec<String>,
+
+    #[cfg(feature = "acme")]
+    #[arg(long)]
+    pub acme_contact: Vec<String>,
+
+    /// Where issued certificates and the account key are kept between restarts
+    #[cfg(feature = "acme")]
+    #[arg(long)]
+    pub acme_cache: Option<PathBuf>,
+
+    /// Use the production directory rather than the staging one
+    #[cfg(feature = "acme")]
+    #[arg(long)]
+    pub acme_production: bool,
+}
+
+/// HTTP/2 is offered through TLS ALPN, and taken over plain connections from clients that start
+/// with it, as proxies speaking h2c do
+#[derive(clap::Args, Debug)]
+pub struct HttpArgs {
+    /// Seconds a client has to send a request's headers before the connection is closed
+    #[arg(long, default_value = "30")]
+    pub http_header_timeout_secs: u64,
+
+    /// Largest request headers accepted, in kilobytes
+    #[arg(long, default_value = "64", value_parser = clap::value_parser!(u32).range(8..=1024))]
+    pub http_max_header_kb: u32,
+
+    /// Keep HTTP/1.1 connections open for further requests
+    #[arg(long, default_value = "true", action = clap::ArgAction::Set)]
+    pub http_keep_alive: bool,
+
+    /// Requests a client may have in flight at once on one HTTP/2 connection, a gallery page
+    /// asks for hundreds of thumbnails
+    #[arg(long, default_value = "256")]
+    pub http2_max_streams: u32,
+
+    /// Seconds between pings on idle HTTP/2 connections, which notice dead clients and keep
+    /// NAT and proxy mappings alive, 0 to not send any
+    #[arg(long, default_value = "60")]
+    pub http2_keep_alive_secs: u64,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CorsArgs {
+    /// Origins allowed to call the API from a browser, * for any, cross-origin requests are refused by default
+    #[arg(long = "cors-origin")]
+    pub cors_origins: Vec<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "GET,HEAD,POST,PUT,PATCH,DELETE"
+    )]
+    pub cors_methods: Vec<Method>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "authorization,content-type"
+    )]
+    pub cors_headers: Vec<HeaderName>,
+
+    /// Seconds browsers may cache a preflight response
+    #[arg(long, default_value = "3600")]
+    pub cors_max_age: u64,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SecurityHeaderArgs {
+    /// Content-Security-Policy sent with every response, frame-ancestors is appended
+    #[arg(
+        long,
+        default_value = "default-src 'self'; img-src 'self' data: blob:; media-src 'self' blob:; \
+                         style-src 'self' 'unsafe-inline'; object-src 'none'; base-uri 'self'; \
+                         form-action 'self'"
+    )]
+    pub content_security_policy: String,
+
+    /// Sites allowed to embed pages in a frame, as a CSP source list
+    #[arg(long, default_value = "'none'")]
+    pub frame_ancestors: String,
+
+    #[arg(long, default_value = "same-origin")]
+    pub referrer_policy: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct NetworkArgs {
+    /// Only accept clients in these CIDR ranges, everyone is accepted when none are given
+    #[arg(long = "allow-ip")]
+    pub allow: Vec<IpNet>,
+
+    /// Refuse clients in these CIDR ranges, even if they are allowed
+    #[arg(long = "deny-ip")]
+    pub deny: Vec<IpNet>,
+
+    /// Reverse proxies whose X-Forwarded-For is believed, as CIDR ranges
+    #[arg(long = "trusted-proxy")]
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+/// Format, quality and effort take a value for every size, then SIZE=VALUE for any size that
+/// differs, such as avif,180=jpeg
+#[derive(clap::Args, Debug)]
+pub struct ThumbnailArgs {
+    /// Longest edges of the thumbnails made for each photo, clients ask for the one they need
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "180,360,720",
+        value_parser = clap::value_parser!(u32).range(16..=4096)
+    )]
+    pub thumbnail_sizes: Vec<u32>,
+
+    /// WebP and AVIF are a good deal smaller than JPEG, AVIF takes much longer to encode
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "jpeg",
+        value_parser = parse_thumbnail_format
+    )]
+    pub thumbnail_format: Vec<PerSize<ThumbnailFormat>>,
+
+    /// From 1 to 100. AVIF looks as good as JPEG at lower values, around 60.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "80",
+        value_parser = parse_thumbnail_quality
+    )]
+    pub thumbnail_quality: Vec<PerSize<u8>>,
+
+    /// From 1 to 10, higher is slower for slightly smaller files. JPEG has no such setting.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "5",
+        value_parser = parse_thumbnail_effort
+    )]
+    pub thumbnail_effort: Vec<PerSize<u8>>,
+}
+
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailFormat {
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+/// A setting for every thumbnail size, or only the one given
+#[derive(Clone, Copy, Debug)]
+pub struct PerSize<T> {
+    pub size: Option<u32>,
+    pub value: T,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum LogFormat {
+    Text,
+    /// One object per line with the fields of each event and its spans, for Loki or Elasticsearch
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LogFileArgs {
+    /// Also write logs to this file, in the same format as standard output
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+
+    /// Start a new file every hour or day, the old one gets the time it was closed appended
+    #[arg(long, value_enum, default_value = "daily")]
+    pub log_file_rotate: Rotation,
+
+    /// Also start a new file once the current one reaches this many megabytes, 0 for no limit
+    #[arg(long, default_value = "100")]
+    pub log_file_max_mb: u64,
+
+    /// How many old files to keep
+    #[arg(long, default_value = "7")]
+    pub log_file_keep: usize,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct AccessLogArgs {
+    /// Log every request to this file, or - for standard output, rotated like --log-file
+    #[arg(long)]
+    pub access_log: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value = "combined")]
+    pub access_log_format: AccessLogFormat,
+}
+
+#[cfg(feature = "otlp")]
+#[derive(clap::Args, Debug)]
+pub struct OtlpArgs {
+    /// Send spans to this OpenTelemetry collector over OTLP/HTTP, e.g. http://localhost:4318
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// What the traces are listed under in Jaeger or Tempo
+    #[arg(long, env = "OTEL_SERVICE_NAME", default_value = "m3s")]
+    pub otlp_service_name: String,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum AccessLogFormat {
+    /// The Apache and nginx combined format with the latency in milliseconds and request ID appended
+    Combined,
+    Json,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Rotation {
+    Hourly,
+    Daily,
+    /// Only by size
+    Never,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ThemeMode {
+    Light,
+    Dark,
+    /// Follow the browser's preference
+    Auto,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ThemeArgs {
+    #[arg(long, value_enum, default_value = "auto")]
+    pub theme: ThemeMode,
+
+    /// Hex colour for links and highlights, such as #6ab0ff
+    #[arg(long, value_parser = parse_hex_color)]
+    pub accent_color: Option<String>,
+
+    /// Shown in the header and the browser tab
+    #[arg(long, default_value = "m3s")]
+    pub title: String,
+
+    /// Image shown in the header in place of the title
+    #[arg(long)]
+    pub logo: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct MqttArgs {
+    /// Publish library events and stats to this MQTT broker, given as mqtt://host:port
+    #[arg(long = "mqtt-url", value_parser = parse_mqtt_url)]
+    pub broker: Option<String>,
+
+    #[arg(long = "mqtt-username")]
+    pub username: Option<String>,
+
+    #[arg(long = "mqtt-password", requires = "username")]
+    pub password: Option<String>,
+
+    /// Every topic published starts with this
+    #[arg(long = "mqtt-topic", default_value = "m3s")]
+    pub topic: String,
+
+    #[arg(long = "mqtt-client-id", default_value = "m3s")]
+    pub client: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SmtpArgs {
+    /// Mail server for weekly digests, as smtp://host:587, which upgrades with STARTTLS when
+    /// offered, or smtps://host:465
+    #[arg(long, requires = "smtp_from")]
+    pub smtp_url: Option<String>,
+
+    #[arg(long)]
+    pub smtp_username: Option<String>,
+
+    #[arg(long, requires = "smtp_username")]
+    pub smtp_password: Option<String>,
+
+    /// Sender of digests, such as "m3s <photos@example.com>"
+    #[arg(long)]
+    pub smtp_from: Option<String>,
+
+    /// Day of the week digests go out on
+    #[arg(long, default_value = "monday", value_parser = parse_weekday)]
+    pub digest_day: Weekday,
+
+    /// Hour of that day digests go out at, in UTC
+    #[arg(long, default_value = "8", value_parser = clap::value_parser!(u8).range(0..24))]
+    pub digest_hour: u8,
+}
+
+fn parse_log_filter(s: &str) -> Result<String, String> {
+    EnvFilter::try_new(s).map_err(|e| e.to_string())?;
+    Ok(s.to_string())
+}
+
+fn parse_per_size<T>(
+    s: &str,
+    parse: impl Fn(&str) -> Option<T>,
+    expected: &str,
+) -> Result<PerSize<T>, String> {
+    let (size, value) = match s.split_once('=') {
+        Some((size, value)) => {
+            let size = size
+                .trim()
+                .parse()
+                .map_err(|_| format!("expected a size in pixels before =, got {size:?}"))?;
+            (Some(size), value)
+        }
+        None => (None, s),
+    };
+    let value = parse(value.trim()).ok_or_else(|| format!("expected {expected}"))?;
+    Ok(PerSize { size, value })
+}
+
+fn parse_thumbnail_format(s: &str) -> Result<PerSize<ThumbnailFormat>, String> {
+    parse_per_size(
+        s,
+        |value| clap::ValueEnum::from_str(value, true).ok(),
+        "jpeg, webp or avif",
+    )
+}
+
+fn parse_thumbnail_quality(s: &str) -> Result<PerSize<u8>, String> {
+    parse_per_size(
+        s,
+        |value| value.parse().ok().filter(|q| (1..=100).contains(q)),
+        "a quality from 1 to 100",
+    )
+}
+
+fn parse_thumbnail_effort(s: &str) -> Result<PerSize<u8>, String> {
+    parse_per_size(
+        s,
+        |value| value.parse().ok().filter(|e| (1..=10).contains(e)),
+        "an effort from 1 to 10",
+    )
+}
+
+fn parse_unix_socket(s: &str) -> Result<PathBuf, String> {
+    match s.strip_prefix("unix:") {
+        Some(path) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => Err("expected a socket like unix:/run/m3s.sock".to_string()),
+    }
+}
+
+fn parse_socket_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| "expected octal permissions like 660".to_string())
+}
+
+fn parse_weekday(s: &str) -> Result<Weekday, String> {
+    let days = [
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+        Weekday::Saturday,
+        Weekday::Sunday,
+    ];
+    days.into_iter()
+        .find(|day| day.to_string().eq_ignore_ascii_case(s))
+        .ok_or_else(|| "expected a day of the week such as monday".to_string())
+}
+
+// To the host and port to connect to
+fn parse_mqtt_url(s: &str) -> Result<String, String> {
+    let address = s
+        .strip_prefix("mqtt://")
+        .ok_or("expected a broker like mqtt://broker:1883, TLS isn't supported")?
+        .trim_end_matches('/');
+    if address.is_empty() || address.contains(['/', '@', '?']) {
+        return Err("expected a broker like mqtt://broker:1883".to_string());
+    }
+    // The port is optional, a bracketed IPv6 address has colons of its own
+    match address.rsplit_once(':') {
+        Some((_, port)) if !port.ends_with(']') => match port.parse::<u16>() {
+            Ok(_) => Ok(address.to_string()),
+            Err(_) => Err(format!("expected a port after the colon, got {port:?}")),
+        },
+        _ => Ok(format!("{address}:1883")),
+    }
+}
+
+// Ends up inside a stylesheet, so nothing but a colour gets through
+fn parse_hex_color(s: &str) -> Result<String, String> {
+    let digits = s
+        .strip_prefix('#')
+        .ok_or("expected a colour like #6ab0ff")?;
+    if !matches!(digits.len(), 3 | 6) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("expected a colour like #6ab0ff".to_string());
+    }
+    Ok(s.to_string())
+}
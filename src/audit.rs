@@ -0,0 +1,233 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead as _, BufReader, ErrorKind, Write as _},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query, State},
+    http::{request::Parts, StatusCode},
+    middleware,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    api_error::ApiError,
+    auth::{require_role, Principal},
+    network::ClientIp,
+    request_id,
+    users::Role,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Login,
+    LoginFailed,
+    Logout,
+    SessionRevoked,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    UserCreated,
+    UserUpdated,
+    UserDeleted,
+    GrantCreated,
+    GrantRevoked,
+    MediaEdited,
+    MediaEditsReset,
+    WebhookCreated,
+    WebhookRemoved,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    pub actor: Option<String>,
+    pub action: Action,
+    pub target: Option<String>,
+    pub ip: Option<IpAddr>,
+    // Entries from before request IDs have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Append-only, one JSON entry per line so a crash can at worst lose the last line
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join("audit.jsonl");
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {path:?}"))?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    // An action that already happened can't be undone, so failing to record it is only logged
+    pub fn record(
+        &self,
+        actor: Option<&str>,
+        action: Action,
+        target: Option<&str>,
+        ip: Option<IpAddr>,
+        request_id: Option<&str>,
+    ) {
+        let entry = Entry {
+            time: OffsetDateTime::now_utc(),
+            actor: actor.map(str::to_string),
+            action,
+            target: target.map(str::to_string),
+            ip,
+            request_id: request_id.map(str::to_string),
+        };
+
+        let mut line = serde_json::to_vec(&entry).expect("audit entries always serialize");
+        line.push(b'\n');
+
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            tracing::error!("Failed to write audit log {:?}: {e}", self.path);
+        }
+    }
+
+    /// Newest first
+    pub fn query(&self, filter: &AuditQuery) -> Result<Vec<Entry>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", self.path)),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            // A torn final line from a crash is skipped rather than failing the whole query
+            let Ok(entry) = serde_json::from_str::<Entry>(&line) else {
+                continue;
+            };
+            if filter.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+
+        entries.reverse();
+        entries.truncate(filter.limit);
+        Ok(entries)
+    }
+}
+
+pub fn describe(principal: &Principal) -> Option<String> {
+    match principal {
+        Principal::Anonymous => None,
+        Principal::ApiKey(key) => Some(format!("key:{}", key.id)),
+        principal => principal.username().map(str::to_string),
+    }
+}
+
+/// Records actions on behalf of whoever made the request
+pub struct Audit {
+    log: Arc<AuditLog>,
+    actor: Option<String>,
+    ip: Option<IpAddr>,
+    request_id: Option<String>,
+}
+
+impl Audit {
+    pub fn record(&self, action: Action, target: &str) {
+        self.log.record(
+            self.actor.as_deref(),
+            action,
+            Some(target),
+            self.ip,
+            self.request_id.as_deref(),
+        );
+    }
+
+    // For requests that aren't authenticated yet, like logins
+    pub fn record_as(&self, actor: Option<&str>, action: Action, target: Option<&str>) {
+        self.log
+            .record(actor, action, target, self.ip, self.request_id.as_deref());
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Audit {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let Some(log) = parts.extensions.get::<Arc<AuditLog>>().cloned() else {
+            tracing::error!("Audit log requested on a route without one");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+
+        Ok(Self {
+            log,
+            actor: parts.extensions.get::<Principal>().and_then(describe),
+            ip: parts.extensions.get::<ClientIp>().map(|ClientIp(ip)| *ip),
+            request_id: request_id::get(&parts.extensions).map(str::to_string),
+        })
+    }
+}
+
+pub fn router() -> Router<Arc<AuditLog>> {
+    Router::new()
+        .route("/", get(query))
+        .route_layer(middleware::from_fn_with_state(Role::Admin, require_role))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    actor: Option<String>,
+    action: Option<Action>,
+    target: Option<String>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    since: Option<OffsetDateTime>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &Entry) -> bool {
+        self.actor
+            .as_ref()
+            .is_none_or(|actor| entry.actor.as_ref() == Some(actor))
+            && self.action.is_none_or(|action| entry.action == action)
+            && self
+                .target
+                .as_ref()
+                .is_none_or(|target| entry.target.as_ref() == Some(target))
+            && self.since.is_none_or(|since| entry.time >= since)
+    }
+}
+
+async fn query(
+    State(log): State<Arc<AuditLog>>,
+    Query(filter): Query<AuditQuery>,
+) -> Result<Json<Vec<Entry>>, ApiError> {
+    let entries = tokio::task::spawn_blocking(move || log.query(&filter))
+        .await
+        .map_err(|e| ApiError::internal("Failed to query audit log", e))?
+        .map_err(|e| ApiError::internal("Failed to query audit log", e))?;
+    Ok(Json(entries))
+}
@@ -0,0 +1,336 @@
+use std::{net::IpAddr, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, SaltString},
+    Argon2, PasswordHasher, PasswordVerifier,
+};
+use axum::{
+    extract::{FromRef, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    Extension,
+};
+use axum_extra::extract::cookie::{Key, SignedCookieJar};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+
+use crate::{
+    access_log,
+    api_keys::{ApiKey, ApiKeyStore},
+    audit,
+    base_path::BasePath,
+    csrf,
+    lockout::LoginGuard,
+    login,
+    network::ClientIp,
+    oidc::Oidc,
+    sessions::{self, CurrentSession, Session, SessionStore},
+    users::{Role, User, UserStore},
+};
+
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    pub username: String,
+    password_hash: String,
+}
+
+impl FromStr for BasicAuth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (username, password_hash) = s
+            .split_once(':')
+            .context("Expected credentials in the form user:password-hash")?;
+
+        // Validate the PHC string up front so a typo fails at startup rather than on every request
+        PasswordHash::new(password_hash).map_err(|e| anyhow!("Invalid password hash: {e}"))?;
+
+        Ok(Self {
+            username: username.to_string(),
+            password_hash: password_hash.to_string(),
+        })
+    }
+}
+
+impl BasicAuth {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        username == self.username && verify_password(password, &self.password_hash)
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Failed to hash password: {e}"))?;
+    Ok(hash.to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false;
+    };
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok()
+}
+
+fn decode_basic(authorization: &str) -> Option<(String, String)> {
+    let encoded = authorization.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+
+    Some((username.to_string(), password.to_string()))
+}
+
+#[derive(Debug, Clone)]
+pub enum Principal {
+    // Authentication is disabled, everyone is trusted
+    Anonymous,
+    // The single user configured with --auth
+    Admin(String),
+    User(User),
+    ApiKey(ApiKey),
+}
+
+impl Principal {
+    pub fn role(&self) -> Role {
+        match self {
+            Principal::Anonymous | Principal::Admin(_) => Role::Admin,
+            Principal::User(user) => user.role,
+            Principal::ApiKey(key) => key.scope.role(),
+        }
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        match self {
+            Principal::Admin(username) => Some(username),
+            Principal::User(user) => Some(&user.username),
+            Principal::Anonymous | Principal::ApiKey(_) => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthState {
+    pub basic: Option<Arc<BasicAuth>>,
+    pub api_keys: Arc<ApiKeyStore>,
+    pub users: Arc<UserStore>,
+    pub sessions: Arc<SessionStore>,
+    pub cookie_key: Key,
+    /// Cookies are only sent back over HTTPS
+    pub secure_cookies: bool,
+    pub oidc: Option<Arc<Oidc>>,
+    pub login_guard: Arc<LoginGuard>,
+}
+
+#[derive(Debug)]
+pub enum LoginError {
+    Invalid,
+    LockedOut(Duration),
+}
+
+impl FromRef<AuthState> for Key {
+    fn from_ref(state: &AuthState) -> Self {
+        state.cookie_key.clone()
+    }
+}
+
+impl AuthState {
+    pub fn is_enabled(&self) -> bool {
+        self.basic.is_some() || !self.users.is_empty()
+    }
+
+    // Blocking, argon2 is deliberately slow
+    pub fn check_credentials(&self, username: &str, password: &str) -> Option<Principal> {
+        if let Some(basic) = &self.basic {
+            if basic.verify(username, password) {
+                return Some(Principal::Admin(username.to_string()));
+            }
+        }
+        self.users.verify(username, password).map(Principal::User)
+    }
+
+    // Blocking, see check_credentials
+    pub fn login(
+        &self,
+        username: &str,
+        password: &str,
+        ip: IpAddr,
+    ) -> Result<Principal, LoginError> {
+        if let Some(remaining) = self.login_guard.locked(Some(username), ip) {
+            return Err(LoginError::LockedOut(remaining));
+        }
+
+        match self.check_credentials(username, password) {
+            Some(principal) => {
+                self.login_guard.succeeded(username);
+                Ok(principal)
+            }
+            None => {
+                self.login_guard.failed(Some(username), ip);
+                Err(LoginError::Invalid)
+            }
+        }
+    }
+
+    fn verify_api_key(&self, token: &str, ip: IpAddr) -> Result<Principal, LoginError> {
+        if let Some(remaining) = self.login_guard.locked(None, ip) {
+            return Err(LoginError::LockedOut(remaining));
+        }
+
+        match self.api_keys.verify(token) {
+            Some(key) => Ok(Principal::ApiKey(key)),
+            None => {
+                self.login_guard.failed(None, ip);
+                Err(LoginError::Invalid)
+            }
+        }
+    }
+
+    fn resolve_session(&self, session: &Session) -> Option<Principal> {
+        if let Some(user) = self.users.get(&session.username) {
+            return Some(Principal::User(user));
+        }
+        self.basic
+            .as_ref()
+            .filter(|basic| basic.username == session.username)
+            .map(|basic| Principal::Admin(basic.username.clone()))
+    }
+}
+
+pub async fn authenticate(
+    State(state): State<AuthState>,
+    ClientIp(ip): ClientIp,
+    Extension(base): Extension<BasePath>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    // Authentication switches on as soon as there is someone to authenticate
+    if !state.is_enabled() {
+        request
+            .extensions_mut()
+            .insert(Authenticated(Principal::Anonymous));
+        return next.run(request).await;
+    }
+
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let mut session_id = None;
+    let principal = match authorization {
+        Some(authorization) => match authorization.strip_prefix("Bearer ") {
+            Some(token) => state.verify_api_key(token, ip),
+            // Argon2 is deliberately slow, keep it off the async workers
+            None => {
+                let state = state.clone();
+                let span = tracing::Span::current();
+                tokio::task::spawn_blocking(move || {
+                    let _span = span.enter();
+                    let (username, password) =
+                        decode_basic(&authorization).ok_or(LoginError::Invalid)?;
+                    state.login(&username, &password, ip)
+                })
+                .await
+                .unwrap_or(Err(LoginError::Invalid))
+            }
+        },
+        None => {
+            let jar = SignedCookieJar::from_headers(request.headers(), state.cookie_key.clone());
+            jar.get(sessions::COOKIE_NAME)
+                .and_then(|cookie| state.sessions.verify(cookie.value()))
+                .and_then(|session| {
+                    let principal = state.resolve_session(&session)?;
+                    session_id = Some(session.id.clone());
+                    request.extensions_mut().insert(CurrentSession(session.id));
+                    Some(principal)
+                })
+                .ok_or(LoginError::Invalid)
+        }
+    };
+
+    match principal {
+        Ok(Principal::ApiKey(key)) if !key.scope.allows(request.method()) => {
+            StatusCode::FORBIDDEN.into_response()
+        }
+        // Browsers attach the cookie to requests from any site, so mutations must also prove
+        // they came from a page of ours that could read the CSRF cookie
+        Ok(_)
+            if !csrf::is_safe(request.method())
+                && session_id
+                    .as_deref()
+                    .is_some_and(|id| !has_csrf_token(&state, &request, id)) =>
+        {
+            (StatusCode::FORBIDDEN, "Missing or invalid CSRF token").into_response()
+        }
+        Ok(principal) => {
+            let user = audit::describe(&principal).map(access_log::User);
+            request.extensions_mut().insert(Authenticated(principal));
+            let mut response = next.run(request).await;
+            if let Some(user) = user {
+                response.extensions_mut().insert(user);
+            }
+            response
+        }
+        Err(LoginError::LockedOut(remaining)) => locked_out(remaining).into_response(),
+        Err(LoginError::Invalid) if wants_html(&request) => {
+            let next = request.uri().path_and_query().map_or("/", |p| p.as_str());
+            let next = login::percent_encode(&base.join(next));
+            Redirect::to(&base.join(&format!("/login?next={next}"))).into_response()
+        }
+        Err(LoginError::Invalid) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, r#"Basic realm="m3s""#)],
+        )
+            .into_response(),
+    }
+}
+
+fn has_csrf_token(state: &AuthState, request: &Request, session_id: &str) -> bool {
+    request
+        .headers()
+        .get(csrf::HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|token| csrf::verify(&state.cookie_key, session_id, token))
+}
+
+pub fn locked_out(remaining: Duration) -> impl IntoResponse {
+    // Round up so clients never retry a moment too early
+    let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, seconds.to_string())],
+    )
+}
+
+// Only require_role hands out the Principal, so a handler on a route that never declared a role
+// fails to extract it rather than silently running unchecked
+#[derive(Debug, Clone)]
+struct Authenticated(Principal);
+
+pub async fn require_role(State(role): State<Role>, mut request: Request, next: Next) -> Response {
+    let Some(Authenticated(principal)) = request.extensions_mut().remove() else {
+        tracing::error!("Role check on a route without authentication");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    if principal.role() < role {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    request.extensions_mut().insert(principal);
+    next.run(request).await
+}
+
+fn wants_html(request: &Request) -> bool {
+    request.method() == Method::GET
+        && request
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"))
+}
@@ -0,0 +1,137 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use crate::safe_path::validate_relative;
+
+// Names inside the archive, the data directory keeps its own layout under data/
+const DATA: &str = "data";
+const INDEX: &str = "index.json";
+const CONFIG: &str = "m3s.toml";
+
+/// Packs the data directory, the index and the config file into a .tar.gz.
+///
+/// Safe while the server runs: stores are replaced by rename rather than rewritten, so every
+/// file read is a whole version of itself.
+pub fn backup(
+    output: &Path,
+    data_dir: &Path,
+    index: Option<&Path>,
+    config: Option<&Path>,
+    thumbnails: bool,
+) -> Result<()> {
+    let mut files = Vec::new();
+    collect(data_dir, Path::new(""), thumbnails, &mut files)?;
+
+    // Renamed into place at the end, so an interrupted backup never looks like a finished one
+    let mut tmp = output.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let file = File::create(&tmp).with_context(|| format!("Failed to create {tmp:?}"))?;
+    let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    for relative in &files {
+        archive
+            .append_path_with_name(data_dir.join(relative), Path::new(DATA).join(relative))
+            .with_context(|| format!("Failed to add {relative:?}"))?;
+    }
+    for (path, name) in [(index, INDEX), (config, CONFIG)] {
+        if let Some(path) = path.filter(|path| path.exists()) {
+            archive
+                .append_path_with_name(path, name)
+                .with_context(|| format!("Failed to add {path:?}"))?;
+        }
+    }
+    archive.into_inner()?.finish()?.sync_all()?;
+    fs::rename(&tmp, output).with_context(|| format!("Failed to write {output:?}"))?;
+
+    println!("Backed up {} files to {output:?}", files.len());
+    Ok(())
+}
+
+fn collect(root: &Path, relative: &Path, thumbnails: bool, files: &mut Vec<PathBuf>) -> Result<()> {
+    let dir = root.join(relative);
+    for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {dir:?}"))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        let kind = entry.file_type()?;
+
+        // Thumbnails are regenerated on demand, and .tmp files are writes still in progress
+        if kind.is_dir() && (thumbnails || path != Path::new("thumbnails")) {
+            collect(root, &path, thumbnails, files)?;
+        } else if kind.is_file() && path.extension().is_none_or(|e| e != "tmp") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Unpacks a backup, the server must not be running.
+///
+/// Refuses to overwrite a data directory or config file that's already there unless forced,
+/// and skips the index when there's no --index to put it at.
+pub fn restore(
+    input: &Path,
+    data_dir: &Path,
+    index: Option<&Path>,
+    config: &Path,
+    force: bool,
+) -> Result<()> {
+    // The thumbnail cache is created by any command, anything else means existing state
+    let in_use = fs::read_dir(data_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|entry| entry.file_name() != "thumbnails");
+    if in_use && !force {
+        bail!("{data_dir:?} is not empty, use --force to replace what's in it");
+    }
+
+    let file = File::open(input).with_context(|| format!("Failed to open {input:?}"))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+    let mut restored = 0;
+    for entry in archive
+        .entries()
+        .with_context(|| format!("Failed to read {input:?}"))?
+    {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path()?.into_owned();
+
+        let target = if let Ok(relative) = name.strip_prefix(DATA) {
+            // Archives can name any path, only ones inside the data directory are followed
+            data_dir.join(validate_relative(relative)?)
+        } else if name == Path::new(INDEX) {
+            let Some(index) = index else {
+                eprintln!("Skipping the index, give --index to restore it");
+                continue;
+            };
+            index.to_path_buf()
+        } else if name == Path::new(CONFIG) {
+            if config.exists() && !force {
+                eprintln!("Skipping the config file, {config:?} already exists");
+                continue;
+            }
+            config.to_path_buf()
+        } else {
+            bail!("Unexpected {name:?} in {input:?}, is it an m3s backup?");
+        };
+
+        if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {parent:?}"))?;
+        }
+        entry
+            .unpack(&target)
+            .with_context(|| format!("Failed to restore {target:?}"))?;
+        restored += 1;
+    }
+
+    println!("Restored {restored} files from {input:?}");
+    Ok(())
+}
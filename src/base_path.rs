@@ -0,0 +1,34 @@
+use std::{str::FromStr, sync::Arc};
+
+/// The prefix every route is served under, empty when serving from the root.
+///
+/// Handlers see paths with the prefix already stripped, anything sent back to the browser
+/// goes through [`BasePath::join`].
+#[derive(Debug, Clone)]
+pub struct BasePath(Arc<str>);
+
+impl BasePath {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Prefixes an absolute path such as `/login`
+    pub fn join(&self, path: &str) -> String {
+        format!("{}{path}", self.0)
+    }
+}
+
+impl FromStr for BasePath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with('/') {
+            return Err("expected a path such as /photos".to_string());
+        }
+        if s.contains(['?', '#', '"', '\'', '<', '>', '\\']) || s.contains("//") {
+            return Err("expected a plain path such as /photos".to_string());
+        }
+        // /photos/ and /photos are the same, and / is no prefix at all
+        Ok(Self(s.trim_end_matches('/').into()))
+    }
+}
@@ -0,0 +1,191 @@
+use std::path::{Path, PathBuf};
+
+use axum::{
+    extract::{Path as UrlPath, Query, State},
+    http::StatusCode,
+    middleware,
+    response::Html,
+    routing::get,
+    Extension, Router,
+};
+use serde::Deserialize;
+
+use crate::{
+    auth::{require_role, Principal},
+    base_path::BasePath,
+    library::{Kind, Media},
+    login::{html_escape, percent_encode},
+    media::MediaState,
+    safe_path::validate_relative,
+    users::Role,
+};
+
+// Plain links and images only, for browsers without JavaScript
+const PAGE_SIZE: usize = 60;
+
+pub fn router() -> Router<MediaState> {
+    Router::new()
+        .route("/", get(timeline))
+        .route("/folders", get(root_folder))
+        .route("/folders/*path", get(folder))
+        .route_layer(middleware::from_fn_with_state(Role::Viewer, require_role))
+}
+
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    #[serde(default)]
+    page: usize,
+}
+
+async fn timeline(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    Extension(base): Extension<BasePath>,
+    Query(PageQuery { page }): Query<PageQuery>,
+) -> Html<String> {
+    let visible = state.timeline(&principal);
+    let pages = visible.len().div_ceil(PAGE_SIZE).max(1);
+    let page = page.min(pages - 1);
+
+    let items: Vec<Media> = visible
+        .into_iter()
+        .skip(page * PAGE_SIZE)
+        .take(PAGE_SIZE)
+        .collect();
+
+    let link = |page: usize, label: &str| {
+        format!(
+            r#"<a href="{}?page={page}">{label}</a>"#,
+            html_escape(&base.join("/basic"))
+        )
+    };
+    let mut pager = Vec::new();
+    if page > 0 {
+        pager.push(link(page - 1, "&lsaquo; Newer"));
+    }
+    pager.push(format!("Page {} of {pages}", page + 1));
+    if page + 1 < pages {
+        pager.push(link(page + 1, "Older &rsaquo;"));
+    }
+    let pager = format!(r#"<p id="status">{}</p>"#, pager.join(" &middot; "));
+
+    render(
+        &base,
+        "Timeline",
+        &format!("{}{pager}", grid(&base, &items)),
+    )
+}
+
+async fn root_folder(
+    state: State<MediaState>,
+    principal: Extension<Principal>,
+    base: Extension<BasePath>,
+) -> Result<Html<String>, StatusCode> {
+    folder(state, principal, base, UrlPath(String::new())).await
+}
+
+async fn folder(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    Extension(base): Extension<BasePath>,
+    UrlPath(path): UrlPath<String>,
+) -> Result<Html<String>, StatusCode> {
+    let path = validate_relative(Path::new(&path)).map_err(|_| StatusCode::NOT_FOUND)?;
+    let (folders, items) = state
+        .folder(&principal, &path)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut breadcrumbs = vec![format!(
+        r#"<a href="{}">Library</a>"#,
+        html_escape(&base.join("/basic/folders"))
+    )];
+    let mut ancestor = PathBuf::new();
+    for part in path.iter() {
+        ancestor.push(part);
+        breadcrumbs.push(folder_link(&base, &ancestor, &part.to_string_lossy()));
+    }
+
+    let folders: String = folders
+        .iter()
+        .map(|folder| {
+            let name = folder.file_name().unwrap_or_default().to_string_lossy();
+            format!("<li>{}</li>", folder_link(&base, folder, &name))
+        })
+        .collect();
+
+    Ok(render(
+        &base,
+        "Folders",
+        &format!(
+            r#"<nav id="breadcrumbs">{}</nav><ul id="folders">{folders}</ul>{}"#,
+            breadcrumbs.join(""),
+            grid(&base, &items)
+        ),
+    ))
+}
+
+fn folder_link(base: &BasePath, path: &Path, label: &str) -> String {
+    format!(
+        r#"<a href="{}">{}</a>"#,
+        html_escape(&base.join(&format!(
+            "/basic/folders/{}",
+            percent_encode(&path.to_string_lossy())
+        ))),
+        html_escape(label)
+    )
+}
+
+// Each tile links straight to the original, which the browser can show on its own
+fn grid(base: &BasePath, items: &[Media]) -> String {
+    let api = html_escape(&base.join("/api/media"));
+    let tiles: String = items
+        .iter()
+        .map(|media| {
+            let path = html_escape(&percent_encode(&media.path.to_string_lossy()));
+            let name = html_escape(&media.path.to_string_lossy());
+            let kind = match media.kind {
+                Kind::Image => "image",
+                Kind::Video => "video",
+            };
+            // A new URL once edited, or the browser would keep showing the one it has
+            let edits = media
+                .edits
+                .as_ref()
+                .map_or(String::new(), |edits| format!("?edits={}", edits.key()));
+            format!(
+                r#"<a class="tile {kind}" href="{api}/file/{path}" title="{name}"><img src="{api}/thumb/{path}{edits}" alt="{name}" loading="lazy"></a>"#
+            )
+        })
+        .collect();
+    format!(r#"<div id="grid">{tiles}</div>"#)
+}
+
+fn render(base: &BasePath, title: &str, body: &str) -> Html<String> {
+    let base = html_escape(base.as_str());
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title} - m3s</title>
+<link rel="stylesheet" href="{base}/style.css">
+</head>
+<body class="basic">
+<header>
+  <a class="brand" href="{base}/basic">m3s</a>
+  <nav>
+    <a href="{base}/basic">Timeline</a>
+    <a href="{base}/basic/folders">Folders</a>
+  </nav>
+  <form method="post" action="{base}/logout">
+    <button type="submit">Sign out</button>
+  </form>
+</header>
+<main>
+{body}
+</main>
+</body>
+</html>"#
+    ))
+}
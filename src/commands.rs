@@ -0,0 +1,265 @@
+use std::{io::BufRead as _, path::Path};
+
+use anyhow::{bail, ensure, Context, Result};
+use time::UtcOffset;
+
+use crate::{
+    api_keys::ApiKeyStore,
+    args::{CacheCommand, Command, ImportCommand, ThumbnailArgs, UsersCommand},
+    audit::AuditLog,
+    backup, config, export, import,
+    library::{Kind, Library},
+    permissions::PermissionStore,
+    safe_path::{validate_relative, LibraryRoot},
+    sessions::SessionStore,
+    taken,
+    thumbnails::Thumbnails,
+    users::{UserStore, UserUpdate},
+};
+
+/// Runs a one-off command against the library and data directory, anything but serve
+pub fn run(
+    command: Command,
+    directory: &Path,
+    data_dir: &Path,
+    index: Option<&Path>,
+    config: Option<&Path>,
+    session_length: time::Duration,
+    thumbnail: &ThumbnailArgs,
+) -> Result<()> {
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Scan { thumbnails, output } => scan(
+            directory,
+            data_dir,
+            thumbnails.then_some(thumbnail),
+            output.as_deref(),
+        ),
+        Command::Check => check(directory, data_dir, session_length),
+        Command::Backup { output, thumbnails } => backup::backup(
+            &output,
+            data_dir,
+            index,
+            config::file(config).as_deref(),
+            thumbnails,
+        ),
+        Command::Restore { input, force } => backup::restore(
+            &input,
+            data_dir,
+            index,
+            config.unwrap_or(Path::new(config::DEFAULT_PATH)),
+            force,
+        ),
+        Command::Export { output, folders } => {
+            let library = library(directory, data_dir)?;
+            let thumbnails = Thumbnails::new(data_dir, thumbnail)?;
+            export::export(&library, &thumbnails, &output, &folders)
+        }
+        Command::Import(command) => import(command, directory, data_dir),
+        Command::Users(command) => users(command, data_dir),
+        Command::Cache(command) => cache(command, directory, data_dir, thumbnail),
+    }
+}
+
+fn library(directory: &Path, data_dir: &Path) -> Result<Library> {
+    let library =
+        Library::new(LibraryRoot::new(directory, &[data_dir])?).with_taken(taken::path(data_dir));
+    library.scan()?;
+    Ok(library)
+}
+
+// Thumbnails are made too when given their sizes
+fn scan(
+    directory: &Path,
+    data_dir: &Path,
+    thumbnails: Option<&ThumbnailArgs>,
+    output: Option<&Path>,
+) -> Result<()> {
+    let root = LibraryRoot::new(directory, &[data_dir])?;
+    let library = match output {
+        Some(output) => Library::with_index(root, output.to_path_buf())?,
+        None => Library::new(root),
+    }
+    .with_taken(taken::path(data_dir));
+    library.scan()?;
+    if let Some(output) = output {
+        println!("Index written to {output:?}");
+    }
+    let media = library.list();
+
+    let videos = media.iter().filter(|m| m.kind == Kind::Video).count();
+    let bytes: u64 = media.iter().map(|m| m.size).sum();
+    println!(
+        "{} files, {} photos and {videos} videos, {:.1} GB",
+        media.len(),
+        media.len() - videos,
+        bytes as f64 / 1e9
+    );
+
+    if let Some(thumbnail) = thumbnails {
+        let cache = Thumbnails::new(data_dir, thumbnail)?;
+        let mut failed = 0;
+        for media in media.iter().filter(|m| m.kind == Kind::Image) {
+            let result = library
+                .root()
+                .resolve(&media.path)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| cache.get(media, &file, cache.closest(None)));
+            if let Err(e) = result {
+                eprintln!("{:?}: {e:#}", media.path);
+                failed += 1;
+            }
+        }
+        let usage = cache.size();
+        println!(
+            "{} thumbnails cached, {failed} failed, {:.1} MB",
+            usage.files,
+            usage.bytes as f64 / 1e6
+        );
+    }
+
+    Ok(())
+}
+
+fn check(directory: &Path, data_dir: &Path, session_length: time::Duration) -> Result<()> {
+    // Same loaders the server starts with, so anything broken fails here first
+    ApiKeyStore::load(data_dir)?;
+    UserStore::load(data_dir)?;
+    PermissionStore::load(data_dir)?;
+    SessionStore::load(data_dir, session_length)?;
+    AuditLog::open(data_dir)?;
+    println!("Data directory {data_dir:?} is readable");
+
+    let library = library(directory, data_dir)?;
+    let media = library.list();
+
+    // The scan reads dimensions from every photo, those without any couldn't be decoded
+    let unreadable: Vec<_> = media
+        .iter()
+        .filter(|m| m.kind == Kind::Image && m.width.is_none())
+        .collect();
+    for media in &unreadable {
+        eprintln!("Unreadable: {:?}", media.path);
+    }
+    println!(
+        "{} files indexed, {} unreadable",
+        media.len(),
+        unreadable.len()
+    );
+
+    ensure!(unreadable.is_empty(), "Some files could not be read");
+    Ok(())
+}
+
+fn import(command: ImportCommand, directory: &Path, data_dir: &Path) -> Result<()> {
+    let (imported, folder) = match command {
+        ImportCommand::Takeout { input, folder } => {
+            let folder = validate_relative(&folder)?;
+            (import::takeout(&input, &directory.join(&folder))?, folder)
+        }
+        ImportCommand::Apple { input, folder } => {
+            let folder = validate_relative(&folder)?;
+            let imported = import::apple(&input, &directory.join(&folder))?;
+            // Scans keep wall clock times as if they were UTC, as they do EXIF's
+            let dates = imported.dates.iter().map(|(path, taken)| {
+                let path = path.strip_prefix(directory).unwrap_or(path);
+                (path.to_path_buf(), taken.replace_offset(UtcOffset::UTC))
+            });
+            taken::add(&taken::path(data_dir), dates)?;
+            (imported, folder)
+        }
+    };
+    println!(
+        "Copied {} files into {folder:?}, {} dated from the export, {} already there",
+        imported.copied, imported.dated, imported.skipped
+    );
+    Ok(())
+}
+
+fn users(command: UsersCommand, data_dir: &Path) -> Result<()> {
+    let users = UserStore::load(data_dir)?;
+
+    match command {
+        UsersCommand::List => {
+            for user in users.list() {
+                let root = user
+                    .root
+                    .map(|root| format!(" in {root:?}"))
+                    .unwrap_or_default();
+                println!("{} ({:?}){root}", user.username, user.role);
+            }
+        }
+        UsersCommand::Add {
+            username,
+            role,
+            root,
+        } => {
+            let password = read_password()?;
+            let user = users.create(username, &password, role, root, Vec::new())?;
+            println!("Created {}", user.username);
+        }
+        UsersCommand::Passwd { username } => {
+            let password = read_password()?;
+            let update = UserUpdate {
+                password: Some(password),
+                ..Default::default()
+            };
+            if users.update(&username, update)?.is_none() {
+                bail!("No user called {username}");
+            }
+            println!("Password changed for {username}");
+        }
+        UsersCommand::Remove { username } => {
+            if !users.delete(&username)? {
+                bail!("No user called {username}");
+            }
+            println!("Removed {username}");
+        }
+    }
+
+    Ok(())
+}
+
+// A line from standard input, so passwords can be piped in by scripts
+fn read_password() -> Result<String> {
+    eprint!("Password: ");
+    let mut password = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .context("Failed to read password")?;
+
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    ensure!(!password.is_empty(), "Password must not be empty");
+    Ok(password)
+}
+
+fn cache(
+    command: CacheCommand,
+    directory: &Path,
+    data_dir: &Path,
+    thumbnail: &ThumbnailArgs,
+) -> Result<()> {
+    let thumbnails = Thumbnails::new(data_dir, thumbnail)?;
+
+    let usage = match command {
+        CacheCommand::Size => thumbnails.size(),
+        CacheCommand::Prune => {
+            let library = library(directory, data_dir)?;
+            let removed = thumbnails.prune(&library.list());
+            println!(
+                "Removed {} thumbnails, {:.1} MB",
+                removed.files,
+                removed.bytes as f64 / 1e6
+            );
+            thumbnails.size()
+        }
+    };
+    println!(
+        "{} thumbnails, {:.1} MB",
+        usage.files,
+        usage.bytes as f64 / 1e6
+    );
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{
+    parser::ValueSource, Arg, ArgAction, ArgMatches, CommandFactory as _, FromArgMatches as _,
+};
+use toml::{Table, Value};
+
+use crate::args::Args;
+
+// Picked up from the working directory when --config isn't given
+pub const DEFAULT_PATH: &str = "m3s.toml";
+
+/// Parses the command line on top of M3S_ environment variables and the config file.
+///
+/// Every flag can be set in the file under its long name, either flat (`oidc-issuer`) or in a
+/// table (`[oidc] issuer`), underscores and hyphens are interchangeable. In the environment it's
+/// the long name in capitals with an M3S_ prefix, `M3S_OIDC_ISSUER`, or `M3S_OIDC_ISSUER_FILE`
+/// naming a file to read it from. The command line wins over the environment, the environment
+/// over the file and the file over defaults.
+pub fn load() -> Result<Args> {
+    let cli: Vec<OsString> = std::env::args_os().collect();
+    let matches = Args::command().get_matches_from(&cli);
+    merge(cli, matches)
+}
+
+/// Reads the command line and config file again, failing rather than exiting on bad settings
+pub fn reload() -> Result<Args> {
+    let cli: Vec<OsString> = std::env::args_os().collect();
+    let matches = Args::command().try_get_matches_from(&cli)?;
+    merge(cli, matches)
+}
+
+/// The config file in use, --config or else m3s.toml if there is one
+pub fn file(config: Option<&Path>) -> Option<PathBuf> {
+    match config {
+        Some(path) => Some(path.to_path_buf()),
+        None => Some(PathBuf::from(DEFAULT_PATH)).filter(|path| path.exists()),
+    }
+}
+
+fn merge(cli: Vec<OsString>, matches: ArgMatches) -> Result<Args> {
+    let command = Args::command();
+    let mut flags = Vec::new();
+    let mut positionals = Vec::new();
+    let mut sources = Vec::new();
+
+    let mut from_env = HashSet::new();
+    for arg in command.get_arguments() {
+        // M3S_CONFIG is read by clap itself, it's needed before any of this
+        let builtin = matches!(arg.get_action(), ArgAction::Help | ArgAction::Version);
+        if builtin || arg.get_id() == "config" || overridden(&matches, arg) {
+            continue;
+        }
+        let name = env_name(arg);
+        let Some(value) = env_value(&name)? else {
+            continue;
+        };
+        // Lists are comma separated, like M3S_ADDRESS=[::],0.0.0.0
+        let values = match arg.get_action() {
+            ArgAction::Append => value.split(',').map(str::to_string).collect(),
+            _ => vec![value],
+        };
+        let from = "from the environment";
+        add(arg, &name, values, from, &mut flags, &mut positionals)?;
+        from_env.insert(arg.get_id().clone());
+    }
+    if !from_env.is_empty() {
+        sources.push("M3S_ variables".to_string());
+    }
+
+    let path = file(matches.get_one::<PathBuf>("config").map(PathBuf::as_path));
+    if let Some(path) = &path {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+        let table: Table = text
+            .parse()
+            .with_context(|| format!("Failed to parse {path:?}"))?;
+
+        let mut settings = Vec::new();
+        flatten(&table, None, &mut settings);
+
+        let from = format!("in {path:?}");
+        for (key, value) in settings {
+            let arg = command
+                .get_arguments()
+                .find(|arg| names(arg).any(|name| name == key))
+                .ok_or_else(|| anyhow!("Unknown setting {key:?} {from}"))?;
+
+            if overridden(&matches, arg) || from_env.contains(arg.get_id()) {
+                continue;
+            }
+            let values = to_strings(&key, value, &from)?;
+            add(arg, &key, values, &from, &mut flags, &mut positionals)?;
+        }
+        sources.push(format!("{path:?}"));
+    }
+
+    if sources.is_empty() {
+        return Ok(Args::from_arg_matches(&matches)?);
+    }
+
+    // These values go ahead of the real arguments, so they land before any subcommand
+    let argv: Vec<OsString> = cli
+        .first()
+        .cloned()
+        .into_iter()
+        .chain(flags)
+        .chain(positionals)
+        .chain(cli.iter().skip(1).cloned())
+        .collect();
+
+    let matches = Args::command().try_get_matches_from(argv).map_err(|e| {
+        // Only the first line, clap's usage hints talk about flags rather than settings
+        let message = e.to_string();
+        let message = message.lines().next().unwrap_or_default();
+        anyhow!(
+            "Invalid setting in {}: {}",
+            sources.join(" or "),
+            message.trim_start_matches("error: ")
+        )
+    })?;
+    Ok(Args::from_arg_matches(&matches)?)
+}
+
+fn add(
+    arg: &Arg,
+    key: &str,
+    values: Vec<String>,
+    from: &str,
+    flags: &mut Vec<OsString>,
+    positionals: &mut Vec<OsString>,
+) -> Result<()> {
+    match (arg.is_positional(), arg.get_long()) {
+        (true, _) => positionals.extend(values.into_iter().map(OsString::from)),
+        (false, Some(long)) => flags.extend(to_flags(arg, long, key, values, from)?),
+        (false, None) => bail!("Setting {key:?} {from} can't be set outside the command line"),
+    }
+    Ok(())
+}
+
+// --oidc-issuer is M3S_OIDC_ISSUER, the library directory M3S_DIRECTORY
+fn env_name(arg: &Arg) -> String {
+    let name = arg.get_long().unwrap_or(arg.get_id().as_str());
+    format!("M3S_{}", name.replace('-', "_").to_ascii_uppercase())
+}
+
+// M3S_X_FILE names a file holding the value instead, for secrets mounted into containers
+fn env_value(name: &str) -> Result<Option<String>> {
+    if let Some(value) = std::env::var(name).ok().filter(|v| !v.is_empty()) {
+        return Ok(Some(value));
+    }
+    let Some(path) = std::env::var_os(format!("{name}_FILE")).filter(|p| !p.is_empty()) else {
+        return Ok(None);
+    };
+    let value = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {path:?} named by {name}_FILE"))?;
+    Ok(Some(value.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+// `[oidc] issuer = ...` becomes ("oidc-issuer", ...)
+fn flatten<'a>(table: &'a Table, prefix: Option<&str>, settings: &mut Vec<(String, &'a Value)>) {
+    for (key, value) in table {
+        let key = key.replace('_', "-");
+        let key = match prefix {
+            Some(prefix) => format!("{prefix}-{key}"),
+            None => key,
+        };
+        match value {
+            Value::Table(table) => flatten(table, Some(&key), settings),
+            value => settings.push((key, value)),
+        }
+    }
+}
+
+fn names(arg: &Arg) -> impl Iterator<Item = String> + '_ {
+    let id = arg.get_id().as_str().replace('_', "-");
+    arg.get_long()
+        .map(str::to_string)
+        .into_iter()
+        .chain(arg.is_positional().then_some(id))
+}
+
+fn overridden(matches: &ArgMatches, arg: &Arg) -> bool {
+    matches!(
+        matches.value_source(arg.get_id().as_str()),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+fn to_strings(key: &str, value: &Value, from: &str) -> Result<Vec<String>> {
+    match value {
+        Value::String(s) => Ok(vec![s.clone()]),
+        Value::Integer(n) => Ok(vec![n.to_string()]),
+        Value::Float(n) => Ok(vec![n.to_string()]),
+        Value::Boolean(b) => Ok(vec![b.to_string()]),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::Array(_) | Value::Table(_) => {
+                    bail!("Setting {key:?} {from} can't contain nested lists or tables")
+                }
+                value => Ok(to_strings(key, value, from)?.remove(0)),
+            })
+            .collect(),
+        Value::Datetime(_) | Value::Table(_) => {
+            bail!("Setting {key:?} {from} has an unsupported type")
+        }
+    }
+}
+
+fn to_flags(
+    arg: &Arg,
+    long: &str,
+    key: &str,
+    values: Vec<String>,
+    from: &str,
+) -> Result<Vec<OsString>> {
+    // Switches like --oidc-create-users take no value, true turns them on
+    if !arg.get_action().takes_values() {
+        return match values.as_slice() {
+            [b] if b == "true" || b == "1" => Ok(vec![format!("--{long}").into()]),
+            [b] if b == "false" || b == "0" => Ok(Vec::new()),
+            _ => bail!("Setting {key:?} {from} must be true or false"),
+        };
+    }
+    Ok(values
+        .into_iter()
+        .map(|value| format!("--{long}={value}").into())
+        .collect())
+}
@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use axum::http::HeaderValue;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::args::CorsArgs;
+
+pub fn layer(
+    CorsArgs {
+        cors_origins,
+        cors_methods,
+        cors_headers,
+        cors_max_age,
+    }: CorsArgs,
+) -> Result<Option<CorsLayer>> {
+    if cors_origins.is_empty() {
+        return Ok(None);
+    }
+
+    let layer = CorsLayer::new()
+        .allow_methods(cors_methods)
+        .allow_headers(cors_headers)
+        .max_age(Duration::from_secs(cors_max_age));
+
+    if cors_origins.iter().any(|origin| origin == "*") {
+        // Browsers refuse credentials with a wildcard origin, so any origin gets anonymous access only
+        return Ok(Some(layer.allow_origin(AllowOrigin::any())));
+    }
+
+    let origins = cors_origins
+        .iter()
+        .map(|origin| {
+            HeaderValue::from_str(origin.trim_end_matches('/'))
+                .with_context(|| format!("Invalid CORS origin {origin}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Some(
+        layer
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_credentials(true),
+    ))
+}
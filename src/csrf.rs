@@ -0,0 +1,121 @@
+use axum::http::{header, HeaderMap, Method};
+use axum_extra::extract::cookie::{Cookie, Key, SameSite};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// Readable by scripts on our own origin, which echo it back in HEADER
+pub const COOKIE_NAME: &str = "m3s_csrf";
+pub const HEADER: &str = "x-csrf-token";
+
+// Derived from the session rather than stored, so there is nothing extra to persist or revoke
+fn mac(key: &Key, session_id: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.signing()).expect("HMAC accepts any key");
+    mac.update(b"csrf:");
+    mac.update(session_id.as_bytes());
+    mac
+}
+
+pub fn token(key: &Key, session_id: &str) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(mac(key, session_id).finalize().into_bytes())
+}
+
+pub fn verify(key: &Key, session_id: &str, token: &str) -> bool {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(token)
+        .is_ok_and(|token| mac(key, session_id).verify_slice(&token).is_ok())
+}
+
+pub fn cookie(token: String, max_age: time::Duration, secure: bool) -> Cookie<'static> {
+    Cookie::build((COOKIE_NAME, token))
+        .path("/")
+        .secure(secure)
+        .same_site(SameSite::Strict)
+        .max_age(max_age)
+        .build()
+}
+
+pub fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Rejects requests a browser marked as coming from another site
+// Clients that send no Origin at all are not browsers acting on someone else's behalf
+pub fn same_origin(headers: &HeaderMap) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN) else {
+        return true;
+    };
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok());
+
+    origin
+        .to_str()
+        .ok()
+        .and_then(|origin| origin.split_once("://"))
+        .is_some_and(|(_, origin)| Some(origin) == host)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn token_verifies_for_its_session_only() {
+        let key = Key::generate();
+        let token = token(&key, "session-a");
+
+        assert!(verify(&key, "session-a", &token));
+        assert!(!verify(&key, "session-b", &token));
+        assert!(!verify(&Key::generate(), "session-a", &token));
+    }
+
+    #[test]
+    fn verify_rejects_forged_tokens() {
+        let key = Key::generate();
+        let token = token(&key, "session");
+        let mut tampered = token.clone().into_bytes();
+        tampered[0] = if tampered[0] == b'A' { b'B' } else { b'A' };
+
+        assert!(!verify(&key, "session", ""));
+        assert!(!verify(&key, "session", "not base64!"));
+        assert!(!verify(
+            &key,
+            "session",
+            &String::from_utf8(tampered).unwrap()
+        ));
+        assert!(!verify(&key, "session", &token[..token.len() - 2]));
+    }
+
+    #[test]
+    fn only_reads_are_safe() {
+        assert!(is_safe(&Method::GET));
+        assert!(is_safe(&Method::HEAD));
+        assert!(is_safe(&Method::OPTIONS));
+        assert!(!is_safe(&Method::POST));
+        assert!(!is_safe(&Method::PATCH));
+        assert!(!is_safe(&Method::DELETE));
+    }
+
+    #[test]
+    fn same_origin_compares_origin_with_host() {
+        let headers = |origin: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::HOST, HeaderValue::from_static("photos.example.com"));
+            if let Some(origin) = origin {
+                headers.insert(header::ORIGIN, HeaderValue::from_static(origin));
+            }
+            headers
+        };
+
+        assert!(same_origin(&headers(None)));
+        assert!(same_origin(&headers(Some("https://photos.example.com"))));
+        assert!(!same_origin(&headers(Some("https://evil.example.com"))));
+        assert!(!same_origin(&headers(Some(
+            "https://photos.example.com.evil.example.com"
+        ))));
+        assert!(!same_origin(&headers(Some("null"))));
+    }
+}
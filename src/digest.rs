@@ -0,0 +1,520 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::{extract::State, middleware, routing::post, Json, Router};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use image::codecs::jpeg::JpegEncoder;
+use rand::RngCore as _;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc2822, Date, OffsetDateTime, Weekday};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::{
+    api_error::ApiError,
+    args::SmtpArgs,
+    auth::{require_role, Principal},
+    jobs::JobLimiter,
+    library::{Kind, Library, LibraryEvent, Media},
+    login::{html_escape, percent_encode},
+    permissions::PermissionStore,
+    smtp::Mailer,
+    store::{load_json, save_json},
+    thumbnails,
+    users::{Role, User, UserStore},
+};
+
+// How often to look whether a digest is due, it goes out within this of the hour
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// A week less some leeway, so a restart on the day doesn't send another
+const MIN_INTERVAL: time::Duration = time::Duration::days(6);
+// Days up to today whose anniversaries count as memories, the week since the last digest
+const MEMORY_DAYS: i64 = 7;
+const MEMORY_YEARS: usize = 3;
+const MEMORIES_PER_YEAR: usize = 4;
+const MAX_NEW: usize = 12;
+// Files remembered between digests, a huge import only needs to be counted roughly
+const MAX_PENDING: usize = 10_000;
+const THUMBNAIL_SIZE: u32 = 240;
+const THUMBNAIL_QUALITY: u8 = 80;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Progress {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    last_sent: Option<OffsetDateTime>,
+    // Added by scans since, in the order they were found
+    #[serde(default)]
+    added: Vec<PathBuf>,
+}
+
+/// Weekly emails to users who asked for them, with the photos added since the last one and
+/// those taken in the same week in earlier years, as inline thumbnails.
+///
+/// What's been added is kept in the data directory, so a restart doesn't lose it. Each user
+/// only gets what they may see, and nothing when there's nothing to show.
+pub struct Digests {
+    path: PathBuf,
+    progress: Mutex<Progress>,
+    mailer: Mailer,
+    day: Weekday,
+    hour: u8,
+    library: Arc<Library>,
+    users: Arc<UserStore>,
+    permissions: Arc<PermissionStore>,
+    jobs: Arc<JobLimiter>,
+    // Links are left out without it, a relative one is no use in an email
+    public_url: Option<String>,
+}
+
+impl Digests {
+    /// None when there's no mail server to send through
+    pub fn start(
+        args: &SmtpArgs,
+        data_dir: &Path,
+        library: Arc<Library>,
+        users: Arc<UserStore>,
+        permissions: Arc<PermissionStore>,
+        jobs: Arc<JobLimiter>,
+        public_url: Option<String>,
+    ) -> Result<Option<Arc<Self>>> {
+        let Some(mailer) = Mailer::new(args)? else {
+            return Ok(None);
+        };
+        let path = data_dir.join("digest.json");
+        let progress = load_json(&path)?;
+
+        let digests = Arc::new(Self {
+            path,
+            progress: Mutex::new(progress),
+            mailer,
+            day: args.digest_day,
+            hour: args.digest_hour,
+            library,
+            users,
+            permissions,
+            jobs,
+            public_url: public_url.map(|url| url.trim_end_matches('/').to_string()),
+        });
+        tokio::spawn(digests.clone().follow_scans());
+        tokio::spawn(digests.clone().schedule());
+        Ok(Some(digests))
+    }
+
+    async fn follow_scans(self: Arc<Self>) {
+        let mut events = self.library.events();
+        loop {
+            let added = match events.recv().await {
+                Ok(LibraryEvent::Scanned { added, .. }) => added,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("The digest missed {missed} library events");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            if added.is_empty() {
+                continue;
+            }
+            let mut progress = self.progress.lock().unwrap();
+            progress
+                .added
+                .extend(added.into_iter().map(|media| media.path));
+            let excess = progress.added.len().saturating_sub(MAX_PENDING);
+            progress.added.drain(..excess);
+            if let Err(e) = save_json(&self.path, &*progress) {
+                warn!("Failed to save digest progress: {e:#}");
+            }
+        }
+    }
+
+    async fn schedule(self: Arc<Self>) {
+        loop {
+            let now = OffsetDateTime::now_utc();
+            let last_sent = self.progress.lock().unwrap().last_sent;
+            let due = now.weekday() == self.day
+                && now.hour() >= self.hour
+                && last_sent.is_none_or(|last| now - last >= MIN_INTERVAL);
+            if due {
+                match self.send().await {
+                    Ok(sent) => info!("Sent {sent} digests"),
+                    Err(e) => warn!("Failed to send digests: {e:#}"),
+                }
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Sends every user who asked for one their digest now, returning how many were sent.
+    /// Anyone it fails for misses this one, rather than everyone getting it twice.
+    pub async fn send(&self) -> Result<usize> {
+        let now = OffsetDateTime::now_utc();
+        let pending = self.progress.lock().unwrap().added.clone();
+        let media = self.library.list();
+
+        let mut sent = 0;
+        for user in self.users.list() {
+            let Some(email) = user.email.clone().filter(|_| user.digest) else {
+                continue;
+            };
+            let Some(digest) = self.digest(&user, &media, &pending, now.date()) else {
+                continue;
+            };
+            let message = match self.compose(digest, &email, now).await {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("Failed to write the digest for {}: {e:#}", user.username);
+                    continue;
+                }
+            };
+            match self.mailer.send(&email, &message).await {
+                Ok(()) => sent += 1,
+                Err(e) => warn!("Failed to send the digest to {}: {e:#}", user.username),
+            }
+        }
+
+        // Anything found while sending waits for the next one
+        let mut progress = self.progress.lock().unwrap();
+        let done = pending.len().min(progress.added.len());
+        progress.added.drain(..done);
+        progress.last_sent = Some(now);
+        save_json(&self.path, &*progress)?;
+        Ok(sent)
+    }
+
+    // What the user gets this week, None when there's nothing
+    fn digest(
+        &self,
+        user: &User,
+        media: &[Media],
+        pending: &[PathBuf],
+        today: Date,
+    ) -> Option<Digest> {
+        let principal = Principal::User(user.clone());
+        let visible = |media: &Media| self.permissions.can_access(&principal, &media.path);
+
+        let by_path: HashMap<&Path, &Media> = media
+            .iter()
+            .map(|media| (media.path.as_path(), media))
+            .collect();
+        let mut added: Vec<Media> = pending
+            .iter()
+            .filter_map(|path| by_path.get(path.as_path()))
+            .filter(|media| visible(media))
+            .map(|&media| media.clone())
+            .collect();
+        added.sort_by(|a, b| b.taken.cmp(&a.taken).then_with(|| a.path.cmp(&b.path)));
+        added.dedup_by(|a, b| a.path == b.path);
+
+        // Newest first, so the fewest years ago come first
+        let mut memories: BTreeMap<i32, Vec<Media>> = BTreeMap::new();
+        for media in media.iter().filter(|m| m.kind == Kind::Image && visible(m)) {
+            let years = today.year() - media.taken.year();
+            // The 29th of February has no anniversary most years
+            let Ok(anniversary) = media.taken.date().replace_year(today.year()) else {
+                continue;
+            };
+            let days = (today - anniversary).whole_days();
+            if years > 0 && (0..MEMORY_DAYS).contains(&days) {
+                let year = memories.entry(years).or_default();
+                if year.len() < MEMORIES_PER_YEAR {
+                    year.push(media.clone());
+                }
+            }
+        }
+        let memories: Vec<(i32, Vec<Media>)> = memories.into_iter().take(MEMORY_YEARS).collect();
+
+        if added.is_empty() && memories.is_empty() {
+            return None;
+        }
+        Some(Digest { added, memories })
+    }
+
+    async fn compose(&self, digest: Digest, to: &str, now: OffsetDateTime) -> Result<Vec<u8>> {
+        let Digest { added, memories } = digest;
+        let mut subject = Vec::new();
+        let mut text = String::new();
+        let mut html = String::new();
+        let mut images = Vec::new();
+
+        if !added.is_empty() {
+            let heading = plural(added.len(), "new photo", "new photos");
+            subject.push(heading.clone());
+            text += &format!("{heading}\n");
+            html += &format!("<h2>{heading}</h2>\n<p>");
+            for media in added.iter().filter(|m| m.kind == Kind::Image).take(MAX_NEW) {
+                text += &format!("  {}\n", media.path.to_string_lossy());
+                html += &self.tile(media, &mut images).await;
+            }
+            html += "</p>\n";
+            let shown = added
+                .iter()
+                .filter(|m| m.kind == Kind::Image)
+                .take(MAX_NEW)
+                .count();
+            if added.len() > shown {
+                let more = format!("and {} more", added.len() - shown);
+                text += &format!("  {more}\n");
+                html += &format!("<p>{more}</p>\n");
+            }
+        }
+        if let Some((years, _)) = memories.last() {
+            subject.push(format!(
+                "your memories from {}",
+                plural(*years as usize, "year ago", "years ago")
+            ));
+        }
+        for (years, photos) in &memories {
+            let heading = plural(*years as usize, "year ago", "years ago");
+            text += &format!("\n{heading}\n");
+            html += &format!("<h2>{heading}</h2>\n<p>");
+            for media in photos {
+                text += &format!("  {}\n", media.path.to_string_lossy());
+                html += &self.tile(media, &mut images).await;
+            }
+            html += "</p>\n";
+        }
+        if let Some(url) = &self.public_url {
+            text += &format!("\n{url}/\n");
+            html += &format!(r#"<p><a href="{}/">Open m3s</a></p>"#, html_escape(url));
+        }
+
+        let subject = capitalize(&subject.join(", "));
+        let html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{subject}</title></head>\n\
+             <body style=\"font-family: sans-serif\">\n{html}</body>\n</html>\n"
+        );
+        Ok(message(
+            &self.mailer.from,
+            to,
+            &subject,
+            now,
+            &text,
+            &html,
+            &images,
+        ))
+    }
+
+    // An inline thumbnail linking to its folder, or nothing when it can't be made
+    async fn tile(&self, media: &Media, images: &mut Vec<Vec<u8>>) -> String {
+        let name = html_escape(&media.path.to_string_lossy());
+        let jpeg = match self.thumbnail(media).await {
+            Ok(jpeg) => jpeg,
+            Err(e) => {
+                warn!("No digest thumbnail for {:?}: {e:#}", media.path);
+                return String::new();
+            }
+        };
+        images.push(jpeg);
+        let img = format!(
+            r#"<img src="cid:{}@m3s" alt="{name}" title="{name}" style="margin: 2px">"#,
+            images.len() - 1
+        );
+        let folder = media.path.parent().unwrap_or(Path::new(""));
+        match &self.public_url {
+            Some(url) => format!(
+                r#"<a href="{}/#/folders/{}">{img}</a>"#,
+                html_escape(url),
+                html_escape(&percent_encode(&folder.to_string_lossy()))
+            ),
+            None => img,
+        }
+    }
+
+    // Always a JPEG, which every mail client shows, whatever the thumbnails are kept as
+    async fn thumbnail(&self, media: &Media) -> Result<Vec<u8>> {
+        let file = self.library.root().resolve(&media.path)?;
+        let permit = self
+            .jobs
+            .acquire_background(thumbnails::decode_megabytes(media, THUMBNAIL_SIZE))
+            .await;
+        let media = media.clone();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let image = thumbnails::preview(&media, &file, THUMBNAIL_SIZE)?.to_rgb8();
+            let mut data = Vec::new();
+            image
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut data, THUMBNAIL_QUALITY))?;
+            Ok(data)
+        })
+        .await?
+    }
+}
+
+struct Digest {
+    added: Vec<Media>,
+    // By how many years ago, fewest first
+    memories: Vec<(i32, Vec<Media>)>,
+}
+
+fn plural(count: usize, one: &str, many: &str) -> String {
+    match count {
+        1 => format!("1 {one}"),
+        count => format!("{count} {many}"),
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// Text and HTML alternatives, the HTML with its images alongside for cid: links to find
+fn message(
+    from: &str,
+    to: &str,
+    subject: &str,
+    date: OffsetDateTime,
+    text: &str,
+    html: &str,
+    images: &[Vec<u8>],
+) -> Vec<u8> {
+    let mut id = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut id);
+    let id: String = id.iter().map(|b| format!("{b:02x}")).collect();
+    let (alternative, related) = (format!("alt-{id}"), format!("rel-{id}"));
+
+    let mut message = format!(
+        "From: {from}\r\nTo: <{to}>\r\nSubject: {subject}\r\nDate: {}\r\nMessage-ID: <{id}@m3s>\r\n\
+         MIME-Version: 1.0\r\nContent-Type: multipart/alternative; boundary=\"{alternative}\"\r\n\r\n",
+        date.format(&Rfc2822).unwrap_or_default()
+    );
+    message += &format!("--{alternative}\r\n");
+    message += &part("text/plain; charset=utf-8", &[], text.as_bytes());
+    message += &format!(
+        "--{alternative}\r\nContent-Type: multipart/related; boundary=\"{related}\"\r\n\r\n"
+    );
+    message += &format!("--{related}\r\n");
+    message += &part("text/html; charset=utf-8", &[], html.as_bytes());
+    for (i, image) in images.iter().enumerate() {
+        let headers = [
+            format!("Content-ID: <{i}@m3s>"),
+            "Content-Disposition: inline".to_string(),
+        ];
+        message += &format!("--{related}\r\n");
+        message += &part("image/jpeg", &headers, image);
+    }
+    message += &format!("--{related}--\r\n--{alternative}--\r\n");
+    message.into_bytes()
+}
+
+// Base64 throughout, in lines of 76, so nothing in the content can look like a boundary
+fn part(content_type: &str, headers: &[String], data: &[u8]) -> String {
+    let mut part = format!("Content-Type: {content_type}\r\nContent-Transfer-Encoding: base64\r\n");
+    for header in headers {
+        part += &format!("{header}\r\n");
+    }
+    part += "\r\n";
+    let encoded = BASE64_STANDARD.encode(data);
+    for line in encoded.as_bytes().chunks(76) {
+        part += std::str::from_utf8(line).expect("base64 is ASCII");
+        part += "\r\n";
+    }
+    part
+}
+
+pub fn router() -> Router<Arc<Digests>> {
+    Router::new()
+        .route("/", post(send_now))
+        .route_layer(middleware::from_fn_with_state(Role::Admin, require_role))
+}
+
+#[derive(Debug, Serialize)]
+struct Sent {
+    sent: usize,
+}
+
+// Doesn't wait for the day, and counts as this week's
+async fn send_now(State(digests): State<Arc<Digests>>) -> Result<Json<Sent>, ApiError> {
+    let sent = digests
+        .send()
+        .await
+        .map_err(|e| ApiError::internal("Failed to send digests", e))?;
+    Ok(Json(Sent { sent }))
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    fn boundary<'a>(message: &'a str, kind: &str) -> &'a str {
+        let start = message
+            .find(&format!("multipart/{kind}; boundary=\""))
+            .unwrap();
+        message[start..].split('"').nth(1).unwrap()
+    }
+
+    // Between the lines --boundary, without the closing one
+    fn parts<'a>(body: &'a str, boundary: &str) -> Vec<&'a str> {
+        let (body, _) = body.split_once(&format!("--{boundary}--\r\n")).unwrap();
+        body.split(&format!("--{boundary}\r\n")).skip(1).collect()
+    }
+
+    fn decoded(part: &str) -> Vec<u8> {
+        let (_, body) = part.split_once("\r\n\r\n").unwrap();
+        BASE64_STANDARD.decode(body.replace("\r\n", "")).unwrap()
+    }
+
+    #[test]
+    fn message_nests_html_and_images_in_an_alternative() {
+        let images = [vec![1, 2, 3], vec![4; 100]];
+        let message = message(
+            "m3s <photos@example.com>",
+            "alice@example.com",
+            "Your week",
+            datetime!(2024-06-03 08:00 UTC),
+            "Text version",
+            "<p>HTML version</p>",
+            &images,
+        );
+        let message = String::from_utf8(message).unwrap();
+
+        let (headers, body) = message.split_once("\r\n\r\n").unwrap();
+        assert!(headers.starts_with(
+            "From: m3s <photos@example.com>\r\nTo: <alice@example.com>\r\nSubject: Your week\r\n\
+             Date: Mon, 03 Jun 2024 08:00:00 +0000\r\n"
+        ));
+        assert!(headers.contains("\r\nMIME-Version: 1.0\r\n"));
+
+        let alternative = parts(body, boundary(headers, "alternative"));
+        assert_eq!(alternative.len(), 2);
+        assert!(alternative[0].starts_with("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert_eq!(decoded(alternative[0]), b"Text version");
+
+        let related = parts(alternative[1], boundary(alternative[1], "related"));
+        assert_eq!(related.len(), 3);
+        assert!(related[0].starts_with("Content-Type: text/html; charset=utf-8\r\n"));
+        assert_eq!(decoded(related[0]), b"<p>HTML version</p>");
+        for (i, image) in images.iter().enumerate() {
+            assert!(related[i + 1].starts_with("Content-Type: image/jpeg\r\n"));
+            assert!(related[i + 1].contains(&format!("\r\nContent-ID: <{i}@m3s>\r\n")));
+            assert_eq!(&decoded(related[i + 1]), image);
+        }
+
+        assert!(message.ends_with("--\r\n"));
+        assert!(!message.replace("\r\n", "").contains(['\r', '\n']));
+    }
+
+    #[test]
+    fn part_wraps_base64_at_76() {
+        let data = vec![0xff; 200];
+        let part = part("image/jpeg", &[], &data);
+        let (_, body) = part.split_once("\r\n\r\n").unwrap();
+        let lines: Vec<_> = body.trim_end().split("\r\n").collect();
+
+        assert_eq!(
+            lines.iter().map(|l| l.len()).collect::<Vec<_>>(),
+            [76, 76, 76, 40]
+        );
+        assert_eq!(decoded(&part), data);
+    }
+}
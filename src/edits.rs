@@ -0,0 +1,234 @@
+use anyhow::{ensure, Result};
+use image::{imageops, DynamicImage, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Past this a photo is on its side rather than crooked, that's what rotate is for
+const MAX_STRAIGHTEN: f32 = 45.0;
+/// Longest edge photos are looked at for [`suggest`]. Edges that matter are long, they show at
+/// any size.
+pub const SUGGEST_SIZE: u32 = 512;
+// Degrees. Scans are seldom further off than this, and edges further round are more likely meant
+// that way.
+const MAX_SUGGESTED: f64 = 10.0;
+// Degrees, finer than anyone would notice
+const SUGGEST_STEP: f64 = 0.25;
+// Bins either side of an angle that count towards it
+const SUGGEST_SPREAD: usize = 2;
+// Of a Sobel gradient, which goes up to about 1000. Weaker ones are texture and JPEG noise.
+const EDGE_STRENGTH: f64 = 100.0;
+// Share of all edge strength the winning angle must have. Angles spread evenly would give each
+// a fraction of a percent.
+const SUGGEST_CONFIDENCE: f64 = 0.05;
+
+/// Changes shown in place of the original, which is never touched. Applied in order: rotate,
+/// straighten, crop.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Edits {
+    /// Clockwise, in quarter turns: 0, 90, 180 or 270
+    #[serde(default)]
+    pub rotate: u16,
+    /// Clockwise degrees, cropped in just enough that no corners are left empty
+    #[serde(default)]
+    pub straighten: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crop: Option<Crop>,
+}
+
+/// Of the rotated and straightened photo, as fractions of its width and height so it holds at
+/// any size
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Crop {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Edits {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            matches!(self.rotate, 0 | 90 | 180 | 270),
+            "rotate must be 0, 90, 180 or 270"
+        );
+        ensure!(
+            self.straighten.abs() <= MAX_STRAIGHTEN,
+            "straighten must be between -{MAX_STRAIGHTEN} and {MAX_STRAIGHTEN} degrees"
+        );
+        if let Some(Crop {
+            x,
+            y,
+            width,
+            height,
+        }) = self.crop
+        {
+            ensure!(
+                x >= 0.0 && y >= 0.0 && width > 0.0 && height > 0.0,
+                "crop must have a positive width and height and start inside the photo"
+            );
+            ensure!(
+                x + width <= 1.0 && y + height <= 1.0,
+                "crop must end inside the photo"
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether this leaves the photo as it is
+    pub fn is_empty(&self) -> bool {
+        self.rotate == 0 && self.straighten == 0.0 && self.crop.is_none()
+    }
+
+    /// Short enough to go into thumbnail names, changes whenever the edits do
+    pub fn key(&self) -> String {
+        let json = serde_json::to_vec(self).expect("edits always serialize");
+        Sha256::digest(json)[..4]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// Width and height of a photo of the given size once edited
+    pub fn size(&self, width: u32, height: u32) -> (u32, u32) {
+        let (width, height) = match self.rotate {
+            90 | 270 => (height, width),
+            _ => (width, height),
+        };
+        let scale = straighten_scale(width, height, self.straighten);
+        let (width, height) = (width as f64 * scale, height as f64 * scale);
+        let (width, height) = match self.crop {
+            Some(crop) => (width * crop.width as f64, height * crop.height as f64),
+            None => (width, height),
+        };
+        (
+            (width.round() as u32).max(1),
+            (height.round() as u32).max(1),
+        )
+    }
+
+    /// How much smaller the edited photo is than the original, along its shorter side. A
+    /// thumbnail of an edited photo needs that much more of the original decoded.
+    pub fn zoom(&self, width: u32, height: u32) -> f64 {
+        let (width, height) = match self.rotate {
+            90 | 270 => (height, width),
+            _ => (width, height),
+        };
+        let scale = straighten_scale(width, height, self.straighten);
+        let crop = self.crop.map_or(1.0, |crop| crop.width.min(crop.height)) as f64;
+        1.0 / (scale * crop)
+    }
+
+    pub fn apply(&self, image: DynamicImage) -> DynamicImage {
+        let image = match self.rotate {
+            90 => image.rotate90(),
+            180 => image.rotate180(),
+            270 => image.rotate270(),
+            _ => image,
+        };
+        let image = match self.straighten == 0.0 {
+            true => image,
+            false => straighten(&image, self.straighten).into(),
+        };
+        match self.crop {
+            Some(crop) => {
+                let (width, height) = (image.width() as f32, image.height() as f32);
+                let x = (crop.x * width).round() as u32;
+                let y = (crop.y * height).round() as u32;
+                let cropped_width = ((crop.width * width).round() as u32).max(1);
+                let cropped_height = ((crop.height * height).round() as u32).max(1);
+                image.crop_imm(x, y, cropped_width, cropped_height)
+            }
+            None => image,
+        }
+    }
+}
+
+/// Straightens a crooked scan: `current` with the turn that lines up the photo's strongest edges
+/// with its frame. None when they already are, or no angle stands out. Takes the photo as it
+/// was taken, edits left off.
+pub fn suggest(image: &DynamicImage, current: Option<&Edits>) -> Option<Edits> {
+    let luma = imageops::blur(&image.to_luma8(), 1.0);
+    let (width, height) = luma.dimensions();
+    let bins = (2.0 * MAX_SUGGESTED / SUGGEST_STEP) as usize + 1;
+    let mut histogram = vec![0.0; bins];
+    let mut total = 0.0;
+
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let p = |dx: u32, dy: u32| f64::from(luma.get_pixel(x + dx - 1, y + dy - 1).0[0]);
+            let gx = p(2, 0) + 2.0 * p(2, 1) + p(2, 2) - p(0, 0) - 2.0 * p(0, 1) - p(0, 2);
+            let gy = p(0, 2) + 2.0 * p(1, 2) + p(2, 2) - p(0, 0) - 2.0 * p(1, 0) - p(2, 0);
+            let strength = gx.hypot(gy);
+            if strength < EDGE_STRENGTH {
+                continue;
+            }
+            total += strength;
+            // Folded so that horizontal and vertical edges both come out near 0
+            let angle = (gy.atan2(gx).to_degrees() + 45.0).rem_euclid(90.0) - 45.0;
+            if angle.abs() <= MAX_SUGGESTED {
+                histogram[((angle + MAX_SUGGESTED) / SUGGEST_STEP).round() as usize] += strength;
+            }
+        }
+    }
+
+    // Each bin with its neighbours, as an edge's angle wavers a little along it
+    let window = |i: usize| i.saturating_sub(SUGGEST_SPREAD)..=(i + SUGGEST_SPREAD).min(bins - 1);
+    let (peak, weight) = (0..bins)
+        .map(|i| (i, histogram[window(i)].iter().sum::<f64>()))
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    if weight <= SUGGEST_CONFIDENCE * total {
+        return None;
+    }
+    // Between bins, weighted by how strong each is
+    let centre = window(peak).map(|i| i as f64 * histogram[i]).sum::<f64>() / weight;
+    let angle = centre * SUGGEST_STEP - MAX_SUGGESTED;
+    if angle.abs() < SUGGEST_STEP {
+        return None;
+    }
+    Some(Edits {
+        // Back the other way, to a tenth of a degree
+        straighten: (-angle * 10.0).round() as f32 / 10.0,
+        ..current.cloned().unwrap_or_default()
+    })
+}
+
+// The largest scale at which the photo's own shape fits inside itself turned by `degrees`
+fn straighten_scale(width: u32, height: u32, degrees: f32) -> f64 {
+    let (sin, cos) = (degrees as f64).to_radians().sin_cos();
+    let (sin, cos) = (sin.abs(), cos.abs());
+    let (width, height) = (width as f64, height as f64);
+    (width / (width * cos + height * sin)).min(height / (width * sin + height * cos))
+}
+
+// Turned about the centre and sampled bilinearly, then cut down to straighten_scale
+fn straighten(image: &DynamicImage, degrees: f32) -> RgbaImage {
+    let source = image.to_rgba8();
+    let (width, height) = (source.width(), source.height());
+    let scale = straighten_scale(width, height, degrees);
+    let out_width = ((width as f64 * scale).round() as u32).max(1);
+    let out_height = ((height as f64 * scale).round() as u32).max(1);
+    let (sin, cos) = (degrees as f64).to_radians().sin_cos();
+
+    let max_x = (width - 1) as f64;
+    let max_y = (height - 1) as f64;
+    RgbaImage::from_fn(out_width, out_height, |x, y| {
+        let dx = x as f64 + 0.5 - out_width as f64 / 2.0;
+        let dy = y as f64 + 0.5 - out_height as f64 / 2.0;
+        // Back to where the pixel came from, turning the other way
+        let sx = (cos * dx + sin * dy + width as f64 / 2.0 - 0.5).clamp(0.0, max_x);
+        let sy = (-sin * dx + cos * dy + height as f64 / 2.0 - 0.5).clamp(0.0, max_y);
+
+        let (x0, y0) = (sx.floor() as u32, sy.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+        let (fx, fy) = (sx - x0 as f64, sy - y0 as f64);
+        let [a, b, c, d] =
+            [(x0, y0), (x1, y0), (x0, y1), (x1, y1)].map(|(x, y)| source.get_pixel(x, y).0);
+        Rgba(std::array::from_fn(|i| {
+            let top = a[i] as f64 * (1.0 - fx) + b[i] as f64 * fx;
+            let bottom = c[i] as f64 * (1.0 - fx) + d[i] as f64 * fx;
+            (top * (1.0 - fy) + bottom * fy).round() as u8
+        }))
+    })
+}
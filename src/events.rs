@@ -0,0 +1,225 @@
+use std::{collections::HashMap, ops::Range};
+
+use serde::Serialize;
+use time::{Date, Duration, OffsetDateTime};
+
+use crate::{i18n::Messages, jpg::Location, library::Media};
+
+// Longer than a night's sleep between photos, whatever comes next is something else
+const EVENT_GAP: Duration = Duration::hours(8);
+// Away from home, a day without photos doesn't end a trip but a longer one does
+const TRIP_GAP: Duration = Duration::hours(36);
+// Kilometres. Photos further apart than this were taken somewhere else.
+const PLACE_DISTANCE: f64 = 50.0;
+// Degrees. Home is the cell this wide with the most photos in it.
+const HOME_CELL: f64 = 0.1;
+
+/// Media taken close together in time and place, such as a party or a trip
+#[derive(Debug, Serialize)]
+pub struct Event {
+    /// Its place and dates, such as "Lisbon, 3–5 June 2024", in the language asked for. Just the
+    /// dates without a place.
+    pub title: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub start: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub end: OffsetDateTime,
+    /// The folder most of it is in, as there's no place database to look locations up in
+    pub place: Option<String>,
+    /// Where its middle photo with a location was taken
+    pub location: Option<Location>,
+    // Position of its newest item in the timeline, for paging straight to it
+    pub offset: usize,
+    pub count: usize,
+}
+
+/// Splits a timeline, newest first, into events in the same order, titled in the language of
+/// `messages`.
+///
+/// A long gap between photos or a move to somewhere else starts a new event. Events away from
+/// home with no more than a day between them are then joined into one trip.
+pub fn cluster(timeline: &[Media], messages: &Messages) -> Vec<Event> {
+    let mut groups: Vec<Range<usize>> = Vec::new();
+    let mut start = 0;
+    let mut last_location = None;
+    for (i, media) in timeline.iter().enumerate().skip(1) {
+        let gap = timeline[i - 1].taken - media.taken;
+        let moved = match (last_location, media.location) {
+            (Some(last), Some(location)) => Location::distance(last, location) > PLACE_DISTANCE,
+            _ => false,
+        };
+        if gap > EVENT_GAP || moved {
+            groups.push(start..i);
+            start = i;
+            last_location = None;
+        }
+        last_location = media.location.or(last_location);
+    }
+    if !timeline.is_empty() {
+        groups.push(start..timeline.len());
+    }
+
+    let home = home(timeline);
+    let away = |group: &Range<usize>| match (home, middle_location(&timeline[group.clone()])) {
+        (Some(home), Some(location)) => home.distance(location) > PLACE_DISTANCE,
+        _ => false,
+    };
+    let mut merged: Vec<Range<usize>> = Vec::new();
+    for group in groups {
+        match merged.last_mut() {
+            Some(last)
+                if timeline[last.end - 1].taken - timeline[group.start].taken <= TRIP_GAP
+                    && away(last)
+                    && away(&group) =>
+            {
+                last.end = group.end
+            }
+            _ => merged.push(group),
+        }
+    }
+
+    merged
+        .into_iter()
+        .map(|group| {
+            let items = &timeline[group.clone()];
+            let (start, end) = (items[items.len() - 1].taken, items[0].taken);
+            let place = place(items);
+            Event {
+                title: title(place.as_deref(), start.date(), end.date(), messages),
+                start,
+                end,
+                place,
+                location: middle_location(items),
+                offset: group.start,
+                count: items.len(),
+            }
+        })
+        .collect()
+}
+
+// Places are folder names, there's no reverse geocoding to name them from locations
+fn title(place: Option<&str>, start: Date, end: Date, messages: &Messages) -> String {
+    let month = |date: Date| messages.format(&format!("month.{}", u8::from(date.month())), &[]);
+    let day = |date: Date| {
+        messages.format(
+            "event.day",
+            &[
+                ("day", &date.day().to_string()),
+                ("month", &month(date)),
+                ("year", &date.year().to_string()),
+            ],
+        )
+    };
+    let (start_day, end_day) = (start.day().to_string(), end.day().to_string());
+    let year = end.year().to_string();
+    let dates = match (start.year() == end.year(), start.month() == end.month()) {
+        _ if start == end => day(start),
+        (true, true) => messages.format(
+            "event.days",
+            &[
+                ("start_day", &start_day),
+                ("end_day", &end_day),
+                ("month", &month(end)),
+                ("year", &year),
+            ],
+        ),
+        (true, false) => messages.format(
+            "event.months",
+            &[
+                ("start_day", &start_day),
+                ("start_month", &month(start)),
+                ("end_day", &end_day),
+                ("end_month", &month(end)),
+                ("year", &year),
+            ],
+        ),
+        (false, _) => messages.format("event.years", &[("start", &day(start)), ("end", &day(end))]),
+    };
+    match place {
+        Some(place) => messages.format("event.title", &[("place", place), ("dates", &dates)]),
+        None => dates,
+    }
+}
+
+// Where most photos with a location were taken
+fn home(timeline: &[Media]) -> Option<Location> {
+    let mut cells: HashMap<(i64, i64), (usize, Location)> = HashMap::new();
+    for location in timeline.iter().filter_map(|media| media.location) {
+        let cell = (
+            (location.latitude / HOME_CELL).floor() as i64,
+            (location.longitude / HOME_CELL).floor() as i64,
+        );
+        cells.entry(cell).or_insert((0, location)).0 += 1;
+    }
+    // Ties go the same way every time
+    cells
+        .into_iter()
+        .max_by_key(|&(cell, (count, _))| (count, cell))
+        .map(|(_, (_, location))| location)
+}
+
+fn middle_location(items: &[Media]) -> Option<Location> {
+    let located: Vec<Location> = items.iter().filter_map(|media| media.location).collect();
+    located.get(located.len() / 2).copied()
+}
+
+// The name of the folder holding at least half of the items, if there is one
+fn place(items: &[Media]) -> Option<String> {
+    let mut folders: HashMap<&str, usize> = HashMap::new();
+    for media in items {
+        let folder = media.path.parent().and_then(|parent| parent.file_name());
+        if let Some(folder) = folder.and_then(|folder| folder.to_str()) {
+            *folders.entry(folder).or_default() += 1;
+        }
+    }
+    folders
+        .into_iter()
+        .filter(|&(_, count)| count * 2 >= items.len())
+        .max_by_key(|&(folder, count)| (count, folder))
+        .map(|(folder, _)| folder.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::date;
+
+    use super::*;
+
+    #[test]
+    fn title_shortens_the_date_range() {
+        let en = Messages::new("en");
+        let title = |start, end| title(None, start, end, &en);
+
+        assert_eq!(
+            title(date!(2024 - 06 - 03), date!(2024 - 06 - 03)),
+            "3 June 2024"
+        );
+        assert_eq!(
+            title(date!(2024 - 06 - 03), date!(2024 - 06 - 05)),
+            "3–5 June 2024"
+        );
+        assert_eq!(
+            title(date!(2024 - 05 - 30), date!(2024 - 06 - 02)),
+            "30 May – 2 June 2024"
+        );
+        assert_eq!(
+            title(date!(2023 - 12 - 30), date!(2024 - 01 - 02)),
+            "30 December 2023 – 2 January 2024"
+        );
+    }
+
+    #[test]
+    fn title_is_localised_with_the_place() {
+        let start = date!(2024 - 03 - 03);
+        let end = date!(2024 - 03 - 05);
+
+        assert_eq!(
+            title(Some("Lisbon"), start, end, &Messages::new("en")),
+            "Lisbon, 3–5 March 2024"
+        );
+        assert_eq!(
+            title(Some("Lissabon"), start, end, &Messages::new("de")),
+            "Lissabon, 3.–5. März 2024"
+        );
+    }
+}
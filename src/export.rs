@@ -0,0 +1,225 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context, Result};
+
+use crate::{
+    library::{Kind, Library, Media},
+    login::{html_escape, percent_encode},
+    safe_path::validate_relative,
+    thumbnails::Thumbnails,
+    ui,
+};
+
+/// Writes a self-contained gallery to `output`, the whole library or only `folders`.
+///
+/// Laid out like the basic pages, a timeline at index.html and a page per folder under
+/// folders/, with originals in media/ and thumbnails in thumbs/. Every link is relative, so
+/// the directory can be uploaded anywhere or opened straight from disk.
+pub fn export(
+    library: &Library,
+    thumbnails: &Thumbnails,
+    output: &Path,
+    folders: &[PathBuf],
+) -> Result<()> {
+    let folders = folders
+        .iter()
+        .map(|folder| {
+            validate_relative(folder).with_context(|| format!("Invalid folder {folder:?}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let selected = |media: &Media| {
+        folders.is_empty() || folders.iter().any(|folder| media.path.starts_with(folder))
+    };
+
+    let media: Vec<Media> = library.list().into_iter().filter(|m| selected(m)).collect();
+    for folder in &folders {
+        ensure!(
+            media.iter().any(|m| m.path.starts_with(folder)),
+            "Nothing to export in {folder:?}"
+        );
+    }
+
+    fs::create_dir_all(output).with_context(|| format!("Failed to create {output:?}"))?;
+    write(&output.join("style.css"), ui::STYLE.as_bytes())?;
+
+    let size = thumbnails.closest(None);
+    let extension = thumbnails.extension(size);
+    let mut failed = 0;
+    for media in &media {
+        let file = library.root().resolve(&media.path)?;
+        copy(file.absolute(), &output.join("media").join(&media.path))?;
+
+        if media.kind == Kind::Image {
+            match thumbnails.get(media, &file, size) {
+                Ok(data) => write(
+                    &output
+                        .join("thumbs")
+                        .join(thumb_name(&media.path, extension)),
+                    &data,
+                )?,
+                Err(e) => {
+                    eprintln!("{:?}: {e:#}", media.path);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    write(
+        &output.join("index.html"),
+        render(0, "Timeline", &grid(0, &media, output, extension)).as_bytes(),
+    )?;
+    let pages = folder_pages(library, output, extension, Path::new(""), &selected)?;
+
+    println!(
+        "Exported {} files and {pages} folder pages to {output:?}, {failed} thumbnails failed",
+        media.len()
+    );
+    Ok(())
+}
+
+// Renders the page for `path` and everything below it, returning how many were written
+fn folder_pages(
+    library: &Library,
+    output: &Path,
+    extension: &str,
+    path: &Path,
+    selected: &impl Fn(&Media) -> bool,
+) -> Result<usize> {
+    let (folders, items) = library.folder(path, selected);
+    // folders/index.html is one deep, folders/a/b/index.html three
+    let depth = 1 + path.iter().count();
+    let up = "../".repeat(depth);
+
+    let mut breadcrumbs = vec![format!(r#"<a href="{up}folders/index.html">Library</a>"#)];
+    let mut ancestor = PathBuf::new();
+    for part in path.iter() {
+        ancestor.push(part);
+        breadcrumbs.push(folder_link(depth, &ancestor, &part.to_string_lossy()));
+    }
+
+    let list: String = folders
+        .iter()
+        .map(|folder| {
+            let name = folder.file_name().unwrap_or_default().to_string_lossy();
+            format!("<li>{}</li>", folder_link(depth, folder, &name))
+        })
+        .collect();
+
+    let body = format!(
+        r#"<nav id="breadcrumbs">{}</nav><ul id="folders">{list}</ul>{}"#,
+        breadcrumbs.join(""),
+        grid(depth, &items, output, extension)
+    );
+    write(
+        &output.join("folders").join(path).join("index.html"),
+        render(depth, "Folders", &body).as_bytes(),
+    )?;
+
+    let mut pages = 1;
+    for folder in &folders {
+        pages += folder_pages(library, output, extension, folder, selected)?;
+    }
+    Ok(pages)
+}
+
+fn folder_link(depth: usize, path: &Path, label: &str) -> String {
+    format!(
+        r#"<a href="{}folders/{}/index.html">{}</a>"#,
+        "../".repeat(depth),
+        html_escape(&percent_encode(&path.to_string_lossy())),
+        html_escape(label)
+    )
+}
+
+// Photos whose thumbnail failed and videos get a bare tile, there's nothing to decode them with
+fn grid(depth: usize, items: &[Media], output: &Path, extension: &str) -> String {
+    let up = "../".repeat(depth);
+    let tiles: String = items
+        .iter()
+        .map(|media| {
+            let path = html_escape(&percent_encode(&media.path.to_string_lossy()));
+            let name = html_escape(&media.path.to_string_lossy());
+            let kind = match media.kind {
+                Kind::Image => "image",
+                Kind::Video => "video",
+            };
+            let thumb = thumb_name(&media.path, extension);
+            let img = match output.join("thumbs").join(&thumb).exists() {
+                true => format!(
+                    r#"<img src="{up}thumbs/{}" alt="{name}" loading="lazy">"#,
+                    html_escape(&percent_encode(&thumb.to_string_lossy()))
+                ),
+                false => String::new(),
+            };
+            format!(r#"<a class="tile {kind}" href="{up}media/{path}" title="{name}">{img}</a>"#)
+        })
+        .collect();
+    format!(r#"<div id="grid">{tiles}</div>"#)
+}
+
+// a/b.png becomes a/b.png.jpg, so b.png and b.jpg next to each other don't collide
+fn thumb_name(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+fn render(depth: usize, title: &str, body: &str) -> String {
+    let up = "../".repeat(depth);
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title} - m3s</title>
+<link rel="stylesheet" href="{up}style.css">
+</head>
+<body class="basic">
+<header>
+  <a class="brand" href="{up}index.html">m3s</a>
+  <nav>
+    <a href="{up}index.html">Timeline</a>
+    <a href="{up}folders/index.html">Folders</a>
+  </nav>
+</header>
+<main>
+{body}
+</main>
+</body>
+</html>"#
+    )
+}
+
+fn write(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create {parent:?}"))?;
+    }
+    fs::write(path, data).with_context(|| format!("Failed to write {path:?}"))
+}
+
+// Exporting again only copies what changed, originals can be large
+fn copy(from: &Path, to: &Path) -> Result<()> {
+    let (Ok(source), Ok(target)) = (fs::metadata(from), fs::metadata(to)) else {
+        return copy_file(from, to);
+    };
+    let unchanged = source.len() == target.len()
+        && matches!((source.modified(), target.modified()), (Ok(s), Ok(t)) if t >= s);
+    match unchanged {
+        true => Ok(()),
+        false => copy_file(from, to),
+    }
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create {parent:?}"))?;
+    }
+    fs::copy(from, to).with_context(|| format!("Failed to copy {from:?} to {to:?}"))?;
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::{fs, io, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use crate::{library::Library, thumbnails::Thumbnails};
+
+// Quick retries before a failed check counts, a busy NAS can miss one
+const RETRIES: u32 = 3;
+// Thumbnails are small, even a busy server takes a while to write a lot of them
+const DISK_INTERVAL: Duration = Duration::from_secs(60);
+const MB: u64 = 1024 * 1024;
+
+/// Checks the library's filesystem every `interval`, which on a network mount can go away or
+/// hang. While it's unreachable, requests that need a file get 503 instead of a stuck worker,
+/// thumbnails already made are still served, and scans are refused. Once it answers again, a
+/// scan picks up whatever changed meanwhile.
+pub fn watch_library(library: Arc<Library>, interval: Duration, timeout: Duration) {
+    let root = library.root().path().to_path_buf();
+    // An unmounted share usually leaves an empty folder behind, which would look like every
+    // file was deleted. Only known when the library was a mount point at startup.
+    let device = device(&root).filter(|&mounted| {
+        root.parent()
+            .and_then(device)
+            .is_some_and(|parent| parent != mounted)
+    });
+
+    tokio::spawn(async move {
+        let mut pending = None;
+        let mut failures = 0;
+        loop {
+            let delay = match check(&root, device, &mut pending, timeout).await {
+                Ok(()) => {
+                    failures = 0;
+                    if !library.set_reachable(true) {
+                        info!("Library at {root:?} is reachable again, rescanning");
+                        let library = library.clone();
+                        tokio::task::spawn_blocking(move || {
+                            if let Err(e) = library.scan() {
+                                error!("Library scan failed: {e:#}");
+                            }
+                        });
+                    }
+                    interval
+                }
+                Err(e) if failures < RETRIES => {
+                    debug!("Library check failed, retrying: {e:#}");
+                    failures += 1;
+                    Duration::from_secs(1 << (failures - 1))
+                }
+                Err(e) => {
+                    if library.set_reachable(false) {
+                        warn!("Library at {root:?} is not reachable, files can't be served until it's back: {e:#}");
+                    }
+                    interval
+                }
+            };
+            tokio::time::sleep(delay).await;
+        }
+    });
+}
+
+async fn check(
+    root: &Path,
+    mounted: Option<u64>,
+    pending: &mut Option<JoinHandle<io::Result<Option<u64>>>>,
+    timeout: Duration,
+) -> Result<()> {
+    // A check that hung last time is waited on again rather than started anew, each one holds
+    // a blocking thread for as long as the filesystem takes
+    let mut handle = pending.take().unwrap_or_else(|| {
+        let root = root.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            fs::read_dir(&root)?.next().transpose()?;
+            Ok(device(&root))
+        })
+    });
+
+    let now = match tokio::time::timeout(timeout, &mut handle).await {
+        Ok(result) => result??,
+        Err(_) => {
+            *pending = Some(handle);
+            bail!("No answer within {}s", timeout.as_secs());
+        }
+    };
+    if mounted.is_some() && now != mounted {
+        bail!("It's on a different filesystem than at startup, the mount is probably gone");
+    }
+    Ok(())
+}
+
+/// Checks every minute that the disk thumbnails are kept on has at least `min_free` bytes
+/// left. Below that, thumbnails asked for are made but only kept in memory, and none are made
+/// ahead of time, so the cache never fills the disk the system runs from.
+pub fn watch_disk(thumbnails: Arc<Thumbnails>, min_free: u64) {
+    tokio::spawn(async move {
+        loop {
+            let free = tokio::task::spawn_blocking({
+                let thumbnails = thumbnails.clone();
+                move || thumbnails.free_space()
+            })
+            .await;
+            match free {
+                Ok(Ok(free)) => {
+                    let low = free.available < min_free;
+                    match (thumbnails.set_low_on_space(low), low) {
+                        (false, true) => warn!(
+                            "Only {} MB left for thumbnails, new ones aren't saved until there's {} MB",
+                            free.available / MB,
+                            min_free / MB
+                        ),
+                        (true, false) => info!("Space for thumbnails again, saving new ones"),
+                        _ => {}
+                    }
+                }
+                Ok(Err(e)) => warn!("Failed to check free space for thumbnails: {e}"),
+                Err(e) => warn!("Failed to check free space for thumbnails: {e}"),
+            }
+            tokio::time::sleep(DISK_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(unix)]
+fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt as _;
+
+    fs::metadata(path).ok().map(|metadata| metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// For load balancers and orchestrators, no authentication needed
+pub fn router(library: Arc<Library>, thumbnails: Arc<Thumbnails>) -> Router {
+    Router::new()
+        .route("/readyz", get(ready))
+        .with_state((library, thumbnails))
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    library: &'static str,
+    // Low on space is still ready, the photos themselves can be served
+    disk: &'static str,
+}
+
+async fn ready(
+    State((library, thumbnails)): State<(Arc<Library>, Arc<Thumbnails>)>,
+) -> (StatusCode, Json<Readiness>) {
+    let reachable = library.reachable();
+    let readiness = Readiness {
+        ready: reachable,
+        library: match reachable {
+            true => "reachable",
+            false => "unreachable",
+        },
+        disk: match thumbnails.low_on_space() {
+            true => "low",
+            false => "ok",
+        },
+    };
+    let status = match reachable {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(readiness))
+}
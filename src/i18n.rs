@@ -0,0 +1,106 @@
+use axum::{
+    http::{header, HeaderMap},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+// Language tag and catalog, the first entry is the fallback for anything missing
+const LOCALES: &[(&str, &str)] = &[
+    ("en", include_str!("../assets/locales/en.json")),
+    ("de", include_str!("../assets/locales/de.json")),
+];
+
+pub fn router() -> Router {
+    Router::new().route("/locale.json", get(locale))
+}
+
+/// Picks the supported locale the client ranks highest from Accept-Language
+pub fn negotiate(headers: &HeaderMap) -> &'static str {
+    let accepted = headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    let mut ranges: Vec<(&str, f32)> = accepted
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse().ok())?;
+            Some((tag, quality))
+        })
+        .filter(|(tag, quality)| !tag.is_empty() && *quality > 0.0)
+        .collect();
+    // Stable, so equally ranked languages keep the client's order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    ranges
+        .iter()
+        .find_map(|(tag, _)| {
+            // de-AT is served the de catalog
+            let primary = tag.split('-').next()?;
+            LOCALES
+                .iter()
+                .find(|(locale, _)| locale.eq_ignore_ascii_case(primary))
+                .map(|(locale, _)| *locale)
+        })
+        .unwrap_or(LOCALES[0].0)
+}
+
+fn catalog(locale: &str) -> Map<String, Value> {
+    LOCALES
+        .iter()
+        .find(|(tag, _)| *tag == locale)
+        .and_then(|(_, json)| serde_json::from_str(json).ok())
+        .unwrap_or_default()
+}
+
+/// A locale's catalog for text the server writes itself, with the fallback's for anything
+/// missing
+#[derive(Debug)]
+pub struct Messages(Map<String, Value>);
+
+impl Messages {
+    pub fn new(locale: &str) -> Self {
+        let mut messages = catalog(LOCALES[0].0);
+        messages.extend(catalog(locale));
+        Self(messages)
+    }
+
+    /// The message with each `{name}` replaced, the same way the UI does it. Unknown keys come
+    /// back as they are.
+    pub fn format(&self, key: &str, values: &[(&str, &str)]) -> String {
+        let mut message = match self.0.get(key).and_then(Value::as_str) {
+            Some(message) => message.to_string(),
+            None => return key.to_string(),
+        };
+        for (name, value) in values {
+            message = message.replace(&format!("{{{name}}}"), value);
+        }
+        message
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Locale {
+    locale: &'static str,
+    messages: Map<String, Value>,
+}
+
+async fn locale(headers: HeaderMap) -> impl IntoResponse {
+    let locale = negotiate(&headers);
+    let Messages(messages) = Messages::new(locale);
+
+    (
+        [
+            (header::CACHE_CONTROL, "no-cache"),
+            (header::VARY, "accept-language"),
+        ],
+        Json(Locale { locale, messages }),
+    )
+}
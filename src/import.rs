@@ -0,0 +1,367 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context, Result};
+use serde::Deserialize;
+use time::{macros::format_description, OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+use crate::library::content_type;
+
+// Google cuts sidecar names, .json included, down to this many characters
+const SIDECAR_NAME_LIMIT: usize = 51;
+
+/// What an import did
+#[derive(Debug, Default)]
+pub struct Imported {
+    pub copied: usize,
+    /// Of those copied, how many got their date from the export's metadata
+    pub dated: usize,
+    /// Already in the library under the same name
+    pub skipped: usize,
+    /// Where each of those dated went, and the date it got
+    pub dates: Vec<(PathBuf, OffsetDateTime)>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Sidecar {
+    photo_taken_time: Option<Timestamp>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Timestamp {
+    // Seconds since the epoch, as a string
+    timestamp: String,
+}
+
+/// Copies an unpacked Google Photos Takeout into `destination`, keeping its album folders.
+///
+/// Takeout files carry the date they were exported rather than taken, which is what photos
+/// without EXIF and videos would be listed under. Each copy gets the time taken from its JSON
+/// sidecar as its modification time instead.
+pub fn takeout(input: &Path, destination: &Path) -> Result<Imported> {
+    // Given the whole archive rather than its photos
+    let photos = input.join("Google Photos");
+    let input = match photos.is_dir() {
+        true => &photos,
+        false => input,
+    };
+    let mut imported = Imported::default();
+    copy_dir(input, destination, &takeout_date, &mut imported)?;
+    Ok(imported)
+}
+
+/// Copies photos exported from Apple Photos into `destination`, keeping their folders. Made for
+/// osxphotos layouts, which can put each album in a folder, and Apple's own export.
+///
+/// Each copy gets the date from its XMP or JSON sidecar, as adjusted in Photos, as its
+/// modification time. The EXIF keeps the original date, which scans would go by, so the caller
+/// keeps [`Imported::dates`] as overrides too, see [`crate::taken`].
+pub fn apple(input: &Path, destination: &Path) -> Result<Imported> {
+    let mut imported = Imported::default();
+    copy_dir(input, destination, &apple_date, &mut imported)?;
+    Ok(imported)
+}
+
+// Finds the time taken of a file, given its folder, name and the other files next to it
+type DateFinder = dyn Fn(&Path, &str, &[String]) -> Option<OffsetDateTime>;
+
+fn copy_dir(
+    input: &Path,
+    destination: &Path,
+    date: &DateFinder,
+    imported: &mut Imported,
+) -> Result<()> {
+    let mut others = Vec::new();
+    let mut files = Vec::new();
+    for entry in fs::read_dir(input).with_context(|| format!("Failed to list {input:?}"))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &destination.join(&name), date, imported)?;
+        } else if content_type(Path::new(&name)).is_some() {
+            files.push(name);
+        } else {
+            others.push(name);
+        }
+    }
+
+    for name in files {
+        let target = destination.join(&name);
+        if target.exists() {
+            imported.skipped += 1;
+            continue;
+        }
+        let source = input.join(&name);
+        let taken = date(input, &name, &others);
+
+        fs::create_dir_all(destination)
+            .with_context(|| format!("Failed to create {destination:?}"))?;
+        fs::copy(&source, &target).with_context(|| format!("Failed to copy {source:?}"))?;
+        // A plain copy gets the time it was made, so the original's is kept when there's no better
+        let modified = match taken {
+            Some(taken) => taken.into(),
+            None => fs::metadata(&source)?.modified()?,
+        };
+        File::options()
+            .write(true)
+            .open(&target)?
+            .set_modified(modified)
+            .with_context(|| format!("Failed to set the date of {target:?}"))?;
+
+        imported.copied += 1;
+        if let Some(taken) = taken {
+            imported.dated += 1;
+            imported.dates.push((target, taken));
+        }
+    }
+    Ok(())
+}
+
+fn takeout_date(dir: &Path, name: &str, others: &[String]) -> Option<OffsetDateTime> {
+    let sidecar = takeout_sidecar(name, others)?;
+    takeout_taken(&dir.join(sidecar))
+        .inspect_err(|e| eprintln!("{sidecar:?}: {e:#}"))
+        .ok()
+}
+
+fn takeout_taken(sidecar: &Path) -> Result<OffsetDateTime> {
+    let sidecar: Sidecar = serde_json::from_slice(&fs::read(sidecar)?)?;
+    let seconds = sidecar
+        .photo_taken_time
+        .context("No photoTakenTime")?
+        .timestamp
+        .parse()
+        .context("Bad photoTakenTime")?;
+    OffsetDateTime::from_unix_timestamp(seconds).context("Bad photoTakenTime")
+}
+
+// Sidecars are named after the file with .json or .supplemental-metadata.json added, and cut
+// short. A duplicate name's "(1)" goes at the end, before .json, and edited copies share the
+// original's.
+fn takeout_sidecar<'a>(name: &str, sidecars: &'a [String]) -> Option<&'a String> {
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let duplicate = stem
+        .strip_suffix(')')
+        .and_then(|stem| stem.rsplit_once('('))
+        .filter(|(_, number)| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+        .map(|(stem, number)| (format!("{stem}.{extension}"), format!("({number})")));
+    let edited = stem
+        .strip_suffix("-edited")
+        .map(|stem| (format!("{stem}.{extension}"), String::new()));
+
+    // Most likely first
+    let candidates = [Some((name.to_string(), String::new())), duplicate, edited];
+    candidates.into_iter().flatten().find_map(|(base, suffix)| {
+        sidecars.iter().find(|sidecar| {
+            let Some(named) = sidecar
+                .strip_suffix(".json")
+                .and_then(|named| named.strip_suffix(suffix.as_str()))
+            else {
+                return false;
+            };
+            named == base
+                || named.starts_with(&format!("{base}."))
+                || (sidecar.len() >= SIDECAR_NAME_LIMIT && base.starts_with(named))
+        })
+    })
+}
+
+fn apple_date(dir: &Path, name: &str, others: &[String]) -> Option<OffsetDateTime> {
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    // osxphotos adds .xmp or .json to the whole name, Photos puts .xmp in place of the extension
+    let candidates = [
+        format!("{name}.xmp"),
+        format!("{stem}.xmp"),
+        format!("{name}.json"),
+        format!("{stem}.json"),
+    ];
+    let sidecar = candidates.iter().find_map(|candidate| {
+        others
+            .iter()
+            .find(|other| other.eq_ignore_ascii_case(candidate))
+    })?;
+    apple_taken(&dir.join(sidecar))
+        .inspect_err(|e| eprintln!("{sidecar:?}: {e:#}"))
+        .ok()
+}
+
+fn apple_taken(sidecar: &Path) -> Result<OffsetDateTime> {
+    let text = fs::read_to_string(sidecar)?;
+    let date = match sidecar.extension().and_then(|e| e.to_str()) {
+        Some(e) if e.eq_ignore_ascii_case("json") => {
+            // exiftool's format, an array of one object with group prefixed tags
+            let json: serde_json::Value = serde_json::from_str(&text)?;
+            let tags = json.get(0).unwrap_or(&json);
+            let tag = |names: &[&str]| {
+                names
+                    .iter()
+                    .find_map(|name| tags.get(name)?.as_str().map(str::to_string))
+            };
+            let date = tag(&[
+                "EXIF:DateTimeOriginal",
+                "XMP:DateCreated",
+                "DateTimeOriginal",
+            ]);
+            let offset = tag(&["EXIF:OffsetTimeOriginal", "OffsetTimeOriginal"]);
+            date.map(|date| date + &offset.unwrap_or_default())
+        }
+        _ => [
+            "photoshop:DateCreated",
+            "exif:DateTimeOriginal",
+            "xmp:CreateDate",
+        ]
+        .iter()
+        .find_map(|name| xmp_value(&text, name)),
+    };
+    parse_date(&date.context("No date")?)
+}
+
+// As an attribute or an element, the two ways XMP writes a simple property
+fn xmp_value(xml: &str, name: &str) -> Option<String> {
+    let attribute = format!("{name}=\"");
+    if let Some(start) = xml.find(&attribute) {
+        let rest = &xml[start + attribute.len()..];
+        return Some(rest[..rest.find('"')?].to_string());
+    }
+    let element = format!("<{name}>");
+    let start = xml.find(&element)? + element.len();
+    let rest = &xml[start..];
+    Some(rest[..rest.find('<')?].trim().to_string())
+}
+
+// ISO 8601 or EXIF's colons, with optional fractions and offset. Without an offset it's a wall
+// clock time, kept as if it were UTC the same way scans keep EXIF times.
+fn parse_date(date: &str) -> Result<OffsetDateTime> {
+    let mut normalized: Vec<u8> = date.bytes().take(19).collect();
+    ensure!(normalized.len() == 19, "Bad date {date:?}");
+    (normalized[4], normalized[7], normalized[10]) = (b'-', b'-', b'T');
+    let wall_clock = PrimitiveDateTime::parse(
+        std::str::from_utf8(&normalized)?,
+        format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]"),
+    )
+    .with_context(|| format!("Bad date {date:?}"))?;
+
+    let rest = date
+        .get(19..)
+        .with_context(|| format!("Bad date {date:?}"))?;
+    let rest = rest.trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match rest {
+        "" | "Z" => UtcOffset::UTC,
+        offset => UtcOffset::parse(
+            &offset.replace(':', ""),
+            format_description!("[offset_hour][offset_minute]"),
+        )
+        .with_context(|| format!("Bad offset in {date:?}"))?,
+    };
+    Ok(wall_clock.assume_offset(offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    fn sidecar<'a>(name: &str, sidecars: &'a [&str]) -> Option<&'a str> {
+        let owned: Vec<String> = sidecars.iter().map(|s| s.to_string()).collect();
+        let found = takeout_sidecar(name, &owned)?;
+        sidecars.iter().copied().find(|s| s == found)
+    }
+
+    #[test]
+    fn takeout_sidecar_adds_json_or_supplemental_metadata() {
+        assert_eq!(sidecar("a.jpg", &["a.jpg.json"]), Some("a.jpg.json"));
+        assert_eq!(
+            sidecar("a.jpg", &["b.jpg.json", "a.jpg.supplemental-metadata.json"]),
+            Some("a.jpg.supplemental-metadata.json")
+        );
+        // Cut short anywhere after the name
+        assert_eq!(
+            sidecar("a.jpg", &["a.jpg.suppl.json"]),
+            Some("a.jpg.suppl.json")
+        );
+        assert_eq!(sidecar("a.jpg", &["ab.jpg.json"]), None);
+    }
+
+    #[test]
+    fn takeout_sidecar_matches_names_cut_at_51() {
+        let name = "a-very-long-file-name-straight-from-the-camera-app-0001.jpg";
+        let cut = &format!("{name}.json")[..SIDECAR_NAME_LIMIT - 5];
+        let cut = format!("{cut}.json");
+        assert_eq!(cut.len(), SIDECAR_NAME_LIMIT);
+
+        assert_eq!(sidecar(name, &[&cut]), Some(cut.as_str()));
+        // Shorter than the limit, it would have been the whole name
+        assert_eq!(sidecar(name, &["a-very-long.json"]), None);
+    }
+
+    #[test]
+    fn takeout_sidecar_moves_the_duplicate_number_before_json() {
+        let sidecars = [
+            "a.jpg.json",
+            "a.jpg(1).json",
+            "a.jpg.supplemental-metadata(2).json",
+        ];
+
+        assert_eq!(sidecar("a(1).jpg", &sidecars), Some("a.jpg(1).json"));
+        assert_eq!(
+            sidecar("a(2).jpg", &sidecars),
+            Some("a.jpg.supplemental-metadata(2).json")
+        );
+        assert_eq!(sidecar("a.jpg", &sidecars), Some("a.jpg.json"));
+        // Its own sidecar beats the duplicate rule
+        assert_eq!(
+            sidecar("b(1).jpg", &["b(1).jpg.json", "b.jpg(1).json"]),
+            Some("b(1).jpg.json")
+        );
+    }
+
+    #[test]
+    fn takeout_sidecar_shares_the_original_with_edited_copies() {
+        let sidecars = ["a.jpg.json"];
+
+        assert_eq!(sidecar("a-edited.jpg", &sidecars), Some("a.jpg.json"));
+        assert_eq!(sidecar("a-edited.png", &sidecars), None);
+    }
+
+    #[test]
+    fn parse_date_takes_iso_and_exif_forms() {
+        assert_eq!(
+            parse_date("2024:06:03 08:05:06").unwrap(),
+            datetime!(2024-06-03 08:05:06 UTC)
+        );
+        assert_eq!(
+            parse_date("2024-06-03T08:05:06Z").unwrap(),
+            datetime!(2024-06-03 08:05:06 UTC)
+        );
+    }
+
+    #[test]
+    fn parse_date_skips_fractions_and_keeps_offsets() {
+        assert_eq!(
+            parse_date("2024-06-03T08:05:06.123").unwrap(),
+            datetime!(2024-06-03 08:05:06 UTC)
+        );
+        assert_eq!(
+            parse_date("2024-06-03T08:05:06.5+02:00").unwrap(),
+            datetime!(2024-06-03 08:05:06 +2)
+        );
+        assert_eq!(
+            parse_date("2024:06:03 08:05:06-0430").unwrap(),
+            datetime!(2024-06-03 08:05:06 -4:30)
+        );
+    }
+
+    #[test]
+    fn parse_date_rejects_garbage() {
+        assert!(parse_date("2024-06-03").is_err());
+        assert!(parse_date("2024-13-03T08:05:06").is_err());
+        assert!(parse_date("2024-06-03T08:05:06 tomorrow").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use axum::http::StatusCode;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::api_error::ApiError;
+
+// Long enough for a few jobs ahead to finish
+const RETRY_AFTER: u64 = 2;
+
+/// Caps how many expensive jobs, like decoding a photo for its thumbnail, run at once, and how
+/// much memory they may use between them.
+///
+/// Past that, requests wait in a queue of bounded length. Anything beyond it is turned away
+/// with 503 rather than piling up in memory.
+#[derive(Debug)]
+pub struct JobLimiter {
+    running: Arc<Semaphore>,
+    // A permit per megabyte
+    memory: Arc<Semaphore>,
+    memory_mb: u32,
+    waiting: AtomicUsize,
+    max_waiting: usize,
+}
+
+/// A job runs for as long as this is held
+#[derive(Debug)]
+pub struct Permit {
+    _running: OwnedSemaphorePermit,
+    _memory: OwnedSemaphorePermit,
+}
+
+impl JobLimiter {
+    pub fn new(concurrent: usize, memory_mb: u32, queue: usize) -> Self {
+        Self {
+            running: Arc::new(Semaphore::new(concurrent)),
+            memory: Arc::new(Semaphore::new(memory_mb as usize)),
+            memory_mb,
+            waiting: AtomicUsize::new(0),
+            max_waiting: queue,
+        }
+    }
+
+    /// Waits for a free slot and `megabytes` of the memory budget. A job bigger than the whole
+    /// budget gets all of it, so it runs alone rather than never.
+    pub async fn acquire(&self, megabytes: u32) -> Result<Permit, ApiError> {
+        let megabytes = megabytes.min(self.memory_mb);
+        if let Ok(running) = self.running.clone().try_acquire_owned() {
+            if let Ok(memory) = self.memory.clone().try_acquire_many_owned(megabytes) {
+                return Ok(Permit {
+                    _running: running,
+                    _memory: memory,
+                });
+            }
+        }
+
+        let queued = self
+            .waiting
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |waiting| {
+                (waiting < self.max_waiting).then_some(waiting + 1)
+            });
+        if queued.is_err() {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too busy, try again shortly",
+            )
+            .with_retry_after(RETRY_AFTER));
+        }
+
+        // Leaves the queue even when the client gives up and the request is dropped
+        let _waiting = Waiting(&self.waiting);
+        Ok(self.wait(megabytes).await)
+    }
+
+    /// For background work, waits without taking a place in the queue so requests are never
+    /// turned away on its account
+    pub async fn acquire_background(&self, megabytes: u32) -> Permit {
+        self.wait(megabytes.min(self.memory_mb)).await
+    }
+
+    // Slot then memory, the same order everywhere, so two jobs can't each hold what the other
+    // is waiting for
+    async fn wait(&self, megabytes: u32) -> Permit {
+        let running = self.running.clone().acquire_owned().await;
+        let memory = self.memory.clone().acquire_many_owned(megabytes).await;
+        Permit {
+            _running: running.expect("the semaphore is never closed"),
+            _memory: memory.expect("the semaphore is never closed"),
+        }
+    }
+}
+
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -1,64 +1,513 @@
-use anyhow::{anyhow, ensure, Result};
+use anyhow::{anyhow, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
 use time::{macros::format_description, PrimitiveDateTime};
 
-pub fn get_timestamp(data: &[u8]) -> Result<Option<time::PrimitiveDateTime>> {
-    ensure!(data[0..2] == [0xff, 0xd8], "Missing SOI marker");
+// Library files are arbitrary, so every read is bounds checked rather than trusted
+fn slice(data: &[u8], start: usize, end: usize) -> Result<&[u8]> {
+    data.get(start..end).context("Unexpected end of data")
+}
 
-    ensure!(data[2] == 0xff, "Expected start of marker");
-    if data[3] != 0xe1 {
-        // Image does not contain metadata
-        return Ok(None);
+#[derive(Debug, Clone, Copy)]
+enum ByteOrder {
+    // "II", Intel
+    Little,
+    // "MM", Motorola
+    Big,
+}
+
+impl ByteOrder {
+    fn u16(self, data: &[u8]) -> u16 {
+        let bytes = data[0..2].try_into().unwrap();
+        match self {
+            ByteOrder::Little => u16::from_le_bytes(bytes),
+            ByteOrder::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn u32(self, data: &[u8]) -> u32 {
+        let bytes = data[0..4].try_into().unwrap();
+        match self {
+            ByteOrder::Little => u32::from_le_bytes(bytes),
+            ByteOrder::Big => u32::from_be_bytes(bytes),
+        }
     }
 
-    // Find APP1 segment length
-    let segment_length = u16::from_be_bytes(data[4..6].try_into().unwrap());
+    fn u64(self, data: &[u8]) -> u64 {
+        let bytes = data[0..8].try_into().unwrap();
+        match self {
+            ByteOrder::Little => u64::from_le_bytes(bytes),
+            ByteOrder::Big => u64::from_be_bytes(bytes),
+        }
+    }
+}
+
+// Enough for the largest possible APP1 segment plus anything in front of it
+pub const METADATA_SIZE: u64 = 128 * 1024;
+
+// MPF index IFD tag, 16 bytes for each image in the file
+const MP_ENTRY: u16 = 0xb002;
+// MPF image types
+const MP_PREVIEW_VGA: u32 = 0x010001;
+const MP_PREVIEW_FULL_HD: u32 = 0x010002;
+const MP_DISPARITY: u32 = 0x020002;
 
-    // Extract APP1 data
-    let app1_data = &data[4..(4 + segment_length as usize)];
+// IFD0 tags
+const MAKE: u16 = 0x010f;
+const MODEL: u16 = 0x0110;
+const ORIENTATION: u16 = 0x0112;
+const SOFTWARE: u16 = 0x0131;
+const DATE_TIME: u16 = 0x0132;
+const EXIF_IFD: u16 = 0x8769;
+const GPS_IFD: u16 = 0x8825;
+// Exif IFD tags
+const EXPOSURE_TIME: u16 = 0x829a;
+const F_NUMBER: u16 = 0x829d;
+const ISO: u16 = 0x8827;
+const DATE_TIME_ORIGINAL: u16 = 0x9003;
+const FOCAL_LENGTH: u16 = 0x920a;
+const LENS_MODEL: u16 = 0xa434;
+// GPS IFD tags
+const GPS_LATITUDE_REF: u16 = 0x0001;
+const GPS_LATITUDE: u16 = 0x0002;
+const GPS_LONGITUDE_REF: u16 = 0x0003;
+const GPS_LONGITUDE: u16 = 0x0004;
+
+/// The parts of the EXIF metadata worth showing
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Exif {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    pub software: Option<String>,
+    #[serde(skip)]
+    pub taken: Option<PrimitiveDateTime>,
+    pub orientation: Option<u16>,
+    // Seconds
+    pub exposure_time: Option<f64>,
+    pub f_number: Option<f64>,
+    pub iso: Option<u32>,
+    // Millimetres
+    pub focal_length: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+}
+
+/// Where a photo was taken, in degrees, north and east positive
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Location {
+    /// Great circle distance in kilometres
+    pub fn distance(self, other: Location) -> f64 {
+        const EARTH_RADIUS: f64 = 6371.0;
+
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+    }
+}
+
+/// Only needs the start of the file, see METADATA_SIZE
+pub fn get_exif(data: &[u8]) -> Result<Option<Exif>> {
+    let Some(app1_data) = find_app1(data)? else {
+        // Image does not contain metadata
+        return Ok(None);
+    };
 
     ensure!(
-        app1_data[2..8] == [0x45, 0x78, 0x69, 0x66, 0x00, 0x00],
+        slice(app1_data, 2, 8)? == [0x45, 0x78, 0x69, 0x66, 0x00, 0x00],
         "Invaid exif header"
     );
 
-    ensure!(
-        app1_data[8..12] == [0x49, 0x49, 0x2a, 0x00],
-        "Invaid tiff header"
-    );
+    // Offsets inside the metadata count from the start of the TIFF header
+    let tiff = &app1_data[8..];
+    let order = match slice(tiff, 0, 4)? {
+        [0x49, 0x49, 0x2a, 0x00] => ByteOrder::Little,
+        [0x4d, 0x4d, 0x00, 0x2a] => ByteOrder::Big,
+        _ => return Err(anyhow!("Invaid tiff header")),
+    };
     // Get IFD0 offset
-    let ifd0_offset = u32::from_le_bytes(app1_data[12..16].try_into().unwrap());
+    let ifd0_offset = order.u32(slice(tiff, 4, 8)?);
+
+    let mut entries = parse_ifd(tiff, ifd0_offset, order).context("IFD0 out of bounds")?;
+    let exif_ifd = entries.iter().find_map(|e| match (e.tag, &e.data) {
+        (EXIF_IFD, IFDValue::UnsignedLong(offset)) => Some(*offset),
+        _ => None,
+    });
+    if let Some(offset) = exif_ifd {
+        entries.extend(parse_ifd(tiff, offset, order).unwrap_or_default());
+    }
+    // Its tags share numbers with IFD0's, so they're kept apart
+    let gps = entries
+        .iter()
+        .find_map(|e| match (e.tag, &e.data) {
+            (GPS_IFD, IFDValue::UnsignedLong(offset)) => parse_ifd(tiff, *offset, order),
+            _ => None,
+        })
+        .unwrap_or_default();
+
+    let text = |tag| {
+        entries.iter().find_map(|e| match &e.data {
+            IFDValue::AsciiStrings(s) if e.tag == tag => Some(s.clone()),
+            _ => None,
+        })
+    };
+    let number = |tag| entries.iter().find(|e| e.tag == tag)?.data.as_f64();
+
+    let taken = match text(DATE_TIME_ORIGINAL).or_else(|| text(DATE_TIME)) {
+        Some(s) => Some(parse_date_time(&s)?),
+        None => None,
+    };
+
+    Ok(Some(Exif {
+        make: text(MAKE),
+        model: text(MODEL),
+        lens: text(LENS_MODEL),
+        software: text(SOFTWARE),
+        taken,
+        orientation: number(ORIENTATION).map(|n| n as u16),
+        exposure_time: number(EXPOSURE_TIME),
+        f_number: number(F_NUMBER),
+        iso: number(ISO).map(|n| n as u32),
+        focal_length: number(FOCAL_LENGTH),
+        location: location(&gps),
+    }))
+}
+
+fn location(gps: &[IFDEntry]) -> Option<Location> {
+    // Degrees, minutes and seconds, negative towards the given reference
+    let coordinate = |tag, reference, negative: &str| {
+        let parts = gps.iter().find_map(|e| match &e.data {
+            IFDValue::UnsignedRationals(parts) if e.tag == tag => Some(parts),
+            _ => None,
+        })?;
+        let value = parts
+            .iter()
+            .zip([1.0, 60.0, 3600.0])
+            .map(|(&(n, d), scale)| (d != 0).then(|| f64::from(n) / f64::from(d) / scale))
+            .sum::<Option<f64>>()?;
+        let negative = gps.iter().any(
+            |e| matches!(&e.data, IFDValue::AsciiStrings(s) if e.tag == reference && s == negative),
+        );
+        Some(if negative { -value } else { value })
+    };
+
+    let location = Location {
+        latitude: coordinate(GPS_LATITUDE, GPS_LATITUDE_REF, "S")?,
+        longitude: coordinate(GPS_LONGITUDE, GPS_LONGITUDE_REF, "W")?,
+    };
+    // Phones without a fix write zeros
+    let valid = location.latitude.abs() <= 90.0
+        && location.longitude.abs() <= 180.0
+        && (location.latitude, location.longitude) != (0.0, 0.0);
+    valid.then_some(location)
+}
+
+// Cameras put APP1 first, but editors often put a JFIF APP0 segment ahead of it
+fn find_app1(data: &[u8]) -> Result<Option<&[u8]>> {
+    ensure!(slice(data, 0, 2)? == [0xff, 0xd8], "Missing SOI marker");
+
+    let mut position = 2;
+    loop {
+        ensure!(
+            slice(data, position, position + 1)? == [0xff],
+            "Expected start of marker"
+        );
+        let marker = slice(data, position + 1, position + 2)?[0];
+
+        // Start of scan, the metadata segments are all behind us
+        if marker == 0xda {
+            return Ok(None);
+        }
+
+        let segment_length =
+            u16::from_be_bytes(slice(data, position + 2, position + 4)?.try_into().unwrap())
+                as usize;
+        let segment = slice(data, position + 2, position + 2 + segment_length)?;
+
+        if marker == 0xe1 && segment.get(2..6) == Some(b"Exif") {
+            return Ok(Some(segment));
+        }
+        position += 2 + segment_length;
+    }
+}
+
+/// Whether the photo is an Ultra HDR JPEG, an SDR image with a gain map after it for screens
+/// that can show more. Its XMP says so, in the same metadata as the EXIF.
+pub fn has_gain_map(data: &[u8]) -> bool {
+    find_xmp(data).is_some_and(|xmp| contains(xmp, b"http://ns.adobe.com/hdr-gain-map/1.0/"))
+}
+
+/// Whether the photo is a 360 degree sphere, as Google's photo sphere XMP describes them
+pub fn is_equirectangular(data: &[u8]) -> bool {
+    find_xmp(data).is_some_and(|xmp| {
+        contains(xmp, b"http://ns.google.com/photos/1.0/panorama/")
+            && contains(xmp, b"ProjectionType")
+            && contains(xmp, b"equirectangular")
+    })
+}
+
+/// The still of a motion photo from the whole file, None if there's no video to leave off
+pub fn strip_motion(mut data: Vec<u8>) -> Option<Vec<u8>> {
+    // Turned off in place, so viewers don't go looking for a video that isn't there
+    const FLAGS: [&[u8]; 2] = [b"MicroVideo=\"1\"", b"MotionPhoto=\"1\""];
 
-    let ifd0_data = &app1_data[(8 + ifd0_offset as usize)..];
-    match parse_ifd0(ifd0_data, app1_data) {
-        Some(Ok(timestamp)) => Ok(Some(timestamp)),
-        None => Ok(None),
-        Some(Err(e)) => Err(e),
+    let start = motion_start(&data)?;
+    data.truncate(start);
+    let head = data.len().min(METADATA_SIZE as usize);
+    for flag in FLAGS {
+        if let Some(at) = data[..head]
+            .windows(flag.len())
+            .position(|window| window == flag)
+        {
+            data[at + flag.len() - 2] = b'0';
+        }
     }
+    Some(data)
+}
+
+// Where the video appended to a motion photo starts in the whole file
+fn motion_start(data: &[u8]) -> Option<usize> {
+    // Samsung's own trailer, which older phones write without any XMP to say so
+    const SAMSUNG: &[u8] = b"MotionPhoto_Data";
+
+    let video = embedded(data, data.len() as u64)
+        .into_iter()
+        .filter(|item| item.kind == EmbeddedKind::Video)
+        .map(|item| item.offset as usize)
+        .min();
+    let samsung = data
+        .windows(SAMSUNG.len())
+        .rposition(|window| window == SAMSUNG);
+    video.into_iter().chain(samsung).min()
+}
+
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|window| window == needle)
+}
+
+// The XMP packet, which is also APP1 and usually right after the EXIF
+fn find_xmp(data: &[u8]) -> Option<&[u8]> {
+    const XMP: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+    segments(data)
+        .find(|(marker, _, segment)| *marker == 0xe1 && segment.starts_with(XMP))
+        .map(|(_, _, segment)| &segment[XMP.len()..])
+}
+
+// Each metadata segment's marker, where its contents start and the contents, up to the start of
+// scan or the first segment that runs past the end of `data`
+fn segments(data: &[u8]) -> impl Iterator<Item = (u8, usize, &[u8])> {
+    let mut position = 2;
+    std::iter::from_fn(move || {
+        let [0xff, marker] = *data.get(position..position + 2)? else {
+            return None;
+        };
+        if marker == 0xda {
+            return None;
+        }
+        let length = data.get(position + 2..position + 4)?;
+        let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+        let start = position + 4;
+        let segment = data.get(start..(position + 2 + length).max(start))?;
+        position += 2 + length;
+        Some((marker, start, segment))
+    })
 }
 
-fn parse_ifd0(data: &[u8], app1_data: &[u8]) -> Option<Result<time::PrimitiveDateTime>> {
-    let number_of_entries = u16::from_le_bytes(data[0..2].try_into().unwrap());
+/// An image or video stored in the same file after the main image
+#[derive(Debug, Clone, Serialize)]
+pub struct Embedded {
+    pub kind: EmbeddedKind,
+    pub mime: String,
+    /// From the start of the file
+    #[serde(skip)]
+    pub offset: u64,
+    pub length: u64,
+}
 
-    let mut entries = (0..number_of_entries).filter_map(|i| {
-        let data_start = 2 + 12 * i as usize;
-        let data_end = 14 + 12 * i as usize;
-        let entry_data = &data[data_start..data_end];
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddedKind {
+    Depth,
+    /// Which pixels are the subject, for portrait mode's blur
+    Matte,
+    GainMap,
+    /// A smaller copy of the main image
+    Preview,
+    /// The video of a motion photo
+    Video,
+    /// Anything not recognised, see [`classify_embedded`]
+    Image,
+}
 
-        parse_ifd_entry(entry_data, app1_data)
+/// Lists what a JPEG carries after its main image, from its head. Cameras list them either in an
+/// MPF index or, on Android, in a GContainer directory in the XMP.
+pub fn embedded(head: &[u8], file_length: u64) -> Vec<Embedded> {
+    let mut found = mpf_images(head);
+    if found.is_empty() {
+        found = container_items(head, file_length);
+    }
+    found.retain(|item| {
+        item.length > 0
+            && item
+                .offset
+                .checked_add(item.length)
+                .is_some_and(|end| end <= file_length)
     });
+    found
+}
+
+/// Refines an [`EmbeddedKind::Image`] from the start of the embedded image itself, whose XMP
+/// says what it's for
+pub fn classify_embedded(head: &[u8]) -> EmbeddedKind {
+    let Some(xmp) = find_xmp(head) else {
+        return EmbeddedKind::Image;
+    };
+    if contains(xmp, b"portraiteffectsmatte") || contains(xmp, b"segmentationmatte") {
+        EmbeddedKind::Matte
+    } else if contains(xmp, b"aux:depth") || contains(xmp, b"aux:disparity") {
+        EmbeddedKind::Depth
+    } else if contains(xmp, b"http://ns.adobe.com/hdr-gain-map/1.0/") {
+        EmbeddedKind::GainMap
+    } else {
+        EmbeddedKind::Image
+    }
+}
+
+// Offsets in the index count from the TIFF header inside the APP2 segment
+fn mpf_images(head: &[u8]) -> Vec<Embedded> {
+    let Some((_, start, segment)) = segments(head)
+        .find(|(marker, _, segment)| *marker == 0xe2 && segment.starts_with(b"MPF\0"))
+    else {
+        return Vec::new();
+    };
+    let tiff = &segment[4..];
+    let order = match tiff.get(0..4) {
+        Some([0x49, 0x49, 0x2a, 0x00]) => ByteOrder::Little,
+        Some([0x4d, 0x4d, 0x00, 0x2a]) => ByteOrder::Big,
+        _ => return Vec::new(),
+    };
+    let Some(entries) = tiff
+        .get(4..8)
+        .and_then(|offset| parse_ifd(tiff, order.u32(offset), order))
+    else {
+        return Vec::new();
+    };
+    let Some(list) = entries.iter().find_map(|e| match (e.tag, &e.data) {
+        (MP_ENTRY, IFDValue::Undefined(list)) => Some(list),
+        _ => None,
+    }) else {
+        return Vec::new();
+    };
 
-    entries.find(|e| e.tag == 0x0132).map(|e| {
-        let IFDValue::AsciiStrings(s) = e.data else {
-            return Err(anyhow!(
-                "DateTime entry contained invalid data format, expected AsciiStrings but got {:?}",
-                e.data
-            ));
+    let base = (start + 4) as u64;
+    list.chunks_exact(16)
+        // The first is the main image
+        .skip(1)
+        .map(|entry| {
+            let kind = match order.u32(&entry[0..4]) & 0x00ff_ffff {
+                MP_PREVIEW_VGA | MP_PREVIEW_FULL_HD => EmbeddedKind::Preview,
+                MP_DISPARITY => EmbeddedKind::Depth,
+                _ => EmbeddedKind::Image,
+            };
+            Embedded {
+                kind,
+                mime: "image/jpeg".to_string(),
+                offset: base + u64::from(order.u32(&entry[8..12])),
+                length: order.u32(&entry[4..8]).into(),
+            }
+        })
+        .collect()
+}
+
+// Items after the first are appended to the file in the order listed, so they're found from
+// the end. Older motion photos only give how far from the end their video starts.
+fn container_items(head: &[u8], file_length: u64) -> Vec<Embedded> {
+    let Some(xmp) = find_xmp(head) else {
+        return Vec::new();
+    };
+    let xmp = String::from_utf8_lossy(xmp);
+    let mut items = Vec::new();
+    // Skipping what's before the first item, then the main image
+    for item in xmp.split("<Container:Item").skip(2) {
+        let attributes = item.split('>').next().unwrap_or_default();
+        let attribute = |name| attribute(attributes, name);
+        let Some(length) = attribute("Item:Length").and_then(|l| l.parse::<u64>().ok()) else {
+            // Can't tell where anything after it starts
+            return Vec::new();
         };
+        let mime = attribute("Item:Mime").unwrap_or_default();
+        let kind = match attribute("Item:Semantic").as_deref() {
+            Some("Depth") => EmbeddedKind::Depth,
+            Some("GainMap") => EmbeddedKind::GainMap,
+            Some("MotionPhoto") => EmbeddedKind::Video,
+            _ if mime.starts_with("video/") => EmbeddedKind::Video,
+            _ => EmbeddedKind::Image,
+        };
+        items.push(Embedded {
+            kind,
+            mime,
+            offset: 0,
+            length,
+        });
+    }
+    if items.is_empty() {
+        if let Some(length) =
+            attribute(&xmp, "GCamera:MicroVideoOffset").and_then(|l| l.parse::<u64>().ok())
+        {
+            items.push(Embedded {
+                kind: EmbeddedKind::Video,
+                mime: "video/mp4".to_string(),
+                offset: 0,
+                length,
+            });
+        }
+    }
+
+    let Some(mut offset) = file_length.checked_sub(items.iter().map(|i| i.length).sum()) else {
+        return Vec::new();
+    };
+    for item in &mut items {
+        item.offset = offset;
+        offset += item.length;
+    }
+    items
+}
+
+// The value of the first `name="value"` in some XML, without unescaping it
+fn attribute(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("{name}=\""))? + name.len() + 2;
+    let end = xml[start..].find('"')?;
+    Some(xml[start..start + end].to_string())
+}
 
-        let date_time_format = format_description!("[year]:[month]:[day] [hour]:[minute]:[second]");
+fn parse_date_time(s: &str) -> Result<PrimitiveDateTime> {
+    let date_time_format = format_description!("[year]:[month]:[day] [hour]:[minute]:[second]");
 
-        Ok(PrimitiveDateTime::parse(&s, &date_time_format)?)
-    })
+    Ok(PrimitiveDateTime::parse(s, &date_time_format)?)
+}
+
+fn parse_ifd(tiff: &[u8], offset: u32, order: ByteOrder) -> Option<Vec<IFDEntry>> {
+    let data = tiff.get(offset as usize..)?;
+    let number_of_entries = order.u16(data.get(0..2)?);
+
+    let entries = (0..number_of_entries)
+        .filter_map(|i| {
+            let data_start = 2 + 12 * i as usize;
+            let data_end = 14 + 12 * i as usize;
+            let entry_data = data.get(data_start..data_end)?;
+
+            parse_ifd_entry(entry_data, tiff, order)
+        })
+        .collect();
+
+    Some(entries)
 }
 
 #[derive(Debug)]
@@ -75,22 +524,44 @@ enum IFDValue {
     AsciiStrings(String),
     UnsignedShort(u16),
     UnsignedLong(u32),
-    UnsignedRational,
+    UnsignedRational(u32, u32),
+    // Of more than one component, such as GPS degrees, minutes and seconds
+    UnsignedRationals(Vec<(u32, u32)>),
     SignedByte(i8),
     Undefined(Vec<u8>),
     SignedShort(i16),
     SignedLong(i32),
-    SignedRational,
+    SignedRational(i32, i32),
     SingleFloat(f32),
     DoubleFloat(f64),
 }
 
-fn parse_ifd_entry(data: &[u8], app1_data: &[u8]) -> Option<IFDEntry> {
-    let tag_number = u16::from_le_bytes(data[0..2].try_into().unwrap());
-    let data_format = u16::from_le_bytes(data[2..4].try_into().unwrap());
-    let number_of_components = u32::from_le_bytes(data[4..8].try_into().unwrap());
+impl IFDValue {
+    fn as_f64(&self) -> Option<f64> {
+        use IFDValue::*;
+        Some(match *self {
+            UnsignedByte(n) => n.into(),
+            UnsignedShort(n) => n.into(),
+            UnsignedLong(n) => n.into(),
+            UnsignedRational(_, 0) | SignedRational(_, 0) => return None,
+            UnsignedRational(n, d) => f64::from(n) / f64::from(d),
+            SignedByte(n) => n.into(),
+            SignedShort(n) => n.into(),
+            SignedLong(n) => n.into(),
+            SignedRational(n, d) => f64::from(n) / f64::from(d),
+            SingleFloat(n) => n.into(),
+            DoubleFloat(n) => n,
+            AsciiStrings(_) | UnsignedRationals(_) | Undefined(_) => return None,
+        })
+    }
+}
+
+fn parse_ifd_entry(data: &[u8], tiff: &[u8], order: ByteOrder) -> Option<IFDEntry> {
+    let tag_number = order.u16(&data[0..2]);
+    let data_format = order.u16(&data[2..4]);
+    let number_of_components = order.u32(&data[4..8]);
 
-    let bytes_per_component = match data_format {
+    let bytes_per_component: u32 = match data_format {
         1 => 1,  // unsigned byte
         2 => 1,  // ascii strings
         3 => 2,  // unsigned short
@@ -104,37 +575,54 @@ fn parse_ifd_entry(data: &[u8], app1_data: &[u8]) -> Option<IFDEntry> {
         11 => 4, // single float
         12 => 8, // double float
         _ => {
-            println!("data format: {data_format} not implemented");
+            tracing::debug!("data format: {data_format} not implemented");
             return None;
         }
     };
 
-    let data_length = bytes_per_component * number_of_components;
+    let data_length = bytes_per_component.checked_mul(number_of_components)?;
 
     let value_data = if data_length <= 4 {
         &data[8..12]
     } else {
-        let offset = u32::from_le_bytes(data[8..12].try_into().unwrap()) + 8;
-        let end = offset + data_length;
-        &app1_data[(offset as usize)..(end as usize)]
+        let offset = order.u32(&data[8..12]);
+        let end = offset.checked_add(data_length)?;
+        tiff.get((offset as usize)..(end as usize))?
     };
+    if value_data.len() < bytes_per_component as usize {
+        return None;
+    }
 
     let value = {
         use IFDValue::*;
         match data_format {
             1 => UnsignedByte(value_data[0]), // unsigned byte
-            2 => AsciiStrings(String::from_utf8_lossy(value_data).into_owned()), // ascii strings
-            3 => UnsignedShort(u16::from_le_bytes(value_data[0..2].try_into().unwrap())), // unsigned short
-            4 => UnsignedLong(u32::from_le_bytes(value_data[0..4].try_into().unwrap())), // unsigned long
-            5 => UnsignedRational, // unsigned rational
-            6 => SignedByte(i8::from_le_bytes([value_data[0]])), // signed byte
-            7 => Undefined(value_data.to_vec()), // undefined
-            8 => SignedShort(i16::from_le_bytes(value_data[0..2].try_into().unwrap())), // signed short
-            9 => SignedLong(i32::from_le_bytes(value_data[0..4].try_into().unwrap())), // signed long
-            10 => SignedRational, // signed rational
-            11 => SingleFloat(f32::from_be_bytes(value_data[0..4].try_into().unwrap())), // single float
-            12 => DoubleFloat(f64::from_le_bytes(value_data[0..8].try_into().unwrap())), // double float
-            _ => unimplemented!(),
+            // ASCII values include their NUL terminator
+            2 => AsciiStrings(
+                String::from_utf8_lossy(&value_data[..data_length as usize])
+                    .trim_end_matches('\0')
+                    .to_string(),
+            ), // ascii strings
+            3 => UnsignedShort(order.u16(value_data)), // unsigned short
+            4 => UnsignedLong(order.u32(value_data)),  // unsigned long
+            5 if number_of_components > 1 => UnsignedRationals(
+                value_data
+                    .chunks_exact(8)
+                    .map(|chunk| (order.u32(chunk), order.u32(&chunk[4..])))
+                    .collect(),
+            ), // unsigned rationals
+            5 => UnsignedRational(order.u32(value_data), order.u32(&value_data[4..])), // unsigned rational
+            6 => SignedByte(value_data[0] as i8), // signed byte
+            7 => Undefined(value_data.to_vec()),  // undefined
+            8 => SignedShort(order.u16(value_data) as i16), // signed short
+            9 => SignedLong(order.u32(value_data) as i32), // signed long
+            10 => SignedRational(
+                order.u32(value_data) as i32,
+                order.u32(&value_data[4..]) as i32,
+            ), // signed rational
+            11 => SingleFloat(f32::from_bits(order.u32(value_data))), // single float
+            12 => DoubleFloat(f64::from_bits(order.u64(value_data))), // double float
+            _ => unreachable!(),
         }
     };
 
@@ -0,0 +1,782 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read as _, Seek as _, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tokio::sync::{broadcast, watch};
+use tracing::{debug, info, info_span, warn};
+
+use crate::{
+    api_error::Invalid,
+    edits::Edits,
+    jpg::{self, Embedded, EmbeddedKind, Exif, Location},
+    mp4::{self, FrameRate},
+    safe_path::{validate_relative, LibraryRoot},
+    store::{load_json, save_json},
+    taken,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Image,
+    Video,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Panorama {
+    /// Much wider or taller than a photo, for panning across rather than fitting on screen
+    Wide,
+    /// A whole sphere unrolled, twice as wide as it is high
+    Equirectangular,
+}
+
+/// Played at another speed than it happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Speed {
+    /// Filmed at a high frame rate, to be played slower
+    SlowMotion,
+    /// Filmed a frame at a time, played faster
+    TimeLapse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Media {
+    pub path: PathBuf,
+    pub kind: Kind,
+    // EXIF has no time zone, so camera wall clock times are stored as if they were UTC
+    #[serde(with = "time::serde::rfc3339")]
+    pub taken: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub modified: OffsetDateTime,
+    pub size: u64,
+    // Missing for videos and anything whose header couldn't be read
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // Of the contents, images only, so thumbnails follow a file when it's renamed or moved and
+    // copies share theirs. Missing in indexes from before it was added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    // Of how the photo looks, in hex, see thumbnails::perceptual_hash. Missing until it's been
+    // worked out in the background, see perceptual_hashes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perceptual_hash: Option<String>,
+    // An Ultra HDR JPEG. The original is served as it is, gain map and all, while thumbnails
+    // are made from the SDR image in front of it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hdr: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub panorama: Option<Panorama>,
+    // Of MP4 and QuickTime videos, as stored. Missing in indexes from before it was added, until
+    // a full scan.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed: Option<Speed>,
+    // From the EXIF GPS tags. Missing in indexes from before it was added, until a full scan.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    // Made through the API, kept by path however the file changes. Width and height above are
+    // of the edited photo.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edits: Option<Edits>,
+    // Worked out from the photo on request, kept the same way as edits until someone sets edits
+    // or dismisses it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suggested_edits: Option<Edits>,
+}
+
+pub fn content_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+
+    Some(match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "tif" | "tiff" => "image/tiff",
+        "mp4" | "m4v" => "video/mp4",
+        "mov" => "video/quicktime",
+        "webm" => "video/webm",
+        "mkv" => "video/x-matroska",
+        _ => return None,
+    })
+}
+
+// Longest edge over shortest. Past 16:9 screens and 3:2 sensors by a good margin, a typical phone
+// panorama is 4:1 or more.
+const PANORAMA_RATIO: f64 = 2.5;
+// Anything filmed this fast is meant to be slowed down, phones record normal video at 60 at most
+const SLOW_MOTION_FPS: f64 = 100.0;
+
+fn kind(path: &Path) -> Option<Kind> {
+    content_type(path).map(|mime| match mime.starts_with("video/") {
+        true => Kind::Video,
+        false => Kind::Image,
+    })
+}
+
+/// What a scan looks at
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScanScope {
+    // Reads every file again, not only new and modified ones, for when headers or hashes from
+    // an older version are suspect
+    #[serde(default)]
+    pub full: bool,
+    // A folder to rescan, the rest of the index is kept as it is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScanStatus {
+    pub scanning: bool,
+    // Of the running scan, or the last one
+    pub scope: ScanScope,
+    // Files looked at so far in the running scan, or in total by the last one
+    pub scanned: usize,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub started: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub finished: Option<OffsetDateTime>,
+    pub error: Option<String>,
+}
+
+/// What happened to the library, see [`Library::events`]
+#[derive(Debug, Clone)]
+pub enum LibraryEvent {
+    ScanStarted,
+    /// A scan replaced the index. Added is the new files, newest first, left empty when the index
+    /// was empty before, where everything would count.
+    Scanned {
+        count: usize,
+        added: Vec<Media>,
+    },
+    ScanFailed {
+        error: String,
+    },
+    Unreachable,
+    Reachable,
+}
+
+/// Every photo and video in the library, newest first
+#[derive(Debug)]
+pub struct Library {
+    root: LibraryRoot,
+    // Written after every scan when set, see with_index
+    index: Option<PathBuf>,
+    // Times taken that scans use before EXIF, see with_taken
+    taken: Option<PathBuf>,
+    media: RwLock<Vec<Media>>,
+    status: RwLock<ScanStatus>,
+    scanned: AtomicUsize,
+    cancel: AtomicBool,
+    reachable: AtomicBool,
+    // Counts finished scans, see subscribe
+    scans: watch::Sender<u64>,
+    events: broadcast::Sender<LibraryEvent>,
+}
+
+impl Library {
+    pub fn new(root: LibraryRoot) -> Self {
+        Self {
+            root,
+            index: None,
+            taken: None,
+            media: RwLock::new(Vec::new()),
+            status: RwLock::new(ScanStatus::default()),
+            scanned: AtomicUsize::new(0),
+            cancel: AtomicBool::new(false),
+            reachable: AtomicBool::new(true),
+            scans: watch::Sender::new(0),
+            events: broadcast::Sender::new(16),
+        }
+    }
+
+    /// Starts from an index written by an earlier scan, possibly on another machine, and keeps
+    /// it up to date. Paths are relative, so the library may be mounted somewhere else here.
+    pub fn with_index(root: LibraryRoot, index: PathBuf) -> Result<Self> {
+        let mut media: Vec<Media> = load_json(&index)?;
+        media.retain(|m| validate_relative(&m.path).is_ok());
+        media.sort_by(|a, b| b.taken.cmp(&a.taken).then_with(|| a.path.cmp(&b.path)));
+        info!("Loaded {} files from {index:?}", media.len());
+
+        Ok(Self {
+            index: Some(index),
+            media: RwLock::new(media),
+            ..Self::new(root)
+        })
+    }
+
+    /// Has scans go by the times taken kept at `path` before any in the EXIF, see
+    /// [`crate::taken`]
+    pub fn with_taken(self, path: PathBuf) -> Self {
+        Self {
+            taken: Some(path),
+            ..self
+        }
+    }
+
+    pub fn status(&self) -> ScanStatus {
+        ScanStatus {
+            scanned: self.scanned.load(Ordering::Relaxed),
+            ..self.status.read().unwrap().clone()
+        }
+    }
+
+    pub fn root(&self) -> &LibraryRoot {
+        &self.root
+    }
+
+    /// False while the library's filesystem isn't answering, see [`crate::health`]. Anything
+    /// that would touch it waits for it to come back rather than hang.
+    pub fn reachable(&self) -> bool {
+        self.reachable.load(Ordering::Relaxed)
+    }
+
+    /// Returns what it was before
+    pub fn set_reachable(&self, reachable: bool) -> bool {
+        let was = self.reachable.swap(reachable, Ordering::Relaxed);
+        if was != reachable {
+            let event = match reachable {
+                true => LibraryEvent::Reachable,
+                false => LibraryEvent::Unreachable,
+            };
+            // Nobody listening is fine
+            let _ = self.events.send(event);
+        }
+        was
+    }
+
+    /// Changes whenever a scan has replaced the list of media
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.scans.subscribe()
+    }
+
+    /// Scans starting, finishing and failing, and changes in reachability. A cancelled scan only
+    /// leaves its start.
+    pub fn events(&self) -> broadcast::Receiver<LibraryEvent> {
+        self.events.subscribe()
+    }
+
+    /// Walks the whole library, blocking until done. Fails straight away if a scan is running.
+    pub fn scan(&self) -> Result<usize> {
+        self.scan_scope(ScanScope::default())
+    }
+
+    /// Like [`Library::scan`], limited to a folder or re-reading everything. Returns the number
+    /// of files in the whole index.
+    pub fn scan_scope(&self, mut scope: ScanScope) -> Result<usize> {
+        let _span = info_span!("scan").entered();
+        scope.path = scope.path.map(|path| self.scan_folder(&path)).transpose()?;
+        let folder = scope.path.clone().unwrap_or_default();
+        // Everything would look deleted, or the walk would hang
+        if !self.reachable() {
+            bail!("The library is not reachable");
+        }
+        let taken = match &self.taken {
+            Some(path) => taken::load(path)?,
+            None => HashMap::new(),
+        };
+        {
+            let mut status = self.status.write().unwrap();
+            if status.scanning {
+                bail!("A scan is already running");
+            }
+            status.scanning = true;
+            status.scope = scope.clone();
+            status.started = Some(OffsetDateTime::now_utc());
+            status.error = None;
+        }
+        self.scanned.store(0, Ordering::Relaxed);
+        let _ = self.events.send(LibraryEvent::ScanStarted);
+        // A cancel that came in after the last scan had already finished
+        self.cancel.store(false, Ordering::Relaxed);
+
+        // Unchanged files keep their entry, only new and modified ones get their headers read.
+        // Everything outside the folder is kept as is. A full scan reads every file, but edits
+        // and suggested edits still carry over.
+        let (previous, mut media): (Vec<Media>, Vec<Media>) = self
+            .list()
+            .into_iter()
+            .partition(|media| media.path.starts_with(&folder));
+        let previous = Previous {
+            media: previous
+                .into_iter()
+                .map(|media| (media.path.clone(), media))
+                .collect(),
+            reuse: !scope.full,
+            taken,
+        };
+
+        let result = info_span!("walk").in_scope(|| self.scan_dir(&folder, &previous, &mut media));
+        let cancelled = self.cancel.swap(false, Ordering::Relaxed);
+
+        let mut status = self.status.write().unwrap();
+        status.scanning = false;
+        status.finished = Some(OffsetDateTime::now_utc());
+        if let Err(e) = &result {
+            status.error = Some(format!("{e:#}"));
+        } else if cancelled {
+            status.error = Some("Cancelled".to_string());
+        }
+        drop(status);
+        if let Err(e) = &result {
+            let _ = self.events.send(LibraryEvent::ScanFailed {
+                error: format!("{e:#}"),
+            });
+        }
+        result?;
+        if cancelled {
+            info!("Scan cancelled, the index is unchanged");
+            return Ok(self.media.read().unwrap().len());
+        }
+
+        media.sort_by(|a, b| b.taken.cmp(&a.taken).then_with(|| a.path.cmp(&b.path)));
+
+        let count = media.len();
+        if let Some(index) = &self.index {
+            info_span!("save_index")
+                .in_scope(|| save_json(index, &media))
+                .inspect_err(|e| {
+                    let _ = self.events.send(LibraryEvent::ScanFailed {
+                        error: format!("{e:#}"),
+                    });
+                })?;
+        }
+        // Only worked out for someone to tell. A modified file keeps its path, so isn't new.
+        let known = !self.media.read().unwrap().is_empty();
+        let added = match known && self.events.receiver_count() > 0 {
+            true => media
+                .iter()
+                .filter(|m| m.path.starts_with(&folder) && !previous.media.contains_key(&m.path))
+                .cloned()
+                .collect(),
+            false => Vec::new(),
+        };
+        *self.media.write().unwrap() = media;
+        self.scans.send_modify(|scans| *scans += 1);
+        let _ = self.events.send(LibraryEvent::Scanned { count, added });
+        info!("Indexed {count} files");
+        Ok(count)
+    }
+
+    /// Asks the running scan to stop, leaving the index as it was before it started. False when
+    /// no scan is running.
+    pub fn cancel_scan(&self) -> bool {
+        let scanning = self.status.read().unwrap().scanning;
+        if scanning {
+            self.cancel.store(true, Ordering::Relaxed);
+        }
+        scanning
+    }
+
+    /// Checks a folder given to [`Library::scan_scope`], the same way a whole scan would get to it
+    pub fn scan_folder(&self, path: &Path) -> Result<PathBuf> {
+        let folder = validate_relative(path)?;
+        let hidden = folder
+            .components()
+            .any(|part| part.as_os_str().to_string_lossy().starts_with('.'));
+        let absolute = self.root.path().join(&folder);
+        // Symlinked folders aren't followed by a scan either, anywhere along the way
+        let is_dir =
+            absolute.canonicalize().is_ok_and(|real| real == absolute) && absolute.is_dir();
+        if hidden || !is_dir || self.root.excludes(&absolute) {
+            bail!(Invalid(format!(
+                "{folder:?} is not a folder in the library"
+            )));
+        }
+        Ok(folder)
+    }
+
+    /// Replaces a photo's edits, or removes them with None, returning its updated entry. Any
+    /// suggestion is settled either way. Blocks while the photo's header is read for its size.
+    /// Fails if a scan is running, whose index wouldn't have them.
+    pub fn set_edits(&self, path: &Path, edits: Option<Edits>) -> Result<Media> {
+        self.update_photo(path, |media| {
+            let file = self.root.resolve(path)?;
+            let head = match content_type(path) {
+                Some("image/jpeg") => read_head(file.absolute()).ok(),
+                _ => None,
+            };
+            let exif = head
+                .as_deref()
+                .and_then(|head| jpg::get_exif(head).ok().flatten());
+            let (width, height) = dimensions(file.absolute(), path, exif.as_ref(), edits.as_ref());
+            media.width = width;
+            media.height = height;
+            media.panorama = panorama(head.as_deref(), width, height, edits.as_ref());
+            media.edits = edits;
+            media.suggested_edits = None;
+            Ok(())
+        })
+    }
+
+    /// Keeps edits for someone to confirm with [`Self::set_edits`], or removes them with None.
+    /// Fails like it.
+    pub fn set_suggested_edits(&self, path: &Path, suggested: Option<Edits>) -> Result<Media> {
+        self.update_photo(path, |media| {
+            media.suggested_edits = suggested;
+            Ok(())
+        })
+    }
+
+    /// Fills in perceptual hashes worked out for the given entries, those that haven't changed
+    /// since. Fails like [`Self::set_edits`].
+    pub fn set_perceptual_hashes(&self, hashes: &[(Media, u64)]) -> Result<()> {
+        let status = self.status.read().unwrap();
+        if status.scanning {
+            bail!("A scan is running");
+        }
+        let hashes: HashMap<&Path, (&Media, u64)> = hashes
+            .iter()
+            .map(|(media, hash)| (media.path.as_path(), (media, *hash)))
+            .collect();
+
+        let mut list = self.media.write().unwrap();
+        for entry in list.iter_mut() {
+            if let Some((media, hash)) = hashes.get(entry.path.as_path()) {
+                if media.size == entry.size && media.modified == entry.modified {
+                    entry.perceptual_hash = Some(format!("{hash:016x}"));
+                }
+            }
+        }
+        let list = list.clone();
+        if let Some(index) = &self.index {
+            save_json(index, &list)?;
+        }
+        Ok(())
+    }
+
+    fn update_photo(
+        &self,
+        path: &Path,
+        update: impl FnOnce(&mut Media) -> Result<()>,
+    ) -> Result<Media> {
+        // Held throughout, so a scan can't start and take a copy of the list in the meantime
+        let status = self.status.read().unwrap();
+        if status.scanning {
+            bail!("A scan is running");
+        }
+        let mut media = self
+            .get(path)
+            .with_context(|| format!("{path:?} is not in the library"))?;
+        if media.kind != Kind::Image {
+            bail!("{path:?} is not a photo");
+        }
+        update(&mut media)?;
+
+        let mut list = self.media.write().unwrap();
+        if let Some(entry) = list.iter_mut().find(|m| m.path == path) {
+            *entry = media.clone();
+        }
+        let list = list.clone();
+        if let Some(index) = &self.index {
+            save_json(index, &list)?;
+        }
+        Ok(media)
+    }
+
+    fn scan_dir(&self, relative: &Path, previous: &Previous, media: &mut Vec<Media>) -> Result<()> {
+        let dir = self.root.path().join(relative);
+        let entries = fs::read_dir(&dir).with_context(|| format!("Failed to list {dir:?}"))?;
+
+        for entry in entries {
+            // Whatever was found is thrown away, see scan_scope
+            if self.cancel.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let entry = entry?;
+            let name = entry.file_name();
+            // Dot files are hidden, which also keeps the default data directory out
+            if name.to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            let relative = relative.join(&name);
+            let file_type = entry.file_type()?;
+
+            if file_type.is_dir() {
+                if self.root.excludes(&entry.path()) {
+                    continue;
+                }
+                if let Err(e) = self.scan_dir(&relative, previous, media) {
+                    warn!("Skipping {relative:?}: {e:#}");
+                }
+            } else if file_type.is_file() || file_type.is_symlink() {
+                // Symlinked folders are not followed, which rules out loops, but linked files
+                // are fine as long as they resolve inside the library
+                if file_type.is_symlink() && self.root.resolve(&relative).is_err() {
+                    continue;
+                }
+                self.scanned.fetch_add(1, Ordering::Relaxed);
+                if let Some(item) = read_media(&entry.path(), relative, previous) {
+                    media.push(item);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<Media> {
+        self.media.read().unwrap().clone()
+    }
+
+    pub fn get(&self, path: &Path) -> Option<Media> {
+        self.media
+            .read()
+            .unwrap()
+            .iter()
+            .find(|m| m.path == path)
+            .cloned()
+    }
+
+    /// Direct subfolders of a folder and the media directly inside it, counting only visible
+    /// media so that folders holding nothing visible don't show up either
+    pub fn folder(
+        &self,
+        folder: &Path,
+        visible: impl Fn(&Media) -> bool,
+    ) -> (Vec<PathBuf>, Vec<Media>) {
+        let mut folders = Vec::new();
+        let mut items = Vec::new();
+
+        for media in self.media.read().unwrap().iter().filter(|m| visible(m)) {
+            let Ok(rest) = media.path.strip_prefix(folder) else {
+                continue;
+            };
+            let mut components = rest.components();
+            match (components.next(), components.next()) {
+                (Some(_), None) => items.push(media.clone()),
+                (Some(sub), Some(_)) => folders.push(folder.join(sub)),
+                _ => {}
+            }
+        }
+
+        folders.sort();
+        folders.dedup();
+        (folders, items)
+    }
+}
+
+// What the index had before a scan
+struct Previous {
+    media: HashMap<PathBuf, Media>,
+    // Whether unchanged files can keep their entries, rather than being read again
+    reuse: bool,
+    taken: HashMap<PathBuf, OffsetDateTime>,
+}
+
+fn read_media(absolute: &Path, relative: PathBuf, previous: &Previous) -> Option<Media> {
+    let kind = kind(&relative)?;
+
+    let metadata = match fs::metadata(absolute) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            warn!("Skipping {relative:?}: {e}");
+            return None;
+        }
+    };
+    let modified = metadata
+        .modified()
+        .map(OffsetDateTime::from)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+
+    let known = previous.media.get(&relative);
+    if let Some(known) = known.filter(|known| {
+        previous.reuse
+            && known.size == metadata.len()
+            && known.modified == modified
+            && (known.kind != Kind::Image || known.hash.is_some())
+    }) {
+        // An import may have given it a time taken since
+        let taken = previous.taken.get(&relative).copied();
+        return Some(Media {
+            taken: taken.unwrap_or(known.taken),
+            ..known.clone()
+        });
+    }
+
+    let head = match content_type(&relative) {
+        Some("image/jpeg") => read_head(absolute)
+            .inspect_err(|e| debug!("Failed to read {relative:?}: {e}"))
+            .ok(),
+        _ => None,
+    };
+    let exif = head.as_deref().and_then(|head| {
+        jpg::get_exif(head)
+            .inspect_err(|e| debug!("No EXIF in {relative:?}: {e:#}"))
+            .ok()
+            .flatten()
+    });
+    let hdr = head.as_deref().is_some_and(jpg::has_gain_map);
+    let taken = match previous.taken.get(&relative) {
+        Some(taken) => *taken,
+        None => exif
+            .as_ref()
+            .and_then(|exif| exif.taken)
+            .map_or(modified, |taken| taken.assume_utc()),
+    };
+    let location = exif.as_ref().and_then(|exif| exif.location);
+    let edits = known.and_then(|known| known.edits.clone());
+    let suggested_edits = known.and_then(|known| known.suggested_edits.clone());
+    let (width, height) = match kind {
+        Kind::Image => dimensions(absolute, &relative, exif.as_ref(), edits.as_ref()),
+        Kind::Video => (None, None),
+    };
+    let panorama = match kind {
+        Kind::Image => panorama(head.as_deref(), width, height, edits.as_ref()),
+        Kind::Video => None,
+    };
+    let frame_rate = match content_type(&relative) {
+        Some("video/mp4" | "video/quicktime") => File::open(absolute)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut file| mp4::frame_rate(&mut file))
+            .inspect_err(|e| debug!("No frame rate for {relative:?}: {e:#}"))
+            .ok()
+            .flatten(),
+        _ => None,
+    };
+    // Videos have no thumbnails and can be huge, they're not worth reading through
+    let hash = match kind {
+        Kind::Image => content_hash(absolute)
+            .inspect_err(|e| warn!("Failed to read {relative:?}: {e}"))
+            .ok(),
+        Kind::Video => None,
+    };
+    // Worked out again in the background when the contents changed
+    let perceptual_hash = known
+        .filter(|known| known.hash.is_some() && known.hash == hash)
+        .and_then(|known| known.perceptual_hash.clone());
+
+    Some(Media {
+        path: relative,
+        kind,
+        taken,
+        modified,
+        size: metadata.len(),
+        width,
+        height,
+        hash,
+        perceptual_hash,
+        hdr,
+        panorama,
+        frame_rate: frame_rate.map(|rate| (rate.playback * 100.0).round() / 100.0),
+        speed: frame_rate.and_then(speed),
+        location,
+        edits,
+        suggested_edits,
+    })
+}
+
+// Photo spheres say so in their XMP, other panoramas only go by their shape. Any edit cuts a
+// sphere's projection short, so it's left as a wide one.
+fn panorama(
+    head: Option<&[u8]>,
+    width: Option<u32>,
+    height: Option<u32>,
+    edits: Option<&Edits>,
+) -> Option<Panorama> {
+    if edits.is_none() && head.is_some_and(jpg::is_equirectangular) {
+        return Some(Panorama::Equirectangular);
+    }
+    let (width, height) = (width?, height?);
+    let ratio = width.max(height) as f64 / width.min(height).max(1) as f64;
+    (ratio >= PANORAMA_RATIO).then_some(Panorama::Wide)
+}
+
+// Only reads the header, the grid needs the aspect ratio before any thumbnail exists. As shown,
+// so turned and edited photos have theirs changed to match.
+fn dimensions(
+    absolute: &Path,
+    relative: &Path,
+    exif: Option<&Exif>,
+    edits: Option<&Edits>,
+) -> (Option<u32>, Option<u32>) {
+    // Taken with the camera on its side, the orientations that rotate by 90 degrees
+    let turned = matches!(exif.and_then(|exif| exif.orientation), Some(5..=8));
+    image::image_dimensions(absolute)
+        .inspect_err(|e| debug!("No dimensions for {relative:?}: {e}"))
+        .ok()
+        .map(|(width, height)| match turned {
+            true => (height, width),
+            false => (width, height),
+        })
+        .map(|(width, height)| match edits {
+            Some(edits) => edits.size(width, height),
+            None => (width, height),
+        })
+        .unzip()
+}
+
+// Half of a SHA-256 in hex, plenty to tell a library's files apart
+fn content_hash(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize()[..16]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+pub fn read_exif(path: &Path) -> Result<Option<Exif>> {
+    jpg::get_exif(&read_head(path)?)
+}
+
+// By the rate it was filmed at when the camera said, or else the rate it plays at
+fn speed(rate: FrameRate) -> Option<Speed> {
+    let capture = rate.capture.unwrap_or(rate.playback);
+    if capture >= SLOW_MOTION_FPS {
+        Some(Speed::SlowMotion)
+    } else if capture * 2.0 <= rate.playback {
+        Some(Speed::TimeLapse)
+    } else {
+        None
+    }
+}
+
+/// What a JPEG carries after its main image, such as depth maps, see [`jpg::embedded`]
+pub fn read_embedded(path: &Path) -> Result<Vec<Embedded>> {
+    let mut file = File::open(path)?;
+    let length = file.metadata()?.len();
+    let mut head = Vec::with_capacity(jpg::METADATA_SIZE as usize);
+    (&mut file)
+        .take(jpg::METADATA_SIZE)
+        .read_to_end(&mut head)?;
+
+    let mut embedded = jpg::embedded(&head, length);
+    for item in &mut embedded {
+        if item.kind != EmbeddedKind::Image || item.mime != "image/jpeg" {
+            continue;
+        }
+        file.seek(SeekFrom::Start(item.offset))?;
+        let mut head = Vec::new();
+        (&mut file)
+            .take(item.length.min(jpg::METADATA_SIZE))
+            .read_to_end(&mut head)?;
+        item.kind = jpg::classify_embedded(&head);
+    }
+    Ok(embedded)
+}
+
+// Where a JPEG's metadata is, see METADATA_SIZE
+fn read_head(path: &Path) -> io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(jpg::METADATA_SIZE as usize);
+    File::open(path)?
+        .take(jpg::METADATA_SIZE)
+        .read_to_end(&mut head)?;
+    Ok(head)
+}
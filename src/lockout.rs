@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+// First lockout length, doubled for every further failure
+const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+// Failures older than this are forgotten
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Account(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct LoginGuard {
+    free_attempts: u32,
+    max_lockout: Duration,
+    failures: Mutex<HashMap<Key, Failures>>,
+}
+
+impl LoginGuard {
+    pub fn new(free_attempts: u32, max_lockout: Duration) -> Self {
+        Self {
+            free_attempts,
+            max_lockout,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn keys(username: Option<&str>, ip: IpAddr) -> impl Iterator<Item = Key> {
+        username
+            .map(|u| Key::Account(u.to_string()))
+            .into_iter()
+            .chain([Key::Ip(ip)])
+    }
+
+    /// Returns how long the account or address remains locked out, if it is
+    pub fn locked(&self, username: Option<&str>, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+
+        Self::keys(username, ip)
+            .filter_map(|key| failures.get(&key)?.locked_until)
+            .filter(|until| *until > now)
+            .max()
+            .map(|until| until - now)
+    }
+
+    pub fn failed(&self, username: Option<&str>, ip: IpAddr) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, f| now.duration_since(f.last) < FORGET_AFTER);
+
+        warn!(
+            target: "m3s::security",
+            username = username.unwrap_or("-"),
+            %ip,
+            "Failed authentication attempt"
+        );
+
+        for key in Self::keys(username, ip) {
+            let entry = failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            entry.count += 1;
+            entry.last = now;
+
+            if entry.count > self.free_attempts {
+                let doublings = (entry.count - self.free_attempts - 1).min(16);
+                let lockout = (BASE_LOCKOUT * 2u32.pow(doublings)).min(self.max_lockout);
+                entry.locked_until = Some(now + lockout);
+
+                warn!(
+                    target: "m3s::security",
+                    ?key,
+                    failures = entry.count,
+                    "Locked out for {}s",
+                    lockout.as_secs()
+                );
+            }
+        }
+    }
+
+    // Only the account is cleared, otherwise one valid login would reset an attacker's address
+    pub fn succeeded(&self, username: &str) {
+        self.failures
+            .lock()
+            .unwrap()
+            .remove(&Key::Account(username.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    // What's left of a lockout just started, give or take the time the test takes
+    fn about(remaining: Option<Duration>, lockout: Duration) -> bool {
+        remaining.is_some_and(|remaining| {
+            remaining <= lockout && remaining > lockout - Duration::from_secs(5)
+        })
+    }
+
+    #[test]
+    fn free_attempts_then_lockout() {
+        let guard = LoginGuard::new(3, Duration::from_secs(3600));
+        for _ in 0..3 {
+            guard.failed(Some("alice"), IP);
+        }
+        assert_eq!(guard.locked(Some("alice"), IP), None);
+
+        guard.failed(Some("alice"), IP);
+        assert!(about(guard.locked(Some("alice"), IP), BASE_LOCKOUT));
+        // Either key is enough
+        assert!(about(guard.locked(Some("alice"), OTHER_IP), BASE_LOCKOUT));
+        assert!(about(guard.locked(Some("bob"), IP), BASE_LOCKOUT));
+        assert!(about(guard.locked(None, IP), BASE_LOCKOUT));
+        assert_eq!(guard.locked(Some("bob"), OTHER_IP), None);
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_the_maximum() {
+        let max = Duration::from_secs(200);
+        let guard = LoginGuard::new(0, max);
+
+        guard.failed(Some("alice"), IP);
+        assert!(about(guard.locked(Some("alice"), IP), BASE_LOCKOUT));
+        guard.failed(Some("alice"), IP);
+        assert!(about(guard.locked(Some("alice"), IP), BASE_LOCKOUT * 2));
+        guard.failed(Some("alice"), IP);
+        assert!(about(guard.locked(Some("alice"), IP), BASE_LOCKOUT * 4));
+        for _ in 0..40 {
+            guard.failed(Some("alice"), IP);
+        }
+        assert!(about(guard.locked(Some("alice"), IP), max));
+    }
+
+    #[test]
+    fn success_clears_the_account_but_not_the_address() {
+        let guard = LoginGuard::new(0, Duration::from_secs(3600));
+        guard.failed(Some("alice"), IP);
+        guard.succeeded("alice");
+
+        assert_eq!(guard.locked(Some("alice"), OTHER_IP), None);
+        assert!(about(guard.locked(Some("alice"), IP), BASE_LOCKOUT));
+    }
+}
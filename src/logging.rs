@@ -0,0 +1,172 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use time::{macros::format_description, OffsetDateTime};
+use tracing_subscriber::{fmt::MakeWriter, Layer, Registry};
+
+use crate::args::{LogFileArgs, LogFormat, Rotation};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Formats events for standard output, and for the log file too when there is one
+pub fn layer(format: LogFormat, log_file: &LogFileArgs) -> Result<BoxedLayer> {
+    let stdout = format_layer(format, io::stdout, true);
+    let Some(path) = &log_file.log_file else {
+        return Ok(stdout);
+    };
+
+    let file = LogFile::open(path, log_file)?;
+    Ok(stdout
+        .and_then(format_layer(format, move || file.clone(), false))
+        .boxed())
+}
+
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.compact().boxed(),
+        LogFormat::Json => layer
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+    }
+}
+
+/// Appends to a file, moving it aside with the time in its name whenever the period or size
+/// limit is reached and keeping only the newest few of those
+#[derive(Debug, Clone)]
+pub struct LogFile(Arc<Mutex<Inner>>);
+
+#[derive(Debug)]
+struct Inner {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    period: i64,
+    rotation: Rotation,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl LogFile {
+    pub fn open(path: &Path, args: &LogFileArgs) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {parent:?}"))?;
+        }
+        let file = append(path).with_context(|| format!("Failed to open log file {path:?}"))?;
+
+        // A file left from before a restart belongs to the period it was last written in
+        let metadata = file.metadata()?;
+        let written = metadata
+            .modified()
+            .map(OffsetDateTime::from)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc());
+
+        Ok(Self(Arc::new(Mutex::new(Inner {
+            path: path.to_path_buf(),
+            file,
+            size: metadata.len(),
+            period: period(args.log_file_rotate, written),
+            rotation: args.log_file_rotate,
+            max_bytes: args.log_file_max_mb * 1024 * 1024,
+            keep: args.log_file_keep,
+        }))))
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().file.flush()
+    }
+}
+
+// Periods are counted in UTC, so daily files turn over at midnight UTC
+fn period(rotation: Rotation, time: OffsetDateTime) -> i64 {
+    match rotation {
+        Rotation::Hourly => time.unix_timestamp() / 3600,
+        Rotation::Daily => time.unix_timestamp() / 86400,
+        Rotation::Never => 0,
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Inner {
+    // Each event arrives as a single write, so rotating here never splits a line
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = OffsetDateTime::now_utc();
+        let too_big = self.max_bytes > 0 && self.size + buf.len() as u64 > self.max_bytes;
+        if self.size > 0 && (too_big || period(self.rotation, now) != self.period) {
+            self.rotate(now)?;
+        }
+
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn rotate(&mut self, now: OffsetDateTime) -> io::Result<()> {
+        let stamp = now
+            .format(format_description!(
+                "[year][month][day]-[hour][minute][second]"
+            ))
+            .map_err(io::Error::other)?;
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+
+        // Size limits can be hit twice in a second, later ones get a counter
+        let mut rotated = self.path.with_file_name(format!("{name}.{stamp}"));
+        for n in 1.. {
+            if !rotated.exists() {
+                break;
+            }
+            rotated = self.path.with_file_name(format!("{name}.{stamp}-{n}"));
+        }
+        fs::rename(&self.path, &rotated)?;
+
+        self.file = append(&self.path)?;
+        self.size = 0;
+        self.period = period(self.rotation, now);
+        self.prune(&name);
+        Ok(())
+    }
+
+    // Timestamps sort by name, so everything before the newest `keep` goes
+    fn prune(&self, name: &str) {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = format!("{name}.");
+        let mut rotated: Vec<PathBuf> = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|entry| entry.path())
+            .collect();
+        rotated.sort();
+
+        let excess = rotated.len().saturating_sub(self.keep);
+        for path in &rotated[..excess] {
+            // Nowhere to report this but the log being rotated, the next rotation tries again
+            let _ = fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Extension, Form, Router,
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar, SignedCookieJar,
+};
+use serde::Deserialize;
+
+use crate::{
+    audit::{Action, Audit},
+    auth::{AuthState, LoginError},
+    base_path::BasePath,
+    csrf,
+    network::ClientIp,
+    sessions::{Session, COOKIE_NAME},
+};
+
+pub fn router() -> Router<AuthState> {
+    Router::new()
+        .route("/login", get(login_page).post(login))
+        .route("/logout", post(logout))
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginQuery {
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+    next: Option<String>,
+}
+
+async fn login_page(
+    State(state): State<AuthState>,
+    Extension(base): Extension<BasePath>,
+    Query(LoginQuery { next }): Query<LoginQuery>,
+) -> Html<String> {
+    Html(render_login(
+        &base,
+        next.as_deref(),
+        None,
+        state.oidc.is_some(),
+    ))
+}
+
+async fn login(
+    State(state): State<AuthState>,
+    ClientIp(ip): ClientIp,
+    Extension(base): Extension<BasePath>,
+    audit: Audit,
+    jar: SignedCookieJar,
+    headers: HeaderMap,
+    Form(LoginForm {
+        username,
+        password,
+        next,
+    }): Form<LoginForm>,
+) -> Response {
+    // A forged login would quietly sign the victim into someone else's account
+    if !csrf::same_origin(&headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let principal = {
+        let state = state.clone();
+        let username = username.clone();
+        // In the request's span, so a failed attempt is logged with its request ID
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || span.in_scope(|| state.login(&username, &password, ip)))
+            .await
+            .unwrap_or(Err(LoginError::Invalid))
+    };
+
+    let error = match principal {
+        Ok(_) => None,
+        Err(LoginError::Invalid) => Some((
+            StatusCode::UNAUTHORIZED,
+            "Incorrect username or password".to_string(),
+        )),
+        Err(LoginError::LockedOut(remaining)) => Some((
+            StatusCode::TOO_MANY_REQUESTS,
+            format!(
+                "Too many failed attempts, try again in {} seconds",
+                remaining.as_secs().max(1)
+            ),
+        )),
+    };
+
+    if let Some((status, message)) = error {
+        audit.record_as(Some(&username), Action::LoginFailed, None);
+        let page = render_login(&base, next.as_deref(), Some(&message), state.oidc.is_some());
+        return (status, Html(page)).into_response();
+    }
+
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let (session, token) = match state.sessions.create(&username, user_agent) {
+        Ok(created) => created,
+        Err(e) => {
+            tracing::error!("Failed to create session: {e:#}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    audit.record_as(Some(&username), Action::Login, Some(&session.id));
+
+    let (cookie, csrf_cookie) = session_cookies(&state, &session, token);
+    (
+        jar.add(cookie),
+        CookieJar::new().add(csrf_cookie),
+        Redirect::to(&safe_next(&base, next.as_deref())),
+    )
+        .into_response()
+}
+
+/// The session cookie and the CSRF cookie that has to accompany it
+pub fn session_cookies(
+    state: &AuthState,
+    session: &Session,
+    token: String,
+) -> (Cookie<'static>, Cookie<'static>) {
+    let lifetime = state.sessions.lifetime();
+    let cookie = Cookie::build((COOKIE_NAME, token))
+        .path("/")
+        .http_only(true)
+        .secure(state.secure_cookies)
+        .same_site(SameSite::Lax)
+        .max_age(lifetime)
+        .build();
+    let csrf_cookie = csrf::cookie(
+        csrf::token(&state.cookie_key, &session.id),
+        lifetime,
+        state.secure_cookies,
+    );
+
+    (cookie, csrf_cookie)
+}
+
+async fn logout(
+    State(state): State<AuthState>,
+    Extension(base): Extension<BasePath>,
+    audit: Audit,
+    jar: SignedCookieJar,
+    headers: HeaderMap,
+) -> Response {
+    if !csrf::same_origin(&headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    if let Some(cookie) = jar.get(COOKIE_NAME) {
+        if let Some(session) = state.sessions.verify(cookie.value()) {
+            audit.record_as(Some(&session.username), Action::Logout, Some(&session.id));
+        }
+        if let Err(e) = state.sessions.revoke_token(cookie.value()) {
+            tracing::error!("Failed to revoke session: {e:#}");
+        }
+    }
+
+    let jar = jar.remove(Cookie::build(COOKIE_NAME).path("/"));
+    let csrf_jar = CookieJar::new().remove(Cookie::build(csrf::COOKIE_NAME).path("/"));
+    (jar, csrf_jar, Redirect::to(&base.join("/login"))).into_response()
+}
+
+// Only follow local redirects, anything else could send users to another site after login.
+// `next` is a full path, --base-path included, as the browser saw it.
+pub fn safe_next(base: &BasePath, next: Option<&str>) -> String {
+    match next {
+        Some(next) if next.starts_with('/') && !next.starts_with("//") => next.to_string(),
+        _ => base.join("/"),
+    }
+}
+
+fn render_login(base: &BasePath, next: Option<&str>, error: Option<&str>, sso: bool) -> String {
+    let action = html_escape(&base.join("/login"));
+    let sso = match sso {
+        true => format!(
+            r#"<a href="{}?next={}">Sign in with single sign-on</a>"#,
+            html_escape(&base.join("/oidc/login")),
+            html_escape(&percent_encode(&safe_next(base, next)))
+        ),
+        false => String::new(),
+    };
+    let next = html_escape(&safe_next(base, next));
+    let error = error
+        .map(|e| format!(r#"<p class="error">{}</p>"#, html_escape(e)))
+        .unwrap_or_default();
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Sign in - m3s</title>
+<style>
+body {{ font-family: sans-serif; display: flex; justify-content: center; margin-top: 15vh; }}
+form {{ display: flex; flex-direction: column; gap: 0.5em; width: 16em; }}
+.error {{ color: #b00020; }}
+</style>
+</head>
+<body>
+<form method="post" action="{action}">
+<h1>m3s</h1>
+{error}
+<input name="username" placeholder="Username" autocomplete="username" required autofocus>
+<input name="password" type="password" placeholder="Password" autocomplete="current-password" required>
+<input name="next" type="hidden" value="{next}">
+<button type="submit">Sign in</button>
+{sso}
+</form>
+</body>
+</html>"#
+    )
+}
+
+pub fn html_escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+pub fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
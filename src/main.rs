@@ -1,34 +1,469 @@
-use anyhow::Result;
-use args::Args;
-use axum::Router;
-use clap::Parser as _;
-use tracing::info;
+use std::{sync::Arc, time::Duration};
 
+use access_log::AccessLog;
+use admin::AdminState;
+use anyhow::{Context, Result};
+use api_keys::ApiKeyStore;
+use args::{Args, Command};
+use audit::AuditLog;
+use auth::AuthState;
+use axum::{
+    extract::{DefaultBodyLimit, Request},
+    middleware, Extension, Router,
+};
+use axum_extra::extract::cookie::Key;
+use digest::Digests;
+use jobs::JobLimiter;
+use library::Library;
+use lockout::LoginGuard;
+use media::MediaState;
+use network::NetworkPolicy;
+use oidc::Oidc;
+use permissions::PermissionStore;
+use pregenerate::Pregenerator;
+use rate_limit::{Budget, RateLimiter};
+use recent_errors::RecentErrors;
+use reload::Reloader;
+use safe_path::LibraryRoot;
+use security_headers::SecurityHeaders;
+use sessions::SessionStore;
+use thumbnails::Thumbnails;
+use timeout::Timeouts;
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    compression::{
+        predicate::{NotForContentType, Predicate as _},
+        CompressionLayer, DefaultPredicate,
+    },
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::{info, info_span};
+use tracing_subscriber::{
+    layer::SubscriberExt as _, util::SubscriberInitExt as _, EnvFilter, Layer as _,
+};
+use ui::Theme;
+use users::UserStore;
+use webhooks::Webhooks;
+
+mod access_log;
+mod admin;
+mod api_error;
+mod api_keys;
 mod args;
+mod audit;
+mod auth;
+mod backup;
+mod base_path;
+mod basic;
+mod commands;
+mod config;
+mod cors;
+mod csrf;
+mod digest;
+mod edits;
+mod events;
+mod export;
+mod health;
+mod i18n;
+mod import;
+mod jobs;
+mod jpg;
+mod library;
+mod lockout;
+mod logging;
+mod login;
+mod media;
+mod mp4;
+mod mqtt;
+mod network;
+mod oidc;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod perceptual_hashes;
+mod permissions;
+mod pregenerate;
+#[cfg(all(unix, feature = "profiling"))]
+mod profiling;
+mod rate_limit;
+mod recent_errors;
+mod reload;
+mod request_id;
+mod safe_path;
+mod security_headers;
+mod server;
+mod sessions;
+mod smtp;
+mod store;
+#[cfg(unix)]
+mod systemd;
+mod taken;
+mod thumbnails;
+mod timeout;
+mod ui;
+mod users;
+mod webhooks;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let Args {
+        command,
+        config,
         directory,
         log_level,
-        address,
+        log_format,
+        log_file,
+        access_log,
+        #[cfg(feature = "otlp")]
+        otlp,
+        addresses,
         port,
-    } = Args::parse();
+        base_path,
+        public_url,
+        listen,
+        socket_mode,
+        shutdown_timeout,
+        auth,
+        data_dir,
+        index,
+        library_check_secs,
+        library_check_timeout_secs,
+        thumbnail,
+        thumbnail_memory_mb,
+        warm_thumbnails,
+        cache_min_free_mb,
+        file_buffer_kb,
+        session_days,
+        oidc,
+        rate_limit_api,
+        rate_limit_expensive,
+        concurrency_expensive,
+        memory_expensive_mb,
+        thumbnail_workers,
+        queue_expensive,
+        timeout_api_secs,
+        timeout_expensive_secs,
+        body_limit_kb,
+        rate_limit_login,
+        lockout_attempts,
+        lockout_max_minutes,
+        tls,
+        http,
+        cors,
+        headers,
+        network,
+        theme,
+        mqtt,
+        smtp,
+    } = config::load()?;
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("no other crypto provider is installed");
+
+    #[cfg(feature = "otlp")]
+    let (otlp_layer, _otlp) = otlp::layer(otlp, &log_level)?.unzip();
+
+    let errors = RecentErrors::default();
+    // Behind a reload handle so SIGHUP can change it, see reload.rs
+    let (log_filter, log_level) =
+        tracing_subscriber::reload::Layer::new(EnvFilter::new(&log_level));
+    let log_output = logging::layer(log_format, &log_file)?;
+    let subscriber = tracing_subscriber::registry()
+        .with(log_output.with_filter(log_filter))
+        .with(errors.clone());
+    #[cfg(feature = "otlp")]
+    let subscriber = subscriber.with(otlp_layer);
+    subscriber.init();
+
+    let directory = directory.map(Ok).unwrap_or_else(std::env::current_dir)?;
+
+    let data_dir = data_dir.unwrap_or_else(|| directory.join(".m3s"));
+    std::fs::create_dir_all(&data_dir)
+        .with_context(|| format!("Failed to create data directory {data_dir:?}"))?;
+
+    let session_length = time::Duration::days(session_days.into());
+    match command {
+        None | Some(Command::Serve) => {}
+        Some(command) => {
+            return commands::run(
+                command,
+                &directory,
+                &data_dir,
+                index.as_deref(),
+                config.as_deref(),
+                session_length,
+                &thumbnail,
+            )
+        }
+    }
+
+    let root = LibraryRoot::new(&directory, &[&data_dir])?;
+    // Edits, suggested edits and perceptual hashes are only kept in the index, so there always is
+    // one
+    let index = index.unwrap_or_else(|| data_dir.join("index.json"));
+    let library = Arc::new(Library::with_index(root, index)?.with_taken(taken::path(&data_dir)));
+    info!("Starting at {:?}", library.root().path());
+
+    let thumbnails = Arc::new(Thumbnails::with_memory(
+        &data_dir,
+        &thumbnail,
+        thumbnail_memory_mb,
+    )?);
+
+    // Listening before the first scan, whose events would otherwise be missed
+    let webhooks = Arc::new(Webhooks::load(&data_dir)?);
+    webhooks.follow(&library);
+    // Where links sent out of m3s start
+    let links = match &public_url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => base_path.as_str().to_string(),
+    };
+    mqtt::start(mqtt, library.clone(), links);
+
+    // Serve straight away, the library fills in as the scan progresses
+    tokio::task::spawn_blocking({
+        let library = library.clone();
+        let thumbnails = thumbnails.clone();
+        move || {
+            // From the index straight away, otherwise once the scan has found something
+            let mut warmed = thumbnails.warm(&library.list(), warm_thumbnails);
+            if let Err(e) = library.scan() {
+                tracing::error!("Library scan failed: {e:#}");
+            }
+            if warmed == 0 {
+                warmed = thumbnails.warm(&library.list(), warm_thumbnails);
+            }
+            if warmed > 0 {
+                info!("Loaded {warmed} thumbnails into memory");
+            }
+        }
+    });
+
+    if cache_min_free_mb > 0 {
+        health::watch_disk(
+            thumbnails.clone(),
+            cache_min_free_mb.saturating_mul(1024 * 1024),
+        );
+    }
+    if library_check_secs > 0 {
+        health::watch_library(
+            library.clone(),
+            Duration::from_secs(library_check_secs),
+            Duration::from_secs(library_check_timeout_secs),
+        );
+    }
 
-    tracing_subscriber::fmt()
-        .with_max_level(log_level)
-        .compact()
-        .init();
+    let api_keys = Arc::new(ApiKeyStore::load(&data_dir)?);
+    let users = Arc::new(UserStore::load(&data_dir)?);
+    let permissions = Arc::new(PermissionStore::load(&data_dir)?);
+    let sessions = Arc::new(SessionStore::load(&data_dir, session_length)?);
+    let audit = Arc::new(AuditLog::open(&data_dir)?);
+    let cookie_key = Key::from(&store::load_secret(&data_dir)?);
 
-    let directory = directory
-        .map(Ok)
-        .unwrap_or_else(|| std::env::current_dir())?;
-    info!("Starting at {directory:?}");
+    if let Some(auth) = &auth {
+        info!("Basic authentication enabled for {}", auth.username);
+    }
 
-    let app = Router::new();
+    let auth_state = AuthState {
+        basic: auth.map(Arc::new),
+        api_keys: api_keys.clone(),
+        users: users.clone(),
+        sessions: sessions.clone(),
+        cookie_key,
+        secure_cookies: tls.secure_cookies || server::serves_https(&tls),
+        oidc: Oidc::discover(oidc).await?.map(Arc::new),
+        login_guard: Arc::new(LoginGuard::new(
+            lockout_attempts,
+            Duration::from_secs(lockout_max_minutes * 60),
+        )),
+    };
 
-    let listener = tokio::net::TcpListener::bind((address.as_str(), port)).await?;
-    axum::serve(listener, app).await?;
+    let limiter = Arc::new(RateLimiter::new(
+        rate_limit_api,
+        rate_limit_expensive,
+        rate_limit_login,
+    ));
+
+    let login_routes = login::router()
+        .merge(oidc::router())
+        .with_state(auth_state.clone())
+        .layer(middleware::from_fn_with_state(
+            (limiter.clone(), Budget::Login),
+            rate_limit::rate_limit,
+        ));
+
+    let reloader = Arc::new(Reloader::new(log_level));
+    #[cfg(unix)]
+    reload::on_hangup(reloader.clone())?;
+
+    let admin = AdminState {
+        library: library.clone(),
+        thumbnails: thumbnails.clone(),
+        errors,
+        reloader,
+        webhooks: webhooks.clone(),
+    };
+    let jobs = Arc::new(JobLimiter::new(
+        concurrency_expensive
+            .or_else(|| std::thread::available_parallelism().ok().map(Into::into))
+            .unwrap_or(1),
+        memory_expensive_mb,
+        queue_expensive,
+    ));
+    let pregenerator = (thumbnail_workers > 0).then(|| {
+        Pregenerator::start(
+            library.clone(),
+            thumbnails.clone(),
+            jobs.clone(),
+            thumbnail_workers,
+        )
+    });
+    perceptual_hashes::start(library.clone(), jobs.clone());
+    let digests = Digests::start(
+        &smtp,
+        &data_dir,
+        library.clone(),
+        users.clone(),
+        permissions.clone(),
+        jobs.clone(),
+        public_url,
+    )?;
+    let digest_routes = match digests {
+        Some(digests) => digest::router().with_state(digests),
+        None => Router::new(),
+    };
+    let health = health::router(library.clone(), thumbnails.clone());
+    let media = MediaState {
+        library,
+        permissions: permissions.clone(),
+        thumbnails,
+        file_buffer: file_buffer_kb as usize * 1024,
+        jobs,
+        pregenerator,
+    };
+
+    let theme = Arc::new(Theme::new(theme, &base_path)?);
+    let timeouts = Timeouts {
+        api: Duration::from_secs(timeout_api_secs),
+        expensive: Duration::from_secs(timeout_expensive_secs),
+    };
+
+    let mut app = Router::new()
+        .merge(ui::router(theme.clone()))
+        .nest("/basic", basic::router().with_state(media.clone()))
+        .nest(
+            "/api",
+            media::router(limiter.clone(), timeouts).with_state(media),
+        )
+        .nest("/api/admin/keys", api_keys::router().with_state(api_keys))
+        .nest("/api/admin/users", users::router().with_state(users))
+        .nest("/api/sessions", sessions::router().with_state(sessions))
+        .nest(
+            "/api/admin/grants",
+            permissions::router().with_state(permissions),
+        )
+        .nest(
+            "/api/admin/audit",
+            audit::router().with_state(audit.clone()),
+        )
+        .nest("/api/admin/digest", digest_routes)
+        .nest(
+            "/api/admin/webhooks",
+            webhooks::router().with_state(webhooks),
+        )
+        .nest("/api/admin", admin::router().with_state(admin))
+        .layer(middleware::from_fn_with_state(
+            auth_state,
+            auth::authenticate,
+        ))
+        .layer(middleware::from_fn_with_state(
+            (limiter, Budget::Api),
+            rate_limit::rate_limit,
+        ))
+        .merge(login_routes)
+        .merge(ui::public_router(&theme))
+        .merge(i18n::router())
+        .merge(health)
+        // Bodies are only read by the Json and Form extractors, which go by this
+        .layer(DefaultBodyLimit::max(body_limit_kb * 1024))
+        .layer(middleware::from_fn_with_state(timeouts, timeout::timeout))
+        .layer(Extension(audit))
+        .layer(Extension(base_path.clone()))
+        .layer(CatchPanicLayer::custom(api_error::panicked))
+        .layer(middleware::from_fn(api_error::problem_details));
+
+    if let Some(cors) = cors::layer(cors)? {
+        app = app.layer(cors);
+    }
+
+    // JSON and HTML shrink a lot, photos and videos are compressed already and would only cost
+    // CPU. Inside the access log, so it records the bytes actually sent.
+    app = app.layer(
+        CompressionLayer::new().compress_when(
+            DefaultPredicate::new()
+                .and(NotForContentType::const_new("video/"))
+                .and(NotForContentType::const_new("audio/"))
+                .and(NotForContentType::const_new("application/octet-stream")),
+        ),
+    );
+
+    // Inside the network filter, which is where the client address comes from
+    if let Some(access_log) = AccessLog::new(access_log, &log_file)? {
+        app = app.layer(middleware::from_fn_with_state(
+            Arc::new(access_log),
+            access_log::access_log,
+        ));
+    }
+
+    // Ahead of authentication and rate limiting, which both key on the client address
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            Arc::new(NetworkPolicy::new(network, listen.is_some())),
+            network::filter,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::new(SecurityHeaders::new(headers)?),
+            security_headers::security_headers,
+        ))
+        // Every event while handling a request carries the request's ID, method and path.
+        // Failures are already logged where they happen, with more to say than the status code.
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &Request| {
+                    info_span!(
+                        "request",
+                        request_id = request_id::get(request.extensions()).unwrap_or_default(),
+                        method = %request.method(),
+                        path = request.uri().path()
+                    )
+                })
+                .on_failure(()),
+        )
+        // An X-Request-Id from a proxy in front is kept, so its logs and ours line up. Every
+        // response carries the ID for users to quote when reporting a problem.
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+    // Handlers see paths with the prefix stripped, links back out go through BasePath::join.
+    // As a service, unlike nest, it also takes /photos/ to the index.
+    let app = match base_path.as_str() {
+        "" => app,
+        base => Router::new().nest_service(base, app),
+    };
+
+    server::serve(
+        app,
+        &addresses,
+        port,
+        listen.as_deref().map(|path| (path, socket_mode)),
+        tls,
+        &http,
+        Duration::from_secs(shutdown_timeout),
+    )
+    .await?;
 
+    // Stores, the index and the audit log are written through on every change, nothing is
+    // buffered. A scan still running is dropped, the index on disk is left as the last one.
+    info!("Stopped");
     Ok(())
 }
@@ -0,0 +1,895 @@
+use std::{
+    fs::File,
+    io::{Read as _, Seek as _, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context as _;
+use axum::{
+    body::Body,
+    extract::{Path as UrlPath, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use rand::seq::SliceRandom as _;
+use serde::{Deserialize, Serialize};
+use tower::ServiceExt as _;
+use tower_http::services::ServeFile;
+
+use crate::{
+    api_error::ApiError,
+    args::ThumbnailFormat,
+    audit::{Action, Audit},
+    auth::{require_role, Principal},
+    edits::{self, Edits},
+    events,
+    i18n::{self, Messages},
+    jobs::JobLimiter,
+    jpg::{self, Embedded, Exif},
+    library::{content_type, read_embedded, read_exif, Kind, Library, Media},
+    permissions::PermissionStore,
+    pregenerate::Pregenerator,
+    rate_limit::{self, Budget, Charge, RateLimiter},
+    safe_path::{validate_relative, SafePath},
+    thumbnails::{self, Export, Thumbnails},
+    timeout::{self, Timeouts},
+    users::Role,
+};
+
+// Seconds clients are asked to wait while the library is unreachable, about how often it's checked
+const RETRY_UNREACHABLE: u64 = 30;
+
+#[derive(Clone)]
+pub struct MediaState {
+    pub library: Arc<Library>,
+    pub permissions: Arc<PermissionStore>,
+    pub thumbnails: Arc<Thumbnails>,
+    /// Bytes read at a time when streaming a file
+    pub file_buffer: usize,
+    pub jobs: Arc<JobLimiter>,
+    /// Told what's being browsed, None with --thumbnail-workers 0
+    pub pregenerator: Option<Arc<Pregenerator>>,
+}
+
+pub fn router(limiter: Arc<RateLimiter>, timeouts: Timeouts) -> Router<MediaState> {
+    // May wait their turn for a job slot, then decode a large photo
+    let expensive = middleware::from_fn_with_state(timeouts, timeout::expensive);
+    Router::new()
+        .route("/timeline", get(timeline))
+        .route("/timeline/months", get(months))
+        .route("/events", get(events))
+        .route("/slideshow", get(slideshow))
+        .route("/folders", get(root_folder))
+        .route("/folders/*path", get(folder))
+        .route("/media/file/*path", get(file))
+        .route("/media/info/*path", get(info))
+        .route("/media/embedded/*path", get(embedded))
+        .route("/media/similar/*path", get(similar))
+        .route(
+            "/media/thumb/*path",
+            get(thumbnail).route_layer(expensive.clone()),
+        )
+        .route(
+            "/media/export/*path",
+            get(export).route_layer(expensive.clone()),
+        )
+        .route_layer(middleware::from_fn_with_state(Role::Viewer, require_role))
+        // Changes what everyone sees, so not for viewers
+        .merge(
+            Router::new()
+                .route("/media/edits/*path", post(set_edits).delete(reset_edits))
+                .route(
+                    "/media/suggested-edits/*path",
+                    post(suggest_edits)
+                        .route_layer(expensive)
+                        .delete(dismiss_suggested_edits),
+                )
+                .route_layer(middleware::from_fn_with_state(Role::Uploader, require_role)),
+        )
+        // Handlers take from the expensive budget only when they decode something
+        .layer(middleware::from_fn_with_state(
+            (limiter, Budget::Expensive),
+            rate_limit::charge_later,
+        ))
+}
+
+impl MediaState {
+    pub fn visible(&self, principal: &Principal, media: &Media) -> bool {
+        self.permissions.can_access(principal, &media.path)
+    }
+
+    /// The whole timeline as the principal sees it, newest first
+    pub fn timeline(&self, principal: &Principal) -> Vec<Media> {
+        self.library
+            .list()
+            .into_iter()
+            .filter(|media| self.visible(principal, media))
+            .collect()
+    }
+
+    /// Subfolders and media directly inside a folder, None when the principal has nothing there
+    pub fn folder(&self, principal: &Principal, path: &Path) -> Option<(Vec<PathBuf>, Vec<Media>)> {
+        let (folders, items) = self
+            .library
+            .folder(path, |media| self.visible(principal, media));
+
+        // Folders are only known through the media in them, an empty listing means nothing to show
+        if folders.is_empty() && items.is_empty() && path != Path::new("") {
+            return None;
+        }
+        Some((folders, items))
+    }
+
+    // Thumbnails for a listing are about to be asked for
+    fn requested(&self, items: &[Media]) {
+        if let Some(pregenerator) = &self.pregenerator {
+            pregenerator.requested(items);
+        }
+    }
+
+    // Anything the principal can't see is reported as missing, so probing reveals nothing
+    fn find(&self, principal: &Principal, path: &str) -> Result<(Media, SafePath), ApiError> {
+        let media = self.lookup(principal, path)?;
+        let file = self.resolve(&media)?;
+        Ok((media, file))
+    }
+
+    // From the index alone, without touching the library
+    fn lookup(&self, principal: &Principal, path: &str) -> Result<Media, ApiError> {
+        let path = validate_relative(Path::new(path)).map_err(|_| StatusCode::NOT_FOUND)?;
+        self.library
+            .get(&path)
+            .filter(|media| self.visible(principal, media))
+            .ok_or(StatusCode::NOT_FOUND.into())
+    }
+
+    fn resolve(&self, media: &Media) -> Result<SafePath, ApiError> {
+        if !self.library.reachable() {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The library is not reachable right now",
+            )
+            .with_retry_after(RETRY_UNREACHABLE));
+        }
+        self.library
+            .root()
+            .resolve(&media.path)
+            .map_err(|_| StatusCode::NOT_FOUND.into())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Page {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    200
+}
+
+#[derive(Debug, Serialize)]
+struct TimelinePage {
+    total: usize,
+    items: Vec<Media>,
+    /// Widths to offer in a srcset, see thumbnail
+    thumbnail_sizes: Vec<u32>,
+}
+
+async fn timeline(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    Query(Page { offset, limit }): Query<Page>,
+) -> Json<TimelinePage> {
+    let visible = state.timeline(&principal);
+    let total = visible.len();
+    let items: Vec<Media> = visible
+        .into_iter()
+        .skip(offset)
+        .take(limit.min(1000))
+        .collect();
+    state.requested(&items);
+
+    Json(TimelinePage {
+        total,
+        items,
+        thumbnail_sizes: state.thumbnails.sizes().to_vec(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct Month {
+    year: i32,
+    month: u8,
+    // Position of the month's first item in the timeline, for paging straight to it
+    offset: usize,
+    count: usize,
+}
+
+async fn months(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+) -> Json<Vec<Month>> {
+    let mut months: Vec<Month> = Vec::new();
+
+    for (offset, media) in state.timeline(&principal).into_iter().enumerate() {
+        let (year, month) = (media.taken.year(), media.taken.month().into());
+        match months.last_mut() {
+            Some(last) if last.year == year && last.month == month => last.count += 1,
+            _ => months.push(Month {
+                year,
+                month,
+                offset,
+                count: 1,
+            }),
+        }
+    }
+
+    Json(months)
+}
+
+// Worked out afresh each time, it's one pass over the timeline like months. Titles are in the
+// language the client asks for.
+async fn events(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let messages = Messages::new(i18n::negotiate(&headers));
+    (
+        [(header::VARY, "accept-language")],
+        Json(events::cluster(&state.timeline(&principal), &messages)),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct SlideshowQuery {
+    /// Only photos in this folder and below
+    folder: Option<PathBuf>,
+}
+
+// Photos only, in random order, so a frame doesn't replay the same run every time it loads
+async fn slideshow(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    Query(SlideshowQuery { folder }): Query<SlideshowQuery>,
+) -> Result<Json<Vec<Media>>, ApiError> {
+    let folder = match folder {
+        Some(folder) => validate_relative(&folder).map_err(|_| StatusCode::NOT_FOUND)?,
+        None => PathBuf::new(),
+    };
+
+    let mut photos: Vec<Media> = state
+        .timeline(&principal)
+        .into_iter()
+        .filter(|media| media.kind == Kind::Image && media.path.starts_with(&folder))
+        .collect();
+    photos.shuffle(&mut rand::thread_rng());
+
+    Ok(Json(photos))
+}
+
+#[derive(Debug, Serialize)]
+struct FolderView {
+    path: PathBuf,
+    folders: Vec<PathBuf>,
+    items: Vec<Media>,
+    thumbnail_sizes: Vec<u32>,
+}
+
+async fn root_folder(
+    state: State<MediaState>,
+    principal: Extension<Principal>,
+) -> Result<Json<FolderView>, ApiError> {
+    folder(state, principal, UrlPath(String::new())).await
+}
+
+async fn folder(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    UrlPath(path): UrlPath<String>,
+) -> Result<Json<FolderView>, ApiError> {
+    let path = validate_relative(Path::new(&path)).map_err(|_| StatusCode::NOT_FOUND)?;
+    let (folders, items) = state
+        .folder(&principal, &path)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    state.requested(&items);
+
+    Ok(Json(FolderView {
+        path,
+        folders,
+        items,
+        thumbnail_sizes: state.thumbnails.sizes().to_vec(),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct FileQuery {
+    /// Leaves the video off a motion photo, for recipients who just want the still
+    #[serde(default)]
+    strip_motion: bool,
+}
+
+// Streamed a buffer at a time, however big the file, and range requests let videos start
+// playing and seek without downloading the whole file
+async fn file(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    UrlPath(path): UrlPath<String>,
+    Query(FileQuery { strip_motion }): Query<FileQuery>,
+    request: Request,
+) -> Result<Response, ApiError> {
+    let (media, file) = state.find(&principal, &path)?;
+
+    if strip_motion && content_type(&media.path) == Some("image/jpeg") {
+        let absolute = file.absolute().to_path_buf();
+        let still = tokio::task::spawn_blocking(move || {
+            anyhow::Ok(jpg::strip_motion(std::fs::read(absolute)?))
+        })
+        .await
+        .map_err(|e| ApiError::internal("Failed to read motion photo", e))?
+        .map_err(|e| ApiError::internal(&format!("Failed to read {:?}", media.path), e))?;
+        // Anything else is already just a still, and goes out as it is below
+        if let Some(still) = still {
+            return Ok((
+                [
+                    (header::CONTENT_TYPE, "image/jpeg"),
+                    (header::CACHE_CONTROL, "private, max-age=3600"),
+                ],
+                still,
+            )
+                .into_response());
+        }
+    }
+
+    let mut response = ServeFile::new(file.absolute())
+        .with_buf_chunk_size(state.file_buffer)
+        .oneshot(request)
+        .await
+        .map_err(|e| ApiError::internal(&format!("Failed to read {:?}", media.path), e))?
+        .map(Body::new);
+
+    let success = response.status().is_success();
+    let headers = response.headers_mut();
+    // Same types the scan went by, rather than ServeFile's own guess
+    if success {
+        let content_type = content_type(&media.path).unwrap_or("application/octet-stream");
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    }
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, max-age=3600"),
+    );
+    Ok(response)
+}
+
+#[derive(Debug, Serialize)]
+struct MediaInfo {
+    #[serde(flatten)]
+    media: Media,
+    exif: Option<Exif>,
+    // In the order /media/embedded numbers them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    embedded: Vec<Embedded>,
+}
+
+async fn info(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    UrlPath(path): UrlPath<String>,
+) -> Result<Json<MediaInfo>, ApiError> {
+    let (media, file) = state.find(&principal, &path)?;
+
+    let (exif, embedded) = match content_type(&media.path) {
+        Some("image/jpeg") => tokio::task::spawn_blocking(move || {
+            let exif = read_exif(file.absolute())
+                .inspect_err(|e| tracing::debug!("No EXIF in {path}: {e:#}"))
+                .ok()
+                .flatten();
+            let embedded = read_embedded(file.absolute())
+                .inspect_err(|e| tracing::debug!("Failed to list images in {path}: {e:#}"))
+                .unwrap_or_default();
+            (exif, embedded)
+        })
+        .await
+        .map_err(|e| ApiError::internal("Failed to read EXIF", e))?,
+        _ => (None, Vec::new()),
+    };
+
+    Ok(Json(MediaInfo {
+        media,
+        exif,
+        embedded,
+    }))
+}
+
+// Bits of 64 that may differ between perceptual hashes of photos that look alike
+const SIMILAR_DISTANCE: u32 = 10;
+
+#[derive(Debug, Serialize)]
+struct Similar {
+    #[serde(flatten)]
+    media: Media,
+    /// Bits of the perceptual hashes that differ, 0 for the same picture at another size or
+    /// quality
+    distance: u32,
+}
+
+/// Photos that look like this one, closest first: other shots of the same scene, and copies
+/// that were resized or converted
+async fn similar(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    UrlPath(path): UrlPath<String>,
+    Query(Page { offset, limit }): Query<Page>,
+) -> Result<Json<Vec<Similar>>, ApiError> {
+    let media = state.lookup(&principal, &path)?;
+    let perceptual_hash =
+        |media: &Media| u64::from_str_radix(media.perceptual_hash.as_deref()?, 16).ok();
+    let Some(hash) = perceptual_hash(&media) else {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "This photo hasn't been compared yet, or can't be decoded",
+        ));
+    };
+
+    let mut similar: Vec<Similar> = state
+        .timeline(&principal)
+        .into_iter()
+        .filter(|other| other.path != media.path)
+        .filter_map(|other| {
+            let distance = (perceptual_hash(&other)? ^ hash).count_ones();
+            (distance <= SIMILAR_DISTANCE).then_some(Similar {
+                media: other,
+                distance,
+            })
+        })
+        .collect();
+    // Stable, so equally close ones stay newest first
+    similar.sort_by_key(|similar| similar.distance);
+
+    Ok(Json(
+        similar
+            .into_iter()
+            .skip(offset)
+            .take(limit.min(1000))
+            .collect(),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddedQuery {
+    /// Position in the info's list
+    index: usize,
+}
+
+/// One of the images or videos stored in a photo's file after it, such as a depth map, as it is
+async fn embedded(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    UrlPath(path): UrlPath<String>,
+    Query(EmbeddedQuery { index }): Query<EmbeddedQuery>,
+) -> Result<Response, ApiError> {
+    let (media, file) = state.find(&principal, &path)?;
+    if content_type(&media.path) != Some("image/jpeg") {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    let data = tokio::task::spawn_blocking(move || {
+        let Some(item) = read_embedded(file.absolute())?.into_iter().nth(index) else {
+            return Ok(None);
+        };
+        let mut data = Vec::new();
+        let mut file = File::open(file.absolute())?;
+        file.seek(SeekFrom::Start(item.offset))?;
+        file.take(item.length).read_to_end(&mut data)?;
+        anyhow::Ok(Some((item.mime, data)))
+    })
+    .await
+    .map_err(|e| ApiError::internal("Failed to read embedded image", e))?
+    .map_err(|e| ApiError::internal("Failed to read embedded image", e))?;
+    let Some((mime, data)) = data else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    // Only types a browser won't run, whatever the file claims
+    let content_type = match mime.as_str() {
+        "image/jpeg" => "image/jpeg",
+        "image/png" => "image/png",
+        "video/mp4" => "video/mp4",
+        "video/quicktime" => "video/quicktime",
+        _ => "application/octet-stream",
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, "private, max-age=3600"),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+/// Stored with the photo and shown in its thumbnails, the file itself is left alone
+async fn set_edits(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    audit: Audit,
+    UrlPath(path): UrlPath<String>,
+    Json(edits): Json<Edits>,
+) -> Result<Json<Media>, ApiError> {
+    edits
+        .validate()
+        .map_err(|e| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    // Nothing to show for an edit that changes nothing, same as having none
+    let edits = Some(edits).filter(|edits| !edits.is_empty());
+    let media = update_edits(&state, &principal, &path, edits).await?;
+    audit.record(Action::MediaEdited, &path);
+    Ok(Json(media))
+}
+
+/// Back to the photo as it was taken
+async fn reset_edits(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    audit: Audit,
+    UrlPath(path): UrlPath<String>,
+) -> Result<Json<Media>, ApiError> {
+    let media = update_edits(&state, &principal, &path, None).await?;
+    audit.record(Action::MediaEditsReset, &path);
+    Ok(Json(media))
+}
+
+/// Looks for a turn that would straighten a crooked scan. Kept with the photo until someone
+/// confirms it by setting it as the photo's edits, or dismisses it. The photo comes back without
+/// one when it looks straight already.
+async fn suggest_edits(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    Extension(charge): Extension<Charge>,
+    UrlPath(path): UrlPath<String>,
+) -> Result<Json<Media>, ApiError> {
+    let (media, file) = editable(&state, &principal, &path)?;
+    charge.take()?;
+    let permit = state
+        .jobs
+        .acquire(thumbnails::decode_megabytes(&media, edits::SUGGEST_SIZE))
+        .await?;
+
+    let media_path = media.path.clone();
+    let span = tracing::Span::current();
+    let suggested = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let image = span.in_scope(|| thumbnails::original(&media, &file, edits::SUGGEST_SIZE))?;
+        anyhow::Ok(edits::suggest(&image, media.edits.as_ref()))
+    })
+    .await
+    .map_err(|e| ApiError::internal("Failed to suggest edits", e))?
+    .map_err(|e| {
+        tracing::debug!("No suggested edits for {path}: {e:#}");
+        ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "Can't convert this file")
+    })?;
+
+    let library = state.library.clone();
+    tokio::task::spawn_blocking(move || library.set_suggested_edits(&media_path, suggested))
+        .await
+        .map_err(|e| ApiError::internal("Failed to save suggested edits", e))?
+        .map(Json)
+        .map_err(|e| ApiError::internal("Failed to save suggested edits", e))
+}
+
+async fn dismiss_suggested_edits(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    UrlPath(path): UrlPath<String>,
+) -> Result<Json<Media>, ApiError> {
+    let (media, _) = editable(&state, &principal, &path)?;
+    let library = state.library.clone();
+    tokio::task::spawn_blocking(move || library.set_suggested_edits(&media.path, None))
+        .await
+        .map_err(|e| ApiError::internal("Failed to dismiss suggested edits", e))?
+        .map(Json)
+        .map_err(|e| ApiError::internal("Failed to dismiss suggested edits", e))
+}
+
+async fn update_edits(
+    state: &MediaState,
+    principal: &Principal,
+    path: &str,
+    edits: Option<Edits>,
+) -> Result<Media, ApiError> {
+    let (media, _) = editable(state, principal, path)?;
+    let library = state.library.clone();
+    tokio::task::spawn_blocking(move || library.set_edits(&media.path, edits))
+        .await
+        .map_err(|e| ApiError::internal("Failed to save edits", e))?
+        .map_err(|e| ApiError::internal("Failed to save edits", e))
+}
+
+// A photo someone may change the edits of right now
+fn editable(
+    state: &MediaState,
+    principal: &Principal,
+    path: &str,
+) -> Result<(Media, SafePath), ApiError> {
+    let (media, file) = state.find(principal, path)?;
+    if media.kind != Kind::Image {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Only photos can be edited",
+        ));
+    }
+    // The scan would write over them with the index it started from
+    if state.library.status().scanning {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "A scan is running, try again once it's done",
+        ));
+    }
+    Ok((media, file))
+}
+
+#[derive(Debug, Deserialize)]
+struct ThumbnailQuery {
+    /// Longest edge wanted in pixels, answered with the closest size made
+    size: Option<u32>,
+}
+
+async fn thumbnail(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    Extension(charge): Extension<Charge>,
+    UrlPath(path): UrlPath<String>,
+    Query(ThumbnailQuery { size }): Query<ThumbnailQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let media = state.lookup(&principal, &path)?;
+    let size = state.thumbnails.closest(size);
+
+    // Same contents, same thumbnail, so a browser holding it needn't have it sent again
+    // Compared as sent, so a tag that can't be a header never matches
+    let etag = state
+        .thumbnails
+        .etag(&media, size)
+        .and_then(|etag| HeaderValue::from_str(&etag).ok());
+    let mut cache_headers = HeaderMap::new();
+    cache_headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, max-age=86400"),
+    );
+    if let Some(etag) = &etag {
+        cache_headers.insert(header::ETAG, etag.clone());
+    }
+    if not_modified(&headers, etag.as_ref()) {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    // Cached ones are cheap and don't need the library, so they're still served while it's
+    // unreachable. Only generating a thumbnail takes a turn.
+    let (file, permit) = match state.thumbnails.is_cached(&media, size) {
+        true => (None, None),
+        false => {
+            let file = state.resolve(&media)?;
+            charge.take()?;
+            let megabytes = state.thumbnails.decode_megabytes(&media);
+            (Some(file), Some(state.jobs.acquire(megabytes).await?))
+        }
+    };
+
+    // Decoding is slow, keep it off the async workers. The span follows it, so the work shows
+    // up under the request.
+    let thumbnails = state.thumbnails.clone();
+    let span = tracing::Span::current();
+    let data = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        span.in_scope(|| match &file {
+            Some(file) => thumbnails.get(&media, file, size),
+            None => thumbnails
+                .cached(&media, size)
+                .context("Thumbnail was removed from the cache"),
+        })
+    })
+    .await
+    .map_err(|e| ApiError::internal("Failed to make thumbnail", e))?
+    .map_err(|e| {
+        tracing::debug!("No thumbnail for {path}: {e:#}");
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Can't make a thumbnail of this file",
+        )
+    })?;
+
+    let content_type = state.thumbnails.content_type(size);
+    Ok(([(header::CONTENT_TYPE, content_type)], cache_headers, data).into_response())
+}
+
+// If-None-Match has the ETag the client holds, or a list of them. A proxy that compressed the
+// response may have weakened it, which still counts.
+fn not_modified(headers: &HeaderMap, etag: Option<&HeaderValue>) -> bool {
+    let Some(etag) = etag else {
+        return false;
+    };
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value.split(',').map(str::trim).any(|tag| {
+                tag == "*" || tag.strip_prefix("W/").unwrap_or(tag).as_bytes() == etag.as_bytes()
+            })
+        })
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    /// Longest edge in pixels, the photo's own size when missing. Never made bigger.
+    max: Option<u32>,
+    #[serde(default = "default_export_format")]
+    format: ThumbnailFormat,
+    #[serde(default = "default_export_quality")]
+    quality: u8,
+    // Photos are shared on, and EXIF carries where they were taken
+    #[serde(default = "default_strip_exif")]
+    strip_exif: bool,
+}
+
+fn default_export_format() -> ThumbnailFormat {
+    ThumbnailFormat::Jpeg
+}
+
+fn default_export_quality() -> u8 {
+    85
+}
+
+fn default_strip_exif() -> bool {
+    true
+}
+
+/// A converted copy for sending on, such as an email sized JPEG. Made with the photo's edits,
+/// and kept so asking again is cheap.
+async fn export(
+    State(state): State<MediaState>,
+    Extension(principal): Extension<Principal>,
+    Extension(charge): Extension<Charge>,
+    UrlPath(path): UrlPath<String>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, ApiError> {
+    if query.max == Some(0) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "max must be above 0",
+        ));
+    }
+    if !(1..=100).contains(&query.quality) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "quality must be between 1 and 100",
+        ));
+    }
+    if query.format == ThumbnailFormat::Webp && !query.strip_exif {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "EXIF can only be kept in JPEG and AVIF exports",
+        ));
+    }
+    let export = Export {
+        max: query.max,
+        format: query.format,
+        quality: query.quality,
+        keep_exif: !query.strip_exif,
+    };
+
+    let media = state.lookup(&principal, &path)?;
+    if media.kind != Kind::Image {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Only photos can be exported",
+        ));
+    }
+    let data = match state.thumbnails.cached_export(&media, &export) {
+        Some(data) => data,
+        None => {
+            let file = state.resolve(&media)?;
+            charge.take()?;
+            let megabytes = state.thumbnails.export_megabytes(&media, &export);
+            let permit = state.jobs.acquire(megabytes).await?;
+
+            let thumbnails = state.thumbnails.clone();
+            let span = tracing::Span::current();
+            tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                span.in_scope(|| thumbnails.export(&media, &file, &export))
+            })
+            .await
+            .map_err(|e| ApiError::internal("Failed to export", e))?
+            .map_err(|e| {
+                tracing::debug!("No export of {path}: {e:#}");
+                ApiError::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "Can't convert this file",
+                )
+            })?
+        }
+    };
+
+    // Named after the original, with the new format's extension
+    let stem = Path::new(&path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default()
+        .replace(
+            |c: char| !c.is_ascii_graphic() || c == '"' || c == '\\',
+            "_",
+        );
+    let disposition = format!(r#"attachment; filename="{stem}.{}""#, export.extension());
+    let disposition = HeaderValue::from_str(&disposition)
+        .map_err(|e| ApiError::internal("Failed to name export", e))?;
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(export.content_type()),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+            (
+                header::CACHE_CONTROL,
+                HeaderValue::from_static("private, max-age=3600"),
+            ),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn if_none_match(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn not_modified_when_the_client_has_the_etag() {
+        let etag = HeaderValue::from_static(r#""abc-180-r2-q80.jpg""#);
+        let etag = Some(&etag);
+        assert!(not_modified(
+            &if_none_match(r#""abc-180-r2-q80.jpg""#),
+            etag
+        ));
+        assert!(not_modified(
+            &if_none_match(r#""other", "abc-180-r2-q80.jpg""#),
+            etag
+        ));
+        assert!(not_modified(
+            &if_none_match(r#"W/"abc-180-r2-q80.jpg""#),
+            etag
+        ));
+        assert!(not_modified(&if_none_match("*"), etag));
+    }
+
+    #[test]
+    fn modified_when_the_etag_differs_or_is_missing() {
+        let etag = HeaderValue::from_static(r#""abc-180-r2-q80.jpg""#);
+        let etag = Some(&etag);
+        assert!(!not_modified(&HeaderMap::new(), etag));
+        assert!(!not_modified(
+            &if_none_match(r#""abc-360-r2-q80.jpg""#),
+            etag
+        ));
+        assert!(!not_modified(&if_none_match("abc-180-r2-q80.jpg"), etag));
+        // Nothing to compare with before the photo is hashed
+        assert!(!not_modified(
+            &if_none_match(r#""abc-180-r2-q80.jpg""#),
+            None
+        ));
+        assert!(!not_modified(&if_none_match("*"), None));
+    }
+}
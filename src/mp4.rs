@@ -0,0 +1,224 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{ensure, Result};
+
+// Bigger than any real movie header, which indexes samples rather than holding them
+const MAX_MOOV_SIZE: u64 = 32 * 1024 * 1024;
+// Android's key for the rate a clip was recorded at, when it plays at another
+const CAPTURE_FPS: &[u8] = b"com.android.capture.fps";
+// Well-known type of a big-endian 32 bit float in a metadata item
+const FLOAT32: u32 = 23;
+
+/// Frame rates of an MP4 or QuickTime movie's first video track
+#[derive(Debug, Clone, Copy)]
+pub struct FrameRate {
+    /// As stored
+    pub playback: f64,
+    /// As filmed, when the camera said and it differs from playback
+    pub capture: Option<f64>,
+}
+
+/// Reads only the box headers and the movie header, wherever in the file it is
+pub fn frame_rate(file: &mut (impl Read + Seek)) -> Result<Option<FrameRate>> {
+    let length = file.seek(SeekFrom::End(0))?;
+    let mut position = 0;
+    while position < length {
+        file.seek(SeekFrom::Start(position))?;
+        let (kind, header, size) = read_header(file, length - position)?;
+        if &kind == b"moov" {
+            ensure!(size <= MAX_MOOV_SIZE, "Movie header too big");
+            let mut moov = vec![0; (size - header) as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(parse_moov(&moov));
+        }
+        position += size;
+    }
+    Ok(None)
+}
+
+// Type, header length and whole length of the box at the reader's position
+fn read_header(file: &mut impl Read, remaining: u64) -> Result<([u8; 4], u64, u64)> {
+    let mut header = [0; 8];
+    file.read_exact(&mut header)?;
+    let kind = header[4..].try_into().unwrap();
+    let (size, header) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+        // Runs to the end of the file
+        0 => (remaining, 8),
+        1 => {
+            let mut large = [0; 8];
+            file.read_exact(&mut large)?;
+            (u64::from_be_bytes(large), 16)
+        }
+        size => (size.into(), 8),
+    };
+    ensure!(size >= header && size <= remaining, "Bad box size");
+    Ok((kind, header, size))
+}
+
+// The boxes directly inside some box's contents, as type and contents
+fn boxes(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let (size, header) = match size {
+            0 => (rest.len(), 8),
+            1 => (
+                usize::try_from(u64::from_be_bytes(rest.get(8..16)?.try_into().ok()?)).ok()?,
+                16,
+            ),
+            size => (size, 8),
+        };
+        if size < header || size > rest.len() {
+            return None;
+        }
+        let (item, next) = rest.split_at(size);
+        rest = next;
+        Some((&item[4..8], &item[header..]))
+    })
+}
+
+fn child<'a>(data: &'a [u8], path: &[&[u8]]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, kind| {
+        boxes(data).find_map(|(k, contents)| (k == *kind).then_some(contents))
+    })
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset.checked_add(4)?)?.try_into().ok()?,
+    ))
+}
+
+fn parse_moov(moov: &[u8]) -> Option<FrameRate> {
+    let playback = boxes(moov)
+        .filter(|(kind, _)| *kind == b"trak")
+        .find_map(|(_, trak)| video_frame_rate(trak))?;
+    let capture = capture_fps(moov).filter(|&capture| (capture - playback).abs() >= 1.0);
+    Some(FrameRate { playback, capture })
+}
+
+// Frames per second from the sample table, which edits and odd durations don't throw off
+fn video_frame_rate(trak: &[u8]) -> Option<f64> {
+    let mdia = child(trak, &[b"mdia"])?;
+    // Version and flags, predefined, then the handler type
+    let handler = child(mdia, &[b"hdlr"])?.get(8..12)?;
+    if handler != b"vide" {
+        return None;
+    }
+    let mdhd = child(mdia, &[b"mdhd"])?;
+    let timescale = match mdhd.first()? {
+        1 => u32_at(mdhd, 20)?,
+        _ => u32_at(mdhd, 12)?,
+    };
+
+    let stts = child(mdia, &[b"minf", b"stbl", b"stts"])?;
+    let entries = u32_at(stts, 4)? as usize;
+    let (mut frames, mut duration) = (0u64, 0u64);
+    // A made up table can add up to more than fits, that's no frame rate
+    for i in 0..entries {
+        let offset = i.checked_mul(8)?.checked_add(8)?;
+        let count = u64::from(u32_at(stts, offset)?);
+        let delta = u64::from(u32_at(stts, offset.checked_add(4)?)?);
+        frames = frames.checked_add(count)?;
+        duration = duration.checked_add(count.checked_mul(delta)?)?;
+    }
+    (duration > 0 && timescale > 0).then(|| frames as f64 * f64::from(timescale) / duration as f64)
+}
+
+// From the movie's metadata: key names in keys, values in ilst under their 1-based index
+fn capture_fps(moov: &[u8]) -> Option<f64> {
+    let meta = child(moov, &[b"meta"])?;
+    // QuickTime's meta is a plain box, the MP4 one has a version and flags first
+    let meta = match child(meta, &[b"keys"]) {
+        Some(_) => meta,
+        None => meta.get(4..)?,
+    };
+    let keys = child(meta, &[b"keys"])?;
+    let count = u32_at(keys, 4)?;
+    let mut offset = 8;
+    let mut index = None;
+    for i in 1..=count {
+        let size = u32_at(keys, offset)? as usize;
+        // Size, namespace, then the name
+        let name = keys.get(offset + 8..offset + size)?;
+        if name == CAPTURE_FPS {
+            index = Some(i);
+            break;
+        }
+        offset += size.max(8);
+    }
+    let index = index?.to_be_bytes();
+
+    let item =
+        boxes(child(meta, &[b"ilst"])?).find_map(|(k, item)| (k == index).then_some(item))?;
+    let data = child(item, &[b"data"])?;
+    // Type, locale, then the value
+    if u32_at(data, 0)? != FLOAT32 {
+        return None;
+    }
+    let fps = f32::from_bits(u32_at(data, 8)?);
+    (fps.is_finite() && fps > 0.0).then_some(fps.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        let mut data = (8 + contents.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(contents);
+        data
+    }
+
+    // A video track with a version 0 mdhd and the given (count, delta) sample table
+    fn trak(timescale: u32, stts: &[(u32, u32)]) -> Vec<u8> {
+        let hdlr = [[0; 8].as_slice(), b"vide"].concat();
+        let mut mdhd = vec![0; 12];
+        mdhd.extend_from_slice(&timescale.to_be_bytes());
+        mdhd.extend_from_slice(&[0; 8]);
+        let mut table = vec![0; 4];
+        table.extend_from_slice(&(stts.len() as u32).to_be_bytes());
+        for (count, delta) in stts {
+            table.extend_from_slice(&count.to_be_bytes());
+            table.extend_from_slice(&delta.to_be_bytes());
+        }
+        let stbl = mp4_box(b"stbl", &mp4_box(b"stts", &table));
+        let mdia = [
+            mp4_box(b"hdlr", &hdlr),
+            mp4_box(b"mdhd", &mdhd),
+            mp4_box(b"minf", &stbl),
+        ]
+        .concat();
+        mp4_box(b"mdia", &mdia)
+    }
+
+    #[test]
+    fn frame_rate_from_the_sample_table() {
+        assert_eq!(
+            video_frame_rate(&trak(30_000, &[(300, 1001)])),
+            Some(30_000.0 / 1001.0)
+        );
+        assert_eq!(
+            video_frame_rate(&trak(600, &[(10, 20), (20, 5)])),
+            Some(60.0)
+        );
+        assert_eq!(video_frame_rate(&trak(600, &[])), None);
+        assert_eq!(video_frame_rate(&trak(0, &[(10, 20)])), None);
+    }
+
+    #[test]
+    fn frame_rate_overflow_is_no_frame_rate() {
+        let huge = (u32::MAX, u32::MAX);
+        assert_eq!(video_frame_rate(&trak(600, &[huge, huge])), None);
+    }
+
+    #[test]
+    fn frame_rate_stops_at_the_end_of_the_table() {
+        let mut trak = trak(600, &[(10, 20)]);
+        // Claims more entries than there are
+        let entries = trak.len() - 12;
+        trak[entries..entries + 4].copy_from_slice(&2u32.to_be_bytes());
+        assert_eq!(video_frame_rate(&trak), None);
+    }
+}
@@ -0,0 +1,319 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use time::format_description::well_known::Rfc3339;
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::TcpStream,
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{info, warn};
+
+use crate::{
+    args::MqttArgs,
+    library::{Kind, Library, LibraryEvent},
+    login::percent_encode,
+    webhooks,
+};
+
+// Seconds the broker waits without hearing from us before it publishes the will
+const KEEP_ALIVE: u16 = 60;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Doubles while the broker stays away
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+// Packet types in the first byte's high nibble
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PINGREQ: u8 = 0xc0;
+const RETAIN: u8 = 0x01;
+
+/// Publishes the library's state and events to an MQTT broker, for Home Assistant dashboards
+/// and wall panels, until the server stops.
+///
+/// Under the topic prefix, retained so anyone subscribing later gets them straight away:
+/// `status` is online or offline, `scan` the scan status, `stats` the number of files and how
+/// many the last scan added, and `last_photo` the newest photo with a link to its thumbnail.
+/// `event` gets the same events as webhooks, not retained. Only MQTT 3.1.1 over plain TCP,
+/// everything is sent at QoS 0 and reconnects publish the state again.
+///
+/// Links start with `links`, the public URL or else the base path. The thumbnail link is an
+/// API route, so whoever follows it needs the same credentials as any API client, such as a
+/// read-only API key sent as `Authorization: Bearer`.
+pub fn start(args: MqttArgs, library: Arc<Library>, links: String) {
+    let Some(broker) = args.broker.clone() else {
+        return;
+    };
+    // Subscribed now, so the first scan isn't missed while connecting
+    let events = library.events();
+    let mut publisher = Publisher {
+        args,
+        library,
+        links,
+        events,
+        added: 0,
+    };
+
+    tokio::spawn(async move {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            let Err(e) = publisher.session(&broker, &mut delay).await;
+            warn!(
+                "MQTT connection to {broker} failed, retrying in {}s: {e:#}",
+                delay.as_secs()
+            );
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    });
+}
+
+struct Publisher {
+    args: MqttArgs,
+    library: Arc<Library>,
+    links: String,
+    events: broadcast::Receiver<LibraryEvent>,
+    // By the last scan, for stats
+    added: usize,
+}
+
+impl Publisher {
+    // Runs until the connection fails
+    async fn session(&mut self, broker: &str, delay: &mut Duration) -> Result<Infallible> {
+        let mut stream = tokio::time::timeout(CONNECT_TIMEOUT, self.connect(broker))
+            .await
+            .context("No answer from the broker")??;
+        info!("Connected to MQTT broker {broker}");
+        *delay = RECONNECT_DELAY;
+
+        self.publish(&mut stream, "status", b"online", true).await?;
+        self.publish_state(&mut stream).await?;
+
+        let mut ping = tokio::time::interval(Duration::from_secs(KEEP_ALIVE.into()) / 2);
+        let mut buffer = [0; 256];
+        loop {
+            tokio::select! {
+                event = self.events.recv() => {
+                    let event = match event {
+                        Ok(event) => event,
+                        // The state sent next covers whatever was missed
+                        Err(RecvError::Lagged(_)) => {
+                            self.publish_state(&mut stream).await?;
+                            continue;
+                        }
+                        Err(RecvError::Closed) => std::future::pending().await,
+                    };
+                    if let LibraryEvent::Scanned { added, .. } = &event {
+                        self.added = added.len();
+                    }
+                    for (event, data) in webhooks::payloads(&event) {
+                        let payload = json!({ "event": event, "data": data }).to_string();
+                        self.publish(&mut stream, "event", payload.as_bytes(), false).await?;
+                    }
+                    self.publish_state(&mut stream).await?;
+                }
+                _ = ping.tick() => stream.write_all(&[PINGREQ, 0]).await?,
+                // Ping responses, nothing else comes back at QoS 0 without subscriptions
+                read = stream.read(&mut buffer) => {
+                    if read? == 0 {
+                        bail!("The broker closed the connection");
+                    }
+                }
+            }
+        }
+    }
+
+    async fn connect(&self, broker: &str) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(broker).await?;
+        stream
+            .write_all(&connect_packet(&self.args, &self.topic("status")))
+            .await?;
+
+        let mut ack = [0; 4];
+        stream.read_exact(&mut ack).await?;
+        if ack[0] != CONNACK || ack[1] != 2 {
+            bail!("Not an MQTT broker");
+        }
+        match ack[3] {
+            0 => Ok(stream),
+            1 => bail!("The broker doesn't speak MQTT 3.1.1"),
+            2 => bail!("The broker refused the client ID"),
+            4 | 5 => bail!("The broker refused the username or password"),
+            code => bail!("The broker refused the connection with code {code}"),
+        }
+    }
+
+    async fn publish_state(&self, stream: &mut TcpStream) -> Result<()> {
+        let media = self.library.list();
+        let scan = serde_json::to_vec(&self.library.status())?;
+        let stats = json!({
+            "count": media.len(),
+            "added": self.added,
+            "reachable": self.library.reachable(),
+        });
+        self.publish(stream, "scan", &scan, true).await?;
+        self.publish(stream, "stats", stats.to_string().as_bytes(), true)
+            .await?;
+
+        if let Some(newest) = media.iter().find(|media| media.kind == Kind::Image) {
+            let path = format!(
+                "/api/media/thumb/{}",
+                percent_encode(&newest.path.to_string_lossy())
+            );
+            let last_photo = json!({
+                "path": newest.path,
+                "taken": newest.taken.format(&Rfc3339)?,
+                "thumbnail": format!("{}{path}", self.links),
+            });
+            self.publish(
+                stream,
+                "last_photo",
+                last_photo.to_string().as_bytes(),
+                true,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn publish(
+        &self,
+        stream: &mut TcpStream,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<()> {
+        let mut body = Vec::new();
+        string(&mut body, self.topic(topic).as_bytes());
+        body.extend_from_slice(payload);
+        let kind = PUBLISH | if retain { RETAIN } else { 0 };
+        stream.write_all(&packet(kind, &body)).await?;
+        Ok(())
+    }
+
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{name}", self.args.topic.trim_end_matches('/'))
+    }
+}
+
+// Clean session, with a retained will saying offline. MQTT 3.1.1 only allows a password after a
+// username.
+fn connect_packet(args: &MqttArgs, will_topic: &str) -> Vec<u8> {
+    let mut flags = 0x02 | 0x04 | 0x20;
+    let mut body = Vec::new();
+    string(&mut body, b"MQTT");
+    body.push(4);
+    let flags_at = body.len();
+    body.push(0);
+    body.extend_from_slice(&KEEP_ALIVE.to_be_bytes());
+    string(&mut body, args.client.as_bytes());
+    string(&mut body, will_topic.as_bytes());
+    string(&mut body, b"offline");
+    if let Some(username) = &args.username {
+        flags |= 0x80;
+        string(&mut body, username.as_bytes());
+        if let Some(password) = &args.password {
+            flags |= 0x40;
+            string(&mut body, password.as_bytes());
+        }
+    }
+    body[flags_at] = flags;
+    packet(CONNECT, &body)
+}
+
+// Type and flags, the remaining length in 7 bit groups, then the rest
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        match length {
+            0 => {
+                packet.push(byte);
+                break;
+            }
+            _ => packet.push(byte | 0x80),
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+// Length prefixed, which caps strings at 64K
+fn string(out: &mut Vec<u8>, value: &[u8]) {
+    let value = &value[..value.len().min(u16::MAX.into())];
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(username: Option<&str>, password: Option<&str>) -> MqttArgs {
+        MqttArgs {
+            broker: Some("broker:1883".to_string()),
+            username: username.map(str::to_string),
+            password: password.map(str::to_string),
+            topic: "m3s".to_string(),
+            client: "m3s".to_string(),
+        }
+    }
+
+    #[test]
+    fn packet_encodes_the_remaining_length_in_7_bit_groups() {
+        let cases: [(usize, &[u8]); 6] = [
+            (0, &[0x00]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (321, &[0xc1, 0x02]),
+            (16_383, &[0xff, 0x7f]),
+            (16_384, &[0x80, 0x80, 0x01]),
+        ];
+        for (length, encoded) in cases {
+            let packet = packet(PUBLISH, &vec![0xaa; length]);
+            assert_eq!(packet[0], PUBLISH);
+            assert_eq!(&packet[1..=encoded.len()], encoded, "length {length}");
+            assert_eq!(packet.len(), 1 + encoded.len() + length);
+        }
+    }
+
+    #[test]
+    fn string_is_length_prefixed() {
+        let mut out = Vec::new();
+        string(&mut out, b"MQTT");
+        assert_eq!(out, b"\x00\x04MQTT");
+    }
+
+    #[test]
+    fn connect_has_a_will_and_credentials() {
+        let packet = connect_packet(&args(Some("alice"), Some("pw")), "m3s/status");
+        let mut expected = vec![CONNECT, 47];
+        expected.extend_from_slice(b"\x00\x04MQTT\x04");
+        // Username, password, retained will at QoS 0, clean session
+        expected.push(0x80 | 0x40 | 0x20 | 0x04 | 0x02);
+        expected.extend_from_slice(&[0, 60]);
+        expected.extend_from_slice(b"\x00\x03m3s");
+        expected.extend_from_slice(b"\x00\x0am3s/status\x00\x07offline");
+        expected.extend_from_slice(b"\x00\x05alice\x00\x02pw");
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn connect_sends_no_password_without_a_username() {
+        let anonymous = connect_packet(&args(None, None), "m3s/status");
+        assert_eq!(anonymous[9], 0x20 | 0x04 | 0x02);
+        assert_eq!(
+            connect_packet(&args(None, Some("pw")), "m3s/status"),
+            anonymous
+        );
+
+        let username_only = connect_packet(&args(Some("alice"), None), "m3s/status");
+        assert_eq!(username_only[9], 0x80 | 0x20 | 0x04 | 0x02);
+        assert!(username_only.ends_with(b"\x00\x05alice"));
+    }
+}
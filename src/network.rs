@@ -0,0 +1,243 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    http::{request::Parts, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use tracing::warn;
+
+use crate::args::NetworkArgs;
+
+/// Stands in for peers of a Unix socket, which have no address. Only something on this machine
+/// can connect, a proxy, so its X-Forwarded-For is always believed.
+pub const UNIX_PEER: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// The address of the client itself, looking through trusted proxies
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+#[derive(Debug)]
+pub struct NetworkPolicy {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+}
+
+fn contains(nets: &[IpNet], ip: IpAddr) -> bool {
+    // A v4 client reaching a dual-stack socket shows up as ::ffff:a.b.c.d
+    let ip = ip.to_canonical();
+    nets.iter().any(|net| net.contains(&ip))
+}
+
+impl NetworkPolicy {
+    /// `unix` when serving on a Unix socket, whose peers are all [`UNIX_PEER`]
+    pub fn new(
+        NetworkArgs {
+            allow,
+            deny,
+            mut trusted_proxies,
+        }: NetworkArgs,
+        unix: bool,
+    ) -> Self {
+        if unix {
+            trusted_proxies.push(UNIX_PEER.into());
+        }
+        Self {
+            allow,
+            deny,
+            trusted_proxies,
+        }
+    }
+
+    fn allows(&self, ip: IpAddr) -> bool {
+        !contains(&self.deny, ip) && (self.allow.is_empty() || contains(&self.allow, ip))
+    }
+
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        // Told apart from the same client over IPv4 otherwise, in logs and limits alike
+        let peer = peer.to_canonical();
+        if !contains(&self.trusted_proxies, peer) {
+            return peer;
+        }
+
+        // Each proxy appends the address it saw, so the rightmost untrusted hop is the client,
+        // anything left of it was written by the client and can't be believed
+        let mut client = peer;
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+
+        for hop in forwarded.into_iter().rev() {
+            let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            client = hop.to_canonical();
+            if !contains(&self.trusted_proxies, hop) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+pub async fn filter(
+    State(policy): State<Arc<NetworkPolicy>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let ip = policy.client_ip(address.ip(), request.headers());
+
+    if !policy.allows(ip) {
+        warn!(target: "m3s::security", %ip, "Refused connection from blocked address");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    request.extensions_mut().insert(ClientIp(ip));
+    next.run(request).await
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        match parts.extensions.get::<ClientIp>() {
+            Some(ip) => Ok(*ip),
+            None => {
+                tracing::error!("Client address requested on a route without the network filter");
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn trusting(trusted_proxies: &[&str]) -> NetworkPolicy {
+        NetworkPolicy {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            trusted_proxies: trusted_proxies
+                .iter()
+                .map(|net| net.parse().unwrap())
+                .collect(),
+        }
+    }
+
+    fn forwarded(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("x-forwarded-for", HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peers_cant_forward() {
+        let policy = trusting(&["10.0.0.0/8"]);
+        let spoofed = forwarded(&["1.1.1.1"]);
+
+        assert_eq!(
+            policy.client_ip(ip("203.0.113.9"), &spoofed),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            trusting(&[]).client_ip(ip("10.0.0.1"), &spoofed),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn trusted_proxies_are_looked_through_to_the_first_untrusted_hop() {
+        let policy = trusting(&["10.0.0.0/8", "192.168.1.1/32"]);
+        // The client made up the first, the proxies appended the rest
+        let headers = forwarded(&["1.1.1.1, 203.0.113.9", "192.168.1.1"]);
+
+        assert_eq!(
+            policy.client_ip(ip("10.0.0.1"), &headers),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn a_chain_of_only_trusted_proxies_gives_the_furthest() {
+        let policy = trusting(&["10.0.0.0/8"]);
+        let headers = forwarded(&["10.0.0.3, 10.0.0.2"]);
+
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.3"));
+    }
+
+    #[test]
+    fn a_garbage_hop_stops_the_walk() {
+        let policy = trusting(&["10.0.0.0/8"]);
+        let headers = forwarded(&["1.1.1.1, unknown, 10.0.0.2"]);
+
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.2"));
+        let headers = forwarded(&["1.1.1.1, not-an-ip"]);
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_count_as_ipv4() {
+        let policy = trusting(&["10.0.0.0/8"]);
+        let headers = forwarded(&["::ffff:203.0.113.9"]);
+
+        assert_eq!(
+            policy.client_ip(ip("::ffff:10.0.0.1"), &headers),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            policy.client_ip(ip("::ffff:203.0.113.9"), &forwarded(&["1.1.1.1"])),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn unix_socket_peers_are_trusted_proxies() {
+        let args = || NetworkArgs {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            trusted_proxies: Vec::new(),
+        };
+        let headers = forwarded(&["203.0.113.9"]);
+
+        let unix = NetworkPolicy::new(args(), true);
+        assert_eq!(unix.client_ip(UNIX_PEER, &headers), ip("203.0.113.9"));
+        let tcp = NetworkPolicy::new(args(), false);
+        assert_eq!(tcp.client_ip(UNIX_PEER, &headers), UNIX_PEER);
+    }
+
+    #[test]
+    fn allows_checks_deny_before_allow() {
+        let policy = NetworkPolicy {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.0.0.5/32".parse().unwrap()],
+            trusted_proxies: Vec::new(),
+        };
+
+        assert!(policy.allows(ip("10.0.0.1")));
+        assert!(policy.allows(ip("::ffff:10.0.0.1")));
+        assert!(!policy.allows(ip("10.0.0.5")));
+        assert!(!policy.allows(ip("203.0.113.9")));
+    }
+}
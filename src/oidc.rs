@@ -0,0 +1,425 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+    Extension, Router,
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar, SignedCookieJar,
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine as _};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{
+    args::OidcArgs,
+    audit::{Action, Audit},
+    auth::AuthState,
+    base_path::BasePath,
+    login::{percent_encode, safe_next, session_cookies},
+    sessions::Session,
+    users::{Role, UserUpdate},
+};
+
+// How long a user has to complete the round trip through the provider
+const PENDING_TIMEOUT: Duration = Duration::from_secs(600);
+// The state again, signed, so only the browser that started a login can finish it
+const STATE_COOKIE: &str = "m3s_oidc_state";
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug)]
+struct PendingLogin {
+    verifier: String,
+    nonce: String,
+    next: Option<String>,
+    started: Instant,
+}
+
+#[derive(Debug)]
+pub struct Oidc {
+    args: OidcArgs,
+    client_id: String,
+    redirect_url: String,
+    metadata: ProviderMetadata,
+    http: reqwest::Client,
+    jwks: RwLock<JwkSet>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+impl Oidc {
+    pub async fn discover(args: OidcArgs) -> Result<Option<Self>> {
+        let Some(issuer) = &args.issuer else {
+            return Ok(None);
+        };
+        let client_id = args
+            .client_id
+            .clone()
+            .context("--oidc-client-id is required with --oidc-issuer")?;
+        let redirect_url = args
+            .redirect_url
+            .clone()
+            .context("--oidc-redirect-url is required with --oidc-issuer")?;
+
+        let http = reqwest::Client::new();
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = http
+            .get(&discovery_url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .with_context(|| format!("Failed to fetch {discovery_url}"))?
+            .json()
+            .await
+            .context("Invalid OpenID provider metadata")?;
+
+        ensure!(
+            metadata.issuer.trim_end_matches('/') == issuer.trim_end_matches('/'),
+            "Provider reported issuer {} but {issuer} was configured",
+            metadata.issuer
+        );
+
+        let jwks = fetch_jwks(&http, &metadata.jwks_uri).await?;
+        info!("Single sign-on enabled via {}", metadata.issuer);
+
+        Ok(Some(Self {
+            args,
+            client_id,
+            redirect_url,
+            metadata,
+            http,
+            jwks: RwLock::new(jwks),
+            pending: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Returns the provider's login URL and the state to hold on to until the callback
+    fn begin(&self, next: Option<String>) -> (String, String) {
+        let state = random_token();
+        let verifier = random_token();
+        let nonce = random_token();
+        let challenge = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+        let url = format!(
+            "{}?response_type=code&client_id={}&redirect_uri={}&scope={}&state={state}&nonce={nonce}&code_challenge={challenge}&code_challenge_method=S256",
+            self.metadata.authorization_endpoint,
+            percent_encode(&self.client_id),
+            percent_encode(&self.redirect_url),
+            percent_encode(&self.args.scopes),
+        );
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.started.elapsed() < PENDING_TIMEOUT);
+        pending.insert(
+            state.clone(),
+            PendingLogin {
+                verifier,
+                nonce,
+                next,
+                started: Instant::now(),
+            },
+        );
+
+        (url, state)
+    }
+
+    /// Returns the verified ID token claims and where to send the user afterwards
+    async fn complete(&self, state: &str, code: &str) -> Result<(Value, Option<String>)> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|p| p.started.elapsed() < PENDING_TIMEOUT)
+            .context("Unknown or expired login attempt")?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_url),
+            ("client_id", &self.client_id),
+            ("code_verifier", &pending.verifier),
+        ];
+        if let Some(secret) = &self.args.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            id_token: String,
+        }
+
+        let TokenResponse { id_token } = self
+            .http
+            .post(&self.metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .context("Token exchange failed")?
+            .json()
+            .await
+            .context("Invalid token response")?;
+
+        let claims = self.verify_id_token(&id_token).await?;
+        ensure!(
+            claims.get("nonce").and_then(Value::as_str) == Some(pending.nonce.as_str()),
+            "ID token nonce mismatch"
+        );
+
+        Ok((claims, pending.next))
+    }
+
+    async fn verify_id_token(&self, id_token: &str) -> Result<Value> {
+        let header = jsonwebtoken::decode_header(id_token)?;
+        ensure!(
+            !matches!(
+                header.alg,
+                Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+            ),
+            "Symmetric ID token signatures are not supported"
+        );
+
+        let find_key = |jwks: &JwkSet| match &header.kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None => jwks.keys.first().cloned(),
+        };
+
+        let known = find_key(&self.jwks.read().unwrap());
+        let jwk = match known {
+            Some(jwk) => jwk,
+            // The provider may have rotated its keys since startup
+            None => {
+                let jwks = fetch_jwks(&self.http, &self.metadata.jwks_uri).await?;
+                let jwk = find_key(&jwks).context("No matching signing key")?;
+                *self.jwks.write().unwrap() = jwks;
+                jwk
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_issuer(&[&self.metadata.issuer]);
+
+        let token =
+            jsonwebtoken::decode::<Value>(id_token, &DecodingKey::from_jwk(&jwk)?, &validation)?;
+        Ok(token.claims)
+    }
+
+    fn username(&self, claims: &Value) -> Result<String> {
+        claims
+            .get(&self.args.username_claim)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("ID token has no {} claim", self.args.username_claim))
+    }
+
+    // Only the roles the provider has a group for are its to give or take away, None keeps the
+    // user's role as it is
+    fn role(&self, claims: &Value, current: Option<Role>) -> Option<Role> {
+        let groups = claims
+            .get(&self.args.groups_claim)
+            .and_then(Value::as_array);
+        let member = |group: &Option<String>| {
+            group.as_ref().is_some_and(|group| {
+                groups.is_some_and(|groups| groups.iter().any(|g| g.as_str() == Some(group)))
+            })
+        };
+
+        if member(&self.args.admin_group) {
+            return Some(Role::Admin);
+        }
+        if current == Some(Role::Admin) && self.args.admin_group.is_none() {
+            return None;
+        }
+        match &self.args.uploader_group {
+            Some(_) if member(&self.args.uploader_group) => Some(Role::Uploader),
+            Some(_) => Some(Role::Viewer),
+            // Left the admin group, and no group says what else to be
+            None if current == Some(Role::Admin) => Some(Role::Viewer),
+            None => None,
+        }
+    }
+}
+
+async fn fetch_jwks(http: &reqwest::Client, jwks_uri: &str) -> Result<JwkSet> {
+    http.get(jwks_uri)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .with_context(|| format!("Failed to fetch {jwks_uri}"))?
+        .json()
+        .await
+        .context("Invalid JWKS document")
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn router() -> Router<AuthState> {
+    Router::new()
+        .route("/oidc/login", get(login))
+        .route("/oidc/callback", get(callback))
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginQuery {
+    next: Option<String>,
+}
+
+async fn login(
+    State(state): State<AuthState>,
+    jar: SignedCookieJar,
+    Query(LoginQuery { next }): Query<LoginQuery>,
+) -> Response {
+    let Some(oidc) = &state.oidc else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let (url, login_state) = oidc.begin(next);
+    // Lax, as the provider sends the browser back with a top level GET from its own site
+    let cookie = Cookie::build((STATE_COOKIE, login_state))
+        .path("/")
+        .http_only(true)
+        .secure(state.secure_cookies)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(PENDING_TIMEOUT.as_secs() as i64))
+        .build();
+    (jar.add(cookie), Redirect::to(&url)).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct CallbackQuery {
+    state: String,
+    code: Option<String>,
+    error: Option<String>,
+}
+
+async fn callback(
+    State(state): State<AuthState>,
+    Extension(base): Extension<BasePath>,
+    audit: Audit,
+    jar: SignedCookieJar,
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> Response {
+    let Some(oidc) = &state.oidc else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // Whatever happens the attempt is over, so the state cookie goes either way
+    let started = jar
+        .get(STATE_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+    let jar = jar.remove(Cookie::build(STATE_COOKIE).path("/"));
+
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    match sign_in(&state, oidc, query, started, user_agent).await {
+        Ok((session, token, next)) => {
+            audit.record_as(Some(&session.username), Action::Login, Some(&session.id));
+            let (cookie, csrf_cookie) = session_cookies(&state, &session, token);
+            (
+                jar.add(cookie),
+                CookieJar::new().add(csrf_cookie),
+                Redirect::to(&safe_next(&base, next.as_deref())),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            warn!("Single sign-on failed: {e:#}");
+            audit.record_as(None, Action::LoginFailed, None);
+            (StatusCode::UNAUTHORIZED, jar, "Single sign-on failed").into_response()
+        }
+    }
+}
+
+async fn sign_in(
+    state: &AuthState,
+    oidc: &Oidc,
+    CallbackQuery {
+        state: csrf,
+        code,
+        error,
+    }: CallbackQuery,
+    started: Option<String>,
+    user_agent: Option<String>,
+) -> Result<(Session, String, Option<String>)> {
+    // Otherwise anyone could send a victim their own callback and sign them in as themselves
+    ensure!(
+        started.as_deref() == Some(csrf.as_str()),
+        "The login was not started in this browser"
+    );
+    if let Some(error) = error {
+        bail!("Provider returned {error}");
+    }
+    let code = code.context("Missing authorization code")?;
+
+    let (claims, next) = oidc.complete(&csrf, &code).await?;
+    let username = oidc.username(&claims)?;
+    let current = state.users.get(&username).map(|user| user.role);
+    let role = oidc.role(&claims, current);
+
+    let users = state.users.clone();
+    let create_users = oidc.args.create_users;
+    let local_user = username.clone();
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        match users.get(&local_user) {
+            Some(user) if role.is_some_and(|role| role != user.role) => {
+                users.update(
+                    &local_user,
+                    UserUpdate {
+                        role,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Some(_) => {}
+            None if create_users => {
+                info!("Creating user {local_user} from single sign-on");
+                // Nobody knows this password, the account can only sign in through the provider
+                users.create(
+                    local_user,
+                    &random_token(),
+                    role.unwrap_or(Role::Viewer),
+                    None,
+                    Vec::new(),
+                )?;
+            }
+            None => bail!("No local user {local_user}"),
+        }
+        Ok(())
+    })
+    .await??;
+
+    let (session, token) = state.sessions.create(&username, user_agent)?;
+    Ok((session, token, next))
+}
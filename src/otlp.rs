@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig as _};
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use tracing::Subscriber;
+use tracing_subscriber::{registry::LookupSpan, EnvFilter, Layer};
+
+use crate::args::OtlpArgs;
+
+/// Flushes spans still waiting to be sent when dropped, keep it until the end of main
+pub struct Otlp(TracerProvider);
+
+impl Drop for Otlp {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            eprintln!("Failed to send the last traces: {e}");
+        }
+    }
+}
+
+/// Exports spans to the collector, None without --otlp-endpoint.
+///
+/// Filtered by --log-level as it was at startup, a reload only changes what's logged.
+pub fn layer<S>(args: OtlpArgs, log_level: &str) -> Result<Option<(impl Layer<S>, Otlp)>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let Some(endpoint) = args.otlp_endpoint else {
+        return Ok(None);
+    };
+    // Given the collector like OTEL_EXPORTER_OTLP_ENDPOINT is, the traces path goes on the end
+    let endpoint = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&endpoint)
+        .build()
+        .with_context(|| format!("Failed to set up trace export to {endpoint}"))?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            args.otlp_service_name,
+        )]))
+        .build();
+
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("m3s"))
+        .with_filter(EnvFilter::new(log_level));
+    Ok(Some((layer, Otlp(provider))))
+}
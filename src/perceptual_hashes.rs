@@ -0,0 +1,85 @@
+use std::{collections::HashSet, path::PathBuf, sync::Arc};
+
+use time::OffsetDateTime;
+use tracing::{debug, warn};
+
+use crate::{
+    jobs::JobLimiter,
+    library::{Kind, Library, Media},
+    thumbnails::{self, PERCEPTUAL_HASH_SIZE},
+};
+
+// Each batch stored writes out the whole index
+const BATCH: usize = 100;
+
+/// Works out the perceptual hash of every photo that has none, for finding similar ones: after
+/// each scan, and for an index loaded from before there were any.
+///
+/// Not part of the scan, as anything but a JPEG is decoded in full for it. Work goes through the
+/// same [`JobLimiter`] as thumbnails, so it keeps to its share of the CPUs and memory, and runs
+/// until the server stops.
+pub fn start(library: Arc<Library>, jobs: Arc<JobLimiter>) {
+    tokio::spawn(async move {
+        let mut scans = library.subscribe();
+        // An index loaded at startup or a scan that finished before this started counts too
+        scans.mark_changed();
+        // Photos that couldn't be decoded, tried again once they change
+        let mut failed: HashSet<(PathBuf, u64, OffsetDateTime)> = HashSet::new();
+        while scans.changed().await.is_ok() {
+            let missing: Vec<Media> = library
+                .list()
+                .into_iter()
+                .filter(|media| media.kind == Kind::Image && media.perceptual_hash.is_none())
+                .filter(|media| !failed.contains(&(media.path.clone(), media.size, media.modified)))
+                .collect();
+
+            let mut hashes = Vec::new();
+            for media in missing {
+                // The next scan has a newer list to go by
+                if scans.has_changed().unwrap_or(true) || !library.reachable() {
+                    break;
+                }
+                // Gone since the scan
+                let Ok(file) = library.root().resolve(&media.path) else {
+                    continue;
+                };
+
+                let permit = jobs
+                    .acquire_background(thumbnails::decode_megabytes(&media, PERCEPTUAL_HASH_SIZE))
+                    .await;
+                let photo = media.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    let _permit = permit;
+                    thumbnails::perceptual_hash(&photo, &file)
+                })
+                .await;
+                match result {
+                    Ok(Ok(hash)) => hashes.push((media, hash)),
+                    Ok(Err(e)) => {
+                        debug!("No perceptual hash for {:?}: {e:#}", media.path);
+                        failed.insert((media.path, media.size, media.modified));
+                    }
+                    Err(e) => warn!("Failed to hash {:?}: {e}", media.path),
+                }
+                if hashes.len() >= BATCH {
+                    store(&library, std::mem::take(&mut hashes)).await;
+                }
+            }
+            store(&library, hashes).await;
+        }
+    });
+}
+
+async fn store(library: &Arc<Library>, hashes: Vec<(Media, u64)>) {
+    if hashes.is_empty() {
+        return;
+    }
+    let library = library.clone();
+    let result = tokio::task::spawn_blocking(move || library.set_perceptual_hashes(&hashes)).await;
+    match result {
+        Ok(Ok(())) => {}
+        // Worked out again after the scan that's in the way
+        Ok(Err(e)) => debug!("Perceptual hashes not stored: {e:#}"),
+        Err(e) => warn!("Failed to store perceptual hashes: {e}"),
+    }
+}
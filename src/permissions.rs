@@ -0,0 +1,309 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::StatusCode,
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get},
+    Json, Router,
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine as _};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    api_error::ApiError,
+    audit::{Action, Audit},
+    auth::{require_role, Principal},
+    safe_path::validate_relative,
+    store::{load_json, save_json},
+    users::Role,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "type", content = "name")]
+pub enum Subject {
+    User(String),
+    Group(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    pub id: String,
+    // Library relative folder, everything below it is covered by the grant as well
+    pub folder: PathBuf,
+    pub subject: Subject,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: OffsetDateTime,
+}
+
+// A folder without grants is visible to every user whose root contains it, granting access to
+// anyone turns it into a restricted folder that only the granted users and groups can see
+#[derive(Debug)]
+pub struct PermissionStore {
+    path: PathBuf,
+    grants: RwLock<Vec<Grant>>,
+}
+
+impl PermissionStore {
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join("grants.json");
+        let grants = load_json(&path)?;
+
+        Ok(Self {
+            path,
+            grants: RwLock::new(grants),
+        })
+    }
+
+    pub fn list(&self) -> Vec<Grant> {
+        self.grants.read().unwrap().clone()
+    }
+
+    pub fn grant(&self, folder: PathBuf, subject: Subject) -> Result<Grant> {
+        let folder = validate_relative(&folder)?;
+
+        let mut id = [0u8; 6];
+        rand::thread_rng().fill_bytes(&mut id);
+
+        let grant = Grant {
+            id: BASE64_URL_SAFE_NO_PAD.encode(id),
+            folder,
+            subject,
+            created: OffsetDateTime::now_utc(),
+        };
+
+        // Saved before it's swapped in, so a failed write changes nothing
+        let mut grants = self.grants.write().unwrap();
+        let mut updated = grants.clone();
+        updated.push(grant.clone());
+        save_json(&self.path, &updated)?;
+        *grants = updated;
+
+        Ok(grant)
+    }
+
+    pub fn revoke(&self, id: &str) -> Result<bool> {
+        let mut grants = self.grants.write().unwrap();
+        let mut updated = grants.clone();
+        updated.retain(|g| g.id != id);
+
+        if updated.len() == grants.len() {
+            return Ok(false);
+        }
+
+        save_json(&self.path, &updated)?;
+        *grants = updated;
+        Ok(true)
+    }
+
+    /// Whether the principal may see the library relative path, every listing, search and
+    /// media endpoint has to go through this before returning anything about a file
+    pub fn can_access(&self, principal: &Principal, path: &Path) -> bool {
+        if principal.role() == Role::Admin {
+            return true;
+        }
+
+        let user = match principal {
+            Principal::User(user) => Some(user),
+            _ => None,
+        };
+
+        if let Some(root) = user.and_then(|u| u.root.as_ref()) {
+            if !path.starts_with(root) {
+                return false;
+            }
+        }
+
+        let matches = |subject: &Subject| match (subject, user) {
+            (Subject::User(name), Some(user)) => *name == user.username,
+            (Subject::Group(group), Some(user)) => user.groups.contains(group),
+            (_, None) => false,
+        };
+
+        // Every restricted folder on the way down has to let the principal in
+        let grants = self.grants.read().unwrap();
+        let mut restricted: Vec<&Path> = grants
+            .iter()
+            .filter(|g| path.starts_with(&g.folder))
+            .map(|g| g.folder.as_path())
+            .collect();
+        restricted.sort();
+        restricted.dedup();
+
+        restricted.into_iter().all(|folder| {
+            grants
+                .iter()
+                .any(|g| g.folder == folder && matches(&g.subject))
+        })
+    }
+}
+
+pub fn router() -> Router<Arc<PermissionStore>> {
+    Router::new()
+        .route("/", get(list_grants).post(create_grant))
+        .route("/:id", delete(revoke_grant))
+        .route_layer(middleware::from_fn_with_state(Role::Admin, require_role))
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateGrant {
+    folder: PathBuf,
+    subject: Subject,
+}
+
+async fn list_grants(State(store): State<Arc<PermissionStore>>) -> Json<Vec<Grant>> {
+    Json(store.list())
+}
+
+async fn create_grant(
+    State(store): State<Arc<PermissionStore>>,
+    audit: Audit,
+    Json(CreateGrant { folder, subject }): Json<CreateGrant>,
+) -> Result<impl IntoResponse, ApiError> {
+    let grant = store
+        .grant(folder, subject)
+        .map_err(|e| ApiError::invalid_or_internal("Failed to create grant", e))?;
+    audit.record(Action::GrantCreated, &grant.id);
+    Ok((StatusCode::CREATED, Json(grant)))
+}
+
+async fn revoke_grant(
+    State(store): State<Arc<PermissionStore>>,
+    audit: Audit,
+    UrlPath(id): UrlPath<String>,
+) -> Result<StatusCode, ApiError> {
+    let revoked = store
+        .revoke(&id)
+        .map_err(|e| ApiError::internal("Failed to revoke grant", e))?;
+    if !revoked {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    audit.record(Action::GrantRevoked, &id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use super::*;
+
+    // A data directory of its own for each test, removed when it's done
+    struct TempStore(PathBuf, PermissionStore);
+
+    impl TempStore {
+        fn new(name: &str, grants: &[(&str, Subject)]) -> Self {
+            let dir = std::env::temp_dir().join(format!("m3s-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let store = PermissionStore::load(&dir).unwrap();
+            for (folder, subject) in grants {
+                store.grant(PathBuf::from(folder), subject.clone()).unwrap();
+            }
+            Self(dir, store)
+        }
+
+        fn can_access(&self, principal: &Principal, path: &str) -> bool {
+            self.1.can_access(principal, Path::new(path))
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn user(name: &str, role: &str, root: Option<&str>, groups: &[&str]) -> Principal {
+        Principal::User(
+            serde_json::from_value(json!({
+                "username": name,
+                "role": role,
+                "root": root,
+                "groups": groups,
+                "created": "2024-05-01T12:00:00Z",
+                "password_hash": "",
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn api_key(scope: &str) -> Principal {
+        Principal::ApiKey(
+            serde_json::from_value(json!({
+                "id": "key",
+                "name": "key",
+                "scope": scope,
+                "created": "2024-05-01T12:00:00Z",
+                "hash": "",
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn folders_without_grants_are_open_to_everyone() {
+        let store = TempStore::new("grants-open", &[]);
+
+        assert!(store.can_access(&user("alice", "viewer", None, &[]), "trips/a.jpg"));
+        assert!(store.can_access(&api_key("read-only"), "trips/a.jpg"));
+    }
+
+    #[test]
+    fn a_grant_restricts_its_folder_and_everything_below() {
+        let family = Subject::Group("family".to_string());
+        let store = TempStore::new("grants-restrict", &[("private", family)]);
+        let alice = user("alice", "viewer", None, &["family"]);
+        let bob = user("bob", "viewer", None, &[]);
+
+        assert!(store.can_access(&alice, "private/deep/a.jpg"));
+        assert!(!store.can_access(&bob, "private/deep/a.jpg"));
+        assert!(!store.can_access(&bob, "private"));
+        // Only whole path components count
+        assert!(store.can_access(&bob, "private-ish/a.jpg"));
+        assert!(!store.can_access(&api_key("read-only"), "private/a.jpg"));
+    }
+
+    #[test]
+    fn every_restricted_folder_on_the_way_down_has_to_let_in() {
+        let store = TempStore::new(
+            "grants-nested",
+            &[
+                ("private", Subject::Group("family".to_string())),
+                ("private/bob", Subject::User("bob".to_string())),
+            ],
+        );
+        let alice = user("alice", "viewer", None, &["family"]);
+        let bob = user("bob", "viewer", None, &[]);
+        let family_bob = user("bob", "viewer", None, &["family"]);
+
+        assert!(!store.can_access(&alice, "private/bob/a.jpg"));
+        assert!(!store.can_access(&bob, "private/bob/a.jpg"));
+        assert!(store.can_access(&family_bob, "private/bob/a.jpg"));
+        assert!(store.can_access(&alice, "private/a.jpg"));
+    }
+
+    #[test]
+    fn roots_confine_users_and_admins_see_everything() {
+        let store = TempStore::new(
+            "grants-roots",
+            &[("private", Subject::User("nobody".to_string()))],
+        );
+        let confined = user("alice", "viewer", Some("trips"), &[]);
+
+        assert!(store.can_access(&confined, "trips/a.jpg"));
+        assert!(!store.can_access(&confined, "other/a.jpg"));
+        assert!(store.can_access(&user("root", "admin", Some("trips"), &[]), "private/a.jpg"));
+        assert!(store.can_access(&api_key("full"), "private/a.jpg"));
+        assert!(store.can_access(&Principal::Anonymous, "private/a.jpg"));
+    }
+}
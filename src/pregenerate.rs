@@ -0,0 +1,213 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::{
+    jobs::JobLimiter,
+    library::{Kind, Library, Media},
+    thumbnails::Thumbnails,
+};
+
+// Skipped entries may pile up to this many times the live ones before they are cleared out
+const STALE_FACTOR: usize = 4;
+
+/// Makes thumbnails in the background, so opening the gallery after an import isn't a wall of
+/// blank tiles.
+///
+/// Photos in a listing someone just asked for go first, most recent listing first, then
+/// everything else from the last scan, newest taken first. Work goes through the same
+/// [`JobLimiter`] as requests, so it never takes more than its share of the CPUs.
+pub struct Pregenerator {
+    library: Arc<Library>,
+    thumbnails: Arc<Thumbnails>,
+    jobs: Arc<JobLimiter>,
+    queue: Mutex<Queue>,
+    wake: Notify,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Priority {
+    Scanned,
+    Requested,
+}
+
+// Highest first: priority, then the time taken or the order requested in
+type Key = (Priority, i128);
+
+struct Entry {
+    key: Key,
+    media: Media,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    heap: BinaryHeap<Entry>,
+    // The current key of everything queued. Raising a photo's priority pushes it again, and
+    // the heap entry whose key no longer matches is skipped when it comes up.
+    queued: HashMap<PathBuf, Key>,
+    requests: i128,
+}
+
+impl Queue {
+    fn push(&mut self, media: &Media, key: Key) {
+        if self
+            .queued
+            .get(&media.path)
+            .is_some_and(|&queued| queued >= key)
+        {
+            return;
+        }
+        self.queued.insert(media.path.clone(), key);
+        self.heap.push(Entry {
+            key,
+            media: media.clone(),
+        });
+
+        if self.heap.len() > STALE_FACTOR * self.queued.len() + 1024 {
+            let queued = &self.queued;
+            self.heap
+                .retain(|entry| queued.get(&entry.media.path) == Some(&entry.key));
+        }
+    }
+
+    fn pop(&mut self) -> Option<Media> {
+        while let Some(entry) = self.heap.pop() {
+            if self.queued.get(&entry.media.path) == Some(&entry.key) {
+                self.queued.remove(&entry.media.path);
+                return Some(entry.media);
+            }
+        }
+        None
+    }
+}
+
+impl Pregenerator {
+    /// Starts `workers` tasks, which run until the server stops
+    pub fn start(
+        library: Arc<Library>,
+        thumbnails: Arc<Thumbnails>,
+        jobs: Arc<JobLimiter>,
+        workers: usize,
+    ) -> Arc<Self> {
+        let pregenerator = Arc::new(Self {
+            library,
+            thumbnails,
+            jobs,
+            queue: Mutex::new(Queue::default()),
+            wake: Notify::new(),
+        });
+
+        tokio::spawn(pregenerator.clone().follow_scans());
+        for _ in 0..workers {
+            tokio::spawn(pregenerator.clone().work());
+        }
+        pregenerator
+    }
+
+    /// Moves the photos in a listing to the front, its first item first
+    pub fn requested(&self, items: &[Media]) {
+        let mut queue = self.queue.lock().unwrap();
+        for media in items.iter().rev().filter(|m| m.kind == Kind::Image) {
+            queue.requests += 1;
+            let key = (Priority::Requested, queue.requests);
+            queue.push(media, key);
+        }
+        drop(queue);
+        self.wake.notify_waiters();
+    }
+
+    // Anything already made is skipped when it comes up, rather than checked for here
+    async fn follow_scans(self: Arc<Self>) {
+        let mut scans = self.library.subscribe();
+        // An index loaded at startup or a scan that finished before this started counts too
+        scans.mark_changed();
+        while scans.changed().await.is_ok() {
+            let mut queue = self.queue.lock().unwrap();
+            for media in self.library.list() {
+                if media.kind == Kind::Image {
+                    let key = (Priority::Scanned, media.taken.unix_timestamp_nanos());
+                    queue.push(&media, key);
+                }
+            }
+            drop(queue);
+            self.wake.notify_waiters();
+        }
+    }
+
+    async fn work(self: Arc<Self>) {
+        loop {
+            // Created before looking, so a push in between still wakes this
+            let notified = self.wake.notified();
+            let next = self.queue.lock().unwrap().pop();
+            match next {
+                Some(media) => self.generate(media).await,
+                None => notified.await,
+            }
+        }
+    }
+
+    async fn generate(&self, media: Media) {
+        let thumbnails = &self.thumbnails;
+        // Any missing size makes all the missing ones
+        let Some(size) = thumbnails
+            .sizes()
+            .iter()
+            .copied()
+            .find(|&size| !thumbnails.is_cached(&media, size))
+        else {
+            return;
+        };
+        // Queued again by the next scan. Made now, they wouldn't be saved while space is low.
+        if !self.library.reachable() || thumbnails.low_on_space() {
+            return;
+        }
+        // Gone since it was queued
+        let Ok(file) = self.library.root().resolve(&media.path) else {
+            return;
+        };
+
+        let permit = self
+            .jobs
+            .acquire_background(thumbnails.decode_megabytes(&media))
+            .await;
+        let thumbnails = thumbnails.clone();
+        let path = media.path.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            thumbnails.get(&media, &file, size)
+        })
+        .await;
+        match result {
+            Ok(Ok(_)) => {}
+            // Same as when a client asks, some files just can't be decoded
+            Ok(Err(e)) => debug!("No thumbnail for {path:?}: {e:#}"),
+            Err(e) => warn!("Failed to make thumbnail for {path:?}: {e}"),
+        }
+    }
+}
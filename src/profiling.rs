@@ -0,0 +1,112 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use pprof::{protos::Message as _, ProfilerGuardBuilder};
+use serde::Deserialize;
+
+use crate::api_error::ApiError;
+
+// Samples per second, off the beat of anything running at a round rate
+const FREQUENCY: i32 = 99;
+const MAX_SECONDS: u64 = 300;
+
+// The profiler takes over a process wide signal, so one profile at a time
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    /// An SVG to open in a browser
+    #[default]
+    Flamegraph,
+    /// Protobuf for `go tool pprof` and compatible viewers
+    Pprof,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileQuery {
+    #[serde(default = "default_seconds")]
+    seconds: u64,
+    #[serde(default)]
+    format: Format,
+}
+
+fn default_seconds() -> u64 {
+    10
+}
+
+/// Samples the whole server's CPU use for a while, answering when done
+pub async fn profile(
+    Query(ProfileQuery { seconds, format }): Query<ProfileQuery>,
+) -> Result<Response, ApiError> {
+    if !(1..=MAX_SECONDS).contains(&seconds) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("seconds must be between 1 and {MAX_SECONDS}"),
+        ));
+    }
+    if RUNNING.swap(true, Ordering::AcqRel) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "A profile is already being taken",
+        ));
+    }
+    let running = Running;
+
+    // Sampling and symbolizing both block. Held in there, the flag outlives a client that
+    // gives up.
+    tokio::task::spawn_blocking(move || {
+        let _running = running;
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(|e| ApiError::internal("Failed to start profiler", e))?;
+        std::thread::sleep(Duration::from_secs(seconds));
+        let report = guard
+            .report()
+            .build()
+            .map_err(|e| ApiError::internal("Failed to build profile", e))?;
+        // An idle server takes no samples, and an empty flamegraph is an empty file
+        if report.data.is_empty() {
+            return Ok(StatusCode::NO_CONTENT.into_response());
+        }
+
+        Ok(match format {
+            Format::Flamegraph => {
+                let mut svg = Vec::new();
+                report
+                    .flamegraph(&mut svg)
+                    .map_err(|e| ApiError::internal("Failed to draw flamegraph", e))?;
+                ([(header::CONTENT_TYPE, "image/svg+xml")], svg).into_response()
+            }
+            Format::Pprof => {
+                let profile = report
+                    .pprof()
+                    .map_err(|e| ApiError::internal("Failed to encode profile", e))?;
+                (
+                    [(header::CONTENT_TYPE, "application/octet-stream")],
+                    profile.encode_to_vec(),
+                )
+                    .into_response()
+            }
+        })
+    })
+    .await
+    .map_err(|e| ApiError::internal("Profiler failed", e))?
+}
+
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{api_error::ApiError, network::ClientIp, sessions::COOKIE_NAME};
+
+// Past this many tracked clients, idle buckets are dropped
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Budget {
+    // Cheap JSON endpoints
+    Api,
+    // Endpoints that decode, resize or transcode media
+    Expensive,
+    Login,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    Token([u8; 32]),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    api: u32,
+    expensive: u32,
+    login: u32,
+    buckets: Mutex<HashMap<(Budget, Client), Bucket>>,
+}
+
+impl RateLimiter {
+    /// Limits are requests per minute, 0 disables that budget
+    pub fn new(api: u32, expensive: u32, login: u32) -> Self {
+        Self {
+            api,
+            expensive,
+            login,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn per_minute(&self, budget: Budget) -> u32 {
+        match budget {
+            Budget::Api => self.api,
+            Budget::Expensive => self.expensive,
+            Budget::Login => self.login,
+        }
+    }
+
+    /// Takes a token from each client's bucket, or returns how many seconds to wait
+    fn check(&self, budget: Budget, clients: &[Client]) -> Result<(), u64> {
+        let per_minute = self.per_minute(budget);
+        if per_minute == 0 {
+            return Ok(());
+        }
+
+        let capacity = per_minute as f64;
+        let refill_per_second = capacity / 60.0;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|(budget, _), bucket| {
+                let capacity = self.per_minute(*budget) as f64;
+                let refilled = bucket.tokens
+                    + now.duration_since(bucket.updated).as_secs_f64() * capacity / 60.0;
+                refilled < capacity
+            });
+        }
+
+        let mut wait = 0.0f64;
+        for client in clients {
+            let bucket = buckets.entry((budget, client.clone())).or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+            });
+
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * refill_per_second).min(capacity);
+            bucket.updated = now;
+
+            if bucket.tokens < 1.0 {
+                wait = wait.max((1.0 - bucket.tokens) / refill_per_second);
+            }
+        }
+
+        if wait > 0.0 {
+            return Err(wait.ceil() as u64);
+        }
+
+        for client in clients {
+            if let Some(bucket) = buckets.get_mut(&(budget, client.clone())) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Credentials are keyed by hash, the limiter has no business holding on to secrets
+fn credential(request: &Request) -> Option<[u8; 32]> {
+    let headers = request.headers();
+
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .map(|value| value.as_bytes().to_vec());
+    let session = || {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|cookies| cookies.split(';'))
+            .find_map(|cookie| cookie.trim().strip_prefix(&format!("{COOKIE_NAME}=")))
+            .map(|value| value.as_bytes().to_vec())
+    };
+
+    authorization
+        .or_else(session)
+        .map(|credential| Sha256::digest(credential).into())
+}
+
+// Checking the address as well stops clients dodging the limit by inventing credentials
+fn clients(budget: Budget, ip: IpAddr, request: &Request) -> Vec<Client> {
+    let mut clients = vec![Client::Ip(ip)];
+    if budget != Budget::Login {
+        clients.extend(credential(request).map(Client::Token));
+    }
+    clients
+}
+
+pub async fn rate_limit(
+    State((limiter, budget)): State<(Arc<RateLimiter>, Budget)>,
+    ClientIp(ip): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    let clients = clients(budget, ip, &request);
+
+    if let Err(retry_after) = limiter.check(budget, &clients) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+/// A budget the handler charges itself, once it knows the request is going to be expensive
+#[derive(Debug, Clone)]
+pub struct Charge {
+    limiter: Arc<RateLimiter>,
+    budget: Budget,
+    clients: Vec<Client>,
+}
+
+impl Charge {
+    pub fn take(&self) -> Result<(), ApiError> {
+        self.limiter
+            .check(self.budget, &self.clients)
+            .map_err(|retry_after| {
+                ApiError::from(StatusCode::TOO_MANY_REQUESTS).with_retry_after(retry_after)
+            })
+    }
+}
+
+/// Like rate_limit, but leaves a Charge for the handler instead of charging up front. Serving a
+/// cached thumbnail only costs the API budget, making one costs this as well.
+pub async fn charge_later(
+    State((limiter, budget)): State<(Arc<RateLimiter>, Budget)>,
+    ClientIp(ip): ClientIp,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let clients = clients(budget, ip, &request);
+    request.extensions_mut().insert(Charge {
+        limiter,
+        budget,
+        clients,
+    });
+    next.run(request).await
+}
@@ -0,0 +1,112 @@
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write as _},
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use time::OffsetDateTime;
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+// Enough to see what went wrong recently without growing forever
+const CAPACITY: usize = 200;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    /// Of the request being handled, for matching up with what a user reports
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Keeps the latest warnings and errors in memory for the admin dashboard
+#[derive(Debug, Clone, Default)]
+pub struct RecentErrors(Arc<Mutex<VecDeque<LogEntry>>>);
+
+impl RecentErrors {
+    /// Newest first
+    pub fn list(&self) -> Vec<LogEntry> {
+        self.0.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        match field.name() {
+            "message" => write!(self.0, "{value:?}"),
+            name => write!(self.0, "{name}={value:?}"),
+        }
+        .unwrap();
+    }
+}
+
+// Kept with the span it came from, the formatted fields aren't available to other layers
+struct RequestId(String);
+
+impl Visit for RequestId {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "request_id" {
+            self.0 = value.to_string();
+        }
+    }
+
+    fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) {}
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for RecentErrors {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().fields().field("request_id").is_none() {
+            return;
+        }
+        let mut request_id = RequestId(String::new());
+        attrs.record(&mut request_id);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(request_id);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > Level::WARN {
+            return;
+        }
+
+        let mut message = Message::default();
+        event.record(&mut message);
+
+        let request_id = ctx.event_scope(event).and_then(|scope| {
+            scope.from_root().find_map(|span| {
+                let extensions = span.extensions();
+                let RequestId(id) = extensions.get::<RequestId>()?;
+                Some(id.clone())
+            })
+        });
+
+        let mut entries = self.0.lock().unwrap();
+        if entries.len() == CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(LogEntry {
+            time: OffsetDateTime::now_utc(),
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: message.0,
+            request_id: request_id.filter(|id| !id.is_empty()),
+        });
+    }
+}
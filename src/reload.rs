@@ -0,0 +1,73 @@
+use anyhow::Result;
+use tracing::info;
+use tracing_subscriber::{reload::Handle, EnvFilter, Registry};
+
+use crate::config;
+
+/// Applies the settings that can change without a restart, on SIGHUP or from the admin API,
+/// which is only the log level. Everything else in the config file is only read at startup.
+///
+/// That includes thumbnail sizes, format, quality and effort. Cached thumbnails are named after
+/// them and pregeneration works through them, so changing them takes a restart, after which
+/// thumbnails made under the old ones are left for `cache prune`.
+#[derive(Debug)]
+pub struct Reloader {
+    log_level: Handle<EnvFilter, Registry>,
+}
+
+impl Reloader {
+    pub fn new(log_level: Handle<EnvFilter, Registry>) -> Self {
+        Self { log_level }
+    }
+
+    /// Re-reads the command line and config file, returning what changed
+    pub fn reload(&self) -> Result<Vec<String>> {
+        let args = config::reload()?;
+        let mut changed = Vec::new();
+
+        // Filters can't be compared, but their directives can
+        let log_level = EnvFilter::new(&args.log_level);
+        let current = self.log_level.with_current(|filter| filter.to_string())?;
+        if current != log_level.to_string() {
+            changed.push(format!("log level is now {log_level}"));
+            self.log_level.reload(log_level)?;
+        }
+
+        match changed.is_empty() {
+            true => info!("Configuration reloaded, nothing changed"),
+            false => info!("Configuration reloaded: {}", changed.join(", ")),
+        }
+        Ok(changed)
+    }
+
+    /// The filter directives logging goes through right now
+    pub fn log_level(&self) -> Result<String> {
+        Ok(self.log_level.with_current(|filter| filter.to_string())?)
+    }
+
+    /// Swaps the filter for these directives, until the next reload or restart puts back what
+    /// the config file says
+    pub fn set_log_level(&self, directives: &str) -> Result<String> {
+        let filter = EnvFilter::try_new(directives)?;
+        let log_level = filter.to_string();
+        self.log_level.reload(filter)?;
+        info!("Log level is now {log_level}");
+        Ok(log_level)
+    }
+}
+
+/// Reloads whenever the process gets SIGHUP, for `systemctl reload` and friends
+#[cfg(unix)]
+pub fn on_hangup(reloader: std::sync::Arc<Reloader>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if let Err(e) = reloader.reload() {
+                tracing::error!("Failed to reload configuration: {e:#}");
+            }
+        }
+    });
+    Ok(())
+}
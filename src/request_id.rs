@@ -0,0 +1,9 @@
+use axum::http::Extensions;
+use tower_http::request_id::RequestId;
+
+/// The ID SetRequestIdLayer gave the request, from X-Request-Id or freshly generated
+pub fn get(extensions: &Extensions) -> Option<&str> {
+    extensions
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+}
@@ -0,0 +1,229 @@
+use std::{
+    fmt, io,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+#[derive(Debug)]
+pub enum PathError {
+    // Absolute, contains .. or is otherwise not a plain relative path
+    Invalid(PathBuf),
+    NotFound(PathBuf),
+    // Escapes the library, for example through a symlink, or points into server state
+    OutsideRoot(PathBuf),
+    Io(io::Error),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathError::Invalid(path) => {
+                write!(
+                    f,
+                    "Expected a relative path inside the library, got {path:?}"
+                )
+            }
+            PathError::NotFound(path) => write!(f, "{path:?} does not exist"),
+            PathError::OutsideRoot(path) => write!(f, "{path:?} is outside the library"),
+            PathError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+/// Lexically checks a library relative path, without touching the filesystem
+pub fn validate_relative(path: &Path) -> Result<PathBuf, PathError> {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(PathError::Invalid(path.to_path_buf()))
+            }
+        }
+    }
+
+    Ok(normalized)
+}
+
+#[derive(Debug, Clone)]
+pub struct LibraryRoot {
+    root: PathBuf,
+    // Server state that may live inside the library but must never be served from it
+    excluded: Vec<PathBuf>,
+}
+
+/// A path that has been checked to exist inside the library root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafePath {
+    relative: PathBuf,
+    absolute: PathBuf,
+}
+
+impl LibraryRoot {
+    pub fn new(root: &Path, excluded: &[&Path]) -> Result<Self> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("Library directory {root:?} is not accessible"))?;
+        let excluded = excluded
+            .iter()
+            .filter_map(|path| path.canonicalize().ok())
+            .collect();
+
+        Ok(Self { root, excluded })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether an absolute path is server state that must not be served
+    pub fn excludes(&self, absolute: &Path) -> bool {
+        self.excluded.iter().any(|e| absolute.starts_with(e))
+    }
+
+    pub fn resolve(&self, relative: impl AsRef<Path>) -> Result<SafePath, PathError> {
+        let relative = validate_relative(relative.as_ref())?;
+
+        // Canonicalizing follows every symlink, so whatever is left has to still be in the root
+        let absolute = match self.root.join(&relative).canonicalize() {
+            Ok(absolute) => absolute,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(PathError::NotFound(relative))
+            }
+            Err(e) => return Err(PathError::Io(e)),
+        };
+
+        if !absolute.starts_with(&self.root) || self.excludes(&absolute) {
+            return Err(PathError::OutsideRoot(relative));
+        }
+
+        Ok(SafePath { relative, absolute })
+    }
+}
+
+impl SafePath {
+    pub fn relative(&self) -> &Path {
+        &self.relative
+    }
+
+    pub fn absolute(&self) -> &Path {
+        &self.absolute
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    // A library of its own for each test, removed when it's done
+    struct TempLibrary(PathBuf);
+
+    impl TempLibrary {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("m3s-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("library/photos")).unwrap();
+            fs::write(dir.join("library/photos/a.jpg"), b"a").unwrap();
+            fs::write(dir.join("secret.txt"), b"secret").unwrap();
+            Self(dir)
+        }
+
+        fn library(&self) -> PathBuf {
+            self.0.join("library")
+        }
+    }
+
+    impl Drop for TempLibrary {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn validate_relative_normalizes_plain_paths() {
+        let valid = |path: &str| validate_relative(Path::new(path)).unwrap();
+        assert_eq!(valid("photos/a.jpg"), Path::new("photos/a.jpg"));
+        assert_eq!(valid("./photos/./a.jpg"), Path::new("photos/a.jpg"));
+        assert_eq!(valid("photos//a.jpg"), Path::new("photos/a.jpg"));
+        assert_eq!(valid(""), Path::new(""));
+    }
+
+    #[test]
+    fn validate_relative_rejects_traversal() {
+        for path in [
+            "..",
+            "../secret.txt",
+            "photos/../../secret.txt",
+            "photos/..",
+            "/etc/passwd",
+            "/",
+        ] {
+            assert!(
+                matches!(
+                    validate_relative(Path::new(path)),
+                    Err(PathError::Invalid(_))
+                ),
+                "{path} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_finds_files_in_the_library() {
+        let temp = TempLibrary::new("resolve");
+        let root = LibraryRoot::new(&temp.library(), &[]).unwrap();
+
+        let file = root.resolve("photos/a.jpg").unwrap();
+        assert_eq!(file.relative(), Path::new("photos/a.jpg"));
+        assert!(file.absolute().starts_with(root.path()));
+        assert!(matches!(
+            root.resolve("photos/missing.jpg"),
+            Err(PathError::NotFound(_))
+        ));
+        assert!(matches!(
+            root.resolve("../secret.txt"),
+            Err(PathError::Invalid(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_rejects_symlinks_out_of_the_library() {
+        let temp = TempLibrary::new("symlink");
+        std::os::unix::fs::symlink(temp.0.join("secret.txt"), temp.library().join("link.txt"))
+            .unwrap();
+        std::os::unix::fs::symlink(&temp.0, temp.library().join("outside")).unwrap();
+        let root = LibraryRoot::new(&temp.library(), &[]).unwrap();
+
+        assert!(matches!(
+            root.resolve("link.txt"),
+            Err(PathError::OutsideRoot(_))
+        ));
+        assert!(matches!(
+            root.resolve("outside/secret.txt"),
+            Err(PathError::OutsideRoot(_))
+        ));
+    }
+
+    #[test]
+    fn resolve_rejects_excluded_server_state() {
+        let temp = TempLibrary::new("excluded");
+        let state = temp.library().join(".m3s");
+        fs::create_dir_all(&state).unwrap();
+        fs::write(state.join("users.json"), b"[]").unwrap();
+        let root = LibraryRoot::new(&temp.library(), &[&state]).unwrap();
+
+        assert!(matches!(
+            root.resolve(".m3s/users.json"),
+            Err(PathError::OutsideRoot(_))
+        ));
+        assert!(root.resolve("photos/a.jpg").is_ok());
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::args::SecurityHeaderArgs;
+
+#[derive(Debug)]
+pub struct SecurityHeaders(Vec<(HeaderName, HeaderValue)>);
+
+impl SecurityHeaders {
+    pub fn new(
+        SecurityHeaderArgs {
+            content_security_policy,
+            frame_ancestors,
+            referrer_policy,
+        }: SecurityHeaderArgs,
+    ) -> Result<Self> {
+        let policy = format!(
+            "{}; frame-ancestors {frame_ancestors}",
+            content_security_policy.trim().trim_end_matches(';')
+        );
+
+        let mut headers = vec![
+            (
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_str(&policy).context("Invalid Content-Security-Policy")?,
+            ),
+            // Library files are user supplied, never let a browser guess they are HTML
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+            (
+                header::REFERRER_POLICY,
+                HeaderValue::from_str(&referrer_policy).context("Invalid Referrer-Policy")?,
+            ),
+        ];
+
+        // For browsers that predate frame-ancestors
+        let frame_options = match frame_ancestors.trim() {
+            "'none'" => Some("DENY"),
+            "'self'" => Some("SAMEORIGIN"),
+            _ => None,
+        };
+        if let Some(frame_options) = frame_options {
+            headers.push((
+                header::X_FRAME_OPTIONS,
+                HeaderValue::from_static(frame_options),
+            ));
+        }
+
+        Ok(Self(headers))
+    }
+}
+
+// Handlers that need something stricter or looser set the header themselves
+pub async fn security_headers(
+    State(headers): State<Arc<SecurityHeaders>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+
+    for (name, value) in &headers.0 {
+        if !response.headers().contains_key(name) {
+            response.headers_mut().insert(name, value.clone());
+        }
+    }
+
+    response
+}
@@ -0,0 +1,486 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::Host,
+    http::Uri,
+    response::{IntoResponse, Redirect},
+    Router,
+};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use futures_util::{future::try_join_all, FutureExt as _};
+use hyper_util::{
+    rt::{TokioExecutor, TokioTimer},
+    server::conn::auto,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::args::{HttpArgs, TlsArgs};
+#[cfg(unix)]
+use crate::{network, systemd};
+
+/// Where to listen, as `[http://|https://]host[:port]`. Without a scheme a listener uses HTTPS
+/// when a certificate is configured, without a port it uses --port.
+#[derive(Debug, Clone)]
+pub struct Address {
+    scheme: Option<Scheme>,
+    host: String,
+    port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scheme {
+    Http,
+    Https,
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = match (s.strip_prefix("http://"), s.strip_prefix("https://")) {
+            (Some(rest), _) => (Some(Scheme::Http), rest),
+            (_, Some(rest)) => (Some(Scheme::Https), rest),
+            _ => (None, s),
+        };
+
+        let (host, port) = match rest.strip_prefix('[') {
+            // Brackets are only needed to give an IPv6 address a port, as in [::1]:3000
+            Some(rest) => {
+                let (host, rest) = rest.split_once(']').ok_or("missing ] after IPv6 address")?;
+                match rest {
+                    "" => (host, None),
+                    rest => (
+                        host,
+                        Some(rest.strip_prefix(':').ok_or("expected :port after ]")?),
+                    ),
+                }
+            }
+            None => match rest.split_once(':') {
+                Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                // A bare IPv6 address such as ::1
+                _ => (rest, None),
+            },
+        };
+        if host.is_empty() {
+            return Err("expected a host such as 0.0.0.0 or [::]".to_string());
+        }
+        let port = port
+            .map(|port| port.parse().map_err(|_| format!("invalid port {port:?}")))
+            .transpose()?;
+
+        Ok(Self {
+            scheme,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// Whether m3s itself serves HTTPS, from a certificate given or one obtained through ACME
+pub fn serves_https(tls: &TlsArgs) -> bool {
+    #[cfg(feature = "acme")]
+    let acme = !tls.acme_domains.is_empty();
+    #[cfg(not(feature = "acme"))]
+    let acme = false;
+    tls.tls_cert.is_some() || acme
+}
+
+/// Serves until SIGINT or SIGTERM, then stops accepting connections and gives requests in
+/// flight up to `drain` to finish. `unix` is a socket path and mode to use instead of TCP.
+pub async fn serve(
+    app: Router,
+    addresses: &[Address],
+    port: u16,
+    unix: Option<(&Path, u32)>,
+    tls: TlsArgs,
+    http: &HttpArgs,
+    drain: Duration,
+) -> Result<()> {
+    if let Some((path, mode)) = unix {
+        return serve_unix(app, path, mode, tls, http, drain).await;
+    }
+
+    let secure = serves_https(&tls);
+
+    let listeners = match systemd_listener()? {
+        Some(listener) if secure => vec![(listener, Scheme::Https)],
+        Some(listener) => vec![(listener, Scheme::Http)],
+        None => bind(addresses, port, secure).await?,
+    };
+    // Loaded once and shared, so every https:// listener presents the same certificate
+    let acceptor = match listeners.iter().any(|(_, scheme)| *scheme == Scheme::Https) {
+        true => Some(acceptor(&tls).await?),
+        false => None,
+    };
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    let stopping = on_shutdown(drain);
+    // axum_server drains on its own, given the deadline
+    let handle = Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            stopped(stopping).await;
+            handle.graceful_shutdown(Some(drain));
+        }
+    });
+
+    let mut servers = Vec::new();
+    let mut redirected = HashSet::new();
+    for (listener, scheme) in listeners {
+        let addr = listener.local_addr()?;
+        let server = match (scheme, &acceptor) {
+            (Scheme::Https, Some(acceptor)) => {
+                if let Some(redirect_port) = tls.http_redirect_port {
+                    if redirected.insert(addr.ip()) {
+                        tokio::spawn(redirect_to_https(addr.ip(), redirect_port, addr.port()));
+                    }
+                }
+                match acceptor {
+                    Acceptor::Rustls(config) => {
+                        info!("Listening on https://{addr}");
+                        let mut server = axum_server::from_tcp_rustls(listener, config.clone());
+                        configure(server.http_builder(), http);
+                        server.handle(handle.clone()).serve(service.clone()).boxed()
+                    }
+                    #[cfg(feature = "acme")]
+                    Acceptor::Acme(acceptor) => {
+                        info!("Listening on https://{addr} with ACME certificates");
+                        let mut server = axum_server::from_tcp(listener).acceptor(acceptor.clone());
+                        configure(server.http_builder(), http);
+                        server.handle(handle.clone()).serve(service.clone()).boxed()
+                    }
+                }
+            }
+            _ => {
+                info!("Listening on http://{addr}");
+                let mut server = axum_server::from_tcp(listener);
+                configure(server.http_builder(), http);
+                server.handle(handle.clone()).serve(service.clone()).boxed()
+            }
+        };
+        servers.push(server);
+    }
+
+    ready();
+    try_join_all(servers).await?;
+    Ok(())
+}
+
+// The same for every listener, TCP or Unix
+fn configure(builder: &mut auto::Builder<TokioExecutor>, http: &HttpArgs) {
+    let max_header = http.http_max_header_kb * 1024;
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .keep_alive(http.http_keep_alive)
+        .header_read_timeout(Duration::from_secs(http.http_header_timeout_secs))
+        .max_buf_size(max_header as usize);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(http.http2_max_streams)
+        .max_header_list_size(max_header)
+        .keep_alive_interval(
+            (http.http2_keep_alive_secs > 0)
+                .then(|| Duration::from_secs(http.http2_keep_alive_secs)),
+        );
+}
+
+async fn bind(
+    addresses: &[Address],
+    default_port: u16,
+    secure: bool,
+) -> Result<Vec<(std::net::TcpListener, Scheme)>> {
+    let mut resolved = Vec::new();
+    for address in addresses {
+        let host = address.host.as_str();
+        let addr = tokio::net::lookup_host((host, address.port.unwrap_or(default_port)))
+            .await
+            .with_context(|| format!("Failed to resolve {host}"))?
+            .next()
+            .with_context(|| format!("{host} did not resolve to any address"))?;
+        let scheme = match address.scheme {
+            Some(Scheme::Https) if !secure => {
+                bail!("https://{host} needs a certificate, see --tls-cert")
+            }
+            Some(scheme) => scheme,
+            None if secure => Scheme::Https,
+            None => Scheme::Http,
+        };
+        resolved.push((addr, scheme));
+    }
+
+    resolved
+        .iter()
+        .map(|&(addr, scheme)| {
+            // [::] takes IPv4 as well on most systems, which would clash with 0.0.0.0 on the
+            // same port, so it's kept to IPv6 when both are asked for
+            let v6_only = addr.is_ipv6()
+                && resolved
+                    .iter()
+                    .any(|(other, _)| other.is_ipv4() && other.port() == addr.port());
+            Ok((listen(addr, v6_only)?, scheme))
+        })
+        .collect()
+}
+
+fn listen(addr: SocketAddr, v6_only: bool) -> Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    // As std does, so a restart doesn't wait out connections in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("Failed to listen on {addr}"))?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+// Flips to true once a shutdown signal arrives
+fn on_shutdown(drain: Duration) -> watch::Receiver<bool> {
+    let (stop, stopping) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!(
+            "Shutting down, waiting up to {}s for requests in flight",
+            drain.as_secs()
+        );
+        #[cfg(unix)]
+        systemd::stopping();
+        let _ = stop.send(true);
+    });
+    stopping
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(
+    _: Router,
+    _: &Path,
+    _: u32,
+    _: TlsArgs,
+    _: &HttpArgs,
+    _: Duration,
+) -> Result<()> {
+    bail!("Unix sockets are only supported on Unix")
+}
+
+// axum::serve only takes TCP listeners, so connections are handed to hyper directly
+#[cfg(unix)]
+async fn serve_unix(
+    app: Router,
+    path: &Path,
+    mode: u32,
+    tls: TlsArgs,
+    http: &HttpArgs,
+    drain: Duration,
+) -> Result<()> {
+    use std::os::unix::fs::{FileTypeExt as _, PermissionsExt as _};
+
+    use axum::{extract::ConnectInfo, Extension};
+    use hyper_util::{
+        rt::TokioIo, server::graceful::GracefulShutdown, service::TowerToHyperService,
+    };
+
+    // TLS would be terminated by whatever sits in front of the socket
+    #[cfg(feature = "acme")]
+    let acme = !tls.acme_domains.is_empty();
+    #[cfg(not(feature = "acme"))]
+    let acme = false;
+    if tls.tls_cert.is_some() || tls.tls_key.is_some() || acme {
+        bail!("TLS can't be used with a Unix socket, terminate it in the proxy instead");
+    }
+
+    // A socket left behind by a run that didn't stop cleanly would make bind fail
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {path:?}"))?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {path:?}"))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set permissions on {path:?}"))?;
+
+    // Peers have no address, the network filter trusts this one to forward the client's
+    let app = app.layer(Extension(ConnectInfo(SocketAddr::new(
+        network::UNIX_PEER,
+        0,
+    ))));
+
+    let stopping = on_shutdown(drain);
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    configure(&mut builder, http);
+    let graceful = GracefulShutdown::new();
+
+    info!("Listening on unix:{}", path.display());
+    ready();
+    loop {
+        let stream = tokio::select! {
+            result = listener.accept() => match result {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept connection on {path:?}: {e}");
+                    continue;
+                }
+            },
+            _ = stopped(stopping.clone()) => break,
+        };
+
+        let connection = builder
+            .serve_connection_with_upgrades(
+                TokioIo::new(stream),
+                TowerToHyperService::new(app.clone()),
+            )
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("Connection closed with an error: {e}");
+            }
+        });
+    }
+
+    drop(listener);
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!("Failed to remove socket {path:?}: {e}");
+    }
+    tokio::select! {
+        _ = graceful.shutdown() => {}
+        _ = tokio::time::sleep(drain) => {
+            warn!("Requests still running after {}s, closing them", drain.as_secs());
+        }
+    }
+
+    Ok(())
+}
+
+// Socket activation wins over --address and --port
+fn systemd_listener() -> Result<Option<std::net::TcpListener>> {
+    #[cfg(unix)]
+    return systemd::listener();
+    #[cfg(not(unix))]
+    return Ok(None);
+}
+
+fn ready() {
+    #[cfg(unix)]
+    systemd::ready();
+}
+
+enum Acceptor {
+    Rustls(RustlsConfig),
+    #[cfg(feature = "acme")]
+    Acme(rustls_acme::axum::AxumAcceptor),
+}
+
+async fn acceptor(tls: &TlsArgs) -> Result<Acceptor> {
+    #[cfg(feature = "acme")]
+    if !tls.acme_domains.is_empty() {
+        use futures_util::StreamExt as _;
+        use rustls_acme::{caches::DirCache, AcmeConfig};
+
+        let mut state = AcmeConfig::new(tls.acme_domains.clone())
+            .contact(tls.acme_contact.iter().map(|c| format!("mailto:{c}")))
+            .cache_option(tls.acme_cache.clone().map(DirCache::new))
+            .directory_lets_encrypt(tls.acme_production)
+            .state();
+        // The default configuration offers no ALPN, which would leave every client on HTTP/1.1
+        let mut config = (*state.default_rustls_config()).clone();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let acceptor = state.axum_acceptor(std::sync::Arc::new(config));
+
+        tokio::spawn(async move {
+            while let Some(event) = state.next().await {
+                match event {
+                    Ok(event) => info!("ACME: {event:?}"),
+                    Err(e) => warn!("ACME: {e}"),
+                }
+            }
+        });
+        return Ok(Acceptor::Acme(acceptor));
+    }
+
+    let (Some(cert), Some(key)) = (&tls.tls_cert, &tls.tls_key) else {
+        bail!("HTTPS needs a certificate, see --tls-cert");
+    };
+    let config = RustlsConfig::from_pem_file(cert, key)
+        .await
+        .with_context(|| format!("Failed to load TLS certificate {cert:?} and key {key:?}"))?;
+    Ok(Acceptor::Rustls(config))
+}
+
+async fn redirect_to_https(ip: IpAddr, port: u16, https_port: u16) {
+    let redirect = move |Host(host): Host, uri: Uri| async move {
+        let host = match host.rsplit_once(':') {
+            // Don't mistake the inside of an IPv6 literal for a port
+            Some((host, port)) if !port.contains(']') => host,
+            _ => &host,
+        };
+        let path = uri.path_and_query().map_or("/", |p| p.as_str());
+
+        match https_port {
+            443 => Redirect::permanent(&format!("https://{host}{path}")),
+            port => Redirect::permanent(&format!("https://{host}:{port}{path}")),
+        }
+        .into_response()
+    };
+
+    let addr = SocketAddr::new(ip, port);
+    let result = async {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        info!("Redirecting http://{addr} to HTTPS");
+        axum::serve(listener, Router::new().fallback(redirect)).await
+    };
+
+    if let Err(e) = result.await {
+        warn!("HTTP redirect listener on {addr} failed: {e}");
+    }
+}
+
+async fn stopped(mut stopping: watch::Receiver<bool>) {
+    // An error means the sender is gone, which only happens once it has sent
+    let _ = stopping.wait_for(|stop| *stop).await;
+}
+
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for SIGINT: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{Path as UrlPath, Query, State},
+    http::StatusCode,
+    middleware,
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine as _};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    api_error::ApiError,
+    audit::{Action, Audit},
+    auth::{require_role, Principal},
+    store::{load_json, save_json},
+    users::Role,
+};
+
+pub const COOKIE_NAME: &str = "m3s_session";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub username: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires: OffsetDateTime,
+    pub user_agent: Option<String>,
+    token_hash: String,
+}
+
+// Public id of the session the current request was authenticated with
+#[derive(Debug, Clone)]
+pub struct CurrentSession(pub String);
+
+#[derive(Debug)]
+pub struct SessionStore {
+    path: PathBuf,
+    lifetime: Duration,
+    sessions: RwLock<Vec<Session>>,
+}
+
+impl SessionStore {
+    pub fn load(data_dir: &Path, lifetime: Duration) -> Result<Self> {
+        let path = data_dir.join("sessions.json");
+        let mut sessions: Vec<Session> = load_json(&path)?;
+
+        let now = OffsetDateTime::now_utc();
+        sessions.retain(|s| s.expires > now);
+
+        Ok(Self {
+            path,
+            lifetime,
+            sessions: RwLock::new(sessions),
+        })
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Returns the new session and the token to hand to the client
+    pub fn create(&self, username: &str, user_agent: Option<String>) -> Result<(Session, String)> {
+        let mut token = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut token);
+        let token = BASE64_URL_SAFE_NO_PAD.encode(token);
+
+        let mut id = [0u8; 6];
+        rand::thread_rng().fill_bytes(&mut id);
+
+        let now = OffsetDateTime::now_utc();
+        let session = Session {
+            id: BASE64_URL_SAFE_NO_PAD.encode(id),
+            username: username.to_string(),
+            created: now,
+            expires: now + self.lifetime,
+            user_agent,
+            token_hash: hash_token(&token),
+        };
+
+        // Saved before it's swapped in, so a failed write leaves no session that's gone after a
+        // restart
+        let mut sessions = self.sessions.write().unwrap();
+        let mut updated: Vec<_> = sessions
+            .iter()
+            .filter(|s| s.expires > now)
+            .cloned()
+            .collect();
+        updated.push(session.clone());
+        save_json(&self.path, &updated)?;
+        *sessions = updated;
+
+        Ok((session, token))
+    }
+
+    pub fn verify(&self, token: &str) -> Option<Session> {
+        let hash = hash_token(token);
+        let now = OffsetDateTime::now_utc();
+
+        self.sessions
+            .read()
+            .unwrap()
+            .iter()
+            .find(|s| s.token_hash == hash && s.expires > now)
+            .cloned()
+    }
+
+    pub fn list(&self, username: Option<&str>) -> Vec<Session> {
+        let now = OffsetDateTime::now_utc();
+
+        self.sessions
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|s| s.expires > now && username.is_none_or(|u| s.username == u))
+            .cloned()
+            .collect()
+    }
+
+    pub fn revoke(&self, predicate: impl Fn(&Session) -> bool) -> Result<usize> {
+        let mut sessions = self.sessions.write().unwrap();
+        let mut updated = sessions.clone();
+        updated.retain(|s| !predicate(s));
+
+        let revoked = sessions.len() - updated.len();
+        if revoked > 0 {
+            save_json(&self.path, &updated)?;
+            *sessions = updated;
+        }
+
+        Ok(revoked)
+    }
+
+    pub fn revoke_token(&self, token: &str) -> Result<usize> {
+        let hash = hash_token(token);
+        self.revoke(|s| s.token_hash == hash)
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub fn router() -> Router<Arc<SessionStore>> {
+    Router::new()
+        .route("/", get(list_sessions))
+        .route("/:id", delete(revoke_session))
+        .route_layer(middleware::from_fn_with_state(Role::Viewer, require_role))
+}
+
+#[derive(Debug, Serialize)]
+struct SessionView {
+    id: String,
+    username: String,
+    #[serde(with = "time::serde::rfc3339")]
+    created: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    expires: OffsetDateTime,
+    user_agent: Option<String>,
+    current: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    user: Option<String>,
+}
+
+async fn list_sessions(
+    Extension(principal): Extension<Principal>,
+    current: Option<Extension<CurrentSession>>,
+    State(store): State<Arc<SessionStore>>,
+    Query(ListQuery { user }): Query<ListQuery>,
+) -> Result<Json<Vec<SessionView>>, ApiError> {
+    // Admins may look at anyone's sessions, everyone else only at their own
+    let username = match (principal.username(), user) {
+        (_, Some(user)) if principal.role() == Role::Admin => Some(user),
+        (Some(username), _) => Some(username.to_string()),
+        (None, None) if principal.role() == Role::Admin => None,
+        _ => return Err(StatusCode::FORBIDDEN.into()),
+    };
+
+    let current = current.map(|Extension(CurrentSession(id))| id);
+    let sessions: Vec<SessionView> = store
+        .list(username.as_deref())
+        .into_iter()
+        .map(|s| SessionView {
+            current: current.as_ref() == Some(&s.id),
+            id: s.id,
+            username: s.username,
+            created: s.created,
+            expires: s.expires,
+            user_agent: s.user_agent,
+        })
+        .collect();
+
+    Ok(Json(sessions))
+}
+
+async fn revoke_session(
+    Extension(principal): Extension<Principal>,
+    State(store): State<Arc<SessionStore>>,
+    audit: Audit,
+    UrlPath(id): UrlPath<String>,
+) -> Result<StatusCode, ApiError> {
+    let is_admin = principal.role() == Role::Admin;
+    let username = principal.username().map(str::to_string);
+
+    let revoked = store
+        .revoke(|s| s.id == id && (is_admin || username.as_deref() == Some(s.username.as_str())))
+        .map_err(|e| ApiError::internal("Failed to revoke session", e))?;
+    if revoked == 0 {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    audit.record(Action::SessionRevoked, &id);
+    Ok(StatusCode::NO_CONTENT)
+}
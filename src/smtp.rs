@@ -0,0 +1,364 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use rustls::pki_types::ServerName;
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncRead, AsyncWrite, AsyncWriteExt as _, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
+use tracing::debug;
+
+use crate::args::SmtpArgs;
+
+// For the whole conversation, a big digest is a few megabytes
+const TIMEOUT: Duration = Duration::from_secs(120);
+// Given in EHLO, servers only log it
+const HELLO: &str = "m3s";
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+type Connection = BufReader<Box<dyn Stream>>;
+
+/// Sends mail through one server, a new connection for each message.
+///
+/// smtp:// upgrades with STARTTLS whenever the server offers it, and without it only a local
+/// relay that asks for no password is any use. smtps:// is TLS from the start.
+pub struct Mailer {
+    host: String,
+    port: u16,
+    implicit_tls: bool,
+    credentials: Option<(String, String)>,
+    /// As given, for the From header
+    pub from: String,
+    // Just the address, for the envelope
+    sender: String,
+    tls: TlsConnector,
+}
+
+impl Mailer {
+    /// None when no server is configured
+    pub fn new(args: &SmtpArgs) -> Result<Option<Self>> {
+        let Some(server) = &args.smtp_url else {
+            return Ok(None);
+        };
+        let url = reqwest::Url::parse(server).context("Invalid --smtp-url")?;
+        let (implicit_tls, default_port) = match url.scheme() {
+            "smtp" => (false, 587),
+            "smtps" => (true, 465),
+            _ => bail!("--smtp-url must start with smtp:// or smtps://"),
+        };
+        let host = url
+            .host_str()
+            .context("--smtp-url has no host")?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+
+        let from = args.smtp_from.clone().unwrap_or_default();
+        let sender = match from.rsplit_once('<') {
+            Some((_, address)) => address.trim_end_matches('>'),
+            None => &from,
+        };
+        ensure!(
+            sender.contains('@') && !from.contains(|c: char| c.is_control()),
+            "--smtp-from needs an email address"
+        );
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(Some(Self {
+            host,
+            port: url.port().unwrap_or(default_port),
+            implicit_tls,
+            credentials: args
+                .smtp_username
+                .clone()
+                .map(|username| (username, args.smtp_password.clone().unwrap_or_default())),
+            sender: sender.to_string(),
+            from,
+            tls: TlsConnector::from(Arc::new(config)),
+        }))
+    }
+
+    /// `message` is the whole thing, headers included, with CRLF line endings
+    pub async fn send(&self, to: &str, message: &[u8]) -> Result<()> {
+        tokio::time::timeout(TIMEOUT, self.conversation(to, message))
+            .await
+            .context("The mail server took too long")?
+    }
+
+    async fn conversation(&self, to: &str, message: &[u8]) -> Result<()> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Failed to connect to {}:{}", self.host, self.port))?;
+        let mut connection: Connection = match self.implicit_tls {
+            true => BufReader::new(Box::new(self.tls.connect(self.server_name()?, tcp).await?)),
+            false => BufReader::new(Box::new(tcp)),
+        };
+
+        expect(&mut connection, 220).await?;
+        let mut capabilities = command(&mut connection, &format!("EHLO {HELLO}"), 250).await?;
+        let mut encrypted = self.implicit_tls;
+        if !encrypted && has(&capabilities, "STARTTLS") {
+            command(&mut connection, "STARTTLS", 220).await?;
+            // Nothing more was sent before the handshake, so nothing is left in the buffer
+            let tcp = connection.into_inner();
+            let tls = self.tls.connect(self.server_name()?, tcp).await?;
+            connection = BufReader::new(Box::new(tls));
+            capabilities = command(&mut connection, &format!("EHLO {HELLO}"), 250).await?;
+            encrypted = true;
+        }
+
+        if let Some((username, password)) = &self.credentials {
+            ensure!(
+                encrypted,
+                "The mail server doesn't offer STARTTLS, the password isn't sent unencrypted"
+            );
+            ensure!(
+                has(&capabilities, "AUTH"),
+                "The mail server doesn't take a username and password"
+            );
+            let plain = BASE64_STANDARD.encode(format!("\0{username}\0{password}"));
+            command(&mut connection, &format!("AUTH PLAIN {plain}"), 235)
+                .await
+                .context("The mail server refused the username or password")?;
+        }
+
+        command(
+            &mut connection,
+            &format!("MAIL FROM:<{}>", self.sender),
+            250,
+        )
+        .await?;
+        command(&mut connection, &format!("RCPT TO:<{to}>"), 250).await?;
+        command(&mut connection, "DATA", 354).await?;
+        connection.write_all(&dot_stuff(message)).await?;
+        command(&mut connection, ".", 250).await?;
+        // The message is accepted, a rude goodbye doesn't matter
+        let _ = command(&mut connection, "QUIT", 221).await;
+        Ok(())
+    }
+
+    fn server_name(&self) -> Result<ServerName<'static>> {
+        ServerName::try_from(self.host.clone()).context("Invalid mail server name")
+    }
+}
+
+// Sends a line and waits for the reply, failing unless it has the expected code
+async fn command(connection: &mut Connection, line: &str, code: u16) -> Result<Vec<String>> {
+    connection
+        .write_all(format!("{line}\r\n").as_bytes())
+        .await?;
+    connection.flush().await?;
+    expect(connection, code).await.with_context(|| {
+        // The rest may be a password
+        let verb = line.split(' ').next().unwrap_or_default();
+        format!("The mail server refused {verb}")
+    })
+}
+
+// Lines of a reply, continued with a hyphen after the code: 250-SIZE then 250 HELP
+async fn expect(connection: &mut Connection, code: u16) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        ensure!(
+            connection.read_line(&mut line).await? > 0,
+            "The mail server closed the connection"
+        );
+        let line = line.trim_end();
+        debug!("SMTP: {line}");
+        let (reply, more) = match line.get(3..4) {
+            Some("-") => (line.get(..3), true),
+            _ => (line.get(..3), false),
+        };
+        let reply: u16 = reply
+            .and_then(|reply| reply.parse().ok())
+            .context("Not a mail server")?;
+        lines.push(line.get(4..).unwrap_or_default().to_string());
+        if !more {
+            ensure!(reply == code, "{line}");
+            return Ok(lines);
+        }
+    }
+}
+
+fn has(capabilities: &[String], name: &str) -> bool {
+    capabilities.iter().any(|capability| {
+        capability
+            .split(' ')
+            .next()
+            .is_some_and(|word| word.eq_ignore_ascii_case(name))
+    })
+}
+
+// A line starting with a dot gets another, so it isn't taken for the end of the message
+fn dot_stuff(message: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(message.len() + 2);
+    let mut line_start = true;
+    for &byte in message {
+        if line_start && byte == b'.' {
+            out.push(b'.');
+        }
+        out.push(byte);
+        line_start = byte == b'\n';
+    }
+    if !out.ends_with(b"\r\n") {
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Weekday;
+    use tokio::{
+        io::{duplex, AsyncReadExt as _},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    // The client's end of a connection, with everything the server says already written
+    fn replies(text: &str) -> Connection {
+        let (client, mut server) = duplex(4096);
+        let text = text.to_string();
+        tokio::spawn(async move { server.write_all(text.as_bytes()).await });
+        BufReader::new(Box::new(client))
+    }
+
+    fn args(url: String, username: Option<&str>) -> SmtpArgs {
+        SmtpArgs {
+            smtp_url: Some(url),
+            smtp_username: username.map(str::to_string),
+            smtp_password: username.map(|_| "secret".to_string()),
+            smtp_from: Some("m3s <photos@example.com>".to_string()),
+            digest_day: Weekday::Monday,
+            digest_hour: 8,
+        }
+    }
+
+    // Answers each line the client sends with the next reply, returning what it was sent
+    async fn server(listener: TcpListener, script: &'static [&'static str]) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut received = String::new();
+        stream.write_all(b"220 test\r\n").await.unwrap();
+        for reply in script {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            received += &line;
+            if line == "DATA\r\n" {
+                stream.write_all(b"354 go on\r\n").await.unwrap();
+                while !received.ends_with("\r\n.\r\n") {
+                    line.clear();
+                    stream.read_line(&mut line).await.unwrap();
+                    received += &line;
+                }
+            }
+            stream.write_all(reply.as_bytes()).await.unwrap();
+        }
+        let _ = stream.read_to_string(&mut received).await;
+        received
+    }
+
+    #[test]
+    fn dot_stuff_doubles_leading_dots() {
+        assert_eq!(dot_stuff(b".a\r\nb.\r\n.\r\n"), b"..a\r\nb.\r\n..\r\n");
+        assert_eq!(dot_stuff(b"a\r\n..b\r\n"), b"a\r\n...b\r\n");
+    }
+
+    #[test]
+    fn dot_stuff_ends_with_a_line_break() {
+        assert_eq!(dot_stuff(b"a\r\n"), b"a\r\n");
+        assert_eq!(dot_stuff(b"a"), b"a\r\n");
+        assert_eq!(dot_stuff(b""), b"\r\n");
+    }
+
+    #[test]
+    fn has_matches_the_first_word_in_any_case() {
+        let capabilities = ["mail.example.com", "auth PLAIN LOGIN", "8BITMIME"].map(String::from);
+
+        assert!(has(&capabilities, "AUTH"));
+        assert!(has(&capabilities, "8bitmime"));
+        assert!(!has(&capabilities, "PLAIN"));
+        assert!(!has(&capabilities, "BITMIME"));
+        assert!(!has(&capabilities, "STARTTLS"));
+    }
+
+    #[tokio::test]
+    async fn expect_reads_continued_replies() {
+        let mut connection = replies("250-mail.example.com\r\n250-STARTTLS\r\n250 AUTH PLAIN\r\n");
+        let lines = expect(&mut connection, 250).await.unwrap();
+        assert_eq!(lines, ["mail.example.com", "STARTTLS", "AUTH PLAIN"]);
+    }
+
+    #[tokio::test]
+    async fn expect_fails_on_another_code() {
+        let mut connection = replies("250-first\r\n550 No such user\r\n");
+        let error = expect(&mut connection, 250).await.unwrap_err();
+        assert_eq!(error.to_string(), "550 No such user");
+    }
+
+    #[tokio::test]
+    async fn expect_fails_on_garbage_or_a_closed_connection() {
+        let error = expect(&mut replies("HTTP/1.1 400\r\n"), 220)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Not a mail server");
+
+        let error = expect(&mut replies("250-more to come\r\n"), 250)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "The mail server closed the connection");
+    }
+
+    #[tokio::test]
+    async fn sends_a_message_to_a_local_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("smtp://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(server(
+            listener,
+            &[
+                "250 relay\r\n",
+                "250 ok\r\n",
+                "250 ok\r\n",
+                "250 queued\r\n",
+                "221 bye\r\n",
+            ],
+        ));
+
+        let mailer = Mailer::new(&args(url, None)).unwrap().unwrap();
+        mailer
+            .send("alice@example.com", b"Subject: hi\r\n\r\n.hidden\r\n")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            server.await.unwrap(),
+            "EHLO m3s\r\nMAIL FROM:<photos@example.com>\r\nRCPT TO:<alice@example.com>\r\n\
+             DATA\r\nSubject: hi\r\n\r\n..hidden\r\n.\r\nQUIT\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn never_sends_a_password_unencrypted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("smtp://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(server(listener, &["250-relay\r\n250 AUTH PLAIN\r\n"]));
+
+        let mailer = Mailer::new(&args(url, Some("alice"))).unwrap().unwrap();
+        let error = mailer.send("bob@example.com", b"\r\n").await.unwrap_err();
+
+        assert!(error.to_string().contains("isn't sent unencrypted"));
+        assert_eq!(server.await.unwrap(), "EHLO m3s\r\n");
+    }
+}
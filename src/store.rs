@@ -0,0 +1,55 @@
+use std::{
+    fs,
+    io::{ErrorKind, Write as _},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use rand::RngCore as _;
+use serde::{de::DeserializeOwned, Serialize};
+
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    match fs::read(path) {
+        Ok(data) => {
+            serde_json::from_slice(&data).with_context(|| format!("Failed to parse {path:?}"))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {path:?}")),
+    }
+}
+
+pub fn save_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    let _span = tracing::debug_span!("save_json", ?path).entered();
+    // Write then rename so a crash never leaves a truncated file behind
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(value)?)
+        .with_context(|| format!("Failed to write {tmp:?}"))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {path:?}"))?;
+    Ok(())
+}
+
+pub fn load_secret(data_dir: &Path) -> Result<[u8; 64]> {
+    let path = data_dir.join("secret.key");
+
+    match fs::read(&path) {
+        Ok(data) => data
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("{path:?} is corrupt, delete it to generate a new one")),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let mut secret = [0u8; 64];
+            rand::thread_rng().fill_bytes(&mut secret);
+
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options
+                .open(&path)
+                .and_then(|mut file| file.write_all(&secret))
+                .with_context(|| format!("Failed to write {path:?}"))?;
+
+            Ok(secret)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read {path:?}")),
+    }
+}
@@ -0,0 +1,54 @@
+//! Socket activation and readiness notification for running under systemd. Everything here
+//! does nothing when the process wasn't started by systemd.
+
+use std::{net::TcpListener, os::fd::FromRawFd as _};
+
+use anyhow::{ensure, Result};
+use sd_notify::NotifyState;
+use tracing::{info, warn};
+
+/// The listening socket systemd passed in, if the unit is socket activated
+pub fn listener() -> Result<Option<TcpListener>> {
+    let mut fds = sd_notify::listen_fds()?;
+    ensure!(
+        fds.len() <= 1,
+        "systemd passed {} sockets, only one is supported",
+        fds.len()
+    );
+    let Some(fd) = fds.next() else {
+        return Ok(None);
+    };
+
+    // SAFETY: systemd hands over this descriptor for the process to own, and nothing else in
+    // the process knows about it
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    info!("Using the socket passed by systemd");
+    Ok(Some(listener))
+}
+
+/// Tells systemd startup is done and starts the watchdog pings if the unit asks for them
+pub fn ready() {
+    notify(&[NotifyState::Ready]);
+
+    if let Some(timeout) = sd_notify::watchdog_enabled() {
+        // Half the timeout, as sd_watchdog_enabled(3) recommends
+        let mut interval = tokio::time::interval(timeout / 2);
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                notify(&[NotifyState::Watchdog]);
+            }
+        });
+    }
+}
+
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(state) {
+        warn!("Failed to notify systemd: {e}");
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::store::{load_json, save_json};
+
+const FILE: &str = "taken.json";
+
+// Wall clock times kept as if they were UTC, the same as scans keep EXIF times
+#[derive(Debug, Serialize, Deserialize)]
+struct Override {
+    path: PathBuf,
+    #[serde(with = "time::serde::rfc3339")]
+    taken: OffsetDateTime,
+}
+
+/// Where overrides are kept in the data directory, for [`crate::library::Library::with_taken`]
+pub fn path(data_dir: &Path) -> PathBuf {
+    data_dir.join(FILE)
+}
+
+/// Times taken that scans use before any in the EXIF, by library relative path. Read again by
+/// every scan, so an import while the server runs is picked up by the next.
+pub fn load(path: &Path) -> Result<HashMap<PathBuf, OffsetDateTime>> {
+    let overrides: Vec<Override> = load_json(path)?;
+    Ok(overrides.into_iter().map(|o| (o.path, o.taken)).collect())
+}
+
+/// Adds to those kept, replacing any for the same paths
+pub fn add(path: &Path, added: impl IntoIterator<Item = (PathBuf, OffsetDateTime)>) -> Result<()> {
+    let mut overrides = load(path)?;
+    overrides.extend(added);
+    let mut overrides: Vec<Override> = overrides
+        .into_iter()
+        .map(|(path, taken)| Override { path, taken })
+        .collect();
+    overrides.sort_by(|a, b| a.path.cmp(&b.path));
+    save_json(path, &overrides)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use time::macros::datetime;
+
+    use super::*;
+
+    // A data directory of its own, removed when the test is done
+    struct TempDataDir(PathBuf);
+
+    impl Drop for TempDataDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn add_keeps_earlier_overrides_and_replaces_the_same_path() {
+        let dir = std::env::temp_dir().join(format!("m3s-taken-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir = TempDataDir(dir);
+        let path = path(&dir.0);
+
+        assert!(load(&path).unwrap().is_empty());
+
+        let (a, b) = (PathBuf::from("Trip/a.jpg"), PathBuf::from("Trip/b.jpg"));
+        add(&path, [(a.clone(), datetime!(2001-02-03 04:05:06 UTC))]).unwrap();
+        add(
+            &path,
+            [
+                (a.clone(), datetime!(2002-02-03 04:05:06 UTC)),
+                (b.clone(), datetime!(2003-02-03 04:05:06 UTC)),
+            ],
+        )
+        .unwrap();
+
+        let overrides = load(&path).unwrap();
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(overrides[&a], datetime!(2002-02-03 04:05:06 UTC));
+        assert_eq!(overrides[&b], datetime!(2003-02-03 04:05:06 UTC));
+    }
+}
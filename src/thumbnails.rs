@@ -0,0 +1,837 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use axum::body::Bytes;
+use fast_image_resize::{IntoImageView as _, Resizer};
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder},
+    imageops::{self, FilterType},
+    metadata::Orientation,
+    DynamicImage, GrayImage, ImageDecoder as _, ImageEncoder as _, ImageReader, Rgb, RgbImage,
+    RgbaImage,
+};
+use jpeg_decoder::PixelFormat;
+use moka::sync::Cache;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info_span};
+use webp::WebPConfig;
+
+use crate::{
+    args::{PerSize, ThumbnailArgs, ThumbnailFormat},
+    library::{content_type, Kind, Media},
+    safe_path::SafePath,
+};
+
+// Longest edge served when a client doesn't ask for a size, enough for the grid on high DPI
+// screens
+const DEFAULT_SIZE: u32 = 360;
+// Assumed for photos whose header couldn't be read, a 12 megapixel camera's worth
+const UNKNOWN_DECODE_MB: u32 = 48;
+/// In every file name and ETag, bumped when thumbnails come out differently so the ones made
+/// before are made again. Revision 2 turned photos taken on their side the right way up.
+const REVISION: u32 = 2;
+// Exports are made once and kept, so they can take a little longer than the fastest
+const EXPORT_EFFORT: u8 = 5;
+// The perceptual hash grid's width, a JPEG decodes at 1/8 for it
+pub const PERCEPTUAL_HASH_SIZE: u32 = 9;
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct DiskUsage {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct FreeSpace {
+    pub available: u64,
+    pub total: u64,
+}
+
+/// A copy of a photo to take elsewhere, see [`Thumbnails::export`]
+#[derive(Debug, Clone, Copy)]
+pub struct Export {
+    /// Longest edge, None for the photo's own size
+    pub max: Option<u32>,
+    pub format: ThumbnailFormat,
+    pub quality: u8,
+    /// The original's EXIF, camera settings and location included, with the orientation
+    /// reset since the pixels are already turned. JPEG and AVIF only.
+    pub keep_exif: bool,
+}
+
+impl Export {
+    fn encoding(&self) -> Encoding {
+        Encoding {
+            format: self.format,
+            quality: self.quality,
+            effort: EXPORT_EFFORT,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        self.encoding().content_type()
+    }
+
+    pub fn extension(&self) -> &'static str {
+        self.encoding().extension()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Encoding {
+    format: ThumbnailFormat,
+    quality: u8,
+    effort: u8,
+}
+
+impl Encoding {
+    // Settings that change the output are part of the file name, so changing them makes new
+    // thumbnails rather than serving the old ones
+    fn suffix(&self) -> String {
+        match self.format {
+            ThumbnailFormat::Jpeg => format!("q{}.jpg", self.quality),
+            ThumbnailFormat::Webp => format!("q{}e{}.webp", self.quality, self.effort),
+            ThumbnailFormat::Avif => format!("q{}e{}.avif", self.quality, self.effort),
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self.format {
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::Webp => "image/webp",
+            ThumbnailFormat::Avif => "image/avif",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.format {
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::Webp => "webp",
+            ThumbnailFormat::Avif => "avif",
+        }
+    }
+
+    // `exif` is a raw EXIF chunk, which the WebP encoder has no way to add
+    fn encode(&self, thumbnail: &RgbImage, exif: Option<Vec<u8>>) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match self.format {
+            ThumbnailFormat::Jpeg => {
+                let mut encoder = JpegEncoder::new_with_quality(&mut data, self.quality);
+                if let Some(exif) = exif {
+                    encoder.set_exif_metadata(exif)?;
+                }
+                thumbnail.write_with_encoder(encoder)?;
+            }
+            ThumbnailFormat::Webp => {
+                ensure!(exif.is_none(), "WebP can't keep EXIF");
+                let mut config =
+                    WebPConfig::new().map_err(|()| anyhow!("Failed to set up the WebP encoder"))?;
+                config.quality = self.quality.into();
+                // libwebp's methods go from 0 to 6
+                config.method = i32::from(self.effort) * 6 / 10;
+                let encoded =
+                    webp::Encoder::from_rgb(thumbnail, thumbnail.width(), thumbnail.height())
+                        .encode_advanced(&config)
+                        .map_err(|e| anyhow!("Failed to encode WebP: {e:?}"))?;
+                data.extend_from_slice(&encoded);
+            }
+            // Speeds go the other way, from 1 the slowest to 10 the fastest
+            ThumbnailFormat::Avif => {
+                let mut encoder =
+                    AvifEncoder::new_with_speed_quality(&mut data, 11 - self.effort, self.quality);
+                if let Some(exif) = exif {
+                    encoder.set_exif_metadata(exif)?;
+                }
+                thumbnail.write_with_encoder(encoder)?;
+            }
+        }
+        Ok(data)
+    }
+}
+
+#[derive(Debug)]
+pub struct Thumbnails {
+    dir: PathBuf,
+    // Longest edges, smallest first
+    sizes: Vec<u32>,
+    // One for each size
+    encodings: Vec<Encoding>,
+    // Keyed on the cache path, so it goes stale along with the file on disk
+    memory: Option<Cache<PathBuf, Bytes>>,
+    low_on_space: AtomicBool,
+}
+
+impl Thumbnails {
+    /// Makes every one of the sizes from a single decode of each photo
+    pub fn new(data_dir: &Path, args: &ThumbnailArgs) -> Result<Self> {
+        ensure!(
+            !args.thumbnail_sizes.is_empty(),
+            "At least one thumbnail size is needed"
+        );
+        let mut sizes = args.thumbnail_sizes.clone();
+        sizes.sort_unstable();
+        sizes.dedup();
+
+        let encodings = sizes
+            .iter()
+            .map(|&size| {
+                Ok(Encoding {
+                    format: setting(&args.thumbnail_format, size, &sizes)?,
+                    quality: setting(&args.thumbnail_quality, size, &sizes)?,
+                    effort: setting(&args.thumbnail_effort, size, &sizes)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let dir = data_dir.join("thumbnails");
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {dir:?}"))?;
+        Ok(Self {
+            dir,
+            sizes,
+            encodings,
+            memory: None,
+            low_on_space: AtomicBool::new(false),
+        })
+    }
+
+    /// Also keeps up to `megabytes` of the most used thumbnails in memory, in front of the disk
+    pub fn with_memory(data_dir: &Path, args: &ThumbnailArgs, megabytes: u64) -> Result<Self> {
+        let memory = (megabytes > 0).then(|| {
+            Cache::builder()
+                .max_capacity(megabytes * 1024 * 1024)
+                .weigher(|_, data: &Bytes| data.len().try_into().unwrap_or(u32::MAX))
+                .build()
+        });
+        Ok(Self {
+            memory,
+            ..Self::new(data_dir, args)?
+        })
+    }
+
+    pub fn sizes(&self) -> &[u32] {
+        &self.sizes
+    }
+
+    fn encoding(&self, size: u32) -> Encoding {
+        let index = self.sizes.iter().position(|&s| s == size).unwrap_or(0);
+        self.encodings[index]
+    }
+
+    pub fn content_type(&self, size: u32) -> &'static str {
+        self.encoding(size).content_type()
+    }
+
+    pub fn extension(&self, size: u32) -> &'static str {
+        self.encoding(size).extension()
+    }
+
+    /// Same contents and settings, same thumbnail. None for media not hashed yet.
+    pub fn etag(&self, media: &Media, size: u32) -> Option<String> {
+        let hash = media.hash.as_ref()?;
+        let edited = edited(media);
+        let suffix = self.encoding(size).suffix();
+        Some(format!(r#""{hash}{edited}-{size}-r{REVISION}-{suffix}""#))
+    }
+
+    /// Space left on the disk the cache is on
+    pub fn free_space(&self) -> io::Result<FreeSpace> {
+        let stats = fs4::statvfs(&self.dir)?;
+        Ok(FreeSpace {
+            available: stats.available_space(),
+            total: stats.total_space(),
+        })
+    }
+
+    /// Set while the disk is nearly full, see [`crate::health::watch_disk`]. New thumbnails are
+    /// still made when asked for, but not saved.
+    pub fn low_on_space(&self) -> bool {
+        self.low_on_space.load(Ordering::Relaxed)
+    }
+
+    /// Returns what it was before
+    pub fn set_low_on_space(&self, low: bool) -> bool {
+        self.low_on_space.swap(low, Ordering::Relaxed)
+    }
+
+    /// The smallest size at least as big as `wanted`, or the biggest there is
+    pub fn closest(&self, wanted: Option<u32>) -> u32 {
+        let wanted = wanted.unwrap_or(DEFAULT_SIZE);
+        let largest = *self.sizes.last().expect("checked in new");
+        self.sizes
+            .iter()
+            .copied()
+            .find(|&size| size >= wanted)
+            .unwrap_or(largest)
+    }
+
+    fn cache_path(&self, media: &Media, size: u32) -> PathBuf {
+        let name = cache_name(media);
+        let suffix = self.encoding(size).suffix();
+        self.dir
+            .join(&name[..2])
+            .join(format!("{name}-{size}-r{REVISION}-{suffix}"))
+    }
+
+    // Next to the photo's thumbnails, so they're pruned along with them
+    fn export_path(&self, media: &Media, export: &Export) -> PathBuf {
+        let name = cache_name(media);
+        let max = export.max.map_or("full".to_string(), |max| max.to_string());
+        let exif = match export.keep_exif {
+            true => "-exif",
+            false => "",
+        };
+        let suffix = export.encoding().suffix();
+        self.dir
+            .join(&name[..2])
+            .join(format!("{name}-export-{max}-r{REVISION}{exif}-{suffix}"))
+    }
+
+    /// A thumbnail already made, from memory or disk, without looking at the photo itself
+    pub fn cached(&self, media: &Media, size: u32) -> Option<Bytes> {
+        let path = self.cache_path(media, size);
+        if let Some(data) = self.memory.as_ref().and_then(|memory| memory.get(&path)) {
+            return Some(data);
+        }
+        let data = info_span!("read_cache").in_scope(|| fs::read(&path)).ok()?;
+        Some(self.remember(path, data))
+    }
+
+    /// Whether get can answer without decoding anything
+    pub fn is_cached(&self, media: &Media, size: u32) -> bool {
+        let path = self.cache_path(media, size);
+        self.memory
+            .as_ref()
+            .is_some_and(|memory| memory.contains_key(&path))
+            || path.exists()
+    }
+
+    /// Returns the thumbnail at `size`, one of [`Self::sizes`], encoded in the format chosen for
+    /// that size, see [`Self::content_type`]. Blocks while decoding.
+    ///
+    /// A missing thumbnail is made along with every other missing size, so the photo is only
+    /// decoded once.
+    pub fn get(&self, media: &Media, file: &SafePath, size: u32) -> Result<Bytes> {
+        let _span = info_span!("thumbnail", path = ?file.relative(), size).entered();
+        if let Some(data) = self.cached(media, size) {
+            return Ok(data);
+        }
+
+        let missing: Vec<u32> = self
+            .sizes
+            .iter()
+            .copied()
+            .filter(|&other| other == size || !self.cache_path(media, other).exists())
+            .collect();
+        let largest = missing.iter().copied().max().unwrap_or(size);
+        let image = info_span!("decode").in_scope(|| decode(media, file, largest))?;
+
+        let mut wanted = None;
+        for other in missing {
+            let data = self.generate(&image, other, &self.cache_path(media, other))?;
+            if other == size {
+                wanted = Some(data);
+            }
+        }
+        let data = wanted.context("Requested size isn't one of the thumbnail sizes")?;
+        Ok(self.remember(self.cache_path(media, size), data))
+    }
+
+    fn generate(&self, image: &DynamicImage, size: u32, path: &Path) -> Result<Vec<u8>> {
+        let thumbnail = info_span!("resize", size).in_scope(|| resize(image, size))?;
+
+        let data =
+            info_span!("encode", size).in_scope(|| self.encoding(size).encode(&thumbnail, None))?;
+        self.save(path, &data)?;
+        Ok(data)
+    }
+
+    fn save(&self, path: &Path, data: &[u8]) -> Result<()> {
+        if self.low_on_space() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Same write then rename as the JSON stores, a half written thumbnail would stick around
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// An export made before, from disk. Exports can be big, so they're not kept in memory.
+    pub fn cached_export(&self, media: &Media, export: &Export) -> Option<Bytes> {
+        let path = self.export_path(media, export);
+        info_span!("read_cache")
+            .in_scope(|| fs::read(path))
+            .ok()
+            .map(Bytes::from)
+    }
+
+    /// Converts a photo, with its edits, to another size and format. Blocks while decoding.
+    pub fn export(&self, media: &Media, file: &SafePath, export: &Export) -> Result<Bytes> {
+        let _span = info_span!("export", path = ?file.relative(), ?export).entered();
+        if let Some(data) = self.cached_export(media, export) {
+            return Ok(data);
+        }
+
+        let size = export.max.unwrap_or(u32::MAX);
+        let image = info_span!("decode").in_scope(|| decode(media, file, size))?;
+        let image = info_span!("resize").in_scope(|| resize(&image, size))?;
+        let exif = match export.keep_exif {
+            true => exif_chunk(file),
+            false => None,
+        };
+        let data = info_span!("encode").in_scope(|| export.encoding().encode(&image, exif))?;
+        self.save(&self.export_path(media, export), &data)?;
+        Ok(Bytes::from(data))
+    }
+
+    /// Loads the thumbnails already on disk for the first `count` photos in `media` into memory,
+    /// every size, returning how many there were. Does nothing without a memory cache.
+    pub fn warm(&self, media: &[Media], count: usize) -> usize {
+        if self.memory.is_none() {
+            return 0;
+        }
+        let mut warmed = 0;
+        for media in media.iter().filter(|m| m.kind == Kind::Image).take(count) {
+            for &size in &self.sizes {
+                let path = self.cache_path(media, size);
+                if let Ok(data) = fs::read(&path) {
+                    self.remember(path, data);
+                    warmed += 1;
+                }
+            }
+        }
+        warmed
+    }
+
+    /// Memory needed to decode a photo as RGBA, for the job limiter's budget
+    pub fn decode_megabytes(&self, media: &Media) -> u32 {
+        decode_megabytes(media, *self.sizes.last().expect("checked in new"))
+    }
+
+    /// Like [`Self::decode_megabytes`], for an export
+    pub fn export_megabytes(&self, media: &Media, export: &Export) -> u32 {
+        decode_megabytes(media, export.max.unwrap_or(u32::MAX))
+    }
+
+    fn remember(&self, path: PathBuf, data: Vec<u8>) -> Bytes {
+        let data = Bytes::from(data);
+        if let Some(memory) = &self.memory {
+            memory.insert(path, data.clone());
+        }
+        data
+    }
+
+    fn cached_files(&self) -> impl Iterator<Item = fs::DirEntry> {
+        fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|shard| fs::read_dir(shard.path()).ok())
+            .flatten()
+            .flatten()
+    }
+
+    pub fn size(&self) -> DiskUsage {
+        self.cached_files()
+            .fold(DiskUsage::default(), |size, file| DiskUsage {
+                files: size.files + 1,
+                bytes: size.bytes + file.metadata().map_or(0, |m| m.len()),
+            })
+    }
+
+    /// Deletes thumbnails and exports of files that are gone or have changed since
+    pub fn prune(&self, media: &[Media]) -> DiskUsage {
+        // Sizes dropped from the ladder go too
+        let keep: HashSet<PathBuf> = media
+            .iter()
+            .flat_map(|m| self.sizes.iter().map(|&size| self.cache_path(m, size)))
+            .collect();
+        // Exports come in any size and format, they're kept as long as their photo is
+        let names: HashSet<String> = media.iter().map(cache_name).collect();
+        let exported = |path: &Path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split_once("-export-"))
+                .is_some_and(|(name, rest)| {
+                    names.contains(name) && rest.contains(&format!("-r{REVISION}"))
+                })
+        };
+
+        let mut removed = DiskUsage::default();
+        for file in self.cached_files() {
+            let path = file.path();
+            if keep.contains(&path) || exported(&path) {
+                continue;
+            }
+            let bytes = file.metadata().map_or(0, |m| m.len());
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    removed.files += 1;
+                    removed.bytes += bytes;
+                }
+                Err(e) => tracing::warn!("Failed to remove {path:?}: {e}"),
+            }
+        }
+
+        removed
+    }
+}
+
+// The last one given for this size, or else for every size
+fn setting<T: Copy>(values: &[PerSize<T>], size: u32, sizes: &[u32]) -> Result<T> {
+    if let Some(other) = values
+        .iter()
+        .filter_map(|value| value.size)
+        .find(|size| !sizes.contains(size))
+    {
+        bail!("A thumbnail setting is given for {other}, which isn't one of the thumbnail sizes");
+    }
+    values
+        .iter()
+        .rev()
+        .find(|value| value.size == Some(size))
+        .or_else(|| values.iter().rev().find(|value| value.size.is_none()))
+        .map(|value| value.value)
+        .with_context(|| format!("A thumbnail setting is missing for size {size}"))
+}
+
+// `largest` is the longest edge the photo is decoded for
+pub fn decode_megabytes(media: &Media, mut largest: u32) -> u32 {
+    let (Some(width), Some(height)) = (media.width, media.height) else {
+        return UNKNOWN_DECODE_MB;
+    };
+    // JPEGs are decoded at the smallest of 1/8, 1/4 and 1/2 still big enough, see decode.
+    // The rare ones that fall back to a full decode are underestimated. So are edited ones,
+    // whose size is after cropping.
+    if let Some(edits) = &media.edits {
+        largest = scaled(largest, edits.zoom(width, height));
+    }
+    let scale = match content_type(&media.path) {
+        Some("image/jpeg") => [8, 4, 2]
+            .into_iter()
+            .find(|&scale| width.min(height).div_ceil(scale) >= largest)
+            .unwrap_or(1),
+        _ => 1,
+    };
+    let pixels = u64::from(width.div_ceil(scale)) * u64::from(height.div_ceil(scale));
+    (pixels * 4)
+        .div_ceil(1024 * 1024)
+        .try_into()
+        .unwrap_or(u32::MAX)
+}
+
+// Keyed on the contents, so a renamed or moved file keeps its thumbnails and copies share them.
+// Media without a hash, from an index written before there were any, go by path and
+// modification time until the next scan hashes them. Indexes can be edited by hand, so a hash
+// is only used as a file name when it looks like one. Edited photos have their own.
+fn cache_name(media: &Media) -> String {
+    let name = match &media.hash {
+        Some(hash) if hash.len() == 32 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            hash.clone()
+        }
+        _ => {
+            let mut hasher = Sha256::new();
+            hasher.update(media.path.as_os_str().as_encoded_bytes());
+            hasher.update(media.modified.unix_timestamp_nanos().to_le_bytes());
+            let hash = hasher.finalize();
+            hash[..16].iter().map(|b| format!("{b:02x}")).collect()
+        }
+    };
+    format!("{name}{}", edited(media))
+}
+
+fn edited(media: &Media) -> String {
+    match &media.edits {
+        Some(edits) => format!("-e{}", edits.key()),
+        None => String::new(),
+    }
+}
+
+fn scaled(size: u32, zoom: f64) -> u32 {
+    (size as f64 * zoom).ceil().min(u32::MAX as f64) as u32
+}
+
+// `size` is the biggest thumbnail the image will be resized to. Edits are applied, and a
+// cropped photo is decoded bigger to make up for what's cut away.
+fn decode(media: &Media, file: &SafePath, size: u32) -> Result<DynamicImage> {
+    let image = match (&media.edits, media.width, media.height) {
+        (Some(edits), Some(width), Some(height)) => {
+            decode_original(media, file, scaled(size, edits.zoom(width, height)))?
+        }
+        _ => decode_original(media, file, size)?,
+    };
+    Ok(match &media.edits {
+        Some(edits) => info_span!("edit").in_scope(|| edits.apply(image)),
+        None => image,
+    })
+}
+
+/// The photo as shown, edits applied, no longer than `size`. For one-off uses in another
+/// format, so nothing is cached.
+pub fn preview(media: &Media, file: &SafePath, size: u32) -> Result<DynamicImage> {
+    let image = decode(media, file, size)?;
+    Ok(resize(&image, size)?.into())
+}
+
+/// The photo as it was taken, edits left off, no longer than `size`. For looking at rather than
+/// showing, so nothing is cached.
+pub fn original(media: &Media, file: &SafePath, size: u32) -> Result<DynamicImage> {
+    let image = decode_original(media, file, size)?;
+    Ok(resize(&image, size)?.into())
+}
+
+fn decode_original(media: &Media, file: &SafePath, size: u32) -> Result<DynamicImage> {
+    // Most of a library is JPEGs, and those can be decoded at 1/2, 1/4 or 1/8 of their size
+    // for a fraction of the work. Anything that path can't handle goes to the image crate.
+    if content_type(&media.path) == Some("image/jpeg") {
+        match decode_jpeg_scaled(file.absolute(), size) {
+            Ok(Some(image)) => return Ok(image),
+            Ok(None) => {}
+            Err(e) => debug!("Scaled decode failed for {:?}: {e}", file.relative()),
+        }
+    }
+
+    decode_file(file.absolute()).with_context(|| format!("Failed to decode {:?}", file.relative()))
+}
+
+// Any format the image crate knows, the right way up
+fn decode_file(path: &Path) -> Result<DynamicImage> {
+    let mut decoder = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// A difference hash of the photo as it was taken: 64 bits, each whether a spot on a 9 by 8 grid
+/// is darker than the one to its right. Photos that look alike differ in few bits, whatever
+/// their size, format or quality. Takes [`decode_megabytes`] with [`PERCEPTUAL_HASH_SIZE`].
+pub fn perceptual_hash(media: &Media, file: &SafePath) -> Result<u64> {
+    let image = decode_original(media, file, PERCEPTUAL_HASH_SIZE)?;
+
+    let grid = imageops::resize(&image.to_luma8(), 9, 8, FilterType::Triangle);
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let darker = grid.get_pixel(x, y).0[0] < grid.get_pixel(x + 1, y).0[0];
+            hash = hash << 1 | u64::from(darker);
+        }
+    }
+    Ok(hash)
+}
+
+fn decode_jpeg_scaled(path: &Path, size: u32) -> Result<Option<DynamicImage>> {
+    let mut decoder = jpeg_decoder::Decoder::new(BufReader::new(File::open(path)?));
+    // The smallest scale that still has an edge at least `size` long
+    let size = size.try_into().unwrap_or(u16::MAX);
+    decoder.scale(size, size)?;
+    let pixels = decoder.decode()?;
+    let Some(info) = decoder.info() else {
+        return Ok(None);
+    };
+
+    let (width, height) = (info.width.into(), info.height.into());
+    // CMYK and 16 bit greyscale are rare enough to leave to the image crate
+    let image = match info.pixel_format {
+        PixelFormat::RGB24 => RgbImage::from_raw(width, height, pixels).map(DynamicImage::from),
+        PixelFormat::L8 => GrayImage::from_raw(width, height, pixels).map(DynamicImage::from),
+        PixelFormat::L16 | PixelFormat::CMYK32 => None,
+    };
+    let orientation = decoder
+        .exif_data()
+        .and_then(Orientation::from_exif_chunk)
+        .unwrap_or(Orientation::NoTransforms);
+    Ok(image.map(|mut image| {
+        image.apply_orientation(orientation);
+        image
+    }))
+}
+
+// Left out of the export when it can't be read, rather than failing it
+fn exif_chunk(file: &SafePath) -> Option<Vec<u8>> {
+    let mut decoder = ImageReader::open(file.absolute())
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    let mut exif = decoder.exif_metadata().ok()??;
+    // The pixels are turned already. Returns what it was, if there was one.
+    let _ = Orientation::remove_from_exif_chunk(&mut exif);
+    Some(exif)
+}
+
+// SIMD Lanczos3, with alpha premultiplied while filtering so transparent areas don't bleed into
+// the edges around them
+fn resize(image: &DynamicImage, size: u32) -> Result<RgbImage> {
+    let converted;
+    let image = match image.pixel_type() {
+        Some(_) => image,
+        None => {
+            converted = image.to_rgba8().into();
+            &converted
+        }
+    };
+
+    // Fits inside size x size, smaller images are left at their size
+    let scale = (size as f64 / image.width().max(image.height()) as f64).min(1.0);
+    let width = ((image.width() as f64 * scale).round() as u32).max(1);
+    let height = ((image.height() as f64 * scale).round() as u32).max(1);
+
+    let mut thumbnail = DynamicImage::new(width, height, image.color());
+    Resizer::new().resize(image, &mut thumbnail, None)?;
+    match thumbnail.color().has_alpha() {
+        true => Ok(flatten(thumbnail.into_rgba8())),
+        false => Ok(thumbnail.into_rgb8()),
+    }
+}
+
+// JPEG has no alpha, transparent parts are shown over white
+fn flatten(image: RgbaImage) -> RgbImage {
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b, a] = image.get_pixel(x, y).0;
+        let over_white = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        Rgb([over_white(r), over_white(g), over_white(b)])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use serde_json::json;
+
+    use super::*;
+    use crate::edits::Edits;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef";
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        thumbnails: ThumbnailArgs,
+    }
+
+    // A cache of its own for each test, removed when it's done
+    struct TempCache(PathBuf);
+
+    impl TempCache {
+        fn new(name: &str, args: &[&str]) -> (Self, Thumbnails) {
+            let dir = std::env::temp_dir().join(format!("m3s-{name}-{}", std::process::id()));
+            let cli = Cli::parse_from(["m3s"].iter().chain(args));
+            let thumbnails = Thumbnails::new(&dir, &cli.thumbnails).unwrap();
+            (Self(dir), thumbnails)
+        }
+    }
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn photo(path: &str, hash: Option<&str>) -> Media {
+        serde_json::from_value(json!({
+            "path": path,
+            "kind": "image",
+            "taken": "2024-05-01T12:00:00Z",
+            "modified": "2024-05-01T12:00:00Z",
+            "size": 1000,
+            "hash": hash,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn cache_name_follows_the_contents() {
+        let original = photo("2024/a.jpg", Some(HASH));
+        let moved = photo("elsewhere/b.jpg", Some(HASH));
+        assert_eq!(cache_name(&original), HASH);
+        assert_eq!(cache_name(&original), cache_name(&moved));
+        assert_ne!(
+            cache_name(&original),
+            cache_name(&photo("2024/a.jpg", Some(&HASH.replace('0', "f"))))
+        );
+    }
+
+    #[test]
+    fn cache_name_falls_back_to_path_and_time() {
+        let unhashed = photo("2024/a.jpg", None);
+        let name = cache_name(&unhashed);
+        assert_eq!(name.len(), 32);
+        assert!(name.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(name, cache_name(&photo("2024/b.jpg", None)));
+
+        let mut later = unhashed.clone();
+        later.modified += time::Duration::seconds(1);
+        assert_ne!(name, cache_name(&later));
+    }
+
+    #[test]
+    fn cache_name_ignores_hashes_that_could_escape_the_cache() {
+        for hash in ["../../../../etc/passwd", "0123", &format!("{HASH}00"), "zz"] {
+            let name = cache_name(&photo("a.jpg", Some(hash)));
+            assert_eq!(name, cache_name(&photo("a.jpg", None)), "{hash}");
+        }
+    }
+
+    #[test]
+    fn edited_photos_have_their_own_thumbnails() {
+        let mut edited = photo("a.jpg", Some(HASH));
+        edited.edits = Some(Edits {
+            rotate: 90,
+            ..Default::default()
+        });
+        let name = cache_name(&edited);
+        assert!(name.starts_with(HASH));
+        assert_ne!(name, HASH);
+
+        edited.edits = Some(Edits {
+            rotate: 180,
+            ..Default::default()
+        });
+        assert_ne!(name, cache_name(&edited));
+    }
+
+    #[test]
+    fn cache_paths_and_etags_differ_by_size_and_format() {
+        let (_temp, thumbnails) =
+            TempCache::new("cache-paths", &["--thumbnail-format", "webp,180=jpeg"]);
+        let media = photo("a.jpg", Some(HASH));
+
+        let small = thumbnails.cache_path(&media, 180);
+        assert_eq!(
+            small,
+            thumbnails
+                .dir
+                .join("01")
+                .join(format!("{HASH}-180-r{REVISION}-q80.jpg"))
+        );
+        let large = thumbnails.cache_path(&media, 720);
+        assert!(large.to_string_lossy().ends_with(".webp"));
+
+        assert_eq!(
+            thumbnails.etag(&media, 180).unwrap(),
+            format!(r#""{HASH}-180-r{REVISION}-q80.jpg""#)
+        );
+        assert_ne!(thumbnails.etag(&media, 180), thumbnails.etag(&media, 360));
+        assert_eq!(
+            thumbnails.etag(&media, 180),
+            thumbnails.etag(&photo("moved/a.jpg", Some(HASH)), 180)
+        );
+        // Nothing that would stay the same when the file changes
+        assert_eq!(thumbnails.etag(&photo("a.jpg", None), 180), None);
+    }
+}
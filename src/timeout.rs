@@ -0,0 +1,123 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::time::Instant;
+
+use crate::api_error::ApiError;
+
+/// How long a request may take to be answered, by kind of route
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub api: Duration,
+    /// Routes given [`expensive`], which may wait their turn for a job slot and then decode a
+    /// large photo
+    pub expensive: Duration,
+}
+
+// The limit timeout goes by, which a route's own layer changes once the request is routed.
+// None for no limit at all.
+#[derive(Clone)]
+struct Limit(Arc<Mutex<Option<Duration>>>);
+
+/// Answers 503 once a request has run too long, which also covers clients sending a body
+/// slowly. The API limit unless the route has [`expensive`] or [`unlimited`] as a route layer.
+///
+/// Only up to the start of the response. Streaming a large file after that takes as long as it
+/// takes.
+pub async fn timeout(
+    State(timeouts): State<Timeouts>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let limit = Limit(Arc::new(Mutex::new(Some(timeouts.api))));
+    request.extensions_mut().insert(limit.clone());
+    let started = Instant::now();
+    let response = next.run(request);
+    tokio::pin!(response);
+
+    loop {
+        let Some(current) = *limit.0.lock().unwrap() else {
+            return response.await;
+        };
+        tokio::select! {
+            response = &mut response => return response,
+            _ = tokio::time::sleep_until(started + current) => {
+                // Changed by the route since, go by that instead
+                if *limit.0.lock().unwrap() != Some(current) {
+                    continue;
+                }
+                // The request span has the path
+                tracing::warn!("Gave up on the request after {}s", current.as_secs());
+                return ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "Took too long to answer")
+                    .into_response();
+            }
+        }
+    }
+}
+
+/// Route layer giving a route the expensive limit
+pub async fn expensive(State(timeouts): State<Timeouts>, request: Request, next: Next) -> Response {
+    set(&request, Some(timeouts.expensive));
+    next.run(request).await
+}
+
+/// Route layer for routes that take as long as they were asked to, such as profiles
+#[cfg(all(unix, feature = "profiling"))]
+pub async fn unlimited(request: Request, next: Next) -> Response {
+    set(&request, None);
+    next.run(request).await
+}
+
+fn set(request: &Request, limit: Option<Duration>) {
+    if let Some(Limit(current)) = request.extensions().get::<Limit>() {
+        *current.lock().unwrap() = limit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt as _;
+
+    use super::*;
+
+    const TIMEOUTS: Timeouts = Timeouts {
+        api: Duration::from_millis(50),
+        expensive: Duration::from_millis(1000),
+    };
+
+    async fn slow() -> &'static str {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        "done"
+    }
+
+    async fn status(path: &str) -> StatusCode {
+        let app = Router::new()
+            .route("/api", get(slow))
+            .route(
+                "/expensive",
+                get(slow).route_layer(middleware::from_fn_with_state(TIMEOUTS, expensive)),
+            )
+            .layer(middleware::from_fn_with_state(TIMEOUTS, timeout));
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn routes_get_the_api_limit() {
+        assert_eq!(status("/api").await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn expensive_routes_get_longer() {
+        assert_eq!(status("/expensive").await, StatusCode::OK);
+    }
+}
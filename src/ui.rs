@@ -0,0 +1,183 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use axum::{
+    http::header,
+    middleware,
+    response::{Html, IntoResponse},
+    routing::get,
+    Router,
+};
+
+use crate::{
+    args::{ThemeArgs, ThemeMode},
+    auth::require_role,
+    base_path::BasePath,
+    library::content_type,
+    login::html_escape,
+    users::Role,
+};
+
+// Compiled in, so the binary is the whole deployment
+const INDEX: &str = include_str!("../assets/index.html");
+const SCRIPT: &str = include_str!("../assets/app.js");
+pub const STYLE: &str = include_str!("../assets/style.css");
+const ADMIN: &str = include_str!("../assets/admin.html");
+const ADMIN_SCRIPT: &str = include_str!("../assets/admin.js");
+const FRAME: &str = include_str!("../assets/frame.html");
+const FRAME_SCRIPT: &str = include_str!("../assets/frame.js");
+const SERVICE_WORKER: &str = include_str!("../assets/sw.js");
+const MANIFEST: &str = include_str!("../assets/manifest.webmanifest");
+const ICON_192: &[u8] = include_bytes!("../assets/icon-192.png");
+const ICON_512: &[u8] = include_bytes!("../assets/icon-512.png");
+
+/// The pages and stylesheet with the configured theme and base path applied, rendered once at
+/// startup. Scripts read the base path from `data-base` on the page.
+#[derive(Debug)]
+pub struct Theme {
+    index: String,
+    admin: String,
+    frame: String,
+    style: String,
+    manifest: String,
+    logo: Option<(&'static str, Vec<u8>)>,
+}
+
+impl Theme {
+    pub fn new(args: ThemeArgs, base: &BasePath) -> Result<Self> {
+        let ThemeArgs {
+            theme,
+            accent_color,
+            title,
+            logo,
+        } = args;
+
+        let logo = match logo {
+            Some(path) => {
+                let content_type = content_type(&path)
+                    .filter(|t| t.starts_with("image/"))
+                    .with_context(|| format!("Logo {path:?} is not an image"))?;
+                let data = std::fs::read(&path)
+                    .with_context(|| format!("Failed to read logo {path:?}"))?;
+                Some((content_type, data))
+            }
+            None => None,
+        };
+
+        let mode = match theme {
+            ThemeMode::Light => "light",
+            ThemeMode::Dark => "dark",
+            ThemeMode::Auto => "auto",
+        };
+        let title = html_escape(&title);
+        let brand = match logo {
+            Some(_) => format!(r#"<img src="/logo" alt="{title}">"#),
+            None => title.clone(),
+        };
+        let base = base.as_str();
+        let render = |page: &str| {
+            page.replacen(
+                "<html>",
+                &format!(r#"<html data-theme="{mode}" data-base="{base}">"#),
+                1,
+            )
+            .replacen("m3s</title>", &format!("{title}</title>"), 1)
+            .replacen(
+                r#"class="brand" href="/">m3s<"#,
+                &format!(r#"class="brand" href="/">{brand}<"#),
+                1,
+            )
+            .replacen(
+                r##"class="brand" href="#/">m3s<"##,
+                &format!(r##"class="brand" href="#/">{brand}<"##),
+                1,
+            )
+            // Every link in the pages is absolute, so they all gain the prefix
+            .replace(r#"href="/"#, &format!(r#"href="{base}/"#))
+            .replace(r#"src="/"#, &format!(r#"src="{base}/"#))
+            .replace(r#"action="/"#, &format!(r#"action="{base}/"#))
+        };
+
+        let mut style = STYLE.to_string();
+        if let Some(accent) = accent_color {
+            style.push_str(&format!("\n:root {{ --accent: {accent}; }}\n"));
+        }
+
+        Ok(Self {
+            index: render(INDEX),
+            admin: render(ADMIN),
+            frame: render(FRAME),
+            style,
+            manifest: MANIFEST.replace(r#""/"#, &format!(r#""{base}/"#)),
+            logo,
+        })
+    }
+}
+
+pub fn router(theme: Arc<Theme>) -> Router {
+    let admin = Router::new()
+        .route(
+            "/admin",
+            get({
+                let theme = theme.clone();
+                || async move { Html(theme.admin.clone()) }
+            }),
+        )
+        .route("/admin.js", get(|| asset("text/javascript", ADMIN_SCRIPT)))
+        .route_layer(middleware::from_fn_with_state(Role::Admin, require_role));
+
+    Router::new()
+        .route(
+            "/",
+            get({
+                let theme = theme.clone();
+                || async move { Html(theme.index.clone()) }
+            }),
+        )
+        .route("/app.js", get(|| asset("text/javascript", SCRIPT)))
+        .route(
+            "/frame",
+            get({
+                let theme = theme.clone();
+                || async move { Html(theme.frame.clone()) }
+            }),
+        )
+        .route("/frame.js", get(|| asset("text/javascript", FRAME_SCRIPT)))
+        .route(
+            "/style.css",
+            get(|| async move { asset("text/css", theme.style.clone()).await }),
+        )
+        // Served next to the pages so its scope covers the whole app
+        .route("/sw.js", get(|| asset("text/javascript", SERVICE_WORKER)))
+        .route_layer(middleware::from_fn_with_state(Role::Viewer, require_role))
+        .merge(admin)
+}
+
+/// Browsers fetch the manifest and icons without cookies, so these skip authentication
+pub fn public_router(theme: &Theme) -> Router {
+    let manifest = theme.manifest.clone();
+    let router = Router::new()
+        .route(
+            "/manifest.webmanifest",
+            get(|| asset("application/manifest+json", manifest)),
+        )
+        .route("/icon-192.png", get(|| asset("image/png", ICON_192)))
+        .route("/icon-512.png", get(|| asset("image/png", ICON_512)));
+
+    match theme.logo.clone() {
+        Some((content_type, data)) => {
+            router.route("/logo", get(move || asset(content_type, data.clone())))
+        }
+        None => router,
+    }
+}
+
+async fn asset<B: IntoResponse>(content_type: &'static str, body: B) -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        body,
+    )
+}
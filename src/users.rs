@@ -0,0 +1,339 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{ensure, Result};
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::StatusCode,
+    middleware,
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    api_error::{ApiError, Invalid},
+    audit::{Action, Audit},
+    auth::{hash_password, require_role, verify_password},
+    safe_path::validate_relative,
+    store::{load_json, save_json},
+};
+
+// Ordered from least to most privileged, each role can do everything the ones before it can
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    // Accounts created before roles were split up were plain users
+    #[serde(alias = "user")]
+    Viewer,
+    Uploader,
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    pub role: Role,
+    // Library subdirectory the user is confined to, the whole shared library when unset
+    pub root: Option<PathBuf>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    // Sent the weekly digest at email
+    #[serde(default)]
+    pub digest: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: OffsetDateTime,
+    password_hash: String,
+}
+
+#[derive(Debug)]
+pub struct UserStore {
+    path: PathBuf,
+    users: RwLock<Vec<User>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UserUpdate {
+    pub password: Option<String>,
+    pub role: Option<Role>,
+    // Distinguishes a missing field (keep) from an explicit null (reset to the whole library)
+    #[serde(default, deserialize_with = "explicit_null")]
+    pub root: Option<Option<PathBuf>>,
+    pub groups: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    pub email: Option<Option<String>>,
+    pub digest: Option<bool>,
+}
+
+fn explicit_null<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl UserStore {
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join("users.json");
+        let users = load_json(&path)?;
+
+        Ok(Self {
+            path,
+            users: RwLock::new(users),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.read().unwrap().is_empty()
+    }
+
+    pub fn list(&self) -> Vec<User> {
+        self.users.read().unwrap().clone()
+    }
+
+    pub fn get(&self, username: &str) -> Option<User> {
+        self.users
+            .read()
+            .unwrap()
+            .iter()
+            .find(|u| u.username == username)
+            .cloned()
+    }
+
+    pub fn create(
+        &self,
+        username: String,
+        password: &str,
+        role: Role,
+        root: Option<PathBuf>,
+        groups: Vec<String>,
+    ) -> Result<User> {
+        ensure!(
+            !username.is_empty() && !username.contains(':'),
+            Invalid("Username must be non-empty and must not contain ':'".into())
+        );
+        let root = root.as_deref().map(validate_relative).transpose()?;
+
+        let user = User {
+            username,
+            role,
+            root,
+            groups,
+            email: None,
+            digest: false,
+            created: OffsetDateTime::now_utc(),
+            password_hash: hash_password(password)?,
+        };
+
+        // Saved before it's swapped in, so a failed write changes nothing
+        let mut users = self.users.write().unwrap();
+        ensure!(
+            users.iter().all(|u| u.username != user.username),
+            Invalid(format!("User {} already exists", user.username))
+        );
+        let mut updated = users.clone();
+        updated.push(user.clone());
+        save_json(&self.path, &updated)?;
+        *users = updated;
+
+        Ok(user)
+    }
+
+    pub fn update(&self, username: &str, update: UserUpdate) -> Result<Option<User>> {
+        let password_hash = update.password.as_deref().map(hash_password).transpose()?;
+        let root = match update.root {
+            Some(Some(root)) => Some(Some(validate_relative(&root)?)),
+            root => root,
+        };
+        if let Some(Some(email)) = &update.email {
+            validate_email(email)?;
+        }
+
+        let mut users = self.users.write().unwrap();
+        let mut updated = users.clone();
+        let Some(user) = updated.iter_mut().find(|u| u.username == username) else {
+            return Ok(None);
+        };
+        let email = update.email.unwrap_or_else(|| user.email.clone());
+        let digest = update.digest.unwrap_or(user.digest);
+        ensure!(
+            !digest || email.is_some(),
+            Invalid("A digest needs an email address".into())
+        );
+
+        if let Some(password_hash) = password_hash {
+            user.password_hash = password_hash;
+        }
+        if let Some(role) = update.role {
+            user.role = role;
+        }
+        if let Some(root) = root {
+            user.root = root;
+        }
+        if let Some(groups) = update.groups {
+            user.groups = groups;
+        }
+        user.email = email;
+        user.digest = digest;
+
+        let user = user.clone();
+        save_json(&self.path, &updated)?;
+        *users = updated;
+
+        Ok(Some(user))
+    }
+
+    pub fn delete(&self, username: &str) -> Result<bool> {
+        let mut users = self.users.write().unwrap();
+        let mut updated = users.clone();
+        updated.retain(|u| u.username != username);
+
+        if updated.len() == users.len() {
+            return Ok(false);
+        }
+
+        save_json(&self.path, &updated)?;
+        *users = updated;
+        Ok(true)
+    }
+
+    pub fn verify(&self, username: &str, password: &str) -> Option<User> {
+        let user = self.get(username)?;
+        verify_password(password, &user.password_hash).then_some(user)
+    }
+}
+
+// Ends up in mail headers, where a line break would let it add its own
+fn validate_email(email: &str) -> Result<()> {
+    let valid = email.split_once('@').is_some_and(|(local, domain)| {
+        !local.is_empty() && !domain.is_empty() && !domain.contains('@')
+    });
+    ensure!(
+        valid
+            && !email
+                .contains(|c: char| c.is_whitespace() || c.is_control() || "<>,;\"".contains(c)),
+        Invalid(format!("Invalid email address {email:?}"))
+    );
+    Ok(())
+}
+
+pub fn router() -> Router<Arc<UserStore>> {
+    Router::new()
+        .route("/", get(list_users).post(create_user))
+        .route(
+            "/:username",
+            get(get_user).patch(update_user).delete(delete_user),
+        )
+        .route_layer(middleware::from_fn_with_state(Role::Admin, require_role))
+}
+
+#[derive(Debug, Serialize)]
+struct UserView {
+    username: String,
+    role: Role,
+    root: Option<PathBuf>,
+    groups: Vec<String>,
+    email: Option<String>,
+    digest: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    created: OffsetDateTime,
+}
+
+impl From<User> for UserView {
+    fn from(u: User) -> Self {
+        Self {
+            username: u.username,
+            role: u.role,
+            root: u.root,
+            groups: u.groups,
+            email: u.email,
+            digest: u.digest,
+            created: u.created,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateUser {
+    username: String,
+    password: String,
+    role: Role,
+    root: Option<PathBuf>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+fn internal_error(e: impl std::fmt::Display) -> ApiError {
+    ApiError::internal("Failed to update users", e)
+}
+
+async fn list_users(State(store): State<Arc<UserStore>>) -> Json<Vec<UserView>> {
+    Json(store.list().into_iter().map(Into::into).collect())
+}
+
+async fn get_user(
+    State(store): State<Arc<UserStore>>,
+    UrlPath(username): UrlPath<String>,
+) -> Result<Json<UserView>, ApiError> {
+    let user = store.get(&username).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(user.into()))
+}
+
+async fn create_user(
+    State(store): State<Arc<UserStore>>,
+    audit: Audit,
+    Json(CreateUser {
+        username,
+        password,
+        role,
+        root,
+        groups,
+    }): Json<CreateUser>,
+) -> Result<impl IntoResponse, ApiError> {
+    // Hashing is slow, keep it off the async workers
+    let user =
+        tokio::task::spawn_blocking(move || store.create(username, &password, role, root, groups))
+            .await
+            .map_err(internal_error)?
+            .map_err(|e| ApiError::invalid_or_internal("Failed to create user", e))?;
+
+    audit.record(Action::UserCreated, &user.username);
+    Ok((StatusCode::CREATED, Json(UserView::from(user))))
+}
+
+async fn update_user(
+    State(store): State<Arc<UserStore>>,
+    audit: Audit,
+    UrlPath(username): UrlPath<String>,
+    Json(update): Json<UserUpdate>,
+) -> Result<Json<UserView>, ApiError> {
+    let user = tokio::task::spawn_blocking(move || store.update(&username, update))
+        .await
+        .map_err(internal_error)?
+        .map_err(|e| ApiError::invalid_or_internal("Failed to update user", e))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    audit.record(Action::UserUpdated, &user.username);
+    Ok(Json(user.into()))
+}
+
+async fn delete_user(
+    State(store): State<Arc<UserStore>>,
+    audit: Audit,
+    UrlPath(username): UrlPath<String>,
+) -> Result<StatusCode, ApiError> {
+    if !store.delete(&username).map_err(internal_error)? {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    audit.record(Action::UserDeleted, &username);
+    Ok(StatusCode::NO_CONTENT)
+}
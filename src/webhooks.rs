@@ -0,0 +1,362 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::{header, StatusCode},
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get},
+    Json, Router,
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+use crate::{
+    api_error::ApiError,
+    audit::{Action, Audit},
+    auth::require_role,
+    library::{Library, LibraryEvent},
+    store::{load_json, save_json},
+    users::Role,
+};
+
+// Sent along with each delivery, the signature is of the body with the webhook's secret
+const EVENT_HEADER: &str = "x-m3s-event";
+const DELIVERY_HEADER: &str = "x-m3s-delivery";
+const SIGNATURE_HEADER: &str = "x-m3s-signature";
+// Waits double after each failed attempt, about half a minute in all
+const ATTEMPTS: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const TIMEOUT: Duration = Duration::from_secs(10);
+// New files listed in one media.added, a big import sends the newest and the count
+const MAX_ADDED: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    /// New files found by a scan
+    #[serde(rename = "media.added")]
+    MediaAdded,
+    #[serde(rename = "scan.completed")]
+    ScanCompleted,
+    #[serde(rename = "scan.failed")]
+    ScanFailed,
+    #[serde(rename = "library.unreachable")]
+    LibraryUnreachable,
+    #[serde(rename = "library.reachable")]
+    LibraryReachable,
+    /// A verification found indexed files missing or changed on disk
+    #[serde(rename = "integrity.failed")]
+    IntegrityFailed,
+}
+
+impl Event {
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::MediaAdded => "media.added",
+            Event::ScanCompleted => "scan.completed",
+            Event::ScanFailed => "scan.failed",
+            Event::LibraryUnreachable => "library.unreachable",
+            Event::LibraryReachable => "library.reachable",
+            Event::IntegrityFailed => "integrity.failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// Every event when empty
+    pub events: Vec<Event>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: OffsetDateTime,
+    // Kept as is, unlike API keys, as every delivery is signed with it
+    secret: String,
+}
+
+/// Outgoing webhooks for automations such as Home Assistant or n8n to react to the library.
+///
+/// Each delivery is a JSON POST with the event's name, time and data, signed with HMAC-SHA256
+/// over the body using the webhook's secret. Failed deliveries are retried a few times, waiting
+/// longer each time, and dropped after that.
+#[derive(Debug)]
+pub struct Webhooks {
+    path: PathBuf,
+    hooks: RwLock<Vec<Webhook>>,
+    http: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join("webhooks.json");
+
+        let hooks = load_json(&path)?;
+
+        Ok(Self {
+            path,
+            hooks: RwLock::new(hooks),
+            http: reqwest::Client::builder().timeout(TIMEOUT).build()?,
+        })
+    }
+
+    /// Sends the library's events until the server stops
+    pub fn follow(self: &Arc<Self>, library: &Library) {
+        let mut events = library.events();
+        let webhooks = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Webhooks missed {missed} library events");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                for (event, data) in payloads(&event) {
+                    webhooks.send(event, data);
+                }
+            }
+        });
+    }
+
+    /// Delivers to every webhook that wants the event, in the background
+    pub fn send(&self, event: Event, data: Value) {
+        let hooks: Vec<Webhook> = self
+            .hooks
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|hook| hook.events.is_empty() || hook.events.contains(&event))
+            .cloned()
+            .collect();
+        if hooks.is_empty() {
+            return;
+        }
+
+        let body = json!({
+            "event": event,
+            "time": OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+            "data": data,
+        })
+        .to_string();
+        for hook in hooks {
+            tokio::spawn(deliver(self.http.clone(), hook, event, body.clone()));
+        }
+    }
+
+    /// Returns the stored webhook and its secret, which is only shown here
+    pub fn create(&self, url: String, events: Vec<Event>) -> Result<(Webhook, String)> {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let secret = BASE64_URL_SAFE_NO_PAD.encode(secret);
+        let mut id = [0u8; 6];
+        rand::thread_rng().fill_bytes(&mut id);
+
+        let hook = Webhook {
+            id: BASE64_URL_SAFE_NO_PAD.encode(id),
+            url,
+            events,
+            created: OffsetDateTime::now_utc(),
+            secret: secret.clone(),
+        };
+
+        // Saved before it's swapped in, so a failed write changes nothing
+        let mut hooks = self.hooks.write().unwrap();
+        let mut updated = hooks.clone();
+        updated.push(hook.clone());
+        save_json(&self.path, &updated)?;
+        *hooks = updated;
+
+        Ok((hook, secret))
+    }
+
+    pub fn list(&self) -> Vec<Webhook> {
+        self.hooks.read().unwrap().clone()
+    }
+
+    pub fn remove(&self, id: &str) -> Result<bool> {
+        let mut hooks = self.hooks.write().unwrap();
+        let mut updated = hooks.clone();
+        updated.retain(|hook| hook.id != id);
+
+        if updated.len() == hooks.len() {
+            return Ok(false);
+        }
+
+        save_json(&self.path, &updated)?;
+        *hooks = updated;
+        Ok(true)
+    }
+}
+
+/// What a library event is sent as, none or more events with their data
+pub fn payloads(event: &LibraryEvent) -> Vec<(Event, Value)> {
+    match event {
+        LibraryEvent::ScanStarted => Vec::new(),
+        LibraryEvent::Scanned { count, added } => {
+            let mut payloads = Vec::new();
+            if !added.is_empty() {
+                let media = &added[..added.len().min(MAX_ADDED)];
+                let data = json!({ "count": added.len(), "media": media });
+                payloads.push((Event::MediaAdded, data));
+            }
+            let data = json!({ "count": count, "added": added.len() });
+            payloads.push((Event::ScanCompleted, data));
+            payloads
+        }
+        LibraryEvent::ScanFailed { error } => vec![(Event::ScanFailed, json!({ "error": error }))],
+        LibraryEvent::Unreachable => vec![(Event::LibraryUnreachable, json!({}))],
+        LibraryEvent::Reachable => vec![(Event::LibraryReachable, json!({}))],
+    }
+}
+
+async fn deliver(http: reqwest::Client, hook: Webhook, event: Event, body: String) {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(hook.secret.as_bytes()).expect("HMAC accepts any key");
+    mac.update(body.as_bytes());
+    let signature = format!("sha256={:x}", mac.finalize().into_bytes());
+    // The same for every attempt, so receivers can tell a retry from a new event
+    let mut delivery = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut delivery);
+    let delivery = BASE64_URL_SAFE_NO_PAD.encode(delivery);
+
+    let mut delay = RETRY_DELAY;
+    for attempt in 1..=ATTEMPTS {
+        let response = http
+            .post(&hook.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event.name())
+            .header(DELIVERY_HEADER, &delivery)
+            .header(SIGNATURE_HEADER, &signature)
+            .body(body.clone())
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => {
+                debug!("Delivered {} to webhook {}", event.name(), hook.id);
+                return;
+            }
+            // Trying again won't change the answer, except to slow down
+            Ok(response)
+                if response.status().is_client_error()
+                    && response.status() != StatusCode::TOO_MANY_REQUESTS =>
+            {
+                warn!(
+                    "Webhook {} refused {}: {}",
+                    hook.id,
+                    event.name(),
+                    response.status()
+                );
+                return;
+            }
+            Ok(response) => debug!(
+                "Webhook {} answered {} on attempt {attempt}",
+                hook.id,
+                response.status()
+            ),
+            Err(e) => debug!("Webhook {} failed on attempt {attempt}: {e}", hook.id),
+        }
+        if attempt < ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    warn!(
+        "Gave up delivering {} to webhook {} after {ATTEMPTS} attempts",
+        event.name(),
+        hook.id
+    );
+}
+
+pub fn router() -> Router<Arc<Webhooks>> {
+    Router::new()
+        .route("/", get(list_webhooks).post(create_webhook))
+        .route("/:id", delete(remove_webhook))
+        .route_layer(middleware::from_fn_with_state(Role::Admin, require_role))
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookView {
+    id: String,
+    url: String,
+    events: Vec<Event>,
+    #[serde(with = "time::serde::rfc3339")]
+    created: OffsetDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+impl From<Webhook> for WebhookView {
+    fn from(hook: Webhook) -> Self {
+        Self {
+            id: hook.id,
+            url: hook.url,
+            events: hook.events,
+            created: hook.created,
+            secret: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateWebhook {
+    url: String,
+    #[serde(default)]
+    events: Vec<Event>,
+}
+
+async fn list_webhooks(State(webhooks): State<Arc<Webhooks>>) -> Json<Vec<WebhookView>> {
+    Json(webhooks.list().into_iter().map(Into::into).collect())
+}
+
+async fn create_webhook(
+    State(webhooks): State<Arc<Webhooks>>,
+    audit: Audit,
+    Json(CreateWebhook { url, events }): Json<CreateWebhook>,
+) -> Result<impl IntoResponse, ApiError> {
+    let valid = reqwest::Url::parse(&url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+    if !valid {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Webhook URLs must be absolute http or https URLs",
+        ));
+    }
+    let (hook, secret) = webhooks
+        .create(url, events)
+        .map_err(|e| ApiError::internal("Failed to store webhook", e))?;
+    audit.record(Action::WebhookCreated, &hook.id);
+
+    let view = WebhookView {
+        secret: Some(secret),
+        ..hook.into()
+    };
+    Ok((StatusCode::CREATED, Json(view)))
+}
+
+async fn remove_webhook(
+    State(webhooks): State<Arc<Webhooks>>,
+    audit: Audit,
+    UrlPath(id): UrlPath<String>,
+) -> Result<StatusCode, ApiError> {
+    let removed = webhooks
+        .remove(&id)
+        .map_err(|e| ApiError::internal("Failed to remove webhook", e))?;
+    if !removed {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    audit.record(Action::WebhookRemoved, &id);
+    Ok(StatusCode::NO_CONTENT)
+}